@@ -0,0 +1,48 @@
+use anyhow::Result;
+use serde_json::Value as JsonValue;
+use spin_sdk::sqlite::Connection;
+
+use crate::clock::Stamp;
+use crate::persistence;
+
+/// The entity a command targeted, before and after it ran
+#[derive(Debug, Default)]
+pub(crate) struct Images {
+    pub target: Option<String>,
+    pub before: Option<JsonValue>,
+    pub after: Option<JsonValue>,
+}
+
+/// One record of the audit log
+pub(crate) struct AuditRecord<'a> {
+    /// when and by whom the command ran
+    pub stamp: &'a Stamp,
+    pub request_id: &'a str,
+    pub command: &'a str,
+    pub entity: &'a str,
+    pub images: &'a Images,
+    pub status: u16,
+    pub outcome: &'a str,
+}
+
+/// How a command ended, by the status it answered
+pub(crate) fn outcome(status: u16) -> &'static str {
+    match status {
+        200..=299 => "succeeded",
+        400..=499 => "rejected",
+        _ => "failed",
+    }
+}
+
+/// Inserts the record in the transaction of the write it describes, so both
+/// are committed or neither is
+pub(crate) fn insert(con: &Connection, record: &AuditRecord) -> Result<()> {
+    persistence::insert_audit(con, record)
+}
+
+/// Writes the record of a command that wrote nothing, because it was
+/// rejected, failed, replayed or rolled back, on a connection of its own
+pub(crate) fn write(record: &AuditRecord) -> Result<()> {
+    let con = Connection::open_default()?;
+    persistence::insert_audit(&con, record)
+}
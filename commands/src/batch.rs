@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde_json::{json, Value as JsonValue};
+use spin_sdk::http::{Params, Request, Response, ResponseBuilder};
+use spin_sdk::sqlite::Connection;
+use telemetry::RequestContext;
+
+use crate::audit::{self, AuditRecord, Images};
+use crate::bus::{self, Input, Rejection, Reply};
+use crate::clock::{Clock, Stamp};
+use crate::models::{BatchModel, BatchOperationModel, BatchOperationResultModel, BatchResultModel};
+use crate::persistence;
+
+/// Largest number of operations accepted in a batch
+const MAX_OPERATIONS: usize = 100;
+
+/// Why an operation failed, with the status it would have answered on its own
+struct Failure {
+    status: u16,
+    message: String,
+    /// id of the entity the operation targeted, if known
+    target: Option<String>,
+}
+
+impl Failure {
+    fn new(status: u16, message: impl Into<String>) -> Failure {
+        Failure { status, message: message.into(), target: None }
+    }
+
+    fn on(self, target: Option<String>) -> Failure {
+        Failure { target, ..self }
+    }
+}
+
+impl From<Rejection> for Failure {
+    fn from(rejection: Rejection) -> Failure {
+        match rejection.errors.is_empty() {
+            true => Failure::new(rejection.status, rejection.message),
+            false => Failure::new(rejection.status, format!("{}: {}", rejection.message, rejection.errors.join(", "))),
+        }
+    }
+}
+
+type Outcome = std::result::Result<(u16, Option<String>, JsonValue, Images), Failure>;
+
+/// An operation that ran, recorded in the audit log once the batch ended
+struct Executed {
+    command: String,
+    entity: &'static str,
+    images: Images,
+    status: u16,
+}
+
+impl Executed {
+    fn record<'a>(&'a self, ctx: &'a RequestContext, stamp: &'a Stamp, outcome: &'a str) -> AuditRecord<'a> {
+        AuditRecord {
+            stamp,
+            request_id: &ctx.request_id,
+            command: &self.command,
+            entity: self.entity,
+            images: &self.images,
+            status: self.status,
+            outcome,
+        }
+    }
+}
+
+/// Executes the operations of a batch in order in one transaction. The first
+/// failing operation rolls back everything before it. Operations are audited
+/// in the transaction once they all succeeded, and otherwise after it was
+/// rolled back, as `rolledBack`.
+#[tracing::instrument(name = "batch", skip_all)]
+pub(crate) fn batch(req: Request, _: Params, clock: &dyn Clock) -> Result<Response> {
+    let model: BatchModel = match serde_json::from_slice(req.body()) {
+        Ok(model) => model,
+        Err(e) => return Ok(Response::new(400, format!("invalid batch: {}", e))),
+    };
+    if model.operations.is_empty() || model.operations.len() > MAX_OPERATIONS {
+        return Ok(Response::new(400, format!("a batch has 1 to {} operations", MAX_OPERATIONS)));
+    }
+
+    let ctx = RequestContext::from_request(&req);
+    let stamp = Stamp::new(clock, &ctx);
+    let con = Connection::open_default()?;
+    persistence::begin(&con)?;
+    let mut created: HashMap<String, String> = HashMap::new();
+    let mut results = Vec::new();
+    let mut executed = Vec::new();
+
+    for (index, operation) in model.operations.into_iter().enumerate() {
+        let op = operation.op.clone();
+        let reference = operation.reference.clone();
+        let entity = bus::registry().into_iter().find(|r| r.name == op).map(|r| r.entity);
+        match execute(&con, operation, &created, &stamp) {
+            Ok((status, id, body, images)) => {
+                if let (Some(reference), Some(id)) = (&reference, id) {
+                    created.insert(reference.clone(), id);
+                }
+                if let Some(entity) = entity {
+                    executed.push(Executed { command: op.clone(), entity, images, status });
+                }
+                let body = (!body.is_null()).then_some(body);
+                results.push(BatchOperationResultModel { index, op, reference, status, body });
+            }
+            Err(failure) => {
+                persistence::rollback(&con);
+                let status = failure.status;
+                for operation in &executed {
+                    audit::write(&operation.record(&ctx, &stamp, "rolledBack"))?;
+                }
+                // unknown operations are not commands, there is nothing to audit
+                if let Some(entity) = entity {
+                    let images = Images { target: failure.target.clone(), ..Images::default() };
+                    let failed = Executed { command: op.clone(), entity, images, status };
+                    audit::write(&failed.record(&ctx, &stamp, audit::outcome(status)))?;
+                }
+                results.push(BatchOperationResultModel {
+                    index,
+                    op,
+                    reference,
+                    status,
+                    body: Some(json!({ "error": failure.message })),
+                });
+                let result = BatchResultModel {
+                    committed: false,
+                    failed_at: Some(index),
+                    error: Some(failure.message),
+                    results,
+                };
+                return respond(if status >= 500 { 500 } else { 422 }, &result);
+            }
+        }
+    }
+
+    for operation in &executed {
+        let record = operation.record(&ctx, &stamp, audit::outcome(operation.status));
+        if let Err(e) = audit::insert(&con, &record) {
+            persistence::rollback(&con);
+            return Err(e);
+        }
+    }
+    persistence::commit(&con, &ctx)?;
+    respond(200, &BatchResultModel { committed: true, failed_at: None, error: None, results })
+}
+
+fn respond(status: u16, result: &BatchResultModel) -> Result<Response> {
+    Ok(ResponseBuilder::new(status)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(result)?)
+        .build())
+}
+
+/// Runs one operation through the handler of its command, returning its
+/// status, the id it created, its body and the images of its entity
+fn execute(con: &Connection, operation: BatchOperationModel, created: &HashMap<String, String>,
+           stamp: &Stamp) -> Outcome {
+    let Some(registration) = bus::registry().into_iter().find(|r| r.name == operation.op) else {
+        return Err(Failure::new(400, format!("unknown operation {}", operation.op)));
+    };
+    let id = match operation.id.map(|id| resolve(id, created)).transpose()? {
+        None => None,
+        Some(JsonValue::String(id)) => Some(id),
+        Some(_) => return Err(Failure::new(400, "id must be a string or a reference")),
+    };
+    let body = match operation.body {
+        Some(body) => resolve(body, created)?,
+        None => JsonValue::Null,
+    };
+    let target = id.clone();
+    let (reply, images) = (registration.execute)(con, Input { id, body }, stamp)
+        .map_err(|rejection| Failure::from(rejection).on(target.clone()))?;
+    match reply {
+        Reply::Created { id, body } => Ok((201, Some(id), body, images)),
+        Reply::Updated(body) => Ok((200, None, body, images)),
+        Reply::Deleted => Ok((204, None, JsonValue::Null, images)),
+        Reply::NotFound => {
+            let message = format!("{}: {} not found", operation.op, target.as_deref().unwrap_or_default());
+            Err(Failure::new(404, message).on(target))
+        }
+        Reply::Conflict(error) => Err(Failure::new(409, error).on(target)),
+        Reply::Invalid(errors) => Err(Failure::new(422, format!("invalid state: {}", errors.join(", "))).on(target)),
+    }
+}
+
+/// Replaces every `{"$ref": "<ref>"}` by the id created under that reference
+fn resolve(value: JsonValue, created: &HashMap<String, String>) -> std::result::Result<JsonValue, Failure> {
+    match value {
+        JsonValue::Object(map) if map.len() == 1 && map.contains_key("$ref") => {
+            let reference = map["$ref"].as_str().unwrap_or_default();
+            created
+                .get(reference)
+                .map(|id| JsonValue::String(id.clone()))
+                .ok_or_else(|| Failure::new(400, format!("unknown reference {}", reference)))
+        }
+        JsonValue::Object(map) => Ok(JsonValue::Object(
+            map.into_iter()
+                .map(|(key, value)| Ok((key, resolve(value, created)?)))
+                .collect::<std::result::Result<_, Failure>>()?,
+        )),
+        JsonValue::Array(values) => Ok(JsonValue::Array(
+            values
+                .into_iter()
+                .map(|value| resolve(value, created))
+                .collect::<std::result::Result<_, Failure>>()?,
+        )),
+        value => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn created() -> HashMap<String, String> {
+        HashMap::from([("loc".to_string(), "l-1".to_string()), ("emp".to_string(), "e-1".to_string())])
+    }
+
+    fn resolved(value: JsonValue) -> JsonValue {
+        match resolve(value, &created()) {
+            Ok(value) => value,
+            Err(failure) => panic!("{}", failure.message),
+        }
+    }
+
+    fn failure(value: JsonValue) -> Failure {
+        match resolve(value, &created()) {
+            Ok(value) => panic!("resolved to {}", value),
+            Err(failure) => failure,
+        }
+    }
+
+    #[test]
+    fn references_are_replaced_by_created_ids() {
+        assert_eq!(resolved(json!({ "$ref": "loc" })), json!("l-1"));
+        assert_eq!(
+            resolved(json!({ "firstName": "Ada", "plid": { "$ref": "loc" }, "ids": [{ "$ref": "emp" }, 1] })),
+            json!({ "firstName": "Ada", "plid": "l-1", "ids": ["e-1", 1] })
+        );
+    }
+
+    #[test]
+    fn objects_with_more_than_a_reference_are_kept() {
+        let value = json!({ "$ref": "loc", "note": "not a reference" });
+        assert_eq!(resolved(value.clone()), value);
+        assert_eq!(resolved(json!("loc")), json!("loc"));
+    }
+
+    #[test]
+    fn unknown_references_fail_the_operation() {
+        let missing = failure(json!({ "body": { "$ref": "missing" } }));
+        assert_eq!((missing.status, missing.message.as_str()), (400, "unknown reference missing"));
+        assert_eq!(failure(json!({ "$ref": 7 })).status, 400);
+    }
+}
@@ -0,0 +1,335 @@
+use std::rc::Rc;
+use std::time::Instant;
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value as JsonValue};
+use spin_sdk::http::{Params, Request, Response, ResponseBuilder};
+use spin_sdk::sqlite::Connection;
+use telemetry::RequestContext;
+
+use crate::audit::{self, AuditRecord, Images};
+use crate::clock::{Clock, Stamp};
+use crate::handlers::*;
+use crate::persistence;
+
+/// Scope a caller needs for a command unless it asks for another
+pub(crate) const SCOPE_WRITE: &str = "write";
+/// Scope granting every command, and the only one for maintenance routes
+pub(crate) const SCOPE_ADMIN: &str = "admin";
+
+/// What a command is built from: the id in the path, or of a batch operation,
+/// and the JSON body
+pub(crate) struct Input {
+    pub id: Option<String>,
+    pub body: JsonValue,
+}
+
+impl Input {
+    pub(crate) fn id(&self) -> std::result::Result<String, Rejection> {
+        self.id.clone().ok_or_else(|| Rejection::new(400, "id is required"))
+    }
+
+    pub(crate) fn body<T: DeserializeOwned>(&self) -> std::result::Result<T, Rejection> {
+        if self.body.is_null() {
+            return Err(Rejection::new(400, "body is required"));
+        }
+        serde_json::from_value(self.body.clone()).map_err(|e| Rejection::new(400, format!("invalid body: {}", e)))
+    }
+}
+
+/// What a handler did
+pub(crate) enum Reply {
+    Created { id: String, body: JsonValue },
+    Updated(JsonValue),
+    Deleted,
+    NotFound,
+    /// the command conflicts with the current state, e.g. deleting a location
+    /// people still live at
+    Conflict(String),
+    /// the state the command would write fails validation, e.g. a version
+    /// restored from before a rule was introduced
+    Invalid(Vec<String>),
+}
+
+impl Reply {
+    pub(crate) fn created<T: Serialize>(id: String, model: &T) -> Result<Reply> {
+        Ok(Reply::Created { id, body: serde_json::to_value(model)? })
+    }
+
+    /// `Updated` with the model, or `NotFound` when there was nothing to update
+    pub(crate) fn updated<T: Serialize>(model: Option<T>) -> Result<Reply> {
+        match model {
+            Some(model) => Ok(Reply::Updated(serde_json::to_value(&model)?)),
+            None => Ok(Reply::NotFound),
+        }
+    }
+
+    pub(crate) fn deleted(deleted: bool) -> Reply {
+        if deleted { Reply::Deleted } else { Reply::NotFound }
+    }
+
+    fn into_response(self) -> Result<Response> {
+        let json = |status: u16, body: &JsonValue| -> Result<Response> {
+            Ok(ResponseBuilder::new(status)
+                .header("Content-Type", "application/json")
+                .body(serde_json::to_vec(body)?)
+                .build())
+        };
+        match self {
+            Reply::Created { body, .. } => json(201, &body),
+            Reply::Updated(body) => json(200, &body),
+            Reply::Deleted => Ok(Response::new(204, ())),
+            Reply::NotFound => Ok(Response::new(404, "Not Found")),
+            Reply::Conflict(error) => json(409, &json!({ "error": error })),
+            Reply::Invalid(errors) => json(422, &json!({ "error": "invalid state", "errors": errors })),
+        }
+    }
+}
+
+/// Why a command was not executed, with the status it answers
+pub(crate) struct Rejection {
+    pub status: u16,
+    pub message: String,
+    pub errors: Vec<String>,
+}
+
+impl Rejection {
+    pub(crate) fn new(status: u16, message: impl Into<String>) -> Rejection {
+        Rejection { status, message: message.into(), errors: Vec::new() }
+    }
+
+    pub(crate) fn into_response(self) -> Response {
+        let body = json!({ "error": self.message, "errors": self.errors });
+        ResponseBuilder::new(self.status)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&body).unwrap_or_default())
+            .build()
+    }
+}
+
+impl From<anyhow::Error> for Rejection {
+    fn from(e: anyhow::Error) -> Rejection {
+        Rejection::new(500, e.to_string())
+    }
+}
+
+/// A command together with its handler. A new command implements this trait
+/// and is added to `registry()`.
+pub(crate) trait Command: Sized {
+    /// name of the command, also its operation name in batches
+    const NAME: &'static str;
+    /// route of the command, with the id as its only parameter
+    const PATH: &'static str;
+    /// scope the caller needs
+    const SCOPE: &'static str = SCOPE_WRITE;
+    /// entity the command changes, as named in the audit log
+    const ENTITY: &'static str;
+
+    fn from_input(input: Input) -> std::result::Result<Self, Rejection>;
+
+    /// Validation errors, checked by the `Validation` middleware
+    fn validate(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Id of the entity the command changes. Creations have none; their id
+    /// comes with the reply.
+    fn target(&self) -> Option<&str> {
+        None
+    }
+
+    /// Executes the command in the transaction of `con`, stamping the
+    /// entities it changes with `stamp`
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply>;
+}
+
+/// A command as it passes through the middleware
+pub(crate) struct Envelope<'a> {
+    pub command: &'static str,
+    pub scope: &'static str,
+    pub entity: &'static str,
+    pub ctx: RequestContext,
+    pub request: &'a Request,
+    pub errors: Vec<String>,
+    pub started: Instant,
+    /// when and by whom the command runs
+    pub stamp: Stamp,
+    /// the targeted entity, with its images once the handler ran
+    pub images: Images,
+    /// whether the handler ran, set before the `after` hooks
+    pub executed: bool,
+    /// whether the audit record was committed with the command's write
+    pub audited: bool,
+}
+
+/// Hooks around every command received over HTTP. `before` runs in
+/// registration order and may answer instead of the handler; `after` runs in
+/// reverse order with the final response.
+pub(crate) trait Middleware {
+    fn before(&self, _envelope: &Envelope) -> Option<Response> {
+        None
+    }
+
+    fn after(&self, _envelope: &Envelope, _res: &mut Response) {}
+}
+
+/// Dispatches commands to their handlers through the middleware
+pub(crate) struct CommandBus {
+    clock: Rc<dyn Clock>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl CommandBus {
+    pub(crate) fn new(clock: Rc<dyn Clock>, middleware: Vec<Box<dyn Middleware>>) -> CommandBus {
+        CommandBus { clock, middleware }
+    }
+
+    fn dispatch<C: Command>(&self, req: Request, params: Params) -> Response {
+        let started = Instant::now();
+        let param = C::PATH.split('/').find_map(|segment| segment.strip_prefix(':'));
+        let body = match req.body() {
+            [] => Ok(JsonValue::Null),
+            body => serde_json::from_slice(body),
+        };
+        let command = match body {
+            Ok(body) => C::from_input(Input { id: param.and_then(|p| params.get(p)).map(String::from), body }),
+            Err(e) => Err(Rejection::new(400, format!("invalid body: {}", e))),
+        };
+        let command = match command {
+            Ok(command) => command,
+            Err(rejection) => return rejection.into_response(),
+        };
+
+        let ctx = RequestContext::from_request(&req);
+        let mut envelope = Envelope {
+            command: C::NAME,
+            scope: C::SCOPE,
+            entity: C::ENTITY,
+            stamp: Stamp::new(self.clock.as_ref(), &ctx),
+            ctx,
+            request: &req,
+            errors: command.validate(),
+            started,
+            images: Images { target: command.target().map(String::from), ..Images::default() },
+            executed: false,
+            audited: false,
+        };
+        let early = self.middleware.iter().find_map(|m| m.before(&envelope));
+        let mut res = match early {
+            Some(res) => res,
+            None => {
+                envelope.executed = true;
+                match execute(command, &envelope) {
+                    Ok((res, images)) => {
+                        envelope.images = images;
+                        envelope.audited = true;
+                        res
+                    }
+                    Err(e) => Response::new(500, e.to_string()),
+                }
+            }
+        };
+        for middleware in self.middleware.iter().rev() {
+            middleware.after(&envelope, &mut res);
+        }
+        res
+    }
+}
+
+/// Executes the command and records it in the audit log in one transaction:
+/// a command whose record cannot be written fails and writes nothing
+fn execute<C: Command>(command: C, envelope: &Envelope) -> Result<(Response, Images)> {
+    let con = Connection::open_default()?;
+    persistence::in_transaction(&con, &envelope.ctx, |con| {
+        let (reply, images) = run(con, command, &envelope.stamp)?;
+        let res = reply.into_response()?;
+        let status = *res.status();
+        audit::insert(con, &AuditRecord {
+            stamp: &envelope.stamp,
+            request_id: &envelope.ctx.request_id,
+            command: envelope.command,
+            entity: envelope.entity,
+            images: &images,
+            status,
+            outcome: audit::outcome(status),
+        })?;
+        Ok((res, images))
+    })
+}
+
+/// Handles a command in the caller's transaction and takes the images of the
+/// entity it targets before and after. A changed image becomes the next
+/// version in the history of the entity.
+fn run<C: Command>(con: &Connection, command: C, stamp: &Stamp) -> Result<(Reply, Images)> {
+    let target = command.target().map(String::from);
+    let before = match &target {
+        Some(id) => persistence::image(con, C::ENTITY, id)?,
+        None => None,
+    };
+    let reply = command.handle(con, stamp)?;
+    let target = match &reply {
+        Reply::Created { id, .. } => Some(id.clone()),
+        _ => target,
+    };
+    let after = match &target {
+        Some(id) => persistence::image(con, C::ENTITY, id)?,
+        None => None,
+    };
+    if let Some(id) = target.as_deref().filter(|_| before != after) {
+        persistence::insert_version(con, C::ENTITY, id, after.as_ref(), stamp)?;
+    }
+    Ok((reply, Images { target, before, after }))
+}
+
+/// A registered command, with its entry points monomorphized for the router
+/// and for batches
+#[derive(Clone, Copy)]
+pub(crate) struct Registration {
+    pub name: &'static str,
+    pub path: &'static str,
+    pub entity: &'static str,
+    pub dispatch: fn(&CommandBus, Request, Params) -> Response,
+    /// validates and executes the command within a caller's transaction
+    pub execute: fn(&Connection, Input, &Stamp) -> std::result::Result<(Reply, Images), Rejection>,
+}
+
+impl Registration {
+    fn of<C: Command>() -> Registration {
+        Registration {
+            name: C::NAME,
+            path: C::PATH,
+            entity: C::ENTITY,
+            dispatch: |bus, req, params| bus.dispatch::<C>(req, params),
+            execute: |con, input, stamp| {
+                let command = C::from_input(input)?;
+                let errors = command.validate();
+                if !errors.is_empty() {
+                    return Err(Rejection { status: 400, message: "invalid command".to_string(), errors });
+                }
+                Ok(run(con, command, stamp)?)
+            },
+        }
+    }
+}
+
+/// Every command
+pub(crate) fn registry() -> Vec<Registration> {
+    vec![
+        Registration::of::<CreateEmployee>(),
+        Registration::of::<UpdateEmployee>(),
+        Registration::of::<DeleteEmployee>(),
+        Registration::of::<CreateLocation>(),
+        Registration::of::<UpdateLocation>(),
+        Registration::of::<DeleteLocation>(),
+        Registration::of::<CreatePerson>(),
+        Registration::of::<UpdatePerson>(),
+        Registration::of::<DeletePerson>(),
+        Registration::of::<RestoreEmployee>(),
+        Registration::of::<RestoreLocation>(),
+        Registration::of::<RestorePerson>(),
+        Registration::of::<UndeleteEmployee>(),
+        Registration::of::<UndeletePerson>(),
+    ]
+}
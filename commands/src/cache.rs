@@ -0,0 +1,30 @@
+use anyhow::Result;
+use spin_sdk::key_value::Store;
+use uuid::Uuid;
+
+/// Key of the token that marks the responses cached by `queries` as current
+const GENERATION_KEY: &str = "cache:generation";
+/// Prefix of the responses cached by `queries`
+const ENTRY_PREFIX: &str = "cache:query:";
+
+/// Invalidates every cached read model by replacing the generation token.
+/// A fresh token instead of a counter means concurrent writes cannot lose an
+/// increment.
+pub(crate) fn invalidate() -> Result<()> {
+    let store = Store::open_default()?;
+    store.set(GENERATION_KEY, Uuid::new_v4().to_string().as_bytes())?;
+    Ok(())
+}
+
+/// Deletes every cached read model and returns how many there were. Unlike
+/// `invalidate`, which leaves them unused, this removes their data, such as
+/// the personal data of an erased subject.
+pub(crate) fn clear() -> Result<usize> {
+    let store = Store::open_default()?;
+    let mut cleared = 0;
+    for key in store.get_keys()?.iter().filter(|key| key.starts_with(ENTRY_PREFIX)) {
+        store.delete(key)?;
+        cleared += 1;
+    }
+    Ok(cleared)
+}
@@ -0,0 +1,47 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use shared::time::timestamp;
+use telemetry::RequestContext;
+
+/// Actor of commands that did not come through the gateway. Queued commands
+/// run by the worker keep the caller who queued them.
+const SYSTEM_ACTOR: &str = "system";
+
+/// Source of the time changes are stamped with. The router hands one clock
+/// to everything that writes, so a fixed clock can replace the system clock.
+pub(crate) trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// When and by whom a command changes entities
+#[derive(Debug, Clone)]
+pub(crate) struct Stamp {
+    /// UTC time such as `2026-10-19T08:30:00Z`, which sorts as text
+    pub at: String,
+    /// API key id of the caller
+    pub by: String,
+}
+
+impl Stamp {
+    pub(crate) fn new(clock: &dyn Clock, ctx: &RequestContext) -> Stamp {
+        let secs = clock.now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default();
+        Stamp {
+            at: timestamp(secs),
+            by: ctx.client.clone().unwrap_or_else(|| SYSTEM_ACTOR.to_string()),
+        }
+    }
+}
+
+/// The time `days` before now, formatted like stamps
+pub(crate) fn days_ago(clock: &dyn Clock, days: u64) -> String {
+    let secs = clock.now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default();
+    timestamp(secs - days as i64 * 86_400)
+}
@@ -0,0 +1,30 @@
+use std::cell::Cell;
+
+use anyhow::Result;
+use spin_sdk::http::Response;
+use spin_sdk::sqlite::Connection;
+
+use crate::persistence;
+
+/// Header carrying the commit position of a write. A query sent with it
+/// waits until the read models include the write.
+const TOKEN_HEADER: &str = "Consistency-Token";
+
+thread_local! {
+    static POSITION: Cell<Option<i64>> = const { Cell::new(None) };
+}
+
+/// Remembers the position of the transaction just committed on `con`
+pub(crate) fn record(con: &Connection) -> Result<()> {
+    let position = persistence::commit_position(con)?;
+    POSITION.with(|p| p.set(Some(position)));
+    Ok(())
+}
+
+/// Adds the position of the last commit of this request, if any, to its
+/// response
+pub(crate) fn finish(res: &mut Response) {
+    if let Some(position) = POSITION.with(|p| p.take()) {
+        res.set_header(TOKEN_HEADER, position.to_string());
+    }
+}
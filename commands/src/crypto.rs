@@ -0,0 +1,79 @@
+use std::rc::Rc;
+
+use anyhow::Result;
+use serde_json::Value as JsonValue;
+use shared::crypto::{self, Keys, KEYS_VARIABLE};
+use spin_sdk::variables;
+
+pub(crate) use shared::crypto::PREFIX;
+
+/// The keys configured in the Spin variable, read once per instance
+fn keys() -> Result<Rc<Keys>> {
+    crypto::keys(|| variables::get(KEYS_VARIABLE).unwrap_or_default())
+}
+
+/// The version of the key new values are encrypted with, `None` when
+/// encryption is off
+pub(crate) fn current_version() -> Result<Option<u32>> {
+    Ok(keys()?.current_version())
+}
+
+/// Encrypts a value with the current key; unchanged when encryption is off
+pub(crate) fn seal(value: &str) -> Result<String> {
+    keys()?.seal(value, false)
+}
+
+/// Encrypts a value so that equal values give equal ciphertexts under the
+/// same key, for fields looked up by value
+pub(crate) fn seal_searchable(value: &str) -> Result<String> {
+    keys()?.seal(value, true)
+}
+
+/// Every form a searchable value may be stored in
+pub(crate) fn probes(value: &str) -> Result<Vec<String>> {
+    keys()?.probes(value)
+}
+
+/// Decrypts a value; plain values are returned unchanged
+pub(crate) fn open(value: &str) -> Result<String> {
+    keys()?.open(value)
+}
+
+/// Whether a value is not encrypted with the current key, while encryption
+/// is on
+pub(crate) fn stale(value: &str) -> Result<bool> {
+    Ok(keys()?.stale(value))
+}
+
+/// The image of an entity with its encrypted fields encrypted
+pub(crate) fn seal_image(entity: &str, image: &JsonValue) -> Result<JsonValue> {
+    keys()?.seal_image(entity, image)
+}
+
+/// Whether any encrypted field of an image is not encrypted with the
+/// current key
+pub(crate) fn stale_image(entity: &str, image: &JsonValue) -> Result<bool> {
+    Ok(keys()?.stale_image(entity, image))
+}
+
+/// Decrypts every encrypted string of a JSON document in place
+pub(crate) fn open_json(value: &mut JsonValue) -> Result<()> {
+    keys()?.open_json(value)
+}
+
+/// Encrypts a payload as a whole with the current key; unchanged when
+/// encryption is off
+pub(crate) fn seal_payload(payload: &[u8]) -> Result<Vec<u8>> {
+    keys()?.seal_payload(payload)
+}
+
+/// Decrypts a payload; plain payloads are returned unchanged
+pub(crate) fn open_payload(payload: &[u8]) -> Result<Vec<u8>> {
+    keys()?.open_payload(payload)
+}
+
+/// Whether a payload is not encrypted with the current key, while
+/// encryption is on
+pub(crate) fn stale_payload(payload: &[u8]) -> Result<bool> {
+    Ok(keys()?.stale_payload(payload))
+}
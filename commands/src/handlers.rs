@@ -0,0 +1,498 @@
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use serde_json::Value as JsonValue;
+use spin_sdk::sqlite::Connection;
+
+use crate::bus::{Command, Input, Rejection, Reply};
+use crate::clock::Stamp;
+use crate::models::{ChangeModel, CreateEmployeeModel, CreateLocationModel, CreatePersonModel, RestoreModel,
+                    RestoredModel, UpdateEmployeeModel, UpdateLocationModel, UpdatePersonModel};
+use crate::persistence;
+use crate::validation::{check, check_location, check_time, MAX_NAME};
+
+type Built<T> = std::result::Result<T, Rejection>;
+
+fn check_names(first_name: &str, last_name: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+    check(&mut errors, "firstName", first_name, MAX_NAME);
+    check(&mut errors, "lastName", last_name, MAX_NAME);
+    errors
+}
+
+/// `Conflict` when a person would move to a location that does not exist
+fn missing_location(con: &Connection, plid: &str) -> Result<Option<Reply>> {
+    Ok((!persistence::location_exists(con, plid)?)
+        .then(|| Reply::Conflict(format!("location {} does not exist", plid))))
+}
+
+pub(crate) struct CreateEmployee(CreateEmployeeModel);
+
+impl Command for CreateEmployee {
+    const NAME: &'static str = "createEmployee";
+    const PATH: &'static str = "/create_employee";
+    const ENTITY: &'static str = "employees";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(CreateEmployee(input.body()?))
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = check_names(&self.0.first_name, &self.0.last_name);
+        let address = &self.0.address;
+        check_location(&mut errors, &address.street, &address.zip, &address.city);
+        errors
+    }
+
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        let created = persistence::insert_employee(con, self.0, stamp)?;
+        Reply::created(created.id.clone(), &created)
+    }
+}
+
+pub(crate) struct UpdateEmployee {
+    id: String,
+    model: UpdateEmployeeModel,
+}
+
+impl Command for UpdateEmployee {
+    const NAME: &'static str = "updateEmployee";
+    const PATH: &'static str = "/update_employee/:id";
+    const ENTITY: &'static str = "employees";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(UpdateEmployee { id: input.id()?, model: input.body()? })
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.id)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = check_names(&self.model.first_name, &self.model.last_name);
+        let address = &self.model.address;
+        check_location(&mut errors, &address.street, &address.zip, &address.city);
+        errors
+    }
+
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        Reply::updated(persistence::update_employee(con, &self.id, self.model, stamp)?)
+    }
+}
+
+pub(crate) struct DeleteEmployee(String);
+
+impl Command for DeleteEmployee {
+    const NAME: &'static str = "deleteEmployee";
+    const PATH: &'static str = "/delete_employee/:id";
+    const ENTITY: &'static str = "employees";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(DeleteEmployee(input.id()?))
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.0)
+    }
+
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        Ok(Reply::deleted(persistence::delete_employee(con, &self.0, stamp)?))
+    }
+}
+
+pub(crate) struct CreateLocation(CreateLocationModel);
+
+impl Command for CreateLocation {
+    const NAME: &'static str = "createLocation";
+    const PATH: &'static str = "/create_location";
+    const ENTITY: &'static str = "locations";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(CreateLocation(input.body()?))
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        check_location(&mut errors, &self.0.street, &self.0.zip, &self.0.city);
+        errors
+    }
+
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        let created = persistence::insert_location(con, self.0, stamp)?;
+        Reply::created(created.lid.clone(), &created)
+    }
+}
+
+pub(crate) struct UpdateLocation {
+    lid: String,
+    model: UpdateLocationModel,
+}
+
+impl Command for UpdateLocation {
+    const NAME: &'static str = "updateLocation";
+    const PATH: &'static str = "/update_location/:lid";
+    const ENTITY: &'static str = "locations";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(UpdateLocation { lid: input.id()?, model: input.body()? })
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.lid)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        check_location(&mut errors, &self.model.street, &self.model.zip, &self.model.city);
+        errors
+    }
+
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        Reply::updated(persistence::update_location(con, &self.lid, self.model, stamp)?)
+    }
+}
+
+/// Deletes a location nobody lives at anymore
+pub(crate) struct DeleteLocation(String);
+
+impl Command for DeleteLocation {
+    const NAME: &'static str = "deleteLocation";
+    const PATH: &'static str = "/delete_location/:lid";
+    const ENTITY: &'static str = "locations";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(DeleteLocation(input.id()?))
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.0)
+    }
+
+    fn handle(self, con: &Connection, _: &Stamp) -> Result<Reply> {
+        if persistence::location_in_use(con, &self.0)? {
+            // deleted persons count until they are purged, as they may be undeleted
+            return Ok(Reply::Conflict(format!("persons still live at location {}", self.0)));
+        }
+        Ok(Reply::deleted(persistence::delete_location(con, &self.0)?))
+    }
+}
+
+pub(crate) struct CreatePerson(CreatePersonModel);
+
+impl Command for CreatePerson {
+    const NAME: &'static str = "createPerson";
+    const PATH: &'static str = "/create_person";
+    const ENTITY: &'static str = "persons";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(CreatePerson(input.body()?))
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = check_names(&self.0.first_name, &self.0.last_name);
+        if self.0.plid.trim().is_empty() {
+            errors.push("plid is required".to_string());
+        }
+        errors
+    }
+
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        if let Some(conflict) = missing_location(con, &self.0.plid)? {
+            return Ok(conflict);
+        }
+        let created = persistence::insert_person(con, self.0, stamp)?;
+        Reply::created(created.pid.clone(), &created)
+    }
+}
+
+pub(crate) struct UpdatePerson {
+    pid: String,
+    model: UpdatePersonModel,
+}
+
+impl Command for UpdatePerson {
+    const NAME: &'static str = "updatePerson";
+    const PATH: &'static str = "/update_person/:pid";
+    const ENTITY: &'static str = "persons";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(UpdatePerson { pid: input.id()?, model: input.body()? })
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.pid)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = check_names(&self.model.first_name, &self.model.last_name);
+        if self.model.plid.trim().is_empty() {
+            errors.push("plid is required".to_string());
+        }
+        errors
+    }
+
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        if let Some(conflict) = missing_location(con, &self.model.plid)? {
+            return Ok(conflict);
+        }
+        Reply::updated(persistence::update_person(con, &self.pid, self.model, stamp)?)
+    }
+}
+
+pub(crate) struct DeletePerson(String);
+
+impl Command for DeletePerson {
+    const NAME: &'static str = "deletePerson";
+    const PATH: &'static str = "/delete_person/:pid";
+    const ENTITY: &'static str = "persons";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(DeletePerson(input.id()?))
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.0)
+    }
+
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        Ok(Reply::deleted(persistence::delete_person(con, &self.0, stamp)?))
+    }
+}
+
+/// A restore of the entity `id` to one of its versions
+pub(crate) struct Restore {
+    id: String,
+    model: RestoreModel,
+}
+
+impl Restore {
+    fn from_input(input: Input) -> Built<Restore> {
+        Ok(Restore { id: input.id()?, model: input.body()? })
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        match (&self.model.version, &self.model.at) {
+            (Some(_), Some(_)) | (None, None) => errors.push("either version or at is required".to_string()),
+            (Some(version), None) if *version < 1 => errors.push("version must be positive".to_string()),
+            (None, Some(at)) => check_time(&mut errors, "at", at),
+            _ => {}
+        }
+        errors
+    }
+
+    /// Writes the state of the version through the update command `U`, with
+    /// its validation, and reports the fields that changed
+    fn handle<U: Command>(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        let found = persistence::version(con, U::ENTITY, &self.id, self.model.version, self.model.at.as_deref())?;
+        let Some((version, state)) = found else {
+            return Ok(Reply::NotFound);
+        };
+        let Some(state) = state else {
+            return Ok(Reply::Conflict(format!("version {} of {} is its deletion", version, self.id)));
+        };
+        let Some(before) = persistence::image(con, U::ENTITY, &self.id)? else {
+            return Ok(Reply::Conflict(format!("{} is deleted", self.id)));
+        };
+        let update = match U::from_input(Input { id: Some(self.id.clone()), body: state }) {
+            Ok(update) => update,
+            Err(rejection) => return Ok(Reply::Invalid(vec![rejection.message])),
+        };
+        let errors = update.validate();
+        if !errors.is_empty() {
+            return Ok(Reply::Invalid(errors));
+        }
+        match update.handle(con, stamp)? {
+            Reply::Updated(_) => {}
+            reply => return Ok(reply),
+        }
+        let after = persistence::image(con, U::ENTITY, &self.id)?.unwrap_or_default();
+        let mut changes = Vec::new();
+        diff("", &before, &after, &mut changes);
+        let restored = RestoredModel { restored_version: version, changes, state: after };
+        Ok(Reply::Updated(serde_json::to_value(restored)?))
+    }
+}
+
+/// The leaves of two JSON objects that differ, by their dotted path
+fn diff(path: &str, before: &JsonValue, after: &JsonValue, changes: &mut Vec<ChangeModel>) {
+    match (before, after) {
+        (JsonValue::Object(before), JsonValue::Object(after)) => {
+            let fields: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+            for field in fields {
+                let path = if path.is_empty() { field.clone() } else { format!("{}.{}", path, field) };
+                let null = JsonValue::Null;
+                diff(&path, before.get(field).unwrap_or(&null), after.get(field).unwrap_or(&null), changes);
+            }
+        }
+        (before, after) if before != after => changes.push(ChangeModel {
+            field: path.to_string(),
+            from: before.clone(),
+            to: after.clone(),
+        }),
+        _ => {}
+    }
+}
+
+pub(crate) struct RestoreEmployee(Restore);
+
+impl Command for RestoreEmployee {
+    const NAME: &'static str = "restoreEmployee";
+    const PATH: &'static str = "/restore_employee/:id";
+    const ENTITY: &'static str = "employees";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(RestoreEmployee(Restore::from_input(input)?))
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.0.id)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        self.0.validate()
+    }
+
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        self.0.handle::<UpdateEmployee>(con, stamp)
+    }
+}
+
+pub(crate) struct RestoreLocation(Restore);
+
+impl Command for RestoreLocation {
+    const NAME: &'static str = "restoreLocation";
+    const PATH: &'static str = "/restore_location/:lid";
+    const ENTITY: &'static str = "locations";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(RestoreLocation(Restore::from_input(input)?))
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.0.id)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        self.0.validate()
+    }
+
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        self.0.handle::<UpdateLocation>(con, stamp)
+    }
+}
+
+pub(crate) struct RestorePerson(Restore);
+
+impl Command for RestorePerson {
+    const NAME: &'static str = "restorePerson";
+    const PATH: &'static str = "/restore_person/:pid";
+    const ENTITY: &'static str = "persons";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(RestorePerson(Restore::from_input(input)?))
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.0.id)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        self.0.validate()
+    }
+
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        self.0.handle::<UpdatePerson>(con, stamp)
+    }
+}
+
+pub(crate) struct UndeleteEmployee(String);
+
+impl Command for UndeleteEmployee {
+    const NAME: &'static str = "undeleteEmployee";
+    const PATH: &'static str = "/undelete_employee/:id";
+    const ENTITY: &'static str = "employees";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(UndeleteEmployee(input.id()?))
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.0)
+    }
+
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        if !persistence::undelete(con, "Employees", &self.0, stamp)? {
+            return Ok(Reply::NotFound);
+        }
+        Reply::updated(persistence::image(con, Self::ENTITY, &self.0)?)
+    }
+}
+
+pub(crate) struct UndeletePerson(String);
+
+impl Command for UndeletePerson {
+    const NAME: &'static str = "undeletePerson";
+    const PATH: &'static str = "/undelete_person/:pid";
+    const ENTITY: &'static str = "persons";
+
+    fn from_input(input: Input) -> Built<Self> {
+        Ok(UndeletePerson(input.id()?))
+    }
+
+    fn target(&self) -> Option<&str> {
+        Some(&self.0)
+    }
+
+    fn handle(self, con: &Connection, stamp: &Stamp) -> Result<Reply> {
+        if !persistence::undelete(con, "Persons", &self.0, stamp)? {
+            return Ok(Reply::NotFound);
+        }
+        Reply::updated(persistence::image(con, Self::ENTITY, &self.0)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn changes(before: JsonValue, after: JsonValue) -> Vec<(String, JsonValue, JsonValue)> {
+        let mut changes = Vec::new();
+        diff("", &before, &after, &mut changes);
+        changes.into_iter().map(|c| (c.field, c.from, c.to)).collect()
+    }
+
+    #[test]
+    fn equal_states_have_no_changes() {
+        let state = json!({ "id": "1", "firstName": "John", "address": { "city": "Boston" } });
+        assert!(changes(state.clone(), state).is_empty());
+    }
+
+    #[test]
+    fn changed_leaves_are_named_by_their_dotted_path() {
+        let before = json!({ "firstName": "John", "lastName": "Doe", "address": { "city": "Boston" } });
+        let after = json!({ "firstName": "Jane", "lastName": "Doe", "address": { "city": "Salem" } });
+        assert_eq!(changes(before, after), vec![
+            ("address.city".to_string(), json!("Boston"), json!("Salem")),
+            ("firstName".to_string(), json!("John"), json!("Jane")),
+        ]);
+    }
+
+    #[test]
+    fn missing_fields_compare_as_null() {
+        let before = json!({ "pid": "1", "plid": "7" });
+        let after = json!({ "pid": "1", "address": { "zip": "02112" } });
+        assert_eq!(changes(before, after), vec![
+            ("address".to_string(), JsonValue::Null, json!({ "zip": "02112" })),
+            ("plid".to_string(), json!("7"), JsonValue::Null),
+        ]);
+    }
+
+    #[test]
+    fn a_restore_from_nothing_changes_every_field() {
+        let after = json!({ "lid": "1", "city": "Boston" });
+        assert_eq!(changes(JsonValue::Null, after.clone()), vec![("".to_string(), JsonValue::Null, after)]);
+    }
+}
@@ -0,0 +1,377 @@
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde_json::Value as JsonValue;
+use spin_sdk::http::{Params, Request, Response, ResponseBuilder};
+use spin_sdk::sqlite::Connection;
+use telemetry::RequestContext;
+
+use crate::audit::{self, AuditRecord, Images};
+use crate::clock::{Clock, Stamp};
+use crate::models::{CreateLocationModel, CreatePersonModel, ImportPersonModel, ImportReportModel,
+                    ImportRowModel};
+use crate::persistence;
+use crate::validation::{check, check_location, MAX_NAME};
+
+/// Largest number of rows accepted by a single import
+const MAX_ROWS: usize = 1000;
+
+/// Rows as read from the body, each either a model or the reason it could not
+/// be read
+type Rows<T> = Vec<std::result::Result<T, String>>;
+
+/// How an import deals with rejected rows
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// nothing is written unless every row is valid
+    AllOrNothing,
+    /// valid rows are written, rejected rows are reported
+    BestEffort,
+}
+
+impl Mode {
+    fn from_request(req: &Request) -> Option<Mode> {
+        Mode::from_query(req.query())
+    }
+
+    fn from_query(query: &str) -> Option<Mode> {
+        let mode = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == "mode")
+            .map(|(_, value)| value);
+        match mode {
+            None | Some("all-or-nothing") => Some(Mode::AllOrNothing),
+            Some("best-effort") => Some(Mode::BestEffort),
+            Some(_) => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Mode::AllOrNothing => "all-or-nothing",
+            Mode::BestEffort => "best-effort",
+        }
+    }
+}
+
+/// A row that passed validation, ready to be written
+struct Accepted<T> {
+    row: usize,
+    model: T,
+}
+
+/// Outcome of every row, in input order
+struct Report {
+    mode: Mode,
+    rows: Vec<ImportRowModel>,
+}
+
+impl Report {
+    fn new(mode: Mode, total: usize) -> Report {
+        let rows = (1..=total)
+            .map(|row| ImportRowModel { row, status: "skipped".to_string(), id: None, errors: Vec::new() })
+            .collect();
+        Report { mode, rows }
+    }
+
+    fn set(&mut self, row: usize, status: &str, id: Option<String>, errors: Vec<String>) {
+        let entry = &mut self.rows[row - 1];
+        entry.status = status.to_string();
+        entry.id = id;
+        entry.errors = errors;
+    }
+
+    fn count(&self, status: &str) -> usize {
+        self.rows.iter().filter(|r| r.status == status).count()
+    }
+
+    /// `201` when every row was created or already existed, `422` when nothing
+    /// was created because of rejected rows, `200` otherwise
+    fn into_response(self) -> Result<Response> {
+        let model = ImportReportModel {
+            mode: self.mode.name().to_string(),
+            total: self.rows.len(),
+            created: self.count("created"),
+            existing: self.count("existing"),
+            rejected: self.count("rejected"),
+            rows: self.rows,
+        };
+        let status = match (model.rejected, model.created) {
+            (0, _) => 201,
+            (_, 0) => 422,
+            _ => 200,
+        };
+        Ok(ResponseBuilder::new(status)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&model)?)
+            .build())
+    }
+}
+
+/// Reads the rows of a CSV body with a header line, or of a JSON array. A
+/// row that cannot be read is reported on its own instead of failing the
+/// import.
+fn read_rows<T: DeserializeOwned>(req: &Request) -> std::result::Result<Rows<T>, String> {
+    let csv = req
+        .header("content-type")
+        .and_then(|v| v.as_str())
+        .is_some_and(|ct| ct.starts_with("text/csv"));
+    parse_rows(csv, req.body())
+}
+
+fn parse_rows<T: DeserializeOwned>(csv: bool, body: &[u8]) -> std::result::Result<Rows<T>, String> {
+    if csv {
+        let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(body);
+        Ok(reader
+            .deserialize::<T>()
+            .map(|row| row.map_err(|e| format!("unreadable row: {}", e)))
+            .collect())
+    } else {
+        let rows: Vec<JsonValue> =
+            serde_json::from_slice(body).map_err(|e| format!("expected a JSON array: {}", e))?;
+        Ok(rows
+            .into_iter()
+            .map(|row| serde_json::from_value(row).map_err(|e| format!("unreadable row: {}", e)))
+            .collect())
+    }
+}
+
+/// Common start of every import: mode, row limit and readable body
+fn prepare<T: DeserializeOwned>(req: &Request) -> std::result::Result<(Mode, Rows<T>), Response> {
+    let Some(mode) = Mode::from_request(req) else {
+        return Err(Response::new(400, "mode must be all-or-nothing or best-effort"));
+    };
+    let rows = read_rows::<T>(req).map_err(|e| Response::new(400, e))?;
+    if rows.len() > MAX_ROWS {
+        return Err(Response::new(413, format!("at most {} rows per import", MAX_ROWS)));
+    }
+    Ok((mode, rows))
+}
+
+/// Writes the accepted rows in one transaction. In all-or-nothing mode the
+/// transaction is rolled back on the first failure, which fails the request.
+fn write<T, F>(con: &Connection, ctx: &RequestContext, mode: Mode, report: &mut Report,
+               accepted: Vec<Accepted<T>>, insert: F) -> Result<()>
+where
+    F: Fn(&Connection, T) -> Result<String>,
+{
+    if mode == Mode::AllOrNothing && report.count("rejected") > 0 {
+        return Ok(());
+    }
+    persistence::begin(con)?;
+    for Accepted { row, model } in accepted {
+        match insert(con, model) {
+            Ok(id) => report.set(row, "created", Some(id), Vec::new()),
+            Err(e) if mode == Mode::AllOrNothing => {
+                persistence::rollback(con);
+                return Err(e.context(format!("import failed at row {}", row)));
+            }
+            Err(e) => report.set(row, "rejected", None, vec![e.to_string()]),
+        }
+    }
+    persistence::commit(con, ctx)
+}
+
+/// Records the first version of an imported entity in its history, and its
+/// creation in the audit log as a command of its own
+fn recorded(con: &Connection, ctx: &RequestContext, stamp: &Stamp, command: &str, entity: &str,
+            id: String) -> Result<String> {
+    let after = persistence::image(con, entity, &id)?;
+    persistence::insert_version(con, entity, &id, after.as_ref(), stamp)?;
+    let images = Images { target: Some(id.clone()), before: None, after };
+    audit::insert(con, &AuditRecord {
+        stamp,
+        request_id: &ctx.request_id,
+        command,
+        entity,
+        images: &images,
+        status: 201,
+        outcome: audit::outcome(201),
+    })?;
+    Ok(id)
+}
+
+/// Imports persons, resolving each location by `plid` or else by street, zip
+/// and city
+#[tracing::instrument(name = "import_persons", skip_all)]
+pub(crate) fn import_persons(req: Request, _: Params, clock: &dyn Clock) -> Result<Response> {
+    let (mode, rows) = match prepare::<ImportPersonModel>(&req) {
+        Ok(prepared) => prepared,
+        Err(res) => return Ok(res),
+    };
+    let con = Connection::open_default()?;
+    let mut report = Report::new(mode, rows.len());
+    let mut accepted = Vec::new();
+
+    for (index, row) in rows.into_iter().enumerate() {
+        let number = index + 1;
+        let model = match row {
+            Ok(model) => model,
+            Err(e) => {
+                report.set(number, "rejected", None, vec![e]);
+                continue;
+            }
+        };
+        let mut errors = Vec::new();
+        check(&mut errors, "firstName", &model.first_name, MAX_NAME);
+        check(&mut errors, "lastName", &model.last_name, MAX_NAME);
+        let lid = match (&model.plid, &model.street, &model.zip, &model.city) {
+            (Some(plid), _, _, _) if !plid.trim().is_empty() => {
+                let plid = plid.trim();
+                if !persistence::location_exists(&con, plid)? {
+                    errors.push(format!("location {} does not exist", plid));
+                }
+                Some(plid.to_string())
+            }
+            (_, Some(street), Some(zip), Some(city)) => {
+                let before = errors.len();
+                check_location(&mut errors, street, zip, city);
+                if errors.len() > before {
+                    None
+                } else {
+                    let lid = persistence::find_location(&con, street.trim(), zip.trim(), city.trim())?;
+                    if lid.is_none() {
+                        errors.push(format!("no location at {}, {} {}", street.trim(), zip.trim(), city.trim()));
+                    }
+                    lid
+                }
+            }
+            _ => {
+                errors.push("plid or street, zip and city are required".to_string());
+                None
+            }
+        };
+        match lid {
+            Some(plid) if errors.is_empty() => accepted.push(Accepted {
+                row: number,
+                model: CreatePersonModel {
+                    first_name: model.first_name.trim().to_string(),
+                    last_name: model.last_name.trim().to_string(),
+                    plid,
+                },
+            }),
+            _ => report.set(number, "rejected", None, errors),
+        }
+    }
+
+    let ctx = RequestContext::from_request(&req);
+    let stamp = Stamp::new(clock, &ctx);
+    write(&con, &ctx, mode, &mut report, accepted, |con, model| {
+        let pid = persistence::insert_person(con, model, &stamp)?.pid;
+        recorded(con, &ctx, &stamp, "importPersons", "persons", pid)
+    })?;
+    report.into_response()
+}
+
+/// Imports locations. A row matching an existing location, or an earlier row
+/// of the same import, by street, zip and city is reported as existing.
+#[tracing::instrument(name = "import_locations", skip_all)]
+pub(crate) fn import_locations(req: Request, _: Params, clock: &dyn Clock) -> Result<Response> {
+    let (mode, rows) = match prepare::<CreateLocationModel>(&req) {
+        Ok(prepared) => prepared,
+        Err(res) => return Ok(res),
+    };
+    let con = Connection::open_default()?;
+    let mut report = Report::new(mode, rows.len());
+    let mut accepted: Vec<Accepted<CreateLocationModel>> = Vec::new();
+    let mut duplicates = Vec::new();
+
+    for (index, row) in rows.into_iter().enumerate() {
+        let number = index + 1;
+        let model = match row {
+            Ok(model) => CreateLocationModel {
+                street: model.street.trim().to_string(),
+                zip: model.zip.trim().to_string(),
+                city: model.city.trim().to_string(),
+            },
+            Err(e) => {
+                report.set(number, "rejected", None, vec![e]);
+                continue;
+            }
+        };
+        let mut errors = Vec::new();
+        check_location(&mut errors, &model.street, &model.zip, &model.city);
+        if !errors.is_empty() {
+            report.set(number, "rejected", None, errors);
+            continue;
+        }
+        if let Some(lid) = persistence::find_location(&con, &model.street, &model.zip, &model.city)? {
+            report.set(number, "existing", Some(lid), Vec::new());
+            continue;
+        }
+        let duplicate = accepted
+            .iter()
+            .find(|a| a.model.street == model.street && a.model.zip == model.zip && a.model.city == model.city);
+        match duplicate {
+            Some(first) => duplicates.push((number, first.row)),
+            None => accepted.push(Accepted { row: number, model }),
+        }
+    }
+
+    let ctx = RequestContext::from_request(&req);
+    let stamp = Stamp::new(clock, &ctx);
+    write(&con, &ctx, mode, &mut report, accepted, |con, model| {
+        let lid = persistence::insert_location(con, model, &stamp)?.lid;
+        recorded(con, &ctx, &stamp, "importLocations", "locations", lid)
+    })?;
+    // repeated rows share the location created for their first occurrence
+    for (row, first) in duplicates {
+        if let Some(lid) = report.rows[first - 1].id.clone() {
+            report.set(row, "existing", Some(lid), Vec::new());
+        }
+    }
+    report.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_defaults_to_all_or_nothing() {
+        assert_eq!(Mode::from_query(""), Some(Mode::AllOrNothing));
+        assert_eq!(Mode::from_query("mode=all-or-nothing"), Some(Mode::AllOrNothing));
+        assert_eq!(Mode::from_query("x=1&mode=best-effort"), Some(Mode::BestEffort));
+        assert_eq!(Mode::from_query("mode=partial"), None);
+    }
+
+    #[test]
+    fn csv_rows_are_read_by_header_and_trimmed() {
+        let body = b"street,zip,city\n 1 Main St , 12345,Springfield\nElm St,999,Shelbyville\n";
+        let rows = parse_rows::<CreateLocationModel>(true, body).unwrap();
+        assert_eq!(rows.len(), 2);
+        let first = rows[0].as_ref().unwrap();
+        assert_eq!((first.street.as_str(), first.zip.as_str(), first.city.as_str()),
+                   ("1 Main St", "12345", "Springfield"));
+        assert_eq!(rows[1].as_ref().unwrap().city, "Shelbyville");
+    }
+
+    #[test]
+    fn csv_optional_columns_may_be_empty_or_missing() {
+        let body = b"firstName,lastName,plid\nAda,Lovelace,\nAlan,Turing,l-1\n";
+        let rows = parse_rows::<ImportPersonModel>(true, body).unwrap();
+        let (ada, alan) = (rows[0].as_ref().unwrap(), rows[1].as_ref().unwrap());
+        assert_eq!((ada.plid.as_deref(), ada.street.as_deref()), (None, None));
+        assert_eq!(alan.plid.as_deref(), Some("l-1"));
+    }
+
+    #[test]
+    fn unreadable_rows_are_reported_one_by_one() {
+        let body = b"street,zip,city\nMain St,12345,Springfield\nElm St,999\nOak St,1,Ogdenville\n";
+        let rows = parse_rows::<CreateLocationModel>(true, body).unwrap();
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].is_ok() && rows[2].is_ok());
+        assert!(rows[1].as_ref().unwrap_err().starts_with("unreadable row"));
+
+        let body = br#"[{"firstName": "Ada", "lastName": "Lovelace"}, {"firstName": 1}]"#;
+        let rows = parse_rows::<ImportPersonModel>(false, body).unwrap();
+        assert!(rows[0].is_ok());
+        assert!(rows[1].as_ref().unwrap_err().starts_with("unreadable row"));
+    }
+
+    #[test]
+    fn json_body_must_be_an_array() {
+        let rows = parse_rows::<CreateLocationModel>(false, br#"{"street": "Main St"}"#);
+        assert!(rows.unwrap_err().starts_with("expected a JSON array"));
+    }
+}
@@ -1,146 +1,110 @@
+mod audit;
+mod batch;
+mod bus;
+mod cache;
+mod clock;
+mod consistency;
+mod crypto;
+mod handlers;
+mod health;
+mod import;
+mod middleware;
 mod models;
+mod openapi;
 mod persistence;
+mod privacy;
+mod purge;
+mod queue;
+mod rotation;
+mod validation;
+
+use std::rc::Rc;
 
 use anyhow::Result;
-use models::{CreateEmployeeModel, UpdateEmployeeModel, 
-             CreateLocationModel, UpdateLocationModel,
-             CreatePersonModel,   UpdatePersonModel};
-use spin_sdk::http::{IntoResponse, Params, Request, Response, ResponseBuilder, Router};
+use bus::{CommandBus, SCOPE_ADMIN, SCOPE_WRITE};
+use clock::{Clock, SystemClock};
+use middleware::{Audit, Authorization, Idempotency, Metrics, Validation};
+use spin_sdk::http::{IntoResponse, Params, Request, Response, Router};
 use spin_sdk::http_component;
+use telemetry::{RequestContext, Telemetry, TraceParent};
 
 /// A simple Spin HTTP component.
-#[tracing::instrument(name="handle_commands", skip_all)]
 #[http_component]
 fn handle_commands(req: Request) -> anyhow::Result<impl IntoResponse> {
-    let mut router = Router::default();
-
-    router.post("/create_employee",      create_employee);
-    router.post("/update_employee/:id",  update_employee);
-    router.post("/delete_employee/:id",  delete_employee);
-    router.post("/create_location",      create_location);
-    router.post("/create_person",        create_person);
-    router.post("/update_location/:lid", update_location);
-    router.post("/update_person/:pid",   update_person);
-    router.post("/delete_person/:pid",   delete_person);
-    router.any("*", fallback);
-    Ok(router.handle(req))
-}
-
-#[tracing::instrument(name="create_employee", skip_all)]
-fn create_employee(req: Request, _: Params) -> Result<impl IntoResponse> {
-    let model: CreateEmployeeModel = serde_json::from_slice(req.body())?;
-
-    let created = persistence::create_employee(model)?;
-    let b = serde_json::to_vec(&created)?;
-    Ok(ResponseBuilder::new(201)
-        .header("Content-Type", "application/json")
-        .body(b)
-        .build())
-}
-
-#[tracing::instrument(name="update_employee", skip_all)]
-fn update_employee(req: Request, params: Params) -> Result<impl IntoResponse> {
-    let model: UpdateEmployeeModel = serde_json::from_slice(req.body())?;
-
-    let updated = match params.get("id") {
-        Some(id) => persistence::update_employee_by_id(id, model)?,
-        None => return Ok(Response::new(400, ())),
-    };
-    match updated {
-        Some(u) => {
-            let b = serde_json::to_vec(&u)?;
-            Ok(ResponseBuilder::new(200)
-                .header("Content-Type", "application/json")
-                .body(b)
-                .build())
-        }
-        None => Ok(Response::new(404, "Not Found")),
+    let ctx = RequestContext::from_request(&req);
+    let telemetry = Telemetry::new("commands", TraceParent::from_header(ctx.traceparent.as_deref()));
+    let res = telemetry.in_scope(|| commands(&ctx, req));
+    for metric in middleware::take_metrics() {
+        telemetry.record_request(metric.command, "COMMAND", metric.status, metric.duration);
     }
+    telemetry.flush();
+    Ok(res)
 }
 
-#[tracing::instrument(name="delete_employee", skip_all)]
-fn delete_employee(_req: Request, params: Params) -> Result<impl IntoResponse> {
-    match params.get("id") {
-        Some(id) => match persistence::delete_employee_by_id(id)? {
-            true => Ok(Response::new(204, ())),
-            false => Ok(Response::new(404, ())),
-        },
-        None => Ok(Response::new(400, "Bad Request")),
+#[tracing::instrument(name="handle_commands", skip_all,
+                      fields(otel.kind = "server", request_id = %ctx.request_id))]
+fn commands(ctx: &RequestContext, req: Request) -> Response {
+    if queue::wants_async(&req) {
+        let res = queue::enqueue(ctx, &req)
+            .unwrap_or_else(|e| Response::new(500, e.to_string()));
+        return ctx.finish("commands", res);
     }
+    let mut res = router().handle(req);
+    consistency::finish(&mut res);
+    ctx.finish("commands", res)
 }
 
-fn create_location(req: Request, _: Params) -> Result<impl IntoResponse> {
-    let model: CreateLocationModel = serde_json::from_slice(req.body())?;
-
-    let created = persistence::create_location(model)?;
-    let b = serde_json::to_vec(&created)?;
-    Ok(ResponseBuilder::new(201)
-        .header("Content-Type", "application/json")
-        .body(b)
-        .build())
-}
-
-fn create_person(req: Request, _: Params) -> Result<impl IntoResponse> {
-    let model: CreatePersonModel = serde_json::from_slice(req.body())?;
-
-    let created = persistence::create_person(model)?;
-    let b = serde_json::to_vec(&created)?;
-    Ok(ResponseBuilder::new(201)
-        .header("Content-Type", "application/json")
-        .body(b)
-        .build())
-}
-
-fn update_location(req: Request, params: Params) -> Result<impl IntoResponse> {
-    let model: UpdateLocationModel = serde_json::from_slice(req.body())?;
+/// Routes of the commands, shared by synchronous requests and the queue worker
+fn router() -> Router {
+    let mut router = Router::default();
 
-    let updated = match params.get("lid") {
-        Some(lid) => persistence::update_location_by_id(lid, model)?,
-        None => return Ok(Response::new(400, ())),
-    };
-    match updated {
-        Some(u) => {
-            let b = serde_json::to_vec(&u)?;
-            Ok(ResponseBuilder::new(200)
-                .header("Content-Type", "application/json")
-                .body(b)
-                .build())
-        }
-        None => Ok(Response::new(404, "Not Found")),
+    let clock: Rc<dyn Clock> = Rc::new(SystemClock);
+    let bus = Rc::new(CommandBus::new(clock.clone(), vec![
+        Box::new(Metrics),
+        Box::new(Audit),
+        Box::new(Authorization),
+        Box::new(Validation),
+        Box::new(Idempotency),
+    ]));
+    for registration in bus::registry() {
+        let bus = bus.clone();
+        router.post(registration.path, move |req: Request, params: Params| {
+            (registration.dispatch)(&bus, req, params)
+        });
     }
-}
-
-fn update_person(req: Request, params: Params) -> Result<impl IntoResponse> {
-    let model: UpdatePersonModel = serde_json::from_slice(req.body())?;
-
-    let updated = match params.get("pid") {
-        Some(pid) => persistence::update_person_by_id(pid, model)?,
-        None => return Ok(Response::new(400, ())),
-    };
-    match updated {
-        Some(u) => {
-            let b = serde_json::to_vec(&u)?;
-            Ok(ResponseBuilder::new(200)
-                .header("Content-Type", "application/json")
-                .body(b)
-                .build())
+    // routes outside the bus check the caller's scopes themselves
+    let writer = |scope: &'static str, handler: fn(Request, Params, &dyn Clock) -> Result<Response>| {
+        let clock = clock.clone();
+        move |req: Request, params: Params| {
+            let scopes = RequestContext::from_request(&req).scopes;
+            let command = req.path().trim_start_matches('/').to_string();
+            match middleware::authorize(scopes.as_deref(), &command, scope) {
+                Some(rejection) => Ok(rejection.into_response()),
+                None => handler(req, params, clock.as_ref()),
+            }
         }
-        None => Ok(Response::new(404, "Not Found")),
-    }
-}
-
-fn delete_person(_req: Request, params: Params) -> Result<impl IntoResponse> {
-    match params.get("pid") {
-        Some(pid) => match persistence::delete_person_by_id(pid)? {
-            true => Ok(Response::new(204, ())),
-            false => Ok(Response::new(404, ())),
-        },
-        None => Ok(Response::new(400, "Bad Request")),
-    }
+    };
+    router.post("/import_persons",       writer(SCOPE_WRITE, import::import_persons));
+    router.post("/import_locations",     writer(SCOPE_WRITE, import::import_locations));
+    router.post("/batch",                writer(SCOPE_WRITE, batch::batch));
+    router.post("/purge",                writer(SCOPE_ADMIN, purge::purge));
+    router.post("/erase_employee/:id",   writer(SCOPE_ADMIN, privacy::erase));
+    router.post("/erase_person/:pid",    writer(SCOPE_ADMIN, privacy::erase));
+    router.get("/export_employee/:id",  writer(SCOPE_ADMIN, privacy::export));
+    router.get("/export_person/:pid",   writer(SCOPE_ADMIN, privacy::export));
+    router.post("/rotate_keys",          writer(SCOPE_ADMIN, rotation::rotate_keys));
+    router.post("/drain",                queue::drain);
+    router.get("/commands/:id",         queue::get_command);
+    router.get("/health",               health::health);
+    router.get("/diagnostics",          health::diagnostics);
+    router.get("/openapi",              openapi::openapi);
+    router.any("*", fallback);
+    router
 }
 
 #[tracing::instrument(name="fallback", skip_all)]
 fn fallback(req: Request, _: Params) -> Result<impl IntoResponse> {
-    println!("commands:fallback {}:{}", req.method(), req.uri());
+    RequestContext::from_request(&req)
+        .log(format!("commands:fallback {}:{}", req.method(), req.uri()));
     Ok(Response::new(404, ()))
 }
@@ -0,0 +1,187 @@
+use std::cell::RefCell;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use spin_sdk::http::{Response, ResponseBuilder};
+
+use crate::audit::{self, AuditRecord};
+use crate::bus::{Envelope, Middleware, Rejection, SCOPE_ADMIN};
+use crate::persistence::{self, StoredResponse};
+
+pub(crate) const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+/// Outcome of one command, exported with the telemetry of the request
+pub(crate) struct CommandMetric {
+    pub command: &'static str,
+    pub status: u16,
+    pub duration: Duration,
+}
+
+thread_local! {
+    static METRICS: RefCell<Vec<CommandMetric>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The metrics recorded since the last call
+pub(crate) fn take_metrics() -> Vec<CommandMetric> {
+    METRICS.with(|metrics| metrics.take())
+}
+
+/// Records count, status and latency of every command
+pub(crate) struct Metrics;
+
+impl Middleware for Metrics {
+    fn after(&self, envelope: &Envelope, res: &mut Response) {
+        let metric = CommandMetric {
+            command: envelope.command,
+            status: *res.status(),
+            duration: envelope.started.elapsed(),
+        };
+        METRICS.with(|metrics| metrics.borrow_mut().push(metric));
+    }
+}
+
+/// Logs who ran which command and how it ended, and records the commands
+/// that wrote nothing in the audit log; the bus records the others with their
+/// write. Replayed responses are recorded as such; the command did not run
+/// again. A command whose record cannot be written answers `500`.
+pub(crate) struct Audit;
+
+impl Middleware for Audit {
+    fn after(&self, envelope: &Envelope, res: &mut Response) {
+        let client = envelope.ctx.client.as_deref().unwrap_or("internal");
+        envelope.ctx.log(format!("commands:audit {} by {} -> {}", envelope.command, client, res.status()));
+        if envelope.audited {
+            return;
+        }
+
+        let status = *res.status();
+        let replayed = !envelope.executed && res.header(REPLAYED_HEADER).is_some();
+        let record = AuditRecord {
+            stamp: &envelope.stamp,
+            request_id: &envelope.ctx.request_id,
+            command: envelope.command,
+            entity: envelope.entity,
+            images: &envelope.images,
+            status,
+            outcome: if replayed { "replayed" } else { audit::outcome(status) },
+        };
+        if let Err(e) = audit::write(&record) {
+            let message = format!("{} not recorded in the audit log: {}", envelope.command, e);
+            *res = Rejection::new(500, message).into_response();
+        }
+    }
+}
+
+/// Rejects commands whose scope the gateway did not grant the caller, and
+/// requests without scopes, which did not pass the gateway. Queued commands
+/// carry the scopes of the caller who queued them.
+pub(crate) struct Authorization;
+
+impl Middleware for Authorization {
+    fn before(&self, envelope: &Envelope) -> Option<Response> {
+        authorize(envelope.ctx.scopes.as_deref(), envelope.command, envelope.scope).map(Rejection::into_response)
+    }
+}
+
+/// Rejects `command` unless the caller was granted `scope` or the admin
+/// scope. Also checks the routes that do not go through the bus.
+pub(crate) fn authorize(scopes: Option<&[String]>, command: &str, scope: &str) -> Option<Rejection> {
+    let granted = scopes.unwrap_or_default().iter().any(|s| s == scope || s == SCOPE_ADMIN);
+    (!granted).then(|| Rejection::new(403, format!("{} requires the {} scope", command, scope)))
+}
+
+/// Answers `400` with the errors of `Command::validate`
+pub(crate) struct Validation;
+
+impl Middleware for Validation {
+    fn before(&self, envelope: &Envelope) -> Option<Response> {
+        (!envelope.errors.is_empty()).then(|| Rejection {
+            status: 400,
+            message: "invalid command".to_string(),
+            errors: envelope.errors.clone(),
+        }.into_response())
+    }
+}
+
+/// Replays the stored response when a client repeats a command with the same
+/// `Idempotency-Key` within a day
+pub(crate) struct Idempotency;
+
+impl Idempotency {
+    fn key<'a>(envelope: &'a Envelope) -> Option<(&'a str, &'a str)> {
+        let key = envelope.request.header(IDEMPOTENCY_KEY_HEADER).and_then(|v| v.as_str())?;
+        Some((envelope.ctx.client.as_deref().unwrap_or("anonymous"), key))
+    }
+
+    fn request_hash(envelope: &Envelope) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(envelope.request.path().as_bytes());
+        hasher.update(envelope.request.body());
+        hex::encode(hasher.finalize())
+    }
+}
+
+impl Middleware for Idempotency {
+    fn before(&self, envelope: &Envelope) -> Option<Response> {
+        let (client, key) = Idempotency::key(envelope)?;
+        let stored = match persistence::idempotent_response(client, key) {
+            Ok(stored) => stored?,
+            Err(e) => return Some(Response::new(500, e.to_string())),
+        };
+        if stored.command != envelope.command || stored.request_hash != Idempotency::request_hash(envelope) {
+            return Some(Rejection::new(422, "idempotency key already used for another request").into_response());
+        }
+        let mut builder = ResponseBuilder::new(stored.status);
+        if let Some(content_type) = stored.content_type {
+            builder.header("Content-Type", content_type);
+        }
+        builder.header(REPLAYED_HEADER, "true");
+        Some(builder.body(stored.body).build())
+    }
+
+    fn after(&self, envelope: &Envelope, res: &mut Response) {
+        let Some((client, key)) = Idempotency::key(envelope) else {
+            return;
+        };
+        if !envelope.executed || *res.status() >= 500 {
+            return;
+        }
+        let stored = StoredResponse {
+            command: envelope.command.to_string(),
+            request_hash: Idempotency::request_hash(envelope),
+            status: *res.status(),
+            content_type: res.header("content-type").and_then(|v| v.as_str()).map(String::from),
+            body: res.body().to_vec(),
+        };
+        if let Err(e) = persistence::store_idempotent_response(client, key, stored) {
+            envelope.ctx.log(format!("commands:idempotency {}", e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::SCOPE_WRITE;
+
+    fn scopes(scopes: &[&str]) -> Vec<String> {
+        scopes.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn the_scope_or_admin_is_required() {
+        assert!(authorize(Some(&scopes(&["read", "write"])), "batch", SCOPE_WRITE).is_none());
+        assert!(authorize(Some(&scopes(&["admin"])), "purge", SCOPE_ADMIN).is_none());
+        assert!(authorize(Some(&scopes(&["admin"])), "batch", SCOPE_WRITE).is_none());
+        let rejection = authorize(Some(&scopes(&["write"])), "purge", SCOPE_ADMIN).unwrap();
+        assert_eq!(rejection.status, 403);
+        assert_eq!(rejection.message, "purge requires the admin scope");
+    }
+
+    #[test]
+    fn requests_without_scopes_are_rejected() {
+        assert!(authorize(None, "batch", SCOPE_WRITE).is_some());
+        assert!(authorize(Some(&[]), "batch", SCOPE_WRITE).is_some());
+    }
+}
@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 /// API Model for creating a new Employee
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct CreateEmployeeModel {
     /// Employee first name
     #[serde(rename = "firstName")]
@@ -14,7 +15,7 @@ pub struct CreateEmployeeModel {
 }
 
 /// API Model for creating a new address
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct CreateAddressModel {
     /// street
     pub street: String,
@@ -24,7 +25,7 @@ pub struct CreateAddressModel {
     pub city: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct CreatePersonModel {
     #[serde(rename = "firstName")]
     pub first_name: String,
@@ -33,7 +34,7 @@ pub struct CreatePersonModel {
     pub plid: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct CreateLocationModel {
     pub street: String,
     pub zip: String,
@@ -41,7 +42,7 @@ pub struct CreateLocationModel {
 }
 
 /// API Model for updating an Employee
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct UpdateEmployeeModel {
     /// first name
     #[serde(rename = "firstName")]
@@ -56,7 +57,7 @@ pub struct UpdateEmployeeModel {
 }
 
 /// API Model for updating an Address
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct UpdateAddressModel {
     /// street
     pub street: String,
@@ -68,7 +69,7 @@ pub struct UpdateAddressModel {
     pub city: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct UpdatePersonModel {
     #[serde(rename = "firstName")]
     pub first_name: String,
@@ -77,15 +78,49 @@ pub struct UpdatePersonModel {
     pub plid: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct UpdateLocationModel {
     pub street: String,
     pub zip: String,
     pub city: String,
 }
 
+/// API Model for restoring an entity to one of its versions, by number or as
+/// of a time
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RestoreModel {
+    /// number of the version, as listed by the history of the entity
+    pub version: Option<i64>,
+    /// UTC date or time; the version current at that time is restored
+    pub at: Option<String>,
+}
+
+/// Response Model for a restored entity
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RestoredModel {
+    /// number of the version that was restored
+    #[serde(rename = "restoredVersion")]
+    pub restored_version: i64,
+    /// fields the restore changed
+    pub changes: Vec<ChangeModel>,
+    /// the entity after the restore
+    #[schema(value_type = Object)]
+    pub state: serde_json::Value,
+}
+
+/// Response Model for a field changed by a restore
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChangeModel {
+    /// path of the field, e.g. `address.city`
+    pub field: String,
+    #[schema(value_type = Object)]
+    pub from: serde_json::Value,
+    #[schema(value_type = Object)]
+    pub to: serde_json::Value,
+}
+
 /// Response Model for a newly created Employee
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct EmployeeCreatedModel {
     /// unique identifier
     pub id: String,
@@ -103,7 +138,7 @@ pub struct EmployeeCreatedModel {
 }
 
 /// API model for a newly created address
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct AddressCreatedModel {
     /// identifier
     pub id: String,
@@ -119,7 +154,7 @@ pub struct AddressCreatedModel {
 }
 
 /// API model for an updated employee
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct EmployeeUpdatedModel {
     /// identifier
     pub id: String,
@@ -137,7 +172,7 @@ pub struct EmployeeUpdatedModel {
 }
 
 /// API model for an updated Address
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct AddressUpdatedModel {
     /// identifier
     pub id: String,
@@ -152,7 +187,7 @@ pub struct AddressUpdatedModel {
     pub city: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct PersonCreatedModel {
     pub pid: String,
     #[serde(rename = "firstName")]
@@ -162,7 +197,7 @@ pub struct PersonCreatedModel {
     pub plid: String    
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct LocationCreatedModel {
     pub lid: String,
     pub street: String,
@@ -170,7 +205,7 @@ pub struct LocationCreatedModel {
     pub city: String
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct PersonUpdatedModel {
     pub pid: String,
     #[serde(rename = "firstName")]
@@ -180,10 +215,324 @@ pub struct PersonUpdatedModel {
     pub plid: String 
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct LocationUpdatedModel {
     pub lid: String,
     pub street: String,
     pub zip: String,
     pub city: String
-}
\ No newline at end of file
+}
+
+/// API Model for one row of a person import. The location is given either by
+/// its id or by street, zip and city.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ImportPersonModel {
+    /// first name
+    #[serde(rename = "firstName")]
+    pub first_name: String,
+    /// last name
+    #[serde(rename = "lastName")]
+    pub last_name: String,
+    /// location identifier
+    #[serde(default)]
+    pub plid: Option<String>,
+    /// location street
+    #[serde(default)]
+    pub street: Option<String>,
+    /// location zip code
+    #[serde(default)]
+    pub zip: Option<String>,
+    /// location city
+    #[serde(default)]
+    pub city: Option<String>,
+}
+
+/// Response Model for an import
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImportReportModel {
+    /// all-or-nothing or best-effort
+    pub mode: String,
+    /// rows received
+    pub total: usize,
+    /// rows created
+    pub created: usize,
+    /// rows matching an existing entity, nothing created
+    pub existing: usize,
+    /// rows rejected by validation or by the database
+    pub rejected: usize,
+    /// outcome of every row, in input order
+    pub rows: Vec<ImportRowModel>,
+}
+
+/// Response Model for the outcome of one imported row
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImportRowModel {
+    /// row number, starting at 1 with the first data row
+    pub row: usize,
+    /// created, existing, rejected or skipped
+    pub status: String,
+    /// identifier of the created or existing entity
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// reasons of a rejection
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+}
+
+/// API Model for a batch of commands executed in one transaction
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct BatchModel {
+    /// commands, executed in order
+    pub operations: Vec<BatchOperationModel>,
+}
+
+/// API Model for one command of a batch. Wherever an id is expected, in `id`
+/// or in `body`, `{"$ref": "<ref>"}` stands for the id created by an earlier
+/// operation of the batch carrying that `ref`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct BatchOperationModel {
+    /// createEmployee, updateEmployee, deleteEmployee, createLocation,
+    /// updateLocation, createPerson, updatePerson, deletePerson,
+    /// restoreEmployee, restoreLocation, restorePerson, undeleteEmployee or
+    /// undeletePerson
+    pub op: String,
+    /// name under which later operations refer to the id this one creates
+    #[serde(rename = "ref", default)]
+    pub reference: Option<String>,
+    /// id of the entity to update or delete
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    pub id: Option<serde_json::Value>,
+    /// request model of the command
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    pub body: Option<serde_json::Value>,
+}
+
+/// Response Model for a batch
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchResultModel {
+    /// whether the transaction was committed
+    pub committed: bool,
+    /// index of the operation that failed, when rolled back
+    #[serde(rename = "failedAt", skip_serializing_if = "Option::is_none")]
+    pub failed_at: Option<usize>,
+    /// reason of the failure, when rolled back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// outcome of every operation executed, in order
+    pub results: Vec<BatchOperationResultModel>,
+}
+
+/// Response Model for the outcome of one command of a batch
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchOperationResultModel {
+    /// position in the batch, starting at 0
+    pub index: usize,
+    /// command
+    pub op: String,
+    /// reference given to the operation
+    #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    /// HTTP status the command would have answered on its own
+    pub status: u16,
+    /// response body of the command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Object)]
+    pub body: Option<serde_json::Value>,
+}
+
+/// Response Model for a command accepted for asynchronous execution
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CommandStatusModel {
+    /// command identifier
+    pub id: String,
+    /// pending, running, succeeded or failed
+    pub status: String,
+    /// path of the command
+    pub command: String,
+    /// time the command was accepted
+    #[serde(rename = "createdAt")]
+    pub created_at: String,
+    /// time a worker started the command
+    #[serde(rename = "startedAt", skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<String>,
+    /// time the command finished
+    #[serde(rename = "finishedAt", skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    /// response of the command, once finished
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<CommandResultModel>,
+}
+
+/// Response Model for the response of an asynchronous command
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CommandResultModel {
+    /// HTTP status the command answered
+    pub status: u16,
+    /// response body, as JSON when the command answered JSON
+    #[schema(value_type = Object)]
+    pub body: serde_json::Value,
+}
+
+/// Response Model for a purge of deleted entities
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PurgeModel {
+    /// days deleted entities are kept
+    #[serde(rename = "retentionDays")]
+    pub retention_days: u64,
+    /// entities deleted before this time were purged
+    pub cutoff: String,
+    /// employees purged
+    pub employees: usize,
+    /// persons purged
+    pub persons: usize,
+}
+
+/// Response Model for the subject-access export of an employee or person:
+/// everything stored that refers to its id
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PersonalDataModel {
+    /// employees or persons
+    pub entity: String,
+    pub id: String,
+    #[serde(rename = "exportedAt")]
+    pub exported_at: String,
+    /// the stored entity with its stamps, soft-deleted or not; absent once purged
+    #[schema(value_type = Object)]
+    pub current: Option<serde_json::Value>,
+    /// every version, oldest first
+    #[schema(value_type = Vec<Object>)]
+    pub history: Vec<serde_json::Value>,
+    /// every audit record targeting the entity, oldest first
+    #[schema(value_type = Vec<Object>)]
+    pub audit: Vec<serde_json::Value>,
+    /// queued commands whose path, body or result mention the id
+    #[schema(value_type = Vec<Object>)]
+    pub commands: Vec<serde_json::Value>,
+    /// responses stored for idempotency keys that mention the id
+    #[serde(rename = "idempotentResponses")]
+    #[schema(value_type = Vec<Object>)]
+    pub idempotent_responses: Vec<serde_json::Value>,
+}
+
+/// Response Model for the erasure of an employee or person
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErasureModel {
+    /// employees or persons
+    pub entity: String,
+    pub id: String,
+    #[serde(rename = "erasedAt")]
+    pub erased_at: String,
+    /// whether the stored entity was anonymized; false once purged
+    pub current: bool,
+    /// versions of the history anonymized
+    pub versions: usize,
+    /// audit records anonymized
+    #[serde(rename = "auditRecords")]
+    pub audit_records: usize,
+    /// queued commands and stored idempotent responses scrubbed
+    pub payloads: usize,
+    /// responses cached by queries deleted
+    #[serde(rename = "cachedResponses")]
+    pub cached_responses: usize,
+}
+
+/// Response Model for a rotation of the encryption keys: rows encrypted again
+/// with the current key
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RotationModel {
+    /// version of the key everything is now encrypted with
+    #[serde(rename = "keyVersion")]
+    pub key_version: u32,
+    pub employees: usize,
+    pub addresses: usize,
+    pub persons: usize,
+    pub locations: usize,
+    /// versions of the history
+    pub versions: usize,
+    /// records of the audit log
+    #[serde(rename = "auditRecords")]
+    pub audit_records: usize,
+    /// queued commands and stored idempotent responses
+    pub payloads: usize,
+}
+
+/// Response Model for a run of the queue worker
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DrainModel {
+    /// commands executed
+    pub processed: usize,
+    /// commands that succeeded
+    pub succeeded: usize,
+    /// commands that failed
+    pub failed: usize,
+    /// commands still pending
+    pub pending: i64,
+}
+
+/// Response Model for the health of the component
+#[derive(Debug, Serialize)]
+pub struct HealthModel {
+    /// component name
+    pub component: String,
+    /// ok or fail
+    pub status: String,
+    /// individual checks
+    pub checks: Vec<CheckModel>,
+}
+
+/// Response Model for a single health check
+#[derive(Debug, Serialize)]
+pub struct CheckModel {
+    /// check name
+    pub name: String,
+    /// ok or fail
+    pub status: String,
+    /// reason of a failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Response Model for the diagnostics of the component
+#[derive(Debug, Serialize)]
+pub struct DiagnosticsModel {
+    /// health of the component
+    #[serde(flatten)]
+    pub health: HealthModel,
+    /// build information
+    pub build: BuildModel,
+    /// schema version
+    pub schema: SchemaModel,
+}
+
+/// API model for build information
+#[derive(Debug, Serialize)]
+pub struct BuildModel {
+    /// crate name
+    pub name: String,
+    /// crate version
+    pub version: String,
+}
+
+/// API model for the schema version
+#[derive(Debug, Serialize)]
+pub struct SchemaModel {
+    /// version this build expects
+    pub expected: i64,
+    /// last migration applied to the database
+    #[serde(rename = "lastMigration")]
+    pub last_migration: Option<MigrationModel>,
+}
+
+/// API model for an applied migration
+#[derive(Debug, Serialize)]
+pub struct MigrationModel {
+    /// schema version
+    pub version: i64,
+    /// migration name
+    pub name: String,
+    /// time the migration was applied
+    #[serde(rename = "appliedAt")]
+    pub applied_at: String,
+}
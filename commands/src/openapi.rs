@@ -0,0 +1,27 @@
+use anyhow::Result;
+use spin_sdk::http::{IntoResponse, Params, Request, ResponseBuilder};
+use utoipa::OpenApi;
+
+use crate::models::*;
+
+/// Request and response models of the commands, merged by the gateway into
+/// the API specification it serves
+#[derive(OpenApi)]
+#[openapi(components(schemas(
+    CreateEmployeeModel, CreateAddressModel, UpdateEmployeeModel, UpdateAddressModel,
+    EmployeeCreatedModel, AddressCreatedModel, EmployeeUpdatedModel, AddressUpdatedModel,
+    CreateLocationModel, UpdateLocationModel, LocationCreatedModel, LocationUpdatedModel,
+    CreatePersonModel, UpdatePersonModel, PersonCreatedModel, PersonUpdatedModel,
+    RestoreModel, RestoredModel, ChangeModel,
+    ImportPersonModel, ImportReportModel, ImportRowModel,
+    BatchModel, BatchOperationModel, BatchResultModel, BatchOperationResultModel,
+    CommandStatusModel, CommandResultModel, DrainModel, PurgeModel, PersonalDataModel, ErasureModel, RotationModel,
+)))]
+struct CommandsApi;
+
+pub(crate) fn openapi(_req: Request, _: Params) -> Result<impl IntoResponse> {
+    Ok(ResponseBuilder::new(200)
+        .header("Content-Type", "application/json")
+        .body(CommandsApi::openapi().to_json()?)
+        .build())
+}
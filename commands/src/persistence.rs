@@ -1,12 +1,21 @@
-use anyhow::Result;
-use spin_sdk::sqlite::{Connection, Value};
+
+use anyhow::{anyhow, Result};
+use serde_json::Value as JsonValue;
+use spin_sdk::sqlite::{Connection, Error, QueryResult, Value};
+use telemetry::RequestContext;
 use uuid::Uuid;
 
+use crate::audit::AuditRecord;
+use crate::clock::Stamp;
+use crate::privacy::Subject;
+use crate::{cache, consistency, crypto};
+
 use crate::models::{
     AddressCreatedModel, AddressUpdatedModel, CreateEmployeeModel, EmployeeCreatedModel,
     EmployeeUpdatedModel, UpdateEmployeeModel,
     CreateLocationModel, UpdateLocationModel, LocationCreatedModel, LocationUpdatedModel,
-    CreatePersonModel, UpdatePersonModel, PersonCreatedModel, PersonUpdatedModel
+    CreatePersonModel, UpdatePersonModel, PersonCreatedModel, PersonUpdatedModel,
+    CommandStatusModel, CommandResultModel, MigrationModel, PersonalDataModel, ErasureModel, RotationModel
 };
 
 const COMMAND_CREATE_EMPLOYEE: &str =
@@ -19,37 +28,240 @@ const COMMAND_CREATE_LOCATION: &str =
     "INSERT INTO Locations (Lid, Street, Zip, City) VALUES (?, ?, ?, ?);";
 
 const COMMAND_UPDATE_EMPLOYEE: &str =
-    "UPDATE Employees SET FirstName = ?, LastName = ? WHERE Id = ?; RETURNING Id;";
+    "UPDATE Employees SET FirstName = ?, LastName = ? WHERE Id = ? AND NOT EXISTS (SELECT 1 FROM Deletions WHERE Entity = 'Employees' AND Id = Employees.Id) RETURNING Id";
 const COMMAND_UPDATE_ADDRESS: &str =
-    "UPDATE Addresses SET Street = ?, Zip = ?, City = ? WHERE EmployeeId = ? RETURNING EmployeeId";
+    "UPDATE Addresses SET Street = ?, Zip = ?, City = ? WHERE EmployeeId = ? \
+     AND NOT EXISTS (SELECT 1 FROM Deletions WHERE Entity = 'Employees' AND Id = Addresses.EmployeeId) RETURNING EmployeeId";
 const COMMAND_UPDATE_PERSON: &str =
-    "UPDATE Persons SET FirstName = ?, LastName = ?, Plid = ? WHERE Pid = ? RETURNING Pid";
+    "UPDATE Persons SET FirstName = ?, LastName = ?, Plid = ? WHERE Pid = ? \
+     AND NOT EXISTS (SELECT 1 FROM Deletions WHERE Entity = 'Persons' AND Id = Persons.Pid) RETURNING Pid";
 const COMMAND_UPDATE_LOCATION: &str =
     "UPDATE Locations SET Street = ?, Zip = ?, City = ? WHERE Lid = ? RETURNING Lid";
 
-const COMMAND_DELETE_EMPLOYEE: &str = 
-    "DELETE FROM Employees WHERE Id = ? RETURNING Id";
-const COMMAND_DELETE_PERSON: &str = 
-    "DELETE FROM Persons WHERE Pid = ? RETURNING Pid";
+const COMMAND_DELETE_EMPLOYEE: &str =
+    "INSERT OR IGNORE INTO Deletions (Entity, Id, DeletedAt, DeletedBy) \
+     SELECT 'Employees', Id, ?, ? FROM Employees WHERE Id = ? RETURNING Id";
+const COMMAND_DELETE_PERSON: &str =
+    "INSERT OR IGNORE INTO Deletions (Entity, Id, DeletedAt, DeletedBy) \
+     SELECT 'Persons', Pid, ?, ? FROM Persons WHERE Pid = ? RETURNING Id";
+const COMMAND_UNDELETE: &str =
+    "DELETE FROM Deletions WHERE Entity = ? AND Id = ? RETURNING Id";
+const COMMAND_PURGE_EMPLOYEES: &str =
+    "DELETE FROM Employees WHERE Id IN (SELECT Id FROM Deletions WHERE Entity = 'Employees' AND DeletedAt < ?) \
+     RETURNING Id";
+const COMMAND_PURGE_PERSONS: &str =
+    "DELETE FROM Persons WHERE Pid IN (SELECT Id FROM Deletions WHERE Entity = 'Persons' AND DeletedAt < ?) \
+     RETURNING Pid Id";
+const QUERY_EMPLOYEE_RECORD: &str =
+    "SELECT json_object('id', Employees.Id, 'firstName', Employees.FirstName, 'lastName', Employees.LastName, \
+     'address', json_object('street', Addresses.Street, 'zip', Addresses.Zip, 'city', Addresses.City), \
+     'createdAt', Stamps.CreatedAt, 'createdBy', Stamps.CreatedBy, 'updatedAt', Stamps.UpdatedAt, 'updatedBy', Stamps.UpdatedBy, \
+     'deletedAt', Deletions.DeletedAt, 'deletedBy', Deletions.DeletedBy) Record \
+     FROM Employees LEFT JOIN Addresses ON Addresses.EmployeeId = Employees.Id \
+     LEFT JOIN ChangeStamps Stamps ON Stamps.Entity = 'Employees' AND Stamps.Id = Employees.Id \
+     LEFT JOIN Deletions ON Deletions.Entity = 'Employees' AND Deletions.Id = Employees.Id WHERE Employees.Id = ?";
+const QUERY_PERSON_RECORD: &str =
+    "SELECT json_object('pid', Pid, 'firstName', FirstName, 'lastName', LastName, 'plid', Plid, \
+     'createdAt', Stamps.CreatedAt, 'createdBy', Stamps.CreatedBy, 'updatedAt', Stamps.UpdatedAt, 'updatedBy', Stamps.UpdatedBy, \
+     'deletedAt', Deletions.DeletedAt, 'deletedBy', Deletions.DeletedBy) Record \
+     FROM Persons LEFT JOIN ChangeStamps Stamps ON Stamps.Entity = 'Persons' AND Stamps.Id = Persons.Pid \
+     LEFT JOIN Deletions ON Deletions.Entity = 'Persons' AND Deletions.Id = Persons.Pid WHERE Pid = ?";
+const QUERY_HISTORY_RECORDS: &str =
+    "SELECT json_object('version', Version, 'at', At, 'actor', Actor, 'operation', Operation, 'state', json(State)) Record \
+     FROM EntityVersions WHERE Entity = ? AND Id = ? ORDER BY Version";
+const QUERY_AUDIT_RECORDS: &str =
+    "SELECT json_object('seq', Seq, 'at', At, 'actor', Actor, 'requestId', RequestId, 'command', Command, \
+     'before', json(Before), 'after', json(After), 'status', Status, 'outcome', Outcome) Record \
+     FROM AuditLog WHERE Entity = ? AND TargetId = ? ORDER BY Seq";
+const QUERY_QUEUED_RECORDS: &str =
+    "SELECT json_object('id', Id, 'command', Path, 'status', Status, 'createdAt', CreatedAt, 'finishedAt', FinishedAt, \
+     'body', CAST(Body AS TEXT), 'result', CAST(ResultBody AS TEXT)) Record FROM CommandQueue \
+     WHERE instr(Path, ?1) > 0 OR instr(CAST(Body AS TEXT), ?1) > 0 OR instr(CAST(ResultBody AS TEXT), ?1) > 0 \
+        OR CAST(Body AS TEXT) LIKE 'enc:%' OR CAST(ResultBody AS TEXT) LIKE 'enc:%' \
+     ORDER BY CreatedAt";
+const QUERY_IDEMPOTENT_RECORDS: &str =
+    "SELECT json_object('client', Client, 'key', Key, 'command', Command, 'status', Status, 'createdAt', CreatedAt, \
+     'body', CAST(Body AS TEXT)) Record FROM IdempotencyKeys \
+     WHERE instr(CAST(Body AS TEXT), ?1) > 0 OR CAST(Body AS TEXT) LIKE 'enc:%' ORDER BY CreatedAt";
+const QUERY_PENDING_FOR: &str =
+    "SELECT Id FROM CommandQueue WHERE Status IN ('pending', 'running') AND instr(Path, ?) > 0 LIMIT 1";
+const QUERY_QUEUED_PAYLOADS: &str =
+    "SELECT Id, Path, Body, ResultBody FROM CommandQueue \
+     WHERE instr(Path, ?1) > 0 OR instr(CAST(Body AS TEXT), ?1) > 0 OR instr(CAST(ResultBody AS TEXT), ?1) > 0 \
+        OR CAST(Body AS TEXT) LIKE 'enc:%' OR CAST(ResultBody AS TEXT) LIKE 'enc:%'";
+const COMMAND_SCRUB_QUEUED: &str =
+    "UPDATE CommandQueue SET Body = ?, ResultBody = ? WHERE Id = ?";
+const QUERY_IDEMPOTENT_PAYLOADS: &str =
+    "SELECT Client, Key, Body FROM IdempotencyKeys \
+     WHERE instr(CAST(Body AS TEXT), ?1) > 0 OR CAST(Body AS TEXT) LIKE 'enc:%'";
+const QUERY_ALL_QUEUED_PAYLOADS: &str =
+    "SELECT Id, Body, ResultBody FROM CommandQueue";
+const QUERY_ALL_IDEMPOTENT_PAYLOADS: &str =
+    "SELECT Client, Key, Body FROM IdempotencyKeys";
+const COMMAND_SCRUB_IDEMPOTENT: &str =
+    "UPDATE IdempotencyKeys SET Body = ? WHERE Client = ? AND Key = ?";
+const COMMAND_ERASE_EMPLOYEE: &str =
+    "UPDATE Employees SET FirstName = ?2, LastName = ?2 WHERE Id = ?1 RETURNING Id";
+const COMMAND_ERASE_ADDRESS: &str =
+    "UPDATE Addresses SET Street = ?2, Zip = ?2, City = ?2 WHERE EmployeeId = ?1 RETURNING EmployeeId Id";
+const COMMAND_ERASE_PERSON: &str =
+    "UPDATE Persons SET FirstName = ?2, LastName = ?2 WHERE Pid = ?1 RETURNING Pid Id";
+const COMMAND_ERASE_EMPLOYEE_VERSIONS: &str =
+    "UPDATE EntityVersions SET State = json_replace(State, '$.firstName', ?2, '$.lastName', ?2, \
+     '$.address.street', ?2, '$.address.zip', ?2, '$.address.city', ?2) \
+     WHERE Entity = 'employees' AND Id = ?1 AND State IS NOT NULL RETURNING Version";
+const COMMAND_ERASE_PERSON_VERSIONS: &str =
+    "UPDATE EntityVersions SET State = json_replace(State, '$.firstName', ?2, '$.lastName', ?2) \
+     WHERE Entity = 'persons' AND Id = ?1 AND State IS NOT NULL RETURNING Version";
+const COMMAND_ERASE_EMPLOYEE_AUDIT: &str =
+    "UPDATE AuditLog SET \
+     Before = json_replace(Before, '$.firstName', ?2, '$.lastName', ?2, '$.address.street', ?2, '$.address.zip', ?2, '$.address.city', ?2), \
+     After = json_replace(After, '$.firstName', ?2, '$.lastName', ?2, '$.address.street', ?2, '$.address.zip', ?2, '$.address.city', ?2) \
+     WHERE Entity = 'employees' AND TargetId = ?1 RETURNING Seq";
+const COMMAND_ERASE_PERSON_AUDIT: &str =
+    "UPDATE AuditLog SET Before = json_replace(Before, '$.firstName', ?2, '$.lastName', ?2), \
+     After = json_replace(After, '$.firstName', ?2, '$.lastName', ?2) \
+     WHERE Entity = 'persons' AND TargetId = ?1 RETURNING Seq";
+const QUERY_EMPLOYEE_SECRETS: &str = "SELECT Id Key, FirstName, LastName FROM Employees";
+const COMMAND_RESEAL_EMPLOYEE: &str = "UPDATE Employees SET FirstName = ?, LastName = ? WHERE Id = ?";
+const QUERY_ADDRESS_SECRETS: &str = "SELECT EmployeeId Key, Street FROM Addresses";
+const COMMAND_RESEAL_ADDRESS: &str = "UPDATE Addresses SET Street = ? WHERE EmployeeId = ?";
+const QUERY_PERSON_SECRETS: &str = "SELECT Pid Key, FirstName, LastName FROM Persons";
+const COMMAND_RESEAL_PERSON: &str = "UPDATE Persons SET FirstName = ?, LastName = ? WHERE Pid = ?";
+const QUERY_LOCATION_SECRETS: &str = "SELECT Lid Key, Street FROM Locations";
+const COMMAND_RESEAL_LOCATION: &str = "UPDATE Locations SET Street = ? WHERE Lid = ?";
+const QUERY_VERSION_STATES: &str =
+    "SELECT Entity, Id, Version, State FROM EntityVersions WHERE State IS NOT NULL";
+const COMMAND_RESEAL_VERSION: &str =
+    "UPDATE EntityVersions SET State = ? WHERE Entity = ? AND Id = ? AND Version = ?";
+const QUERY_AUDIT_IMAGES: &str =
+    "SELECT Seq, Entity, Before, After FROM AuditLog WHERE Before IS NOT NULL OR After IS NOT NULL";
+const COMMAND_RESEAL_AUDIT: &str =
+    "UPDATE AuditLog SET Before = ?, After = ? WHERE Seq = ?";
+const COMMAND_DELETE_LOCATION: &str =
+    "DELETE FROM Locations WHERE Lid = ? RETURNING Lid";
 
-pub(crate) fn create_employee(model: CreateEmployeeModel) -> Result<EmployeeCreatedModel> {
-    let con = Connection::open_default()?;
+const QUERY_LOCATION_EXISTS: &str =
+    "SELECT Lid FROM Locations WHERE Lid = ?";
+const QUERY_LOCATION_IN_USE: &str =
+    "SELECT Pid FROM Persons WHERE Plid = ? LIMIT 1";
+const QUERY_LOCATION_BY_KEY: &str =
+    "SELECT Lid FROM Locations WHERE Street = ? AND Zip = ? AND City = ? LIMIT 1";
+
+const COMMAND_ENQUEUE: &str =
+    "INSERT OR IGNORE INTO CommandQueue (Id, Path, ContentType, Body, RequestId, Client, Scopes, IdempotencyKey)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?)";
+const QUERY_QUEUED_BY_KEY: &str =
+    "SELECT Id, Path, Body FROM CommandQueue WHERE Client IS ? AND IdempotencyKey = ? ORDER BY CreatedAt LIMIT 1";
+const COMMAND_CLAIM: &str =
+    "UPDATE CommandQueue SET Status = 'running', StartedAt = CURRENT_TIMESTAMP WHERE Id = (
+        SELECT Id FROM CommandQueue
+        WHERE Status = 'pending' OR (Status = 'running' AND StartedAt < datetime('now', ?))
+        ORDER BY CreatedAt LIMIT 1)
+    RETURNING Id, Path, ContentType, Body, RequestId, Client, Scopes, IdempotencyKey";
+const COMMAND_FINISH: &str =
+    "UPDATE CommandQueue SET Status = ?, ResultStatus = ?, ResultContentType = ?, ResultBody = ?,
+        FinishedAt = CURRENT_TIMESTAMP WHERE Id = ?";
+const QUERY_COMMAND: &str =
+    "SELECT Id, Path, Status, ResultStatus, ResultContentType, ResultBody, CreatedAt, StartedAt, FinishedAt
+    FROM CommandQueue WHERE Id = ?";
+const QUERY_PENDING_COMMANDS: &str =
+    "SELECT COUNT(*) AS Pending FROM CommandQueue WHERE Status = 'pending'";
+
+const QUERY_IDEMPOTENCY_KEY: &str =
+    "SELECT Command, RequestHash, Status, ContentType, Body FROM IdempotencyKeys
+    WHERE Client = ? AND Key = ? AND CreatedAt >= datetime('now', '-1 day')";
+const COMMAND_STORE_IDEMPOTENCY_KEY: &str =
+    "INSERT OR REPLACE INTO IdempotencyKeys (Client, Key, Command, RequestHash, Status, ContentType, Body)
+    VALUES (?, ?, ?, ?, ?, ?, ?)";
+const COMMAND_EXPIRE_IDEMPOTENCY_KEYS: &str =
+    "DELETE FROM IdempotencyKeys WHERE CreatedAt < datetime('now', '-1 day')";
+
+const COMMAND_STAMP_CREATED: &str =
+    "INSERT INTO ChangeStamps (Entity, Id, CreatedAt, CreatedBy, UpdatedAt, UpdatedBy) VALUES (?, ?, ?, ?, ?, ?)";
+const COMMAND_STAMP_UPDATED: &str =
+    "INSERT INTO ChangeStamps (Entity, Id, UpdatedAt, UpdatedBy) VALUES (?, ?, ?, ?) \
+     ON CONFLICT (Entity, Id) DO UPDATE SET UpdatedAt = excluded.UpdatedAt, UpdatedBy = excluded.UpdatedBy";
+
+const QUERY_EMPLOYEE_IMAGE: &str =
+    "SELECT json_object('id', Employees.Id, 'firstName', Employees.FirstName, 'lastName', Employees.LastName, 'address', json_object('street', Addresses.Street, 'zip', Addresses.Zip, 'city', Addresses.City)) Image FROM Employees LEFT JOIN Addresses ON Addresses.EmployeeId = Employees.Id WHERE Employees.Id = ? AND NOT EXISTS (SELECT 1 FROM Deletions WHERE Entity = 'Employees' AND Id = Employees.Id)";
+const QUERY_PERSON_IMAGE: &str =
+    "SELECT json_object('pid', Pid, 'firstName', FirstName, 'lastName', LastName, 'plid', Plid) Image FROM Persons WHERE Pid = ? AND NOT EXISTS (SELECT 1 FROM Deletions WHERE Entity = 'Persons' AND Id = Persons.Pid)";
+const QUERY_LOCATION_IMAGE: &str =
+    "SELECT json_object('lid', Lid, 'street', Street, 'zip', Zip, 'city', City) Image FROM Locations WHERE Lid = ?";
+const COMMAND_INSERT_VERSION: &str =
+    "INSERT INTO EntityVersions (Entity, Id, Version, At, Actor, Operation, State) \
+     SELECT ?1, ?2, COALESCE(MAX(Version), 0) + 1, ?3, ?4, \
+     CASE WHEN ?5 IS NULL THEN 'deleted' WHEN MAX(Version) IS NULL THEN 'created' \
+     WHEN (SELECT Latest.State FROM EntityVersions Latest WHERE Latest.Entity = ?1 AND Latest.Id = ?2 \
+     ORDER BY Latest.Version DESC LIMIT 1) IS NULL THEN 'undeleted' ELSE 'updated' END, ?5 \
+     FROM EntityVersions WHERE Entity = ?1 AND Id = ?2";
+const QUERY_VERSION: &str =
+    "SELECT Version, State FROM EntityVersions WHERE Entity = ?1 AND Id = ?2 \
+     AND (?3 IS NULL OR Version = ?3) AND (?4 IS NULL OR At <= ?4) ORDER BY Version DESC LIMIT 1";
+const COMMAND_INSERT_AUDIT: &str =
+    "INSERT INTO AuditLog (At, Actor, RequestId, Command, Entity, TargetId, Before, After, Status, Outcome) \
+     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+
+const QUERY_LAST_MIGRATION: &str =
+    "SELECT Version, Name, AppliedAt FROM SchemaMigrations ORDER BY Version DESC LIMIT 1";
+const QUERY_COMMIT_POSITION: &str =
+    "SELECT COALESCE(SUM(Version), 0) Position FROM TableVersions";
+
+/// Runs a statement in its own span, so every SQL statement shows up in the
+/// exported traces.
+#[tracing::instrument(name = "sqlite.execute", skip_all,
+                      fields(db.system = "sqlite", db.statement = statement))]
+fn execute(con: &Connection, statement: &str, parameters: &[Value]) -> Result<QueryResult, Error> {
+    con.execute(statement, parameters)
+}
+
+/// Records who created an entity and when; a new entity counts as updated
+/// at the same time
+fn stamp_created(con: &Connection, entity: &str, id: &str, stamp: &Stamp) -> Result<()> {
+    let params = [
+        Value::Text(entity.to_string()),
+        Value::Text(id.to_string()),
+        Value::Text(stamp.at.clone()),
+        Value::Text(stamp.by.clone()),
+        Value::Text(stamp.at.clone()),
+        Value::Text(stamp.by.clone()),
+    ];
+    execute(con, COMMAND_STAMP_CREATED, &params)?;
+    Ok(())
+}
+
+/// Records who updated an entity and when. Entities created before stamps
+/// were kept get a stamp without creation.
+fn stamp_updated(con: &Connection, entity: &str, id: &str, stamp: &Stamp) -> Result<()> {
+    let params = [
+        Value::Text(entity.to_string()),
+        Value::Text(id.to_string()),
+        Value::Text(stamp.at.clone()),
+        Value::Text(stamp.by.clone()),
+    ];
+    execute(con, COMMAND_STAMP_UPDATED, &params)?;
+    Ok(())
+}
+
+pub(crate) fn insert_employee(con: &Connection, model: CreateEmployeeModel,
+                              stamp: &Stamp) -> Result<EmployeeCreatedModel> {
     let id = Uuid::new_v4();
     let employee_params = [
         Value::Text(id.to_string()),
-        Value::Text(model.first_name.clone()),
-        Value::Text(model.last_name.clone()),
+        Value::Text(crypto::seal(&model.first_name)?),
+        Value::Text(crypto::seal(&model.last_name)?),
     ];
     let address_params = [
         Value::Text(id.to_string()),
-        Value::Text(model.address.street.clone()),
+        Value::Text(crypto::seal(&model.address.street)?),
         Value::Text(model.address.zip.clone()),
         Value::Text(model.address.city.clone()),
     ];
-    let _ = con.execute("BEGIN TRANSACTION;", &[]);
-    let _ = con.execute(COMMAND_CREATE_EMPLOYEE, &employee_params)?;
-    let _ = con.execute(COMMAND_CREATE_ADDRESS, &address_params);
-    let _ = con.execute("END TRANSACTION;", &[]);
+    let _ = execute(con, COMMAND_CREATE_EMPLOYEE, &employee_params)?;
+    let _ = execute(con, COMMAND_CREATE_ADDRESS, &address_params)?;
+    stamp_created(con, "Employees", &id.to_string(), stamp)?;
+    stamp_created(con, "Addresses", &id.to_string(), stamp)?;
     Ok(EmployeeCreatedModel {
         id: id.to_string(),
         first_name: model.first_name,
@@ -63,32 +275,36 @@ pub(crate) fn create_employee(model: CreateEmployeeModel) -> Result<EmployeeCrea
     })
 }
 
-pub(crate) fn delete_employee_by_id(id: &str) -> Result<bool> {
-    let con = Connection::open_default()?;
-    let params = [Value::Text(id.to_string())];
-    let query_result = con.execute(COMMAND_DELETE_EMPLOYEE, &params)?;
+/// Marks the employee deleted; `false` when there is no employee with this
+/// id or it is already deleted
+pub(crate) fn delete_employee(con: &Connection, id: &str, stamp: &Stamp) -> Result<bool> {
+    let params = [Value::Text(stamp.at.clone()), Value::Text(stamp.by.clone()), Value::Text(id.to_string())];
+    let query_result = execute(con, COMMAND_DELETE_EMPLOYEE, &params)?;
     let count = query_result.rows().count();
     Ok(count > 0)
 }
 
-pub(crate) fn update_employee_by_id(id: &str,
-                                    model: UpdateEmployeeModel) -> Result<Option<EmployeeUpdatedModel>> {
-    let con = Connection::open_default()?;
+/// `None` when there is no employee with this id
+pub(crate) fn update_employee(con: &Connection, id: &str, model: UpdateEmployeeModel,
+                              stamp: &Stamp) -> Result<Option<EmployeeUpdatedModel>> {
     let employee_params = [
-        Value::Text(model.first_name.clone()),
-        Value::Text(model.last_name.clone()),
+        Value::Text(crypto::seal(&model.first_name)?),
+        Value::Text(crypto::seal(&model.last_name)?),
         Value::Text(id.to_string()),
     ];
     let address_params = [
-        Value::Text(model.address.street.clone()),
+        Value::Text(crypto::seal(&model.address.street)?),
         Value::Text(model.address.zip.clone()),
         Value::Text(model.address.city.clone()),
         Value::Text(id.to_string()),
     ];
-    let _ = con.execute("BEGIN TRANSACTION;", &[]);
-    let _ = con.execute(COMMAND_UPDATE_EMPLOYEE, &employee_params)?;
-    let _ = con.execute(COMMAND_UPDATE_ADDRESS, &address_params)?;
-    let _ = con.execute("END TRANSACTION;", &[])?;
+    let _ = execute(con, COMMAND_UPDATE_EMPLOYEE, &employee_params)?;
+    let updated = execute(con, COMMAND_UPDATE_ADDRESS, &address_params)?;
+    if updated.rows.is_empty() {
+        return Ok(None);
+    }
+    stamp_updated(con, "Employees", id, stamp)?;
+    stamp_updated(con, "Addresses", id, stamp)?;
     Ok(Some(EmployeeUpdatedModel {
         id: id.to_string(),
         first_name: model.first_name,
@@ -102,17 +318,18 @@ pub(crate) fn update_employee_by_id(id: &str,
     }))
 }
 
-pub(crate) fn create_location(model: CreateLocationModel) -> Result<LocationCreatedModel> {
-    let con = Connection::open_default()?;
+pub(crate) fn insert_location(con: &Connection, model: CreateLocationModel,
+                              stamp: &Stamp) -> Result<LocationCreatedModel> {
     let lid = Uuid::new_v4();
     let params = [
         Value::Text(lid.to_string()),
-        Value::Text(model.street.clone()),
+        Value::Text(crypto::seal_searchable(&model.street)?),
         Value::Text(model.zip.clone()),
         Value::Text(model.city.clone()),
     ]; 
 
-    let _ = con.execute(COMMAND_CREATE_LOCATION, &params)?;
+    let _ = execute(con, COMMAND_CREATE_LOCATION, &params)?;
+    stamp_created(con, "Locations", &lid.to_string(), stamp)?;
 
     Ok(LocationCreatedModel{
         lid: lid.to_string(),
@@ -122,17 +339,18 @@ pub(crate) fn create_location(model: CreateLocationModel) -> Result<LocationCrea
     })
 }
 
-pub(crate) fn create_person(model: CreatePersonModel) -> Result<PersonCreatedModel> {
-    let con = Connection::open_default()?;
+pub(crate) fn insert_person(con: &Connection, model: CreatePersonModel,
+                            stamp: &Stamp) -> Result<PersonCreatedModel> {
     let pid = Uuid::new_v4();
     let params = [
         Value::Text(pid.to_string()),
-        Value::Text(model.first_name.clone()),
-        Value::Text(model.last_name.clone()),
+        Value::Text(crypto::seal(&model.first_name)?),
+        Value::Text(crypto::seal(&model.last_name)?),
         Value::Text(model.plid.clone())
     ];
 
-    let _ = con.execute(COMMAND_CREATE_PERSON, &params)?;
+    let _ = execute(con, COMMAND_CREATE_PERSON, &params)?;
+    stamp_created(con, "Persons", &pid.to_string(), stamp)?;
 
     Ok(PersonCreatedModel{
         pid: pid.to_string(),
@@ -143,17 +361,93 @@ pub(crate) fn create_person(model: CreatePersonModel) -> Result<PersonCreatedMod
     })
 }
 
-pub(crate) fn update_location_by_id(lid: &str,
-                                    model: UpdateLocationModel) -> Result<Option<LocationUpdatedModel>> {
-    let con = Connection::open_default()?;
+/// Whether a location with this id exists
+pub(crate) fn location_exists(con: &Connection, lid: &str) -> Result<bool> {
+    let result = execute(con, QUERY_LOCATION_EXISTS, &[Value::Text(lid.to_string())])?;
+    Ok(!result.rows.is_empty())
+}
+
+/// The id of the location with this street, zip and city, the natural key of
+/// a location. The street is looked up in every form it may be stored in.
+pub(crate) fn find_location(con: &Connection, street: &str, zip: &str, city: &str) -> Result<Option<String>> {
+    for probe in crypto::probes(street)? {
+        let params = [
+            Value::Text(probe),
+            Value::Text(zip.to_string()),
+            Value::Text(city.to_string()),
+        ];
+        let result = execute(con, QUERY_LOCATION_BY_KEY, &params)?;
+        if let Some(lid) = result.rows().next().and_then(|row| row.get::<&str>("Lid").map(String::from)) {
+            return Ok(Some(lid));
+        }
+    }
+    Ok(None)
+}
+
+pub(crate) fn begin(con: &Connection) -> Result<()> {
+    execute(con, "BEGIN TRANSACTION;", &[])?;
+    Ok(())
+}
+
+/// Commits the transaction, invalidates the read models cached by `queries`
+/// and records the commit position for the consistency token. The write
+/// stands even when either fails.
+pub(crate) fn commit(con: &Connection, ctx: &RequestContext) -> Result<()> {
+    execute(con, "COMMIT;", &[])?;
+    if let Err(e) = cache::invalidate() {
+        ctx.log(format!("commands: cache invalidation failed: {}", e));
+    }
+    if let Err(e) = consistency::record(con) {
+        ctx.log(format!("commands: commit position not recorded: {}", e));
+    }
+    Ok(())
+}
+
+/// Number of writes to the tables of the read models so far, counted by the
+/// triggers on `TableVersions`
+pub(crate) fn commit_position(con: &Connection) -> Result<i64> {
+    let result = execute(con, QUERY_COMMIT_POSITION, &[])?;
+    result.rows().next()
+        .and_then(|row| row.get::<i64>("Position"))
+        .ok_or_else(|| anyhow!("commit position not present"))
+}
+
+pub(crate) fn rollback(con: &Connection) {
+    let _ = execute(con, "ROLLBACK;", &[]);
+}
+
+/// Runs `f` in a transaction, committed when `f` succeeds and rolled back
+/// otherwise
+pub(crate) fn in_transaction<T>(con: &Connection, ctx: &RequestContext,
+                                f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+    begin(con)?;
+    match f(con) {
+        Ok(value) => {
+            commit(con, ctx)?;
+            Ok(value)
+        }
+        Err(e) => {
+            rollback(con);
+            Err(e)
+        }
+    }
+}
+
+/// `None` when there is no location with this id
+pub(crate) fn update_location(con: &Connection, lid: &str, model: UpdateLocationModel,
+                              stamp: &Stamp) -> Result<Option<LocationUpdatedModel>> {
     let params = [
-        Value::Text(model.street.clone()),
+        Value::Text(crypto::seal_searchable(&model.street)?),
         Value::Text(model.zip.clone()),
         Value::Text(model.city.clone()),
         Value::Text(lid.to_string().clone())
     ];
 
-    let _ = con.execute(COMMAND_UPDATE_LOCATION, &params)?;
+    let updated = execute(con, COMMAND_UPDATE_LOCATION, &params)?;
+    if updated.rows.is_empty() {
+        return Ok(None);
+    }
+    stamp_updated(con, "Locations", lid, stamp)?;
 
     Ok(Some(LocationUpdatedModel{
         lid: lid.to_string(),
@@ -163,17 +457,21 @@ pub(crate) fn update_location_by_id(lid: &str,
     }))                                       
 }
 
-pub(crate) fn update_person_by_id(pid: &str,
-                                  model: UpdatePersonModel) -> Result<Option<PersonUpdatedModel>> {
-    let con = Connection::open_default()?;
+/// `None` when there is no person with this id
+pub(crate) fn update_person(con: &Connection, pid: &str, model: UpdatePersonModel,
+                            stamp: &Stamp) -> Result<Option<PersonUpdatedModel>> {
     let params = [
-        Value::Text(model.first_name.clone()),
-        Value::Text(model.last_name.clone()),
+        Value::Text(crypto::seal(&model.first_name)?),
+        Value::Text(crypto::seal(&model.last_name)?),
         Value::Text(model.plid.clone()),
         Value::Text(pid.to_string().clone())
     ];
                                 
-    let _ = con.execute(COMMAND_UPDATE_PERSON, &params)?;
+    let updated = execute(con, COMMAND_UPDATE_PERSON, &params)?;
+    if updated.rows.is_empty() {
+        return Ok(None);
+    }
+    stamp_updated(con, "Persons", pid, stamp)?;
                                 
     Ok(Some(PersonUpdatedModel{
         pid: pid.to_string(),
@@ -183,10 +481,656 @@ pub(crate) fn update_person_by_id(pid: &str,
     }))                                                       
 }
 
-pub(crate) fn delete_person_by_id(pid: &str) -> Result<bool> {
-    let con = Connection::open_default()?;
-    let params = [Value::Text(pid.to_string())];
-    let query_result = con.execute(COMMAND_DELETE_PERSON, &params)?;
+/// Marks the person deleted; `false` when there is no person with this id or
+/// it is already deleted
+pub(crate) fn delete_person(con: &Connection, pid: &str, stamp: &Stamp) -> Result<bool> {
+    let params = [Value::Text(stamp.at.clone()), Value::Text(stamp.by.clone()), Value::Text(pid.to_string())];
+    let query_result = execute(con, COMMAND_DELETE_PERSON, &params)?;
+    let count = query_result.rows().count();
+    Ok(count > 0)
+}
+
+/// Brings back a deleted employee or person, by table; `false` when it is not
+/// deleted
+pub(crate) fn undelete(con: &Connection, table: &str, id: &str, stamp: &Stamp) -> Result<bool> {
+    let params = [Value::Text(table.to_string()), Value::Text(id.to_string())];
+    if execute(con, COMMAND_UNDELETE, &params)?.rows().count() == 0 {
+        return Ok(false);
+    }
+    stamp_updated(con, table, id, stamp)?;
+    Ok(true)
+}
+
+/// Removes the employees and persons deleted before `cutoff` for good,
+/// returning the entity and id of each
+pub(crate) fn purge(con: &Connection, cutoff: &str) -> Result<Vec<(&'static str, String)>> {
+    let mut purged = Vec::new();
+    for (entity, statement) in [("employees", COMMAND_PURGE_EMPLOYEES), ("persons", COMMAND_PURGE_PERSONS)] {
+        let result = execute(con, statement, &[Value::Text(cutoff.to_string())])?;
+        for row in result.rows() {
+            let id = row.get::<&str>("Id").ok_or_else(|| anyhow!("purged Id not present"))?;
+            purged.push((entity, id.to_string()));
+        }
+    }
+    Ok(purged)
+}
+
+/// Replaces the personal data of an erased employee or person
+pub(crate) const ERASED: &str = "[erased]";
+
+/// The personal fields of an entity, as JSON pointers into its images
+fn personal_fields(entity: &str) -> Result<&'static [&'static str]> {
+    match entity {
+        "employees" => Ok(&["/firstName", "/lastName", "/address/street", "/address/zip", "/address/city"]),
+        "persons" => Ok(&["/firstName", "/lastName"]),
+        _ => Err(anyhow!("no personal data of entity {}", entity)),
+    }
+}
+
+/// The JSON objects a statement selects as `Record`
+fn records(con: &Connection, statement: &str, params: &[Value]) -> Result<Vec<JsonValue>> {
+    let result = execute(con, statement, params)?;
+    let mut records: Vec<JsonValue> = result.rows()
+        .filter_map(|row| row.get::<&str>("Record").map(serde_json::from_str))
+        .collect::<serde_json::Result<_>>()?;
+    records.iter_mut().try_for_each(crypto::open_json)?;
+    Ok(records)
+}
+
+/// The records of the payloads mentioning `id`. Encrypted payloads are
+/// selected whatever they hold, and kept once decrypted if they mention it.
+fn mentioning(con: &Connection, statement: &str, id: &str) -> Result<Vec<JsonValue>> {
+    let mut records = records(con, statement, &[Value::Text(id.to_string())])?;
+    records.retain(|record| record.to_string().contains(id));
+    Ok(records)
+}
+
+/// The stored employee or person with its stamps, soft-deleted or not
+fn record(con: &Connection, entity: &str, id: &str) -> Result<Option<JsonValue>> {
+    let statement = match entity {
+        "employees" => QUERY_EMPLOYEE_RECORD,
+        "persons" => QUERY_PERSON_RECORD,
+        _ => return Err(anyhow!("no personal data of entity {}", entity)),
+    };
+    Ok(records(con, statement, &[Value::Text(id.to_string())])?.pop())
+}
+
+/// Everything stored that refers to an employee or person, `None` when
+/// nothing does
+pub(crate) fn personal_data(con: &Connection, entity: &str, id: &str,
+                            exported_at: &str) -> Result<Option<PersonalDataModel>> {
+    let subject = [Value::Text(entity.to_string()), Value::Text(id.to_string())];
+    let current = record(con, entity, id)?;
+    let history = records(con, QUERY_HISTORY_RECORDS, &subject)?;
+    if current.is_none() && history.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(PersonalDataModel {
+        entity: entity.to_string(),
+        id: id.to_string(),
+        exported_at: exported_at.to_string(),
+        current,
+        history,
+        audit: records(con, QUERY_AUDIT_RECORDS, &subject)?,
+        commands: mentioning(con, QUERY_QUEUED_RECORDS, id)?,
+        idempotent_responses: mentioning(con, QUERY_IDEMPOTENT_RECORDS, id)?,
+    }))
+}
+
+/// Whether a queued command targeting the id waits to run
+pub(crate) fn pending_for(con: &Connection, id: &str) -> Result<bool> {
+    let result = execute(con, QUERY_PENDING_FOR, &[Value::Text(id.to_string())])?;
+    Ok(!result.rows.is_empty())
+}
+
+/// Anonymizes the personal fields of an employee or person in its stored
+/// state, its history, its audit records and the queued commands and stored
+/// idempotent responses mentioning its id; ids and references are kept.
+/// `None` when nothing refers to it.
+pub(crate) fn erase(con: &Connection, entity: &str, id: &str, stamp: &Stamp) -> Result<Option<ErasureModel>> {
+    let fields = personal_fields(entity)?;
+    let current = record(con, entity, id)?;
+    let entity_id = [Value::Text(entity.to_string()), Value::Text(id.to_string())];
+    let history = records(con, QUERY_HISTORY_RECORDS, &entity_id)?;
+    if current.is_none() && history.is_empty() {
+        return Ok(None);
+    }
+    let params = [Value::Text(id.to_string()), Value::Text(ERASED.to_string())];
+    let (tables, versions, audit): (&[(&str, &str)], _, _) = match entity {
+        "employees" => (
+            &[("Employees", COMMAND_ERASE_EMPLOYEE), ("Addresses", COMMAND_ERASE_ADDRESS)],
+            COMMAND_ERASE_EMPLOYEE_VERSIONS,
+            COMMAND_ERASE_EMPLOYEE_AUDIT,
+        ),
+        _ => (
+            &[("Persons", COMMAND_ERASE_PERSON)],
+            COMMAND_ERASE_PERSON_VERSIONS,
+            COMMAND_ERASE_PERSON_AUDIT,
+        ),
+    };
+    for (table, statement) in tables {
+        if execute(con, statement, &params)?.rows().count() > 0 {
+            stamp_updated(con, table, id, stamp)?;
+        }
+    }
+    let versions = execute(con, versions, &params)?.rows().count();
+    let audit_records = execute(con, audit, &params)?.rows().count();
+
+    let subject = Subject { key: if entity == "employees" { "id" } else { "pid" }, id, fields };
+    let mention = [Value::Text(id.to_string())];
+    let mut payloads = 0;
+    let queued = execute(con, QUERY_QUEUED_PAYLOADS, &mention)?;
+    for row in queued.rows() {
+        let path = row.get::<&str>("Path").unwrap_or_default();
+        let stored_body = row.get::<&[u8]>("Body").unwrap_or_default();
+        let stored_result = row.get::<&[u8]>("ResultBody");
+        let body = crypto::open_payload(stored_body)?;
+        let result = stored_result.map(crypto::open_payload).transpose()?;
+        let mut parsed = result
+            .as_deref()
+            .and_then(|result| serde_json::from_slice::<JsonValue>(result).ok());
+        let scrubbed_body = subject.scrub_request(path, &body, parsed.as_ref());
+        let scrubbed_result = parsed
+            .as_mut()
+            .filter(|result| subject.scrub_identified(result))
+            .and_then(|result| serde_json::to_vec(result).ok());
+        if scrubbed_body.is_none() && scrubbed_result.is_none() {
+            continue;
+        }
+        let queued_id = row.get::<&str>("Id").ok_or_else(|| anyhow!("CommandQueue.Id not present"))?;
+        let body = match scrubbed_body {
+            Some(body) => crypto::seal_payload(&body)?,
+            None => stored_body.to_vec(),
+        };
+        let result = match scrubbed_result {
+            Some(result) => Some(crypto::seal_payload(&result)?),
+            None => stored_result.map(<[u8]>::to_vec),
+        };
+        let params = [
+            Value::Blob(body),
+            result.map_or(Value::Null, Value::Blob),
+            Value::Text(queued_id.to_string()),
+        ];
+        execute(con, COMMAND_SCRUB_QUEUED, &params)?;
+        payloads += 1;
+    }
+    let stored = execute(con, QUERY_IDEMPOTENT_PAYLOADS, &mention)?;
+    for row in stored.rows() {
+        let body = crypto::open_payload(row.get::<&[u8]>("Body").unwrap_or_default())?;
+        let mut body = serde_json::from_slice::<JsonValue>(&body).unwrap_or_default();
+        if !subject.scrub_identified(&mut body) {
+            continue;
+        }
+        let body = crypto::seal_payload(&serde_json::to_vec(&body)?)?;
+        let text = |column: &str| {
+            row.get::<&str>(column)
+                .map(|value| Value::Text(value.to_string()))
+                .ok_or_else(|| anyhow!("IdempotencyKeys.{} not present", column))
+        };
+        execute(con, COMMAND_SCRUB_IDEMPOTENT, &[Value::Blob(body), text("Client")?, text("Key")?])?;
+        payloads += 1;
+    }
+
+    Ok(Some(ErasureModel {
+        entity: entity.to_string(),
+        id: id.to_string(),
+        erased_at: stamp.at.clone(),
+        current: current.is_some(),
+        versions,
+        audit_records,
+        payloads,
+        // deleted by the caller once the erasure is committed
+        cached_responses: 0,
+    }))
+}
+
+/// Encrypts the `columns` of a table again with the current key where they
+/// are plain or encrypted with an older one, `searchable` columns
+/// deterministically. Returns the number of rows rewritten.
+fn reseal_columns(con: &Connection, select: &str, update: &str, columns: &[&str],
+                  searchable: bool) -> Result<usize> {
+    let result = execute(con, select, &[])?;
+    let mut resealed = 0;
+    for row in result.rows() {
+        let values = columns
+            .iter()
+            .map(|column| row.get::<&str>(column).ok_or_else(|| anyhow!("{} not present", column)))
+            .collect::<Result<Vec<_>>>()?;
+        let mut stale = false;
+        for value in &values {
+            stale |= crypto::stale(value)?;
+        }
+        if !stale {
+            continue;
+        }
+        let mut params = Vec::with_capacity(values.len() + 1);
+        for value in values {
+            let plain = crypto::open(value)?;
+            let sealed = if searchable { crypto::seal_searchable(&plain)? } else { crypto::seal(&plain)? };
+            params.push(Value::Text(sealed));
+        }
+        let key = row.get::<&str>("Key").ok_or_else(|| anyhow!("Key not present"))?;
+        params.push(Value::Text(key.to_string()));
+        execute(con, update, &params)?;
+        resealed += 1;
+    }
+    Ok(resealed)
+}
+
+/// An image of the history or audit log encrypted again with the current
+/// key, `None` when it already is
+fn reseal_image(entity: &str, image: Option<&str>) -> Result<Option<String>> {
+    let Some(image) = image else {
+        return Ok(None);
+    };
+    let image: JsonValue = serde_json::from_str(image)?;
+    if !crypto::stale_image(entity, &image)? {
+        return Ok(None);
+    }
+    Ok(Some(crypto::seal_image(entity, &image)?.to_string()))
+}
+
+/// A payload encrypted again with the current key, `None` when it already is
+fn reseal_payload(payload: Option<&[u8]>) -> Result<Option<Vec<u8>>> {
+    match payload {
+        Some(payload) if crypto::stale_payload(payload)? => {
+            Ok(Some(crypto::seal_payload(&crypto::open_payload(payload)?)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Encrypts every personal field with the current key: the columns of the
+/// entity tables, the images of the history and the audit log, and the
+/// payloads of queued commands and stored idempotent responses. Plain
+/// values, written before encryption was turned on, are encrypted too.
+pub(crate) fn rotate_keys(con: &Connection, key_version: u32) -> Result<RotationModel> {
+    let names = ["FirstName", "LastName"];
+    let employees = reseal_columns(con, QUERY_EMPLOYEE_SECRETS, COMMAND_RESEAL_EMPLOYEE, &names, false)?;
+    let addresses = reseal_columns(con, QUERY_ADDRESS_SECRETS, COMMAND_RESEAL_ADDRESS, &["Street"], false)?;
+    let persons = reseal_columns(con, QUERY_PERSON_SECRETS, COMMAND_RESEAL_PERSON, &names, false)?;
+    let locations = reseal_columns(con, QUERY_LOCATION_SECRETS, COMMAND_RESEAL_LOCATION, &["Street"], true)?;
+
+    let mut versions = 0;
+    let states = execute(con, QUERY_VERSION_STATES, &[])?;
+    for row in states.rows() {
+        let text = |column: &str| {
+            row.get::<&str>(column).ok_or_else(|| anyhow!("EntityVersions.{} not present", column))
+        };
+        let entity = text("Entity")?;
+        let Some(state) = reseal_image(entity, row.get::<&str>("State"))? else {
+            continue;
+        };
+        let version = row.get::<i64>("Version").ok_or_else(|| anyhow!("EntityVersions.Version not present"))?;
+        let params = [
+            Value::Text(state),
+            Value::Text(entity.to_string()),
+            Value::Text(text("Id")?.to_string()),
+            Value::Integer(version),
+        ];
+        execute(con, COMMAND_RESEAL_VERSION, &params)?;
+        versions += 1;
+    }
+
+    let mut audit_records = 0;
+    let images = execute(con, QUERY_AUDIT_IMAGES, &[])?;
+    for row in images.rows() {
+        let entity = row.get::<&str>("Entity").ok_or_else(|| anyhow!("AuditLog.Entity not present"))?;
+        let (before, after) = (row.get::<&str>("Before"), row.get::<&str>("After"));
+        let (sealed_before, sealed_after) = (reseal_image(entity, before)?, reseal_image(entity, after)?);
+        if sealed_before.is_none() && sealed_after.is_none() {
+            continue;
+        }
+        let image = |sealed: Option<String>, stored: Option<&str>| {
+            sealed.or_else(|| stored.map(String::from)).map_or(Value::Null, Value::Text)
+        };
+        let seq = row.get::<i64>("Seq").ok_or_else(|| anyhow!("AuditLog.Seq not present"))?;
+        let params = [image(sealed_before, before), image(sealed_after, after), Value::Integer(seq)];
+        execute(con, COMMAND_RESEAL_AUDIT, &params)?;
+        audit_records += 1;
+    }
+
+    let mut payloads = 0;
+    let queued = execute(con, QUERY_ALL_QUEUED_PAYLOADS, &[])?;
+    for row in queued.rows() {
+        let (body, result) = (row.get::<&[u8]>("Body"), row.get::<&[u8]>("ResultBody"));
+        let (sealed_body, sealed_result) = (reseal_payload(body)?, reseal_payload(result)?);
+        if sealed_body.is_none() && sealed_result.is_none() {
+            continue;
+        }
+        let payload = |sealed: Option<Vec<u8>>, stored: Option<&[u8]>| {
+            sealed.or_else(|| stored.map(<[u8]>::to_vec)).map_or(Value::Null, Value::Blob)
+        };
+        let id = row.get::<&str>("Id").ok_or_else(|| anyhow!("CommandQueue.Id not present"))?;
+        let params = [
+            payload(sealed_body, body),
+            payload(sealed_result, result),
+            Value::Text(id.to_string()),
+        ];
+        execute(con, COMMAND_SCRUB_QUEUED, &params)?;
+        payloads += 1;
+    }
+    let stored = execute(con, QUERY_ALL_IDEMPOTENT_PAYLOADS, &[])?;
+    for row in stored.rows() {
+        let Some(body) = reseal_payload(row.get::<&[u8]>("Body"))? else {
+            continue;
+        };
+        let text = |column: &str| {
+            row.get::<&str>(column)
+                .map(|value| Value::Text(value.to_string()))
+                .ok_or_else(|| anyhow!("IdempotencyKeys.{} not present", column))
+        };
+        execute(con, COMMAND_SCRUB_IDEMPOTENT, &[Value::Blob(body), text("Client")?, text("Key")?])?;
+        payloads += 1;
+    }
+
+    Ok(RotationModel {
+        key_version,
+        employees,
+        addresses,
+        persons,
+        locations,
+        versions,
+        audit_records,
+        payloads,
+    })
+}
+
+pub(crate) fn delete_location(con: &Connection, lid: &str) -> Result<bool> {
+    let params = [Value::Text(lid.to_string())];
+    let query_result = execute(con, COMMAND_DELETE_LOCATION, &params)?;
     let count = query_result.rows().count();
     Ok(count > 0)
 }
+
+/// Whether any person still lives at the location
+pub(crate) fn location_in_use(con: &Connection, lid: &str) -> Result<bool> {
+    let result = execute(con, QUERY_LOCATION_IN_USE, &[Value::Text(lid.to_string())])?;
+    let in_use = !result.rows.is_empty();
+    Ok(in_use)
+}
+
+/// The stored state of an entity as JSON, `None` when it does not exist
+pub(crate) fn image(con: &Connection, entity: &str, id: &str) -> Result<Option<JsonValue>> {
+    let statement = match entity {
+        "employees" => QUERY_EMPLOYEE_IMAGE,
+        "persons" => QUERY_PERSON_IMAGE,
+        "locations" => QUERY_LOCATION_IMAGE,
+        _ => return Err(anyhow!("no image of entity {}", entity)),
+    };
+    let result = execute(con, statement, &[Value::Text(id.to_string())])?;
+    let Some(image) = result.rows().next().and_then(|row| row.get::<&str>("Image").map(String::from)) else {
+        return Ok(None);
+    };
+    let mut image = serde_json::from_str(&image)?;
+    crypto::open_json(&mut image)?;
+    Ok(Some(image))
+}
+
+/// Adds the next version of an entity to its history, `state` being `None`
+/// for a deletion
+pub(crate) fn insert_version(con: &Connection, entity: &str, id: &str, state: Option<&JsonValue>,
+                             stamp: &Stamp) -> Result<()> {
+    let state = state.map(|state| crypto::seal_image(entity, state)).transpose()?;
+    let params = [
+        Value::Text(entity.to_string()),
+        Value::Text(id.to_string()),
+        Value::Text(stamp.at.clone()),
+        Value::Text(stamp.by.clone()),
+        state.map_or(Value::Null, |s| Value::Text(s.to_string())),
+    ];
+    execute(con, COMMAND_INSERT_VERSION, &params)?;
+    Ok(())
+}
+
+/// A version of an entity with its state, `None` for a deletion: the given
+/// version, or the one current at `at`
+pub(crate) fn version(con: &Connection, entity: &str, id: &str, version: Option<i64>,
+                      at: Option<&str>) -> Result<Option<(i64, Option<JsonValue>)>> {
+    let params = [
+        Value::Text(entity.to_string()),
+        Value::Text(id.to_string()),
+        version.map_or(Value::Null, Value::Integer),
+        at.map_or(Value::Null, |at| Value::Text(at.to_string())),
+    ];
+    let result = execute(con, QUERY_VERSION, &params)?;
+    let Some(row) = result.rows().next() else {
+        return Ok(None);
+    };
+    let number = row.get::<i64>("Version").ok_or_else(|| anyhow!("EntityVersions.Version not present"))?;
+    let mut state: Option<JsonValue> = row.get::<&str>("State").map(serde_json::from_str).transpose()?;
+    if let Some(state) = state.as_mut() {
+        crypto::open_json(state)?;
+    }
+    Ok(Some((number, state)))
+}
+
+pub(crate) fn insert_audit(con: &Connection, record: &AuditRecord) -> Result<()> {
+    let text = |value: Option<&str>| value.map_or(Value::Null, |v| Value::Text(v.to_string()));
+    let json = |image: &Option<JsonValue>| -> Result<Value> {
+        let sealed = image.as_ref().map(|image| crypto::seal_image(record.entity, image)).transpose()?;
+        Ok(sealed.map_or(Value::Null, |i| Value::Text(i.to_string())))
+    };
+    let params = [
+        Value::Text(record.stamp.at.clone()),
+        Value::Text(record.stamp.by.clone()),
+        Value::Text(record.request_id.to_string()),
+        Value::Text(record.command.to_string()),
+        Value::Text(record.entity.to_string()),
+        text(record.images.target.as_deref()),
+        json(&record.images.before)?,
+        json(&record.images.after)?,
+        Value::Integer(record.status as i64),
+        Value::Text(record.outcome.to_string()),
+    ];
+    execute(con, COMMAND_INSERT_AUDIT, &params)?;
+    Ok(())
+}
+
+pub(crate) fn last_migration() -> Result<Option<MigrationModel>> {
+    let con = Connection::open_default()?;
+    let query_result = execute(&con, QUERY_LAST_MIGRATION, &[])?;
+    let Some(row) = query_result.rows().next() else {
+        return Ok(None);
+    };
+    Ok(Some(MigrationModel {
+        version: row.get::<i64>("Version")
+            .ok_or_else(|| anyhow!("SchemaMigrations.Version not present"))?,
+        name: String::from(row.get::<&str>("Name")
+            .ok_or_else(|| anyhow!("SchemaMigrations.Name not present"))?),
+        applied_at: String::from(row.get::<&str>("AppliedAt")
+            .ok_or_else(|| anyhow!("SchemaMigrations.AppliedAt not present"))?),
+    }))
+}
+
+/// A queued command as claimed by the worker
+pub(crate) struct QueuedCommand {
+    pub id: String,
+    pub path: String,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+    pub request_id: String,
+    /// caller the gateway authenticated, replayed when the command runs
+    pub client: Option<String>,
+    /// scopes the gateway granted the caller, separated by commas
+    pub scopes: Option<String>,
+    /// `Idempotency-Key` the caller sent with the command
+    pub idempotency_key: Option<String>,
+}
+
+/// Outcome of queueing a command
+pub(crate) enum Enqueued {
+    Queued(String),
+    /// the caller queued the same request with the same idempotency key
+    /// before, as the command of this id
+    Repeated(String),
+    /// the caller used the idempotency key for another request
+    Conflicting,
+}
+
+/// Records a command for asynchronous execution under the caller of `ctx`.
+/// A command is queued once per caller and idempotency key.
+pub(crate) fn enqueue_command(
+    path: &str,
+    content_type: Option<&str>,
+    body: &[u8],
+    ctx: &RequestContext,
+    idempotency_key: Option<&str>,
+) -> Result<Enqueued> {
+    let con = Connection::open_default()?;
+    let id = Uuid::new_v4().to_string();
+    let params = [
+        Value::Text(id.clone()),
+        Value::Text(path.to_string()),
+        content_type.map_or(Value::Null, |ct| Value::Text(ct.to_string())),
+        Value::Blob(crypto::seal_payload(body)?),
+        Value::Text(ctx.request_id.clone()),
+        ctx.client.clone().map_or(Value::Null, Value::Text),
+        ctx.scopes.as_ref().map_or(Value::Null, |scopes| Value::Text(scopes.join(","))),
+        idempotency_key.map_or(Value::Null, |key| Value::Text(key.to_string())),
+    ];
+    execute(&con, COMMAND_ENQUEUE, &params)?;
+    let Some(key) = idempotency_key else {
+        return Ok(Enqueued::Queued(id));
+    };
+    let params = [
+        ctx.client.clone().map_or(Value::Null, Value::Text),
+        Value::Text(key.to_string()),
+    ];
+    let result = execute(&con, QUERY_QUEUED_BY_KEY, &params)?;
+    let row = result.rows().next().ok_or_else(|| anyhow!("queued command {} not found", id))?;
+    let queued = row.get::<&str>("Id").ok_or_else(|| anyhow!("CommandQueue.Id not present"))?;
+    Ok(if queued == id {
+        Enqueued::Queued(id)
+    } else if row.get::<&str>("Path") == Some(path)
+        && row.get::<&[u8]>("Body").map(crypto::open_payload).transpose()?.as_deref() == Some(body)
+    {
+        Enqueued::Repeated(queued.to_string())
+    } else {
+        Enqueued::Conflicting
+    })
+}
+
+/// Marks the oldest pending command as running and returns it. Commands left
+/// running for longer than `stale_minutes` by a worker that died are claimed
+/// again.
+pub(crate) fn claim_command(stale_minutes: u32) -> Result<Option<QueuedCommand>> {
+    let con = Connection::open_default()?;
+    let params = [Value::Text(format!("-{} minutes", stale_minutes))];
+    let result = execute(&con, COMMAND_CLAIM, &params)?;
+    let Some(row) = result.rows().next() else {
+        return Ok(None);
+    };
+    let text = |column: &str| {
+        row.get::<&str>(column)
+            .map(String::from)
+            .ok_or_else(|| anyhow!("CommandQueue.{} not present", column))
+    };
+    Ok(Some(QueuedCommand {
+        id: text("Id")?,
+        path: text("Path")?,
+        content_type: row.get::<&str>("ContentType").map(String::from),
+        body: crypto::open_payload(row.get::<&[u8]>("Body").unwrap_or_default())?,
+        request_id: text("RequestId")?,
+        client: row.get::<&str>("Client").map(String::from),
+        scopes: row.get::<&str>("Scopes").map(String::from),
+        idempotency_key: row.get::<&str>("IdempotencyKey").map(String::from),
+    }))
+}
+
+/// Stores the response of a command that ran
+pub(crate) fn finish_command(id: &str, status: u16, content_type: Option<&str>, body: &[u8]) -> Result<()> {
+    let con = Connection::open_default()?;
+    let params = [
+        Value::Text(if status < 400 { "succeeded" } else { "failed" }.to_string()),
+        Value::Integer(status as i64),
+        content_type.map_or(Value::Null, |ct| Value::Text(ct.to_string())),
+        Value::Blob(crypto::seal_payload(body)?),
+        Value::Text(id.to_string()),
+    ];
+    execute(&con, COMMAND_FINISH, &params)?;
+    Ok(())
+}
+
+pub(crate) fn command_status(id: &str) -> Result<Option<CommandStatusModel>> {
+    let con = Connection::open_default()?;
+    let result = execute(&con, QUERY_COMMAND, &[Value::Text(id.to_string())])?;
+    let Some(row) = result.rows().next() else {
+        return Ok(None);
+    };
+    let text = |column: &str| row.get::<&str>(column).map(String::from);
+    let result = match row.get::<i64>("ResultStatus") {
+        Some(status) => {
+            let body = crypto::open_payload(row.get::<&[u8]>("ResultBody").unwrap_or_default())?;
+            let json = text("ResultContentType").is_some_and(|ct| ct.starts_with("application/json"));
+            let body = match json {
+                true => serde_json::from_slice(&body).unwrap_or_default(),
+                false if body.is_empty() => serde_json::Value::Null,
+                false => serde_json::Value::String(String::from_utf8_lossy(&body).into_owned()),
+            };
+            Some(CommandResultModel { status: status as u16, body })
+        }
+        None => None,
+    };
+    Ok(Some(CommandStatusModel {
+        id: text("Id").ok_or_else(|| anyhow!("CommandQueue.Id not present"))?,
+        status: text("Status").ok_or_else(|| anyhow!("CommandQueue.Status not present"))?,
+        command: text("Path").ok_or_else(|| anyhow!("CommandQueue.Path not present"))?,
+        created_at: text("CreatedAt").ok_or_else(|| anyhow!("CommandQueue.CreatedAt not present"))?,
+        started_at: text("StartedAt"),
+        finished_at: text("FinishedAt"),
+        result,
+    }))
+}
+
+pub(crate) fn pending_commands() -> Result<i64> {
+    let con = Connection::open_default()?;
+    let result = execute(&con, QUERY_PENDING_COMMANDS, &[])?;
+    let pending = result.rows().next().and_then(|row| row.get::<i64>("Pending")).unwrap_or_default();
+    Ok(pending)
+}
+
+/// The response stored for an idempotency key
+pub(crate) struct StoredResponse {
+    pub command: String,
+    pub request_hash: String,
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// The response stored within the last day for a client's idempotency key
+pub(crate) fn idempotent_response(client: &str, key: &str) -> Result<Option<StoredResponse>> {
+    let con = Connection::open_default()?;
+    let params = [Value::Text(client.to_string()), Value::Text(key.to_string())];
+    let result = execute(&con, QUERY_IDEMPOTENCY_KEY, &params)?;
+    let Some(row) = result.rows().next() else {
+        return Ok(None);
+    };
+    let text = |column: &str| row.get::<&str>(column).map(String::from);
+    Ok(Some(StoredResponse {
+        command: text("Command").ok_or_else(|| anyhow!("IdempotencyKeys.Command not present"))?,
+        request_hash: text("RequestHash").ok_or_else(|| anyhow!("IdempotencyKeys.RequestHash not present"))?,
+        status: row.get::<i64>("Status").ok_or_else(|| anyhow!("IdempotencyKeys.Status not present"))? as u16,
+        content_type: text("ContentType"),
+        body: crypto::open_payload(row.get::<&[u8]>("Body").unwrap_or_default())?,
+    }))
+}
+
+/// Stores the response of a command for its idempotency key and forgets
+/// keys older than a day
+pub(crate) fn store_idempotent_response(client: &str, key: &str, response: StoredResponse) -> Result<()> {
+    let con = Connection::open_default()?;
+    execute(&con, COMMAND_EXPIRE_IDEMPOTENCY_KEYS, &[])?;
+    let params = [
+        Value::Text(client.to_string()),
+        Value::Text(key.to_string()),
+        Value::Text(response.command),
+        Value::Text(response.request_hash),
+        Value::Integer(response.status as i64),
+        response.content_type.map_or(Value::Null, Value::Text),
+        Value::Blob(crypto::seal_payload(&response.body)?),
+    ];
+    execute(&con, COMMAND_STORE_IDEMPOTENCY_KEY, &params)?;
+    Ok(())
+}
@@ -0,0 +1,308 @@
+use anyhow::Result;
+use serde_json::Value as JsonValue;
+use spin_sdk::http::{Params, Request, Response, ResponseBuilder};
+use spin_sdk::sqlite::Connection;
+use telemetry::RequestContext;
+
+use crate::audit::{self, AuditRecord, Images};
+use crate::cache;
+use crate::clock::{Clock, Stamp};
+use crate::persistence::{self, ERASED};
+
+/// The employee or person a request is about, from its path: the entity and
+/// the erase command recorded in the audit log
+fn subject(req: &Request, params: &Params) -> Option<(&'static str, &'static str, String)> {
+    let path = req.path();
+    if path.contains("_employee/") {
+        params.get("id").map(|id| ("employees", "eraseEmployee", id.to_string()))
+    } else if path.contains("_person/") {
+        params.get("pid").map(|pid| ("persons", "erasePerson", pid.to_string()))
+    } else {
+        None
+    }
+}
+
+fn json(status: u16, body: &impl serde::Serialize) -> Result<Response> {
+    Ok(ResponseBuilder::new(status)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(body)?)
+        .build())
+}
+
+/// Answers a subject-access request: everything stored that refers to an
+/// employee or person, as one JSON document
+#[tracing::instrument(name = "export_personal_data", skip_all)]
+pub(crate) fn export(req: Request, params: Params, clock: &dyn Clock) -> Result<Response> {
+    let Some((entity, _, id)) = subject(&req, &params) else {
+        return Ok(Response::new(400, ()));
+    };
+    let stamp = Stamp::new(clock, &RequestContext::from_request(&req));
+    let con = Connection::open_default()?;
+    match persistence::personal_data(&con, entity, &id, &stamp.at)? {
+        Some(model) => json(200, &model),
+        None => Ok(Response::new(404, "Not Found")),
+    }
+}
+
+/// Erases the personal data of an employee or person everywhere it is
+/// stored. The erasure itself is recorded in the audit log, without images.
+#[tracing::instrument(name = "erase_personal_data", skip_all)]
+pub(crate) fn erase(req: Request, params: Params, clock: &dyn Clock) -> Result<Response> {
+    let Some((entity, command, id)) = subject(&req, &params) else {
+        return Ok(Response::new(400, ()));
+    };
+    let ctx = RequestContext::from_request(&req);
+    let stamp = Stamp::new(clock, &ctx);
+    let con = Connection::open_default()?;
+    // a queued update would write the personal data back once it runs
+    if persistence::pending_for(&con, &id)? {
+        let error = format!("a queued command targeting {} has not run yet", id);
+        return json(409, &serde_json::json!({ "error": error }));
+    }
+    let erased = persistence::in_transaction(&con, &ctx, |con| {
+        let erased = persistence::erase(con, entity, &id, &stamp)?;
+        if erased.is_some() {
+            let images = Images { target: Some(id.clone()), ..Images::default() };
+            audit::insert(con, &AuditRecord {
+                stamp: &stamp,
+                request_id: &ctx.request_id,
+                command,
+                entity,
+                images: &images,
+                status: 200,
+                outcome: audit::outcome(200),
+            })?;
+        }
+        Ok(erased)
+    })?;
+    let Some(mut model) = erased else {
+        return Ok(Response::new(404, "Not Found"));
+    };
+    // cached read models may still show the personal data
+    model.cached_responses = cache::clear()?;
+    json(200, &model)
+}
+
+/// An employee or person being erased, as it appears in the payloads of
+/// queued commands and stored responses. Only the members belonging to it
+/// are erased, so another entity holding the same value keeps it.
+pub(crate) struct Subject<'a> {
+    /// member holding the id in the models of the entity
+    pub key: &'static str,
+    pub id: &'a str,
+    /// JSON pointers of the personal fields in the models of the entity
+    pub fields: &'a [&'a str],
+}
+
+impl Subject<'_> {
+    /// Erases the personal fields of every object identifying the subject by
+    /// its id member, such as a response model or a batch result. Whether
+    /// anything changed.
+    pub(crate) fn scrub_identified(&self, value: &mut JsonValue) -> bool {
+        let mut changed = self.identifies(value) && self.scrub_fields(value);
+        match value {
+            JsonValue::Object(members) => {
+                for member in members.values_mut() {
+                    changed |= self.scrub_identified(member);
+                }
+            }
+            JsonValue::Array(items) => {
+                for item in items {
+                    changed |= self.scrub_identified(item);
+                }
+            }
+            _ => {}
+        }
+        changed
+    }
+
+    /// The body of a queued command with the subject's personal fields
+    /// erased: the whole body when the command targeted the subject, the
+    /// operations of a batch and the rows of an import its `result` shows
+    /// created or changed the subject, and every object identifying it.
+    /// `None` when nothing changed.
+    pub(crate) fn scrub_request(&self, path: &str, body: &[u8],
+                                result: Option<&JsonValue>) -> Option<Vec<u8>> {
+        let path = path.split('?').next().unwrap_or_default();
+        let command = path.trim_start_matches('/').split('/').next().unwrap_or_default();
+        let indexes = self.indexes(command, result);
+        let Ok(mut value) = serde_json::from_slice::<JsonValue>(body) else {
+            return self.scrub_csv_rows(body, &indexes);
+        };
+
+        let mut changed = self.scrub_identified(&mut value);
+        let targeted = path.split('/').any(|segment| segment == self.id)
+            || result.is_some_and(|result| self.identifies(result));
+        if targeted {
+            changed |= self.scrub_fields(&mut value);
+        }
+        if command == "batch" {
+            let operations = value.get_mut("operations").and_then(JsonValue::as_array_mut);
+            for (index, operation) in operations.into_iter().flatten().enumerate() {
+                let named = operation.get("id").and_then(JsonValue::as_str) == Some(self.id);
+                if let Some(body) = operation.get_mut("body").filter(|_| named || indexes.contains(&index)) {
+                    changed |= self.scrub_fields(body);
+                }
+            }
+        } else {
+            for index in &indexes {
+                if let Some(row) = value.get_mut(index) {
+                    changed |= self.scrub_fields(row);
+                }
+            }
+        }
+        changed.then(|| serde_json::to_vec(&value).ok()).flatten()
+    }
+
+    fn identifies(&self, value: &JsonValue) -> bool {
+        value.get(self.key).and_then(JsonValue::as_str) == Some(self.id)
+    }
+
+    /// Erases the personal fields of one model
+    fn scrub_fields(&self, model: &mut JsonValue) -> bool {
+        let mut changed = false;
+        for field in self.fields {
+            if let Some(value) = model.pointer_mut(field).filter(|v| v.is_string() && **v != ERASED) {
+                *value = JsonValue::from(ERASED);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Positions in the request of the batch operations or import rows whose
+    /// result identifies the subject
+    fn indexes(&self, command: &str, result: Option<&JsonValue>) -> Vec<usize> {
+        let entries = |name: &str| {
+            result
+                .and_then(|result| result.get(name))
+                .and_then(JsonValue::as_array)
+                .into_iter()
+                .flatten()
+        };
+        let position = |entry: &JsonValue, name: &str| entry.get(name).and_then(JsonValue::as_u64);
+        match command {
+            "batch" => entries("results")
+                .filter(|entry| entry.get("body").is_some_and(|body| self.identifies(body)))
+                .filter_map(|entry| position(entry, "index"))
+                .map(|index| index as usize)
+                .collect(),
+            // rows are numbered from 1, and import reports name every id `id`
+            c if c.starts_with("import_") => entries("rows")
+                .filter(|entry| entry.get("id").and_then(JsonValue::as_str) == Some(self.id))
+                .filter_map(|entry| position(entry, "row")?.checked_sub(1))
+                .map(|index| index as usize)
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Erases the personal columns of the rows of a CSV import at `indexes`
+    fn scrub_csv_rows(&self, body: &[u8], indexes: &[usize]) -> Option<Vec<u8>> {
+        if indexes.is_empty() {
+            return None;
+        }
+        let mut reader = csv::Reader::from_reader(body);
+        let headers = reader.headers().ok()?.clone();
+        let personal = |column: &str| {
+            self.fields.iter().any(|field| field.strip_prefix('/') == Some(column.trim()))
+        };
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(&headers).ok()?;
+        for (index, record) in reader.records().enumerate() {
+            let record = record.ok()?;
+            let record: csv::StringRecord = match indexes.contains(&index) {
+                true => record
+                    .iter()
+                    .zip(headers.iter())
+                    .map(|(value, column)| if personal(column) { ERASED } else { value })
+                    .collect(),
+                false => record,
+            };
+            writer.write_record(&record).ok()?;
+        }
+        writer.into_inner().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const FIELDS: [&str; 2] = ["/firstName", "/lastName"];
+
+    fn person(pid: &str) -> Subject<'_> {
+        Subject { key: "pid", id: pid, fields: &FIELDS }
+    }
+
+    #[test]
+    fn only_the_members_of_the_subject_are_erased() {
+        let mut stored = json!([
+            { "pid": "p-1", "firstName": "Jane", "lastName": "Doe", "plid": "l-1" },
+            { "pid": "p-2", "firstName": "Jane", "lastName": "Doe", "plid": "l-1" },
+        ]);
+        assert!(person("p-1").scrub_identified(&mut stored));
+        assert_eq!(stored, json!([
+            { "pid": "p-1", "firstName": ERASED, "lastName": ERASED, "plid": "l-1" },
+            { "pid": "p-2", "firstName": "Jane", "lastName": "Doe", "plid": "l-1" },
+        ]));
+        assert!(!person("p-1").scrub_identified(&mut stored));
+    }
+
+    #[test]
+    fn the_body_of_a_command_targeting_the_subject_is_erased() {
+        let body = br#"{"firstName":"Jane","lastName":"Doe","plid":"l-1"}"#;
+        let scrubbed = person("p-1").scrub_request("/update_person/p-1", body, None).unwrap();
+        assert_eq!(serde_json::from_slice::<JsonValue>(&scrubbed).unwrap(),
+                   json!({ "firstName": ERASED, "lastName": ERASED, "plid": "l-1" }));
+        assert_eq!(person("p-2").scrub_request("/update_person/p-1", body, None), None);
+
+        let created = json!({ "pid": "p-1", "firstName": "Jane", "lastName": "Doe", "plid": "l-1" });
+        assert!(person("p-1").scrub_request("/create_person", body, Some(&created)).is_some());
+    }
+
+    #[test]
+    fn batch_operations_of_the_subject_are_found_by_index() {
+        let body = serde_json::to_vec(&json!({ "operations": [
+            { "op": "createPerson", "ref": "a", "body": { "firstName": "Jane", "lastName": "Doe" } },
+            { "op": "createPerson", "ref": "b", "body": { "firstName": "Jane", "lastName": "Doe" } },
+            { "op": "updatePerson", "id": "p-2", "body": { "firstName": "Jane", "lastName": "Roe" } },
+        ]})).unwrap();
+        let result = json!({ "committed": true, "results": [
+            { "index": 0, "status": 201, "body": { "pid": "p-1", "firstName": "Jane" } },
+            { "index": 1, "status": 201, "body": { "pid": "p-2", "firstName": "Jane" } },
+            { "index": 2, "status": 200, "body": { "pid": "p-2", "firstName": "Jane" } },
+        ]});
+        let scrubbed = person("p-2").scrub_request("/batch", &body, Some(&result)).unwrap();
+        let scrubbed: JsonValue = serde_json::from_slice(&scrubbed).unwrap();
+        assert_eq!(scrubbed["operations"][0]["body"]["firstName"], "Jane");
+        assert_eq!(scrubbed["operations"][1]["body"]["firstName"], ERASED);
+        assert_eq!(scrubbed["operations"][2]["body"]["lastName"], ERASED);
+
+        let mut result = result;
+        assert!(person("p-2").scrub_identified(&mut result));
+        assert_eq!(result["results"][0]["body"]["firstName"], "Jane");
+        assert_eq!(result["results"][1]["body"]["firstName"], ERASED);
+    }
+
+    #[test]
+    fn import_rows_of_the_subject_are_found_by_row_number() {
+        let report = json!({ "rows": [
+            { "row": 1, "status": "created", "id": "p-1" },
+            { "row": 2, "status": "created", "id": "p-2" },
+        ]});
+        let body = b"firstName,lastName,plid\nJane,Doe,l-1\nJane,Doe,l-1\n";
+        let scrubbed = person("p-2").scrub_request("/import_persons", body, Some(&report)).unwrap();
+        assert_eq!(String::from_utf8(scrubbed).unwrap(),
+                   "firstName,lastName,plid\nJane,Doe,l-1\n[erased],[erased],l-1\n");
+
+        let body = br#"[{"firstName":"Jane","lastName":"Doe"},{"firstName":"Jane","lastName":"Doe"}]"#;
+        let scrubbed = person("p-1").scrub_request("/import_persons?mode=best-effort", body, Some(&report));
+        assert_eq!(serde_json::from_slice::<JsonValue>(&scrubbed.unwrap()).unwrap(), json!([
+            { "firstName": ERASED, "lastName": ERASED },
+            { "firstName": "Jane", "lastName": "Doe" },
+        ]));
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::Result;
+use spin_sdk::http::{Params, Request, Response, ResponseBuilder};
+use spin_sdk::sqlite::Connection;
+use spin_sdk::variables;
+use telemetry::RequestContext;
+
+use crate::audit::{self, AuditRecord, Images};
+use crate::clock::{self, Clock, Stamp};
+use crate::models::PurgeModel;
+use crate::persistence;
+
+/// Spin variable holding the days deleted employees and persons are kept
+const RETENTION_VARIABLE: &str = "soft_delete_retention_days";
+const DEFAULT_RETENTION_DAYS: u64 = 30;
+
+fn retention_days() -> u64 {
+    variables::get(RETENTION_VARIABLE)
+        .ok()
+        .and_then(|days| days.trim().parse().ok())
+        .unwrap_or(DEFAULT_RETENTION_DAYS)
+}
+
+/// Removes the employees and persons deleted longer ago than the retention
+/// period for good, together with their addresses and stamps. Their history
+/// is kept. Each purged entity is recorded in the audit log.
+#[tracing::instrument(name = "purge", skip_all)]
+pub(crate) fn purge(req: Request, _: Params, clock: &dyn Clock) -> Result<Response> {
+    let ctx = RequestContext::from_request(&req);
+    let stamp = Stamp::new(clock, &ctx);
+    let retention_days = retention_days();
+    let cutoff = clock::days_ago(clock, retention_days);
+
+    let con = Connection::open_default()?;
+    let purged = persistence::in_transaction(&con, &ctx, |con| {
+        let purged = persistence::purge(con, &cutoff)?;
+        for (entity, id) in &purged {
+            let images = Images { target: Some(id.clone()), ..Images::default() };
+            audit::insert(con, &AuditRecord {
+                stamp: &stamp,
+                request_id: &ctx.request_id,
+                command: "purge",
+                entity,
+                images: &images,
+                status: 200,
+                outcome: audit::outcome(200),
+            })?;
+        }
+        Ok(purged)
+    })?;
+
+    let count = |entity: &str| purged.iter().filter(|(e, _)| *e == entity).count();
+    let model = PurgeModel { retention_days, cutoff, employees: count("employees"), persons: count("persons") };
+    Ok(ResponseBuilder::new(200)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&model)?)
+        .build())
+}
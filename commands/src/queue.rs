@@ -0,0 +1,181 @@
+use anyhow::Result;
+use shared::query::QueryString;
+use spin_sdk::http::{IntoResponse, Method, Params, Request, Response, ResponseBuilder};
+use telemetry::RequestContext;
+
+use crate::bus::Rejection;
+use crate::middleware::IDEMPOTENCY_KEY_HEADER;
+use crate::models::{CommandStatusModel, DrainModel};
+use crate::persistence::{self, Enqueued};
+
+/// Commands a single drain executes unless `?limit` says otherwise
+const DEFAULT_DRAIN_LIMIT: usize = 10;
+const MAX_DRAIN_LIMIT: usize = 100;
+
+/// Minutes after which a running command is considered abandoned by its
+/// worker and executed again
+const STALE_MINUTES: u32 = 15;
+
+/// Headers replaying the caller of a queued command, as the gateway sets them
+const CLIENT_HEADER: &str = "x-api-client";
+const SCOPES_HEADER: &str = "x-api-scopes";
+const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+/// Whether the client asked for the command to run asynchronously with
+/// `Prefer: respond-async`
+pub(crate) fn wants_async(req: &Request) -> bool {
+    prefers_async(req.method(), req.path(), req.header("prefer").and_then(|v| v.as_str()))
+}
+
+/// Reading routes, the worker itself, the purge, erasures and key rotations
+/// always run synchronously.
+fn prefers_async(method: &Method, path: &str, prefer: Option<&str>) -> bool {
+    *method == Method::Post
+        && path != "/drain"
+        && path != "/purge"
+        && path != "/rotate_keys"
+        && !path.starts_with("/erase_")
+        && prefer.is_some_and(|prefer| {
+            prefer
+                .split(',')
+                .any(|p| p.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("respond-async"))
+        })
+}
+
+/// Records the command in the queue and answers `202` with the status
+/// resource in `Location`. Repeating a command with the same
+/// `Idempotency-Key` answers with the command queued first.
+pub(crate) fn enqueue(ctx: &RequestContext, req: &Request) -> Result<Response> {
+    let path = req.path_and_query().unwrap_or(req.path());
+    let content_type = req.header("content-type").and_then(|v| v.as_str());
+    let key = req.header(IDEMPOTENCY_KEY_HEADER).and_then(|v| v.as_str());
+    let (id, replayed) = match persistence::enqueue_command(path, content_type, req.body(), ctx, key)? {
+        Enqueued::Queued(id) => (id, false),
+        Enqueued::Repeated(id) => (id, true),
+        Enqueued::Conflicting => {
+            let rejection = Rejection::new(422, "idempotency key already used for another request");
+            return Ok(rejection.into_response());
+        }
+    };
+    let status = persistence::command_status(&id)?
+        .ok_or_else(|| anyhow::anyhow!("queued command {} not found", id))?;
+    let mut builder = ResponseBuilder::new(202);
+    builder
+        .header("Content-Type", "application/json")
+        .header("Location", format!("/commands/{}", id))
+        .header("Preference-Applied", "respond-async");
+    if replayed {
+        builder.header(REPLAYED_HEADER, "true");
+    }
+    Ok(builder.body(serde_json::to_vec(&status)?).build())
+}
+
+#[tracing::instrument(name = "get_command", skip_all)]
+pub(crate) fn get_command(_req: Request, params: Params) -> Result<impl IntoResponse> {
+    let Some(id) = params.get("id") else {
+        return Ok(Response::new(400, "Bad Request"));
+    };
+    match persistence::command_status(id)? {
+        Some(status) => respond(&status),
+        None => Ok(Response::new(404, "Not Found")),
+    }
+}
+
+/// Worker entry point: executes up to `?limit` queued commands, oldest first,
+/// and stores their responses
+#[tracing::instrument(name = "drain", skip_all)]
+pub(crate) fn drain(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let limit = QueryString(req.query()).get("limit").map(|value| value.parse::<usize>());
+    let limit = match limit {
+        None => DEFAULT_DRAIN_LIMIT,
+        Some(Ok(limit)) if (1..=MAX_DRAIN_LIMIT).contains(&limit) => limit,
+        Some(_) => return Ok(Response::new(400, format!("limit must be between 1 and {}", MAX_DRAIN_LIMIT))),
+    };
+
+    let mut result = DrainModel { processed: 0, succeeded: 0, failed: 0, pending: 0 };
+    while result.processed < limit {
+        let Some(command) = persistence::claim_command(STALE_MINUTES)? else {
+            break;
+        };
+        let ctx = RequestContext {
+            request_id: command.request_id.clone(),
+            traceparent: None,
+            client: command.client.clone(),
+            scopes: command.scopes.as_ref().map(|scopes| scopes.split(',').map(String::from).collect()),
+        };
+        ctx.log(format!("commands:drain {} {}", command.id, command.path));
+
+        // the command runs as the caller who queued it, with the scopes the
+        // gateway granted then. Without a key of the caller, the id of the
+        // queued command keys its response, so that a command claimed again
+        // after its worker died replays the response instead of running twice.
+        let idempotency_key = command
+            .idempotency_key
+            .clone()
+            .unwrap_or_else(|| format!("queued:{}", command.id));
+        let mut builder = Request::builder();
+        builder
+            .method(Method::Post)
+            .uri(command.path.as_str())
+            .header("x-request-id", command.request_id.as_str())
+            .header(IDEMPOTENCY_KEY_HEADER, idempotency_key.as_str());
+        for (name, value) in [
+            ("content-type", &command.content_type),
+            (CLIENT_HEADER, &command.client),
+            (SCOPES_HEADER, &command.scopes),
+        ] {
+            if let Some(value) = value {
+                builder.header(name, value.as_str());
+            }
+        }
+        let res = ctx.finish("commands", crate::router().handle(builder.body(command.body).build()));
+
+        let status = *res.status();
+        let content_type = res.header("content-type").and_then(|v| v.as_str());
+        persistence::finish_command(&command.id, status, content_type, res.body())?;
+        result.processed += 1;
+        match status < 400 {
+            true => result.succeeded += 1,
+            false => result.failed += 1,
+        }
+    }
+    result.pending = persistence::pending_commands()?;
+    Ok(ResponseBuilder::new(200)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&result)?)
+        .build())
+}
+
+fn respond(status: &CommandStatusModel) -> Result<Response> {
+    Ok(ResponseBuilder::new(200)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(status)?)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_run_asynchronously_when_preferred() {
+        assert!(prefers_async(&Method::Post, "/create_employee", Some("respond-async")));
+        assert!(prefers_async(&Method::Post, "/import_persons", Some("wait=10, Respond-Async")));
+        assert!(prefers_async(&Method::Post, "/create_person", Some("respond-async; foo=bar")));
+    }
+
+    #[test]
+    fn commands_run_synchronously_otherwise() {
+        assert!(!prefers_async(&Method::Post, "/create_employee", None));
+        assert!(!prefers_async(&Method::Post, "/create_employee", Some("return=minimal")));
+        assert!(!prefers_async(&Method::Post, "/create_employee", Some("respond-async-later")));
+        assert!(!prefers_async(&Method::Get, "/export_employee/1", Some("respond-async")));
+    }
+
+    #[test]
+    fn maintenance_commands_always_run_synchronously() {
+        for path in ["/drain", "/purge", "/rotate_keys", "/erase_employee/1", "/erase_person/1"] {
+            assert!(!prefers_async(&Method::Post, path, Some("respond-async")), "{}", path);
+        }
+    }
+}
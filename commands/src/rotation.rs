@@ -0,0 +1,33 @@
+use anyhow::Result;
+use spin_sdk::http::{Params, Request, Response, ResponseBuilder};
+use spin_sdk::sqlite::Connection;
+use telemetry::RequestContext;
+
+use crate::clock::Clock;
+use crate::{crypto, persistence};
+
+/// Encrypts every personal field again with the current key, after a new key
+/// was added to `pii_encryption_keys`, or for the first time after encryption
+/// was turned on. Older keys can be removed once it succeeded.
+#[tracing::instrument(name = "rotate_keys", skip_all)]
+pub(crate) fn rotate_keys(req: Request, _: Params, _: &dyn Clock) -> Result<Response> {
+    let ctx = RequestContext::from_request(&req);
+    let Some(key_version) = crypto::current_version()? else {
+        let error = serde_json::json!({ "error": "no encryption keys configured" });
+        return Ok(ResponseBuilder::new(409)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&error)?)
+            .build());
+    };
+    let con = Connection::open_default()?;
+    let model = persistence::in_transaction(&con, &ctx, |con| persistence::rotate_keys(con, key_version))?;
+    ctx.log(format!(
+        "commands:rotate_keys {} employees, {} addresses, {} persons, {} locations, {} versions, \
+         {} audit records, {} payloads",
+        model.employees, model.addresses, model.persons, model.locations, model.versions, model.audit_records,
+        model.payloads));
+    Ok(ResponseBuilder::new(200)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&model)?)
+        .build())
+}
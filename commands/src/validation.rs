@@ -0,0 +1,36 @@
+use shared::time;
+
+use crate::crypto;
+
+/// Column limits of the `Employees`, `Persons`, `Locations` and `Addresses`
+/// tables
+pub(crate) const MAX_NAME: usize = 100;
+pub(crate) const MAX_STREET: usize = 50;
+pub(crate) const MAX_ZIP: usize = 10;
+pub(crate) const MAX_CITY: usize = 50;
+
+/// Records an error when `value` is blank, longer than `max` characters or
+/// looks like an encrypted value
+pub(crate) fn check(errors: &mut Vec<String>, field: &str, value: &str, max: usize) {
+    if value.trim().is_empty() {
+        errors.push(format!("{} is required", field));
+    } else if value.chars().count() > max {
+        errors.push(format!("{} is longer than {} characters", field, max));
+    } else if value.starts_with(crypto::PREFIX) {
+        errors.push(format!("{} must not start with {}", field, crypto::PREFIX));
+    }
+}
+
+/// Records an error unless `value` is a UTC date (`2026-10-01`) or time
+/// (`2026-10-01T08:30:00Z`) as stamps are written
+pub(crate) fn check_time(errors: &mut Vec<String>, field: &str, value: &str) {
+    if let Err(error) = time::check_utc_time(field, value) {
+        errors.push(error);
+    }
+}
+
+pub(crate) fn check_location(errors: &mut Vec<String>, street: &str, zip: &str, city: &str) {
+    check(errors, "street", street, MAX_STREET);
+    check(errors, "zip", zip, MAX_ZIP);
+    check(errors, "city", city, MAX_CITY);
+}
@@ -0,0 +1,364 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use shared::query::QueryString;
+use spin_sdk::http::{IntoResponse, Method, Params, Request, RequestBuilder, Response, ResponseBuilder};
+use spin_sdk::key_value::Store;
+use uuid::Uuid;
+
+use crate::config;
+use crate::rate_limit::RateLimit;
+use crate::models::{ApiKeyCreatedModel, ApiKeyModel, CreateApiKeyModel, QuotaModel};
+
+const KEY_PREFIX: &str = "apikey:";
+const HASH_PREFIX: &str = "apikey-hash:";
+const QUOTA_PREFIX: &str = "quota:";
+
+pub(crate) const SCOPE_READ: &str = "read";
+pub(crate) const SCOPE_WRITE: &str = "write";
+pub(crate) const SCOPE_ADMIN: &str = "admin";
+const SCOPES: [&str; 3] = [SCOPE_READ, SCOPE_WRITE, SCOPE_ADMIN];
+
+/// Key identifier used for the bootstrap key configured in `admin_api_key`
+const BOOTSTRAP_KID: &str = "bootstrap";
+
+/// Headers telling `commands` who the caller is and which scopes it holds
+const CLIENT_HEADER: &str = "x-api-client";
+const SCOPES_HEADER: &str = "x-api-scopes";
+/// Query parameter showing soft-deleted entities, reserved to admins
+const INCLUDE_DELETED: &str = "includeDeleted";
+
+/// An API key as persisted in the key-value store. Only the SHA-256 hash of
+/// the key is kept, the key itself is returned once when it is created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ApiKey {
+    pub kid: String,
+    pub name: String,
+    pub hash: String,
+    pub scopes: Vec<String>,
+    pub quota: Option<QuotaModel>,
+    pub created_at: u64,
+    pub revoked_at: Option<u64>,
+}
+
+impl ApiKey {
+    fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope || s == SCOPE_ADMIN)
+    }
+}
+
+/// Outcome of checking the API key presented with a request
+pub(crate) enum Access {
+    Granted {
+        key: Option<ApiKey>,
+        limit: Option<RateLimit>,
+    },
+    Denied(Response),
+}
+
+/// Authenticates the API key presented in `X-Api-Key` or
+/// `Authorization: ApiKey <key>`, checks its scopes against the request and
+/// consumes one request of its quota.
+#[tracing::instrument(name = "authorize", skip_all)]
+pub(crate) fn authorize(req: &Request) -> Result<Access> {
+    let scope = required_scope(req.method(), req.path(), req.query());
+    let Some(presented) = presented_key(req) else {
+        if scope == SCOPE_ADMIN || config::get_or("require_api_key", false) {
+            return Ok(Access::Denied(unauthorized()));
+        }
+        return Ok(Access::Granted { key: None, limit: None });
+    };
+
+    let Some(key) = lookup(&presented)? else {
+        return Ok(Access::Denied(unauthorized()));
+    };
+    if !key.has_scope(scope) {
+        return Ok(Access::Denied(Response::new(403, "Forbidden")));
+    }
+
+    let limit = match &key.quota {
+        Some(quota) => {
+            let (allowed, limit) = consume_quota(&key.kid, quota)?;
+            if !allowed {
+                return Ok(Access::Denied(limit.exceeded(limit.reset)));
+            }
+            Some(limit)
+        }
+        None => None,
+    };
+    Ok(Access::Granted { key: Some(key), limit })
+}
+
+/// Records the authenticated caller on the request, replacing whatever the
+/// client sent, so that the components can authorize and audit commands.
+/// Callers without a key hold every scope but `admin`.
+pub(crate) fn identify(req: &mut Request, key: Option<&ApiKey>) {
+    let (client, scopes) = match key {
+        Some(key) => (key.kid.clone(), key.scopes.join(",")),
+        None => ("anonymous".to_string(), [SCOPE_READ, SCOPE_WRITE].join(",")),
+    };
+    req.set_header(CLIENT_HEADER, client);
+    req.set_header(SCOPES_HEADER, scopes);
+}
+
+/// Copies the caller recorded by `identify` onto an outgoing request
+pub(crate) fn forward(req: &Request, builder: &mut RequestBuilder) {
+    for name in [CLIENT_HEADER, SCOPES_HEADER] {
+        if let Some(value) = req.header(name).and_then(|v| v.as_str()) {
+            builder.header(name, value);
+        }
+    }
+}
+
+fn required_scope(method: &Method, path: &str, query: &str) -> &'static str {
+    // route statistics and entity counts are no business of ordinary callers
+    if path.starts_with("/admin") || matches!(path, "/metrics" | "/diagnostics" | "/audit") {
+        return SCOPE_ADMIN;
+    }
+    // soft-deleted employees and persons are visible to admins only. The
+    // query string is decoded as `queries` decodes it, and any value needs
+    // the scope, so that no spelling of `true` slips through.
+    if QueryString(query).get(INCLUDE_DELETED).is_some() {
+        return SCOPE_ADMIN;
+    }
+    match method {
+        Method::Get | Method::Head | Method::Options => SCOPE_READ,
+        _ => SCOPE_WRITE,
+    }
+}
+
+fn presented_key(req: &Request) -> Option<String> {
+    if let Some(key) = req.header("x-api-key").and_then(|v| v.as_str()) {
+        return Some(key.trim().to_string());
+    }
+    req.header("authorization")
+        .and_then(|v| v.as_str())
+        .and_then(|v| v.strip_prefix("ApiKey "))
+        .map(|v| v.trim().to_string())
+}
+
+fn lookup(presented: &str) -> Result<Option<ApiKey>> {
+    let hash = hash_key(presented);
+    if let Some(bootstrap) = config::get("admin_api_key") {
+        if hash_key(&bootstrap) == hash {
+            return Ok(Some(ApiKey {
+                kid: BOOTSTRAP_KID.to_string(),
+                name: BOOTSTRAP_KID.to_string(),
+                hash,
+                scopes: vec![SCOPE_ADMIN.to_string()],
+                quota: None,
+                created_at: 0,
+                revoked_at: None,
+            }));
+        }
+    }
+
+    let store = Store::open_default()?;
+    let Some(kid) = store.get(&format!("{HASH_PREFIX}{hash}"))? else {
+        return Ok(None);
+    };
+    let kid = String::from_utf8(kid)?;
+    let key = store.get_json::<ApiKey>(format!("{KEY_PREFIX}{kid}"))?;
+    Ok(key.filter(|k| k.revoked_at.is_none() && k.hash == hash))
+}
+
+/// Counts the request against a fixed window quota. The previous window's
+/// counter is dropped when a new window starts so counters do not pile up.
+///
+/// The quota is best-effort: the key-value store has no atomic increment, so
+/// concurrent requests may read the same count and overrun it slightly.
+fn consume_quota(kid: &str, quota: &QuotaModel) -> Result<(bool, RateLimit)> {
+    let store = Store::open_default()?;
+    let now = unix_now();
+    let window = now / quota.window_secs.max(1);
+    let counter = format!("{QUOTA_PREFIX}{kid}:{window}");
+
+    let used = store
+        .get(&counter)?
+        .and_then(|v| String::from_utf8(v).ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    if used == 0 {
+        store.delete(&format!("{QUOTA_PREFIX}{kid}:{}", window.saturating_sub(1)))?;
+    }
+
+    let (allowed, limit) = spend(quota, used, now);
+    if allowed {
+        store.set(&counter, (used + 1).to_string().as_bytes())?;
+    }
+    Ok((allowed, limit))
+}
+
+/// Spends one request of a quota of which `used` are already spent in the
+/// current window
+fn spend(quota: &QuotaModel, used: u64, now: u64) -> (bool, RateLimit) {
+    let window_secs = quota.window_secs.max(1);
+    let reset = (now / window_secs + 1) * window_secs - now;
+    if used >= quota.requests {
+        return (false, RateLimit { limit: quota.requests, remaining: 0, reset });
+    }
+    let limit = RateLimit {
+        limit: quota.requests,
+        remaining: quota.requests - used - 1,
+        reset,
+    };
+    (true, limit)
+}
+
+fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn unauthorized() -> Response {
+    ResponseBuilder::new(401)
+        .header("WWW-Authenticate", "ApiKey")
+        .body("Unauthorized")
+        .build()
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[tracing::instrument(name = "create_api_key", skip_all)]
+pub(crate) fn create_api_key(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let model: CreateApiKeyModel = serde_json::from_slice(req.body())?;
+    if model.name.trim().is_empty()
+        || model.scopes.is_empty()
+        || model.scopes.iter().any(|s| !SCOPES.contains(&s.as_str()))
+    {
+        return Ok(Response::new(400, "Bad Request"));
+    }
+    let quota = model.quota.unwrap_or_else(|| QuotaModel {
+        requests: config::get_or("api_key_default_quota", 1000),
+        window_secs: config::get_or("api_key_default_window_secs", 3600),
+    });
+    if quota.window_secs == 0 {
+        return Ok(Response::new(400, "Bad Request"));
+    }
+
+    let kid = Uuid::new_v4().to_string();
+    let key = format!("ck_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let record = ApiKey {
+        kid: kid.clone(),
+        name: model.name,
+        hash: hash_key(&key),
+        scopes: model.scopes,
+        quota: Some(quota.clone()),
+        created_at: unix_now(),
+        revoked_at: None,
+    };
+
+    let store = Store::open_default()?;
+    store.set_json(format!("{KEY_PREFIX}{kid}"), &record)?;
+    store.set(&format!("{HASH_PREFIX}{}", record.hash), kid.as_bytes())?;
+
+    let created = ApiKeyCreatedModel {
+        kid,
+        name: record.name,
+        key,
+        scopes: record.scopes,
+        quota,
+        created_at: record.created_at,
+    };
+    Ok(ResponseBuilder::new(201)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&created)?)
+        .build())
+}
+
+#[tracing::instrument(name = "list_api_keys", skip_all)]
+pub(crate) fn list_api_keys(_req: Request, _: Params) -> Result<impl IntoResponse> {
+    let store = Store::open_default()?;
+    let mut keys: Vec<ApiKeyModel> = Vec::new();
+    for name in store.get_keys()? {
+        if !name.starts_with(KEY_PREFIX) {
+            continue;
+        }
+        if let Some(key) = store.get_json::<ApiKey>(&name)? {
+            keys.push(ApiKeyModel {
+                kid: key.kid,
+                name: key.name,
+                scopes: key.scopes,
+                quota: key.quota.unwrap_or(QuotaModel { requests: 0, window_secs: 0 }),
+                created_at: key.created_at,
+                revoked_at: key.revoked_at,
+            });
+        }
+    }
+    keys.sort_by_key(|k| k.created_at);
+
+    Ok(ResponseBuilder::new(200)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&keys)?)
+        .build())
+}
+
+#[tracing::instrument(name = "revoke_api_key", skip_all)]
+pub(crate) fn revoke_api_key(_req: Request, params: Params) -> Result<impl IntoResponse> {
+    let Some(kid) = params.get("kid") else {
+        return Ok(Response::new(400, "Bad Request"));
+    };
+    let store = Store::open_default()?;
+    let Some(mut key) = store.get_json::<ApiKey>(format!("{KEY_PREFIX}{kid}"))? else {
+        return Ok(Response::new(404, "Not Found"));
+    };
+    if key.revoked_at.is_none() {
+        key.revoked_at = Some(unix_now());
+        store.set_json(format!("{KEY_PREFIX}{kid}"), &key)?;
+        store.delete(&format!("{HASH_PREFIX}{}", key.hash))?;
+    }
+    Ok(Response::new(204, ()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_routes_require_the_admin_scope() {
+        for path in ["/admin/keys", "/admin/keys/k-1", "/metrics", "/diagnostics", "/audit"] {
+            assert_eq!(required_scope(&Method::Get, path, ""), SCOPE_ADMIN, "{path}");
+        }
+    }
+
+    #[test]
+    fn deleted_entities_require_the_admin_scope() {
+        for query in ["includeDeleted=true", "includeDeleted=1", "includeDeleted=", "limit=5&includeDeleted=%54"] {
+            assert_eq!(required_scope(&Method::Get, "/employees", query), SCOPE_ADMIN, "{query}");
+        }
+        assert_eq!(required_scope(&Method::Get, "/employees", "include_deleted=true"), SCOPE_READ);
+    }
+
+    #[test]
+    fn other_routes_require_read_or_write() {
+        assert_eq!(required_scope(&Method::Get, "/employees", "limit=5"), SCOPE_READ);
+        assert_eq!(required_scope(&Method::Head, "/persons/p-1", ""), SCOPE_READ);
+        assert_eq!(required_scope(&Method::Options, "/persons", ""), SCOPE_READ);
+        assert_eq!(required_scope(&Method::Post, "/persons", ""), SCOPE_WRITE);
+        assert_eq!(required_scope(&Method::Delete, "/persons/p-1", ""), SCOPE_WRITE);
+    }
+
+    #[test]
+    fn quota_is_spent_until_exhausted() {
+        let quota = QuotaModel { requests: 2, window_secs: 60 };
+        let (allowed, limit) = spend(&quota, 0, 1_800_000_010);
+        assert_eq!((allowed, limit.limit, limit.remaining, limit.reset), (true, 2, 1, 50));
+        let (allowed, limit) = spend(&quota, 1, 1_800_000_059);
+        assert_eq!((allowed, limit.remaining, limit.reset), (true, 0, 1));
+        let (allowed, limit) = spend(&quota, 2, 1_800_000_059);
+        assert_eq!((allowed, limit.remaining, limit.reset), (false, 0, 1));
+    }
+
+    #[test]
+    fn quota_without_a_window_resets_every_second() {
+        let quota = QuotaModel { requests: 1, window_secs: 0 };
+        let (allowed, limit) = spend(&quota, 0, 1_800_000_000);
+        assert_eq!((allowed, limit.remaining, limit.reset), (true, 0, 1));
+    }
+}
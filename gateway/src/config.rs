@@ -0,0 +1,17 @@
+use std::str::FromStr;
+
+use spin_sdk::variables;
+
+/// Reads a Spin variable, treating missing and empty values alike.
+pub(crate) fn get(name: &str) -> Option<String> {
+    variables::get(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Reads and parses a Spin variable, falling back to `default` when it is
+/// missing or cannot be parsed.
+pub(crate) fn get_or<T: FromStr>(name: &str, default: T) -> T {
+    get(name).and_then(|v| v.parse().ok()).unwrap_or(default)
+}
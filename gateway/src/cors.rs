@@ -0,0 +1,217 @@
+use spin_sdk::http::{Method, Request, Response};
+
+use crate::config;
+use crate::routes::join_methods;
+
+/// CORS policy read from the `cors_*` Spin variables. With no allowed origins
+/// configured the gateway adds no CORS headers at all.
+pub(crate) struct Cors {
+    allowed_origins: Vec<String>,
+    allowed_methods: Vec<String>,
+    allowed_headers: String,
+    exposed_headers: String,
+    allow_credentials: bool,
+    max_age: u64,
+}
+
+impl Cors {
+    pub(crate) fn from_config() -> Cors {
+        Cors {
+            allowed_origins: list(config::get("cors_allowed_origins")),
+            allowed_methods: list(config::get("cors_allowed_methods"))
+                .into_iter()
+                .map(|m| m.to_uppercase())
+                .collect(),
+            allowed_headers: config::get("cors_allowed_headers")
+                .unwrap_or_else(|| "Content-Type, Authorization, X-Api-Key, Prefer, Idempotency-Key, If-None-Match, If-Modified-Since, Consistency-Token".to_string()),
+            exposed_headers: config::get("cors_exposed_headers").unwrap_or_else(|| {
+                "RateLimit-Limit, RateLimit-Remaining, RateLimit-Reset, Retry-After, Api-Version, Deprecation, Sunset, Link, Location, Preference-Applied, Idempotent-Replayed, ETag, X-Cache, Consistency-Token".to_string()
+            }),
+            allow_credentials: config::get_or("cors_allow_credentials", false),
+            max_age: config::get_or("cors_max_age", 600),
+        }
+    }
+
+    /// The value for `Access-Control-Allow-Origin`, if the origin is allowed.
+    /// Origins only matched by `*` get the wildcard, which browsers never
+    /// combine with credentials.
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        if self.allowed_origins.iter().any(|o| o == origin) {
+            return Some(origin.to_string());
+        }
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            return Some("*".to_string());
+        }
+        None
+    }
+
+    /// Answers an `OPTIONS` request for a path whose registered methods are
+    /// `allowed`. Preflights get the CORS headers, plain `OPTIONS` requests
+    /// only the `Allow` header.
+    pub(crate) fn preflight(&self, req: &Request, allowed: &[Method]) -> Response {
+        if allowed.is_empty() {
+            return Response::new(404, ());
+        }
+        let mut res = Response::new(204, ());
+        res.set_header("Allow", join_methods(allowed));
+
+        let origin = req.header("origin").and_then(|v| v.as_str());
+        let requested = req
+            .header("access-control-request-method")
+            .and_then(|v| v.as_str());
+        let (Some(origin), Some(requested)) = (origin, requested) else {
+            return res;
+        };
+        let methods: Vec<Method> = allowed
+            .iter()
+            .filter(|m| {
+                self.allowed_methods.is_empty() || self.allowed_methods.contains(&m.to_string())
+            })
+            .cloned()
+            .collect();
+        if !methods.iter().any(|m| m.to_string() == requested.to_uppercase()) {
+            return res;
+        }
+        let Some(allow_origin) = self.allow_origin(origin) else {
+            return res;
+        };
+
+        self.set_origin_headers(&mut res, allow_origin);
+        res.set_header("Access-Control-Allow-Methods", join_methods(&methods));
+        let headers = req
+            .header("access-control-request-headers")
+            .and_then(|v| v.as_str())
+            .filter(|_| self.allowed_headers == "*")
+            .unwrap_or(&self.allowed_headers)
+            .to_string();
+        res.set_header("Access-Control-Allow-Headers", headers);
+        res.set_header("Access-Control-Max-Age", self.max_age.to_string());
+        res
+    }
+
+    /// Adds the CORS headers to an actual (non-preflight) response
+    pub(crate) fn apply(&self, origin: Option<&str>, res: &mut Response) {
+        let Some(allow_origin) = origin.and_then(|o| self.allow_origin(o)) else {
+            return;
+        };
+        self.set_origin_headers(res, allow_origin);
+        if !self.exposed_headers.is_empty() {
+            res.set_header("Access-Control-Expose-Headers", self.exposed_headers.clone());
+        }
+    }
+
+    /// Credentials are only allowed for origins listed by name: `*` with
+    /// credentials would let every site make authenticated calls.
+    fn set_origin_headers(&self, res: &mut Response, allow_origin: String) {
+        let wildcard = allow_origin == "*";
+        if !wildcard {
+            vary(res, "Origin");
+        }
+        res.set_header("Access-Control-Allow-Origin", allow_origin);
+        if self.allow_credentials && !wildcard {
+            res.set_header("Access-Control-Allow-Credentials", "true");
+        }
+    }
+}
+
+/// Adds `header` to the `Vary` header, keeping the headers already listed
+/// there, such as `Accept` when the API version was negotiated
+pub(crate) fn vary(res: &mut Response, header: &str) {
+    let vary = match res.header("vary").and_then(|v| v.as_str()) {
+        Some(listed) if listed.split(',').any(|v| v.trim().eq_ignore_ascii_case(header)) => return,
+        Some(listed) => format!("{}, {}", listed, header),
+        None => header.to_string(),
+    };
+    res.set_header("Vary", vary);
+}
+
+fn list(value: Option<String>) -> Vec<String> {
+    value
+        .map(|v| {
+            v.split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &[&str], allow_credentials: bool) -> Cors {
+        Cors {
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: "Content-Type, X-Api-Key".to_string(),
+            exposed_headers: "ETag".to_string(),
+            allow_credentials,
+            max_age: 600,
+        }
+    }
+
+    fn preflight(origin: &str, method: &str) -> Request {
+        let mut builder = Request::builder();
+        builder
+            .method(Method::Options)
+            .uri("/persons")
+            .header("origin", origin)
+            .header("access-control-request-method", method);
+        builder.build()
+    }
+
+    fn header<'a>(res: &'a Response, name: &str) -> Option<&'a str> {
+        res.header(name).and_then(|v| v.as_str())
+    }
+
+    #[test]
+    fn listed_origins_are_echoed() {
+        let listed = cors(&["https://app.example"], false);
+        assert_eq!(listed.allow_origin("https://app.example").as_deref(), Some("https://app.example"));
+        assert_eq!(listed.allow_origin("https://other.example"), None);
+        let wildcard = cors(&["https://app.example", "*"], true);
+        assert_eq!(wildcard.allow_origin("https://app.example").as_deref(), Some("https://app.example"));
+        assert_eq!(wildcard.allow_origin("https://other.example").as_deref(), Some("*"));
+    }
+
+    #[test]
+    fn credentials_are_never_allowed_for_the_wildcard() {
+        let cors = cors(&["https://app.example", "*"], true);
+        let mut res = Response::new(200, ());
+        cors.apply(Some("https://evil.example"), &mut res);
+        assert_eq!(header(&res, "access-control-allow-origin"), Some("*"));
+        assert_eq!(header(&res, "access-control-allow-credentials"), None);
+
+        let mut res = Response::new(200, ());
+        cors.apply(Some("https://app.example"), &mut res);
+        assert_eq!(header(&res, "access-control-allow-origin"), Some("https://app.example"));
+        assert_eq!(header(&res, "access-control-allow-credentials"), Some("true"));
+        assert_eq!(header(&res, "vary"), Some("Origin"));
+    }
+
+    #[test]
+    fn preflight_offers_the_allowed_methods_of_the_route() {
+        let cors = cors(&["https://app.example"], false);
+        let allowed = [Method::Get, Method::Post, Method::Delete];
+        let res = cors.preflight(&preflight("https://app.example", "post"), &allowed);
+        assert_eq!(*res.status(), 204);
+        assert_eq!(header(&res, "allow"), Some("GET, POST, DELETE"));
+        assert_eq!(header(&res, "access-control-allow-origin"), Some("https://app.example"));
+        assert_eq!(header(&res, "access-control-allow-methods"), Some("GET, POST"));
+        assert_eq!(header(&res, "access-control-allow-headers"), Some("Content-Type, X-Api-Key"));
+        assert_eq!(header(&res, "access-control-max-age"), Some("600"));
+    }
+
+    #[test]
+    fn preflight_without_cors_headers_when_not_allowed() {
+        let cors = cors(&["https://app.example"], false);
+        let allowed = [Method::Get, Method::Post, Method::Delete];
+        for req in [preflight("https://app.example", "DELETE"), preflight("https://other.example", "GET")] {
+            let res = cors.preflight(&req, &allowed);
+            assert_eq!(*res.status(), 204);
+            assert_eq!(header(&res, "access-control-allow-origin"), None);
+        }
+        assert_eq!(*cors.preflight(&preflight("https://app.example", "GET"), &[]).status(), 404);
+    }
+}
@@ -0,0 +1,125 @@
+use std::cell::RefCell;
+
+use anyhow::{anyhow, Result};
+use futures::{SinkExt, StreamExt};
+use spin_sdk::http::{IncomingResponse, OutgoingResponse, Response, ResponseOutparam};
+
+/// Content types of the CSV and NDJSON exports of `queries`, whose bodies are
+/// passed on as they arrive rather than read whole
+const STREAMED: [&str; 2] = ["text/csv", "application/x-ndjson"];
+
+thread_local! {
+    /// Body of the export answering the request being handled
+    static EXPORT: RefCell<Option<IncomingResponse>> = const { RefCell::new(None) };
+}
+
+/// Reads the response of a component. The body of a successful export stays
+/// in the component's stream, kept for `forward`, and the response only
+/// carries its status and headers.
+pub(crate) async fn receive(res: IncomingResponse) -> Result<Response> {
+    let status = res.status();
+    let headers = res.headers().entries();
+    let streamed = (200..300).contains(&status)
+        && headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("content-type")
+                && STREAMED.iter().any(|t| value.starts_with(t.as_bytes()))
+        });
+
+    let mut builder = Response::builder();
+    builder.status(status);
+    for (name, value) in headers {
+        builder.header(name, String::from_utf8_lossy(&value));
+    }
+    if streamed {
+        EXPORT.with(|export| *export.borrow_mut() = Some(res));
+        return Ok(builder.body(()).build());
+    }
+    let body = res.into_body().await.map_err(|e| anyhow!("{:?}", e))?;
+    Ok(builder.body(body).build())
+}
+
+/// The export kept by `receive` for the request just handled
+pub(crate) fn take() -> Option<IncomingResponse> {
+    EXPORT.with(|export| export.borrow_mut().take())
+}
+
+/// Sends a response whose body is complete
+pub(crate) async fn respond(mut res: Response, response_out: ResponseOutparam) -> Result<()> {
+    let body = std::mem::take(res.body_mut());
+    response_out.set_with_body(res.into(), body).await?;
+    Ok(())
+}
+
+/// Sends the headers of `res`, then the body of `export` as it arrives. NDJSON
+/// rows are passed through `translate` a batch of whole lines at a time, and
+/// pass unchanged when it gives `None`.
+pub(crate) async fn forward(
+    res: Response,
+    export: IncomingResponse,
+    response_out: ResponseOutparam,
+    translate: impl Fn(&[u8]) -> Option<Vec<u8>>,
+) -> Result<()> {
+    let ndjson = res
+        .header("content-type")
+        .and_then(|v| v.as_str())
+        .is_some_and(|ct| ct.starts_with("application/x-ndjson"));
+    let outgoing = OutgoingResponse::from(res);
+    let mut body = outgoing.take_body();
+    response_out.set(outgoing);
+
+    let mut stream = export.take_body_stream();
+    let mut lines = Lines::default();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| anyhow!("{:?}", e))?;
+        let chunk = match ndjson {
+            true => lines.push(&chunk),
+            false => chunk,
+        };
+        if !chunk.is_empty() {
+            body.send(translate(&chunk).unwrap_or(chunk)).await?;
+        }
+    }
+    let rest = lines.finish();
+    if !rest.is_empty() {
+        body.send(translate(&rest).unwrap_or(rest)).await?;
+    }
+    body.close().await?;
+    Ok(())
+}
+
+/// Splits a stream into whole lines, however its chunks fall
+#[derive(Default)]
+struct Lines(Vec<u8>);
+
+impl Lines {
+    /// The lines `chunk` completes, with their newlines
+    fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.0.extend_from_slice(chunk);
+        match self.0.iter().rposition(|b| *b == b'\n') {
+            Some(end) => {
+                let rest = self.0.split_off(end + 1);
+                std::mem::replace(&mut self.0, rest)
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// The last line, when the stream does not end with a newline
+    fn finish(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_whole_however_the_chunks_fall() {
+        let mut lines = Lines::default();
+        assert_eq!(lines.push(b"{\"a\":1}\n{\"a\""), b"{\"a\":1}\n");
+        assert_eq!(lines.push(b":2}"), b"");
+        assert_eq!(lines.push(b"\n{\"a\":3}\n{\"a\":4}"), b"{\"a\":2}\n{\"a\":3}\n");
+        assert_eq!(lines.finish(), b"{\"a\":4}");
+    }
+}
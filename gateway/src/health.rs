@@ -0,0 +1,86 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use spin_sdk::http::{send, IntoResponse, Params, Request, RequestBuilder, Response, ResponseBuilder};
+use spin_sdk::key_value::Store;
+
+use crate::{trace_context, COMMAND_ROOT_URL, QUERY_ROOT_URL};
+
+/// Probe paths, answered without API key or rate limit checks
+pub(crate) const PROBES: [&str; 2] = ["/healthz", "/readyz"];
+
+fn json_response(status: u16, body: &Value) -> Result<Response> {
+    Ok(ResponseBuilder::new(status)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(serde_json::to_vec(body)?)
+        .build())
+}
+
+/// Calls a private endpoint of a component. Unreachable components are
+/// reported as failed instead of failing the probe.
+async fn fetch(incoming: &Request, url: String) -> (bool, Value) {
+    let mut builder = RequestBuilder::new(spin_sdk::http::Method::Get, url.as_str());
+    builder.header("Accept", "application/json");
+    trace_context::forward(incoming, &mut builder);
+    match send::<_, Response>(builder.build()).await {
+        Ok(res) => {
+            let body = serde_json::from_slice(res.body())
+                .unwrap_or_else(|_| json!({ "status": "fail", "detail": format!("status {}", res.status()) }));
+            (*res.status() == 200, body)
+        }
+        Err(e) => (false, json!({ "status": "fail", "detail": e.to_string() })),
+    }
+}
+
+/// The gateway's own checks: the key-value store holding API keys, rate
+/// limits and metrics opens.
+fn gateway_checks() -> (bool, Value) {
+    let kv = Store::open_default();
+    let ok = kv.is_ok();
+    let check = match kv {
+        Ok(_) => json!({ "name": "key_value", "status": "ok" }),
+        Err(e) => json!({ "name": "key_value", "status": "fail", "detail": e.to_string() }),
+    };
+    let status = if ok { "ok" } else { "fail" };
+    (ok, json!({ "component": "gateway", "status": status, "checks": [check] }))
+}
+
+/// Liveness: the gateway is running
+#[tracing::instrument(name = "healthz", skip_all)]
+pub(crate) fn healthz(_req: Request, _: Params) -> Result<impl IntoResponse> {
+    json_response(200, &json!({ "status": "ok" }))
+}
+
+/// Readiness: the gateway can reach `commands` and `queries`, and each of
+/// them can open SQLite and finds the schema version it expects.
+#[tracing::instrument(name = "readyz", skip_all)]
+pub(crate) async fn readyz(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let (gateway_ok, gateway) = gateway_checks();
+    let (commands_ok, commands) = fetch(&req, format!("{}/health", COMMAND_ROOT_URL)).await;
+    let (queries_ok, queries) = fetch(&req, format!("{}/health", QUERY_ROOT_URL)).await;
+
+    let ready = gateway_ok && commands_ok && queries_ok;
+    let body = json!({
+        "status": if ready { "ok" } else { "fail" },
+        "components": { "gateway": gateway, "commands": commands, "queries": queries },
+    });
+    json_response(if ready { 200 } else { 503 }, &body)
+}
+
+/// Diagnostics: build information, schema version, last migration and row
+/// counts, as contributed by each component
+#[tracing::instrument(name = "diagnostics", skip_all)]
+pub(crate) async fn diagnostics(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let (_, mut gateway) = gateway_checks();
+    gateway["build"] = json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+    });
+    let (_, commands) = fetch(&req, format!("{}/diagnostics", COMMAND_ROOT_URL)).await;
+    let (_, queries) = fetch(&req, format!("{}/diagnostics", QUERY_ROOT_URL)).await;
+
+    let body = json!({
+        "components": { "gateway": gateway, "commands": commands, "queries": queries },
+    });
+    json_response(200, &body)
+}
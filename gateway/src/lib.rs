@@ -1,75 +1,231 @@
+mod api_keys;
+mod config;
+mod cors;
+mod exports;
+mod health;
+mod metrics;
+mod models;
+mod openapi;
+mod rate_limit;
+mod routes;
+mod trace_context;
+mod versions;
+
 use anyhow::Result;
+use api_keys::Access;
+use cors::Cors;
+use rate_limit::{RateLimit, Throttle};
+use routes::{join_methods, Routes};
+use trace_context::TraceContext;
+use versions::ApiVersion;
 use spin_sdk::http::{
-    send, HeaderValue, IntoResponse, Params, Request, RequestBuilder, Response, ResponseBuilder,
-    Router,
+    send, HeaderValue, IncomingResponse, IntoResponse, Method, Params, Request, RequestBuilder,
+    Response, ResponseBuilder, ResponseOutparam,
 };
 use spin_sdk::http_component;
+use std::time::Instant;
+use telemetry::Telemetry;
 
 const QUERY_ROOT_URL: &str = "https://queries.spin.internal";
 const COMMAND_ROOT_URL: &str = "https://commands.spin.internal";
 
-#[tracing::instrument(name="execute_command", skip_all)]
-async fn execute_command(url: String,
+/// Response headers of the components passed on to the client
+const FORWARDED_HEADERS: [&str; 9] = [
+    "content-disposition", "location", "preference-applied", "idempotent-replayed", "etag", "last-modified",
+    "cache-control", "x-cache", "consistency-token",
+];
+
+#[tracing::instrument(name="execute_command", skip_all,
+                      fields(otel.kind = "client", url.full = %url, http.response.status_code))]
+async fn execute_command(incoming: &Request,
+                         url: String,
                          content_type: Option<&HeaderValue>,
                          payload: Option<Vec<u8>>) -> Result<Response> {
+    let mut builder = RequestBuilder::new(spin_sdk::http::Method::Post, url.as_str());
+    builder.header("Accept", "application/json");
+    for name in ["prefer", "idempotency-key"] {
+        if let Some(value) = incoming.header(name).and_then(|v| v.as_str()) {
+            builder.header(name, value);
+        }
+    }
+    trace_context::forward(incoming, &mut builder);
+    api_keys::forward(incoming, &mut builder);
     let req: Request = match content_type {
-        Some(ct) => RequestBuilder::new(spin_sdk::http::Method::Post, url)
-            .header("Accept", "application/json")
+        Some(ct) => builder
             .header("Content-Type", ct.as_str().unwrap())
             .body(payload)
             .build(),
-        None => RequestBuilder::new(spin_sdk::http::Method::Post, url)
-            .header("Accept", "application/json")
+        None => builder
             .body(())
             .build(),
     };
 
+    let started = Instant::now();
     let res: Response = send(req).await?;
+    tracing::Span::current().record("http.response.status_code", *res.status());
+    metrics::observe_call("commands", &url, *res.status(), started.elapsed());
     parse_result(res)
 }
 
-#[tracing::instrument(name="execute_query", skip_all)]
-async fn execute_query(url: &str) -> Result<Response> {
-    let req: Request = RequestBuilder::new(spin_sdk::http::Method::Get, url)
-        .header("Accept", "application/json")
-        .build();
-    let res: Response = send(req).await?;
-    parse_result(res)
+#[tracing::instrument(name="execute_query", skip_all,
+                      fields(otel.kind = "client", url.full = %url, http.response.status_code))]
+async fn execute_query(incoming: &Request, url: &str) -> Result<Response> {
+    let accept = incoming.header("accept").and_then(|v| v.as_str()).unwrap_or("application/json");
+    let mut builder = RequestBuilder::new(spin_sdk::http::Method::Get, url);
+    builder.header("Accept", accept);
+    for name in ["if-none-match", "if-modified-since", "consistency-token"] {
+        if let Some(value) = incoming.header(name).and_then(|v| v.as_str()) {
+            builder.header(name, value);
+        }
+    }
+    trace_context::forward(incoming, &mut builder);
+    let req: Request = builder.build();
+    let started = Instant::now();
+    let res: IncomingResponse = send(req).await?;
+    tracing::Span::current().record("http.response.status_code", res.status());
+    let component = if url.starts_with(COMMAND_ROOT_URL) { "commands" } else { "queries" };
+    metrics::observe_call(component, url, res.status(), started.elapsed());
+    // CSV and NDJSON exports are passed on as the component streams them
+    parse_result(exports::receive(res).await?)
 }
 
 #[tracing::instrument(name="parse_result", skip_all)]
 fn parse_result(res: Response) -> Result<Response> {
+    let json = res
+        .header("content-type")
+        .and_then(|v| v.as_str())
+        .is_some_and(|ct| ct.starts_with("application/json"));
     match res.status() {
-        300..=499 => Ok(Response::new(*res.status(), ())),
-        500..=599 => {
-            println!("{}", String::from_utf8_lossy(res.body()));
-            Ok(Response::new(500, "Internal Server Error"))
-        }
-        200 | 201 | 204 => Ok(ResponseBuilder::new(*res.status())
+        // reports meant for the client, such as the rows rejected by an import
+        400..=499 if json => Ok(ResponseBuilder::new(*res.status())
             .header("Content-Type", "application/json")
             .body(res.into_body())
             .build()),
+        // the client's cached representation is still current, which the
+        // validators and caching hints confirm
+        304 => {
+            let mut builder = ResponseBuilder::new(304);
+            forward_headers(&res, &mut builder);
+            Ok(builder.body(()).build())
+        }
+        300..=399 => {
+            let mut builder = ResponseBuilder::new(*res.status());
+            if let Some(content_type) = res.header("content-type").and_then(|v| v.as_str()) {
+                builder.header("Content-Type", content_type);
+            }
+            forward_headers(&res, &mut builder);
+            Ok(builder.body(res.into_body()).build())
+        }
+        // the component's error text, which `TraceContext::decorate` wraps
+        // in the JSON error body
+        400..=499 => {
+            let text = String::from_utf8_lossy(res.body()).trim().to_string();
+            Ok(Response::new(*res.status(), text))
+        }
+        // the read models have not reached the consistency token yet: the
+        // client retries after `Retry-After`
+        503 if res.header("retry-after").is_some() => {
+            let mut builder = ResponseBuilder::new(503);
+            for name in ["retry-after", "content-type"] {
+                if let Some(value) = res.header(name).and_then(|v| v.as_str()) {
+                    builder.header(name, value);
+                }
+            }
+            Ok(builder.body(res.into_body()).build())
+        }
+        500..=599 => {
+            tracing::error!("{}", String::from_utf8_lossy(res.body()));
+            Ok(Response::new(500, "Internal Server Error"))
+        }
+        200 | 201 | 202 | 204 => {
+            let content_type = res
+                .header("content-type")
+                .and_then(|v| v.as_str())
+                .unwrap_or("application/json")
+                .to_string();
+            let mut builder = ResponseBuilder::new(*res.status());
+            builder.header("Content-Type", content_type);
+            forward_headers(&res, &mut builder);
+            Ok(builder.body(res.into_body()).build())
+        }
         _ => {
-            println!("{}", String::from_utf8_lossy(res.body()));
+            tracing::warn!("unexpected status {}: {}", res.status(), String::from_utf8_lossy(res.body()));
             Ok(Response::new(*res.status(), ()))
         }
     }
 }
 
+fn forward_headers(res: &Response, builder: &mut ResponseBuilder) {
+    for name in FORWARDED_HEADERS {
+        if let Some(value) = res.header(name).and_then(|v| v.as_str()) {
+            builder.header(name, value);
+        }
+    }
+}
+
 #[tracing::instrument(name="create_employee", skip_all)]
 async fn create_employee(req: Request, _: Params) -> Result<impl IntoResponse> {
     let url = format!("{}/create_employee", COMMAND_ROOT_URL);
-    execute_command(url, req.header("content-type"), Some(req.body().to_vec())).await
+    execute_command(&req, url, req.header("content-type"), Some(req.body().to_vec())).await
 }
 
 async fn create_location(req: Request, _: Params) -> Result<impl IntoResponse> {
     let url = format!("{}/create_location", COMMAND_ROOT_URL);
-    execute_command(url, req.header("content-type"), Some(req.body().to_vec())).await
+    execute_command(&req, url, req.header("content-type"), Some(req.body().to_vec())).await
 }
 
 async fn create_person(req: Request, _: Params) -> Result<impl IntoResponse> {
     let url = format!("{}/create_person", COMMAND_ROOT_URL);
-    execute_command(url, req.header("content-type"), Some(req.body().to_vec())).await
+    execute_command(&req, url, req.header("content-type"), Some(req.body().to_vec())).await
+}
+
+#[tracing::instrument(name="import_persons", skip_all)]
+async fn import_persons(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let url = format!("{}/import_persons?{}", COMMAND_ROOT_URL, req.query());
+    execute_command(&req, url, req.header("content-type"), Some(req.body().to_vec())).await
+}
+
+#[tracing::instrument(name="import_locations", skip_all)]
+async fn import_locations(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let url = format!("{}/import_locations?{}", COMMAND_ROOT_URL, req.query());
+    execute_command(&req, url, req.header("content-type"), Some(req.body().to_vec())).await
+}
+
+#[tracing::instrument(name="batch", skip_all)]
+async fn batch(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let url = format!("{}/batch", COMMAND_ROOT_URL);
+    execute_command(&req, url, req.header("content-type"), Some(req.body().to_vec())).await
+}
+
+#[tracing::instrument(name="get_command", skip_all)]
+async fn get_command(req: Request, params: Params) -> Result<impl IntoResponse> {
+    let Some(id) = params.get("id") else {
+        return Ok(Response::new(400, ()));
+    };
+    let url = format!("{}/commands/{}", COMMAND_ROOT_URL, id);
+    execute_query(&req, url.as_str()).await
+}
+
+#[tracing::instrument(name="drain_commands", skip_all)]
+async fn drain_commands(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let url = format!("{}/drain?{}", COMMAND_ROOT_URL, req.query());
+    execute_command(&req, url, None, None).await
+}
+
+/// Removes the employees and persons deleted longer ago than the retention
+/// period for good
+#[tracing::instrument(name="purge", skip_all)]
+async fn purge(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let url = format!("{}/purge", COMMAND_ROOT_URL);
+    execute_command(&req, url, None, None).await
+}
+
+/// Encrypts the personal data still stored in plain or under an older key
+/// with the current encryption key
+#[tracing::instrument(name="rotate_keys", skip_all)]
+async fn rotate_keys(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let url = format!("{}/rotate_keys", COMMAND_ROOT_URL);
+    execute_command(&req, url, None, None).await
 }
 
 #[tracing::instrument(name="update_employee_by_id", skip_all)]
@@ -79,7 +235,43 @@ async fn update_employee_by_id(req: Request, params: Params) -> Result<impl Into
     };
     let url = format!("{}/update_employee/{}", COMMAND_ROOT_URL, id);
     let ct = req.header("content-type");
-    execute_command(url, ct, Some(req.body().to_vec())).await
+    execute_command(&req, url, ct, Some(req.body().to_vec())).await
+}
+
+/// The command of `commands` an action on an entity runs: the last path
+/// segment names the action, so `/persons/:pid/undelete` runs
+/// `undelete_person/:pid`. Paths under `/admin` name the entity after it.
+fn entity_command(req: &Request, params: &Params) -> Option<String> {
+    let mut segments = req.path().split('/').skip(1).skip_while(|segment| *segment == "admin");
+    let entity = segments.next().unwrap_or_default();
+    let action = segments.last().unwrap_or_default();
+    let (singular, id) = match entity {
+        "employees" => ("employee", params.get("id")?),
+        "locations" => ("location", params.get("lid")?),
+        "persons" => ("person", params.get("pid")?),
+        _ => return None,
+    };
+    Some(format!("{}/{}_{}/{}", COMMAND_ROOT_URL, action, singular, id))
+}
+
+/// Restores an employee, location or person to a previous version,
+/// undeletes a soft-deleted employee or person, or erases its personal data
+#[tracing::instrument(name="entity_action", skip_all)]
+async fn entity_action(req: Request, params: Params) -> Result<impl IntoResponse> {
+    let Some(url) = entity_command(&req, &params) else {
+        return Ok(Response::new(400, ()));
+    };
+    let ct = req.header("content-type");
+    execute_command(&req, url, ct, Some(req.body().to_vec())).await
+}
+
+/// Everything stored about an employee or person, for a subject-access request
+#[tracing::instrument(name="export_personal_data", skip_all)]
+async fn export_personal_data(req: Request, params: Params) -> Result<impl IntoResponse> {
+    let Some(url) = entity_command(&req, &params) else {
+        return Ok(Response::new(400, ()));
+    };
+    execute_query(&req, url.as_str()).await
 }
 
 async fn update_location_by_id(req: Request, params: Params) -> Result<impl IntoResponse> {
@@ -88,7 +280,7 @@ async fn update_location_by_id(req: Request, params: Params) -> Result<impl Into
     };
     let url = format!("{}/update_location/{}", COMMAND_ROOT_URL, id);
     let ct = req.header("content-type");
-    execute_command(url, ct, Some(req.body().to_vec())).await
+    execute_command(&req, url, ct, Some(req.body().to_vec())).await
 }
 
 async fn update_person_by_id(req: Request, params: Params) -> Result<impl IntoResponse> {
@@ -97,99 +289,279 @@ async fn update_person_by_id(req: Request, params: Params) -> Result<impl IntoRe
     };
     let url = format!("{}/update_person/{}", COMMAND_ROOT_URL, id);
     let ct = req.header("content-type");
-    execute_command(url, ct, Some(req.body().to_vec())).await
+    execute_command(&req, url, ct, Some(req.body().to_vec())).await
 }
 
 #[tracing::instrument(name="delete_employee_by_id", skip_all)]
-async fn delete_employee_by_id(_req: Request, params: Params) -> Result<impl IntoResponse> {
+async fn delete_employee_by_id(req: Request, params: Params) -> Result<impl IntoResponse> {
     let Some(id) = params.get("id") else {
         return Ok(Response::new(400, ()));
     };
     let url = format!("{}/delete_employee/{}", COMMAND_ROOT_URL, id);
-    execute_command(url, None, None).await
+    execute_command(&req, url, None, None).await
+}
+
+async fn delete_location_by_id(req: Request, params: Params) -> Result<impl IntoResponse> {
+    let Some(id) = params.get("lid") else {
+        return Ok(Response::new(400, ()));
+    };
+    let url = format!("{}/delete_location/{}", COMMAND_ROOT_URL, id);
+    execute_command(&req, url, None, None).await
 }
 
-async fn delete_person_by_id(_req: Request, params: Params) -> Result<impl IntoResponse> {
+async fn delete_person_by_id(req: Request, params: Params) -> Result<impl IntoResponse> {
     let Some(id) = params.get("pid") else {
         return Ok(Response::new(400, ()));
     };
     let url = format!("{}/delete_person/{}", COMMAND_ROOT_URL, id);
-    execute_command(url, None, None).await
+    execute_command(&req, url, None, None).await
 }
 
 #[tracing::instrument(name="get_employee_by_id", skip_all)]
-async fn get_employee_by_id(_req: Request, params: Params) -> Result<impl IntoResponse> {
+async fn get_employee_by_id(req: Request, params: Params) -> Result<impl IntoResponse> {
     match params.get("id") {
         Some(id) => {
-            let url = format!("{}/employees/{}", QUERY_ROOT_URL, id);
-            execute_query(url.as_str()).await
+            let url = format!("{}/employees/{}?{}", QUERY_ROOT_URL, id, req.query());
+            execute_query(&req, url.as_str()).await
         }
         None => Ok(Response::new(200, ())),
     }
 }
 
 #[tracing::instrument(name="get_employees", skip_all)]
-async fn get_employees(_req: Request, _: Params) -> Result<impl IntoResponse> {
-    let url = format!("{}/employees", QUERY_ROOT_URL);
-    execute_query(url.as_str()).await
+async fn get_employees(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let url = format!("{}/employees?{}", QUERY_ROOT_URL, req.query());
+    execute_query(&req, url.as_str()).await
 }
 
 #[tracing::instrument(name="get_locations", skip_all)]
-async fn get_locations(_req: Request, _: Params) -> Result<impl IntoResponse> {
-    let url = format!("{}/locations", QUERY_ROOT_URL);
-    execute_query(url.as_str()).await
+async fn get_locations(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let url = format!("{}/locations?{}", QUERY_ROOT_URL, req.query());
+    execute_query(&req, url.as_str()).await
 }
 
 #[tracing::instrument(name="get_persons", skip_all)]
-async fn get_persons(_req: Request, _: Params) -> Result<impl IntoResponse> {
-    let url = format!("{}/persons", QUERY_ROOT_URL);
-    execute_query(url.as_str()).await
+async fn get_persons(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let url = format!("{}/persons?{}", QUERY_ROOT_URL, req.query());
+    execute_query(&req, url.as_str()).await
+}
+
+/// Every version of an entity, from the history `commands` keeps
+#[tracing::instrument(name="get_history", skip_all)]
+async fn get_history(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let url = format!("{}{}", QUERY_ROOT_URL, req.path());
+    execute_query(&req, url.as_str()).await
+}
+
+#[tracing::instrument(name="get_audit", skip_all)]
+async fn get_audit(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let url = format!("{}/audit?{}", QUERY_ROOT_URL, req.query());
+    execute_query(&req, url.as_str()).await
 }
 
 #[tracing::instrument(name="get_location_by_id", skip_all)]
-async fn get_location_by_id(_req: Request, params: Params) -> Result<impl IntoResponse> {
+async fn get_location_by_id(req: Request, params: Params) -> Result<impl IntoResponse> {
     match params.get("lid") {
         Some(lid) => {
-            let url = format!("{}/locations/{}", QUERY_ROOT_URL, lid);
-            execute_query(url.as_str()).await
+            let url = format!("{}/locations/{}?{}", QUERY_ROOT_URL, lid, req.query());
+            execute_query(&req, url.as_str()).await
         }
         None => Ok(Response::new(200, ())),
     }
 }
 
+#[tracing::instrument(name="get_persons_by_location", skip_all)]
+async fn get_persons_by_location(req: Request, params: Params) -> Result<impl IntoResponse> {
+    let Some(lid) = params.get("lid") else {
+        return Ok(Response::new(400, ()));
+    };
+    let url = format!("{}/locations/{}/persons?{}", QUERY_ROOT_URL, lid, req.query());
+    execute_query(&req, url.as_str()).await
+}
+
 #[tracing::instrument(name="get_person_by_id", skip_all)]
-async fn get_person_by_id(_req: Request, params: Params) -> Result<impl IntoResponse> {
+async fn get_person_by_id(req: Request, params: Params) -> Result<impl IntoResponse> {
     match params.get("pid") {
         Some(pid) => {
-            let url = format!("{}/persons/{}", QUERY_ROOT_URL, pid);
-            execute_query(url.as_str()).await
+            let url = format!("{}/persons/{}?{}", QUERY_ROOT_URL, pid, req.query());
+            execute_query(&req, url.as_str()).await
         }
         None => Ok(Response::new(200, ())),
     }
 }
 
-#[tracing::instrument(name="handle_gateway", skip_all)]
 #[http_component]
-fn handle_gateway(req: Request) -> anyhow::Result<impl IntoResponse> {
-    let mut router = Router::default();
+async fn handle_gateway(mut req: Request, response_out: ResponseOutparam) {
+    let ctx = TraceContext::from_request(&req);
+    ctx.apply(&mut req);
+    let telemetry = Telemetry::new("gateway", ctx.trace.clone());
+
+    let (req, version) = ApiVersion::negotiate(req);
+    let routes = routes();
+    let route = routes.route(req.path()).unwrap_or("unmatched");
+    let method = req.method().to_string();
+    let started = Instant::now();
+    let res = telemetry.in_scope(|| gateway(&routes, &ctx, version.as_ref(), route, req));
+
+    let status = res.as_ref().map(|r| *r.status()).unwrap_or(500);
+    let res = res.into_response();
+    let sent = match exports::take() {
+        Some(export) => {
+            let translate = |lines: &[u8]| version.as_ref()?.translate_lines(route, lines);
+            exports::forward(res, export, response_out, translate).await
+        }
+        None => exports::respond(res, response_out).await,
+    };
+    if let Err(e) = sent {
+        telemetry.in_scope(|| tracing::warn!(request_id = %ctx.request_id, "response not sent: {}", e));
+    }
+    telemetry.record_request(route, &method, status, started.elapsed());
+    telemetry.flush();
+}
+
+#[tracing::instrument(name="handle_gateway", skip_all,
+                      fields(otel.kind = "server", request_id = %ctx.request_id, http.route = route,
+                             http.response.status_code))]
+fn gateway(routes: &Routes, ctx: &TraceContext, version: Option<&ApiVersion>, route: &str, req: Request)
+           -> Result<Response> {
+    let method = req.method().to_string();
+    let started = Instant::now();
+    let res = respond(routes, ctx, version, route, req);
+    let status = res.as_ref().map(|r| *r.status()).unwrap_or(500);
+    tracing::Span::current().record("http.response.status_code", status);
+    metrics::observe_request(route, &method, status, started.elapsed());
+    res
+}
+
+fn respond(routes: &Routes, ctx: &TraceContext, version: Option<&ApiVersion>, route: &str, req: Request)
+           -> Result<Response> {
+    let cors = Cors::from_config();
+    let origin = req.header("origin").and_then(|v| v.as_str()).map(String::from);
+    let allowed = routes.allowed_methods(req.path());
+
+    let mut res = match (req.method(), version) {
+        (Method::Options, _) => cors.preflight(&req, &allowed),
+        (_, None) => Response::new(406, "Unsupported API version"),
+        _ => dispatch(routes, req)?,
+    };
+    if *res.status() == 405 {
+        res.set_header("Allow", join_methods(&allowed));
+    }
+    if let Some(version) = version {
+        version.apply(route, &mut res);
+    }
+    cors.apply(origin.as_deref(), &mut res);
+    ctx.decorate(&mut res);
+    Ok(res)
+}
+
+fn routes() -> Routes {
+    let mut router = Routes::default();
 
     router.get_async("/employees",        get_employees);
     router.get_async("/employees/:id",    get_employee_by_id);
     router.get_async("/locations",        get_locations);
     router.get_async("/locations/:lid",   get_location_by_id);
+    router.get_async("/locations/:lid/persons", get_persons_by_location);
     router.get_async("/persons",          get_persons);
     router.get_async("/persons/:pid",     get_person_by_id);
+    router.get_async("/employees/:id/history",  get_history);
+    router.get_async("/locations/:lid/history", get_history);
+    router.get_async("/persons/:pid/history",   get_history);
  
     router.post_async("/employees",       create_employee);
     router.put_async("/employees/:id",    update_employee_by_id);
     router.delete_async("/employees/:id", delete_employee_by_id);
+    router.post_async("/employees/:id/restore", entity_action);
+    router.post_async("/employees/:id/undelete", entity_action);
 
     router.post_async("/locations",       create_location);
+    router.post_async("/locations/import", import_locations);
     router.put_async("/locations/:lid",   update_location_by_id);
+    router.delete_async("/locations/:lid", delete_location_by_id);
+    router.post_async("/locations/:lid/restore", entity_action);
 
     router.post_async("/persons",         create_person);
+    router.post_async("/persons/import",  import_persons);
     router.put_async("/persons/:pid",     update_person_by_id);
     router.delete_async("/persons/:pid",  delete_person_by_id);
+    router.post_async("/persons/:pid/restore", entity_action);
+    router.post_async("/persons/:pid/undelete", entity_action);
+
+    router.post_async("/batch",           batch);
+    router.get_async("/commands/:id",     get_command);
+    router.get_async("/audit",            get_audit);
+
+    router.get_async("/metrics",          metrics::get_metrics);
+    router.get("/healthz",                health::healthz);
+    router.get_async("/readyz",           health::readyz);
+    router.get_async("/diagnostics",      health::diagnostics);
+    router.get_async("/openapi.json",     openapi::get_openapi);
+    router.get("/docs",                   openapi::get_docs);
 
-    Ok(router.handle(req))
-}
\ No newline at end of file
+    router.post("/admin/api-keys",        api_keys::create_api_key);
+    router.get("/admin/api-keys",         api_keys::list_api_keys);
+    router.delete("/admin/api-keys/:kid", api_keys::revoke_api_key);
+    router.post_async("/admin/commands/drain", drain_commands);
+    router.post_async("/admin/purge",     purge);
+    router.get_async("/admin/employees/:id/export", export_personal_data);
+    router.post_async("/admin/employees/:id/erase", entity_action);
+    router.get_async("/admin/persons/:pid/export", export_personal_data);
+    router.post_async("/admin/persons/:pid/erase", entity_action);
+    router.post_async("/admin/encryption/rotate", rotate_keys);
+
+    router
+}
+
+/// Authenticates and rate limits a request before routing it. Health
+/// probes and the API documentation skip both.
+fn dispatch(routes: &Routes, mut req: Request) -> Result<Response> {
+    if health::PROBES.contains(&req.path()) || openapi::PAGES.contains(&req.path()) {
+        return Ok(routes.handle(req));
+    }
+    let (key, quota) = match api_keys::authorize(&req)? {
+        Access::Granted { key, limit } => (key, limit),
+        Access::Denied(res) => return Ok(res),
+    };
+    let bucket = match rate_limit::throttle(&req, key.as_ref())? {
+        Throttle::Allowed(limit) => limit,
+        Throttle::Limited(res) => return Ok(res),
+    };
+
+    api_keys::identify(&mut req, key.as_ref());
+    let mut res = routes.handle(req);
+    if let Some(limit) = RateLimit::most_restrictive(quota, bucket) {
+        limit.set_headers(&mut res);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn component_error(status: u16, retry_after: Option<&str>) -> Response {
+        let mut builder = ResponseBuilder::new(status);
+        builder.header("Content-Type", "application/json");
+        if let Some(retry_after) = retry_after {
+            builder.header("Retry-After", retry_after);
+        }
+        builder.body(r#"{"status":503,"error":"behind","requestId":"r-1"}"#).build()
+    }
+
+    #[test]
+    fn unreached_consistency_tokens_are_passed_through() {
+        let res = parse_result(component_error(503, Some("1"))).unwrap();
+        assert_eq!(*res.status(), 503);
+        assert_eq!(res.header("retry-after").and_then(|v| v.as_str()), Some("1"));
+        assert_eq!(res.header("content-type").and_then(|v| v.as_str()), Some("application/json"));
+        assert_eq!(res.body(), br#"{"status":503,"error":"behind","requestId":"r-1"}"#);
+    }
+
+    #[test]
+    fn other_component_failures_are_hidden() {
+        let res = parse_result(component_error(503, None)).unwrap();
+        assert_eq!(*res.status(), 500);
+        assert_eq!(res.body(), b"Internal Server Error");
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use spin_sdk::http::{send, IntoResponse, Params, Request, RequestBuilder, Response, ResponseBuilder};
+use spin_sdk::key_value::Store;
+
+use crate::{trace_context, QUERY_ROOT_URL};
+
+const COUNTER_PREFIX: &str = "metrics-c:";
+const HISTOGRAM_PREFIX: &str = "metrics-h:";
+
+/// Latency histogram bucket bounds, in seconds
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+const HELP: [(&str, &str, &str); 5] = [
+    ("gateway_http_requests_total", "counter", "Requests handled by the gateway"),
+    ("gateway_http_request_duration_seconds", "histogram", "Gateway request latency"),
+    ("gateway_component_call_duration_seconds", "histogram", "Latency of calls to the commands and queries components"),
+    ("gateway_command_outcomes_total", "counter", "Outcomes of commands by command type"),
+    ("cqrs_entities", "gauge", "Rows per entity in the database"),
+];
+
+/// A histogram as persisted in the key-value store
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+/// Counts a request handled by the gateway.
+pub(crate) fn observe_request(route: &str, method: &str, status: u16, elapsed: Duration) {
+    let labels = labels(&[("route", route), ("method", method), ("status", &status.to_string())]);
+    record(|store| {
+        increment(store, "gateway_http_requests_total", &labels)?;
+        observe(store, "gateway_http_request_duration_seconds", &labels, elapsed)
+    });
+}
+
+/// Records a call to one of the components. Commands additionally count
+/// their outcome.
+pub(crate) fn observe_call(component: &str, url: &str, status: u16, elapsed: Duration) {
+    let operation = operation(url);
+    let call_labels = labels(&[("component", component), ("operation", operation), ("status", &status.to_string())]);
+    let outcome = match status {
+        200..=299 => "success",
+        400..=499 => "rejected",
+        _ => "failed",
+    };
+    record(|store| {
+        observe(store, "gateway_component_call_duration_seconds", &call_labels, elapsed)?;
+        if component == "commands" {
+            let labels = labels(&[("command", operation), ("outcome", outcome)]);
+            increment(store, "gateway_command_outcomes_total", &labels)?;
+        }
+        Ok(())
+    });
+}
+
+/// Metric updates are read-modify-write cycles on the key-value store, so
+/// concurrent instances may occasionally lose an increment. Failures are
+/// logged and never fail the request.
+fn record(f: impl FnOnce(&Store) -> Result<()>) {
+    if let Err(e) = Store::open_default().map_err(anyhow::Error::from).and_then(|store| f(&store)) {
+        tracing::warn!("metrics: {}", e);
+    }
+}
+
+fn increment(store: &Store, name: &str, labels: &str) -> Result<()> {
+    let key = format!("{COUNTER_PREFIX}{name}{labels}");
+    let value = store
+        .get(&key)?
+        .and_then(|v| String::from_utf8(v).ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    store.set(&key, (value + 1).to_string().as_bytes())?;
+    Ok(())
+}
+
+fn observe(store: &Store, name: &str, labels: &str, elapsed: Duration) -> Result<()> {
+    let key = format!("{HISTOGRAM_PREFIX}{name}{labels}");
+    let mut histogram = store.get_json::<Histogram>(&key)?.unwrap_or_default();
+    histogram.buckets.resize(BUCKETS.len(), 0);
+    let seconds = elapsed.as_secs_f64();
+    for (bucket, bound) in histogram.buckets.iter_mut().zip(BUCKETS) {
+        if seconds <= bound {
+            *bucket += 1;
+        }
+    }
+    histogram.count += 1;
+    histogram.sum += seconds;
+    store.set_json(&key, &histogram)
+}
+
+/// Formats a label set as `{name="value",...}`
+fn labels(labels: &[(&str, &str)]) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// The operation a component URL invokes, its first path segment
+fn operation(url: &str) -> &str {
+    let path = url.split("://").nth(1).and_then(|rest| rest.split_once('/')).map(|(_, p)| p);
+    path.and_then(|p| p.split(['/', '?']).next())
+        .filter(|p| !p.is_empty())
+        .unwrap_or("unknown")
+}
+
+/// Serves all metrics in the Prometheus text format
+#[tracing::instrument(name = "metrics", skip_all)]
+pub(crate) async fn get_metrics(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let store = Store::open_default()?;
+    let mut series: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    let mut keys = store.get_keys()?;
+    keys.sort();
+    for key in keys {
+        if let Some(name_labels) = key.strip_prefix(COUNTER_PREFIX) {
+            let Some(value) = store.get(&key)? else { continue };
+            let (name, _) = split_series(name_labels);
+            let Some(name) = HELP.iter().map(|h| h.0).find(|h| *h == name) else { continue };
+            series
+                .entry(name)
+                .or_default()
+                .push(format!("{} {}", name_labels, String::from_utf8_lossy(&value)));
+        } else if let Some(name_labels) = key.strip_prefix(HISTOGRAM_PREFIX) {
+            let Some(histogram) = store.get_json::<Histogram>(&key)? else { continue };
+            let (name, labels) = split_series(name_labels);
+            let Some(name) = HELP.iter().map(|h| h.0).find(|h| *h == name) else { continue };
+            let lines = series.entry(name).or_default();
+            let inner = labels.trim_start_matches('{').trim_end_matches('}');
+            for (bound, count) in BUCKETS.iter().zip(&histogram.buckets) {
+                lines.push(format!("{name}_bucket{{{inner},le=\"{bound}\"}} {count}"));
+            }
+            lines.push(format!("{name}_bucket{{{inner},le=\"+Inf\"}} {}", histogram.count));
+            lines.push(format!("{name}_sum{labels} {}", histogram.sum));
+            lines.push(format!("{name}_count{labels} {}", histogram.count));
+        }
+    }
+
+    match entity_counts(&req).await {
+        Ok(counts) => {
+            let lines = series.entry("cqrs_entities").or_default();
+            for (entity, count) in counts {
+                lines.push(format!("cqrs_entities{} {}", labels(&[("entity", &entity)]), count));
+            }
+        }
+        Err(e) => tracing::warn!("metrics: entity counts unavailable: {}", e),
+    }
+
+    let mut body = String::new();
+    for (name, kind, help) in HELP {
+        let Some(lines) = series.get(name) else { continue };
+        body.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+        for line in lines {
+            body.push_str(line);
+            body.push('\n');
+        }
+    }
+    Ok(ResponseBuilder::new(200)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(body)
+        .build())
+}
+
+fn split_series(series: &str) -> (&str, &str) {
+    match series.find('{') {
+        Some(i) => series.split_at(i),
+        None => (series, ""),
+    }
+}
+
+/// Row counts per entity, from the queries component
+async fn entity_counts(incoming: &Request) -> Result<BTreeMap<String, u64>> {
+    let mut builder = RequestBuilder::new(spin_sdk::http::Method::Get, format!("{}/counts", QUERY_ROOT_URL));
+    builder.header("Accept", "application/json");
+    trace_context::forward(incoming, &mut builder);
+    let res: Response = send(builder.build()).await?;
+    if *res.status() != 200 {
+        anyhow::bail!("queries answered {}", res.status());
+    }
+    Ok(serde_json::from_slice(res.body())?)
+}
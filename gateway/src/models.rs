@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// API Model for creating a new API key
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateApiKeyModel {
+    /// human readable name of the client owning the key
+    pub name: String,
+    /// scopes granted to the key (read, write, admin)
+    pub scopes: Vec<String>,
+    /// request quota, defaults to the configured quota when absent
+    pub quota: Option<QuotaModel>,
+}
+
+/// API Model for a request quota
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct QuotaModel {
+    /// number of requests allowed per window
+    pub requests: u64,
+    /// window length in seconds
+    #[serde(rename = "windowSecs")]
+    pub window_secs: u64,
+}
+
+/// Response Model for a newly created API key, the only time the key is returned
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiKeyCreatedModel {
+    /// key identifier
+    pub kid: String,
+    /// name
+    pub name: String,
+    /// the API key itself
+    pub key: String,
+    /// scopes
+    pub scopes: Vec<String>,
+    /// quota
+    pub quota: QuotaModel,
+    /// creation time in seconds since the epoch
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+}
+
+/// Response Model for listing API keys
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiKeyModel {
+    /// key identifier
+    pub kid: String,
+    /// name
+    pub name: String,
+    /// scopes
+    pub scopes: Vec<String>,
+    /// quota
+    pub quota: QuotaModel,
+    /// creation time in seconds since the epoch
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+    /// revocation time in seconds since the epoch
+    #[serde(rename = "revokedAt")]
+    pub revoked_at: Option<u64>,
+}
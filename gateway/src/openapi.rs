@@ -0,0 +1,451 @@
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+use spin_sdk::http::{send, IntoResponse, Method, Params, Request, RequestBuilder, Response, ResponseBuilder};
+use utoipa::OpenApi;
+
+use crate::models::{ApiKeyCreatedModel, ApiKeyModel, CreateApiKeyModel, QuotaModel};
+use crate::trace_context::{self, ErrorModel};
+use crate::{health, routes, COMMAND_ROOT_URL, QUERY_ROOT_URL};
+
+/// Documentation pages, served without API key or rate limit checks
+pub(crate) const PAGES: [&str; 2] = ["/openapi.json", "/docs"];
+
+const DOCS_PAGE: &str = include_str!("docs.html");
+
+/// Models owned by the gateway itself
+#[derive(OpenApi)]
+#[openapi(components(schemas(ErrorModel, CreateApiKeyModel, QuotaModel, ApiKeyCreatedModel, ApiKeyModel)))]
+struct GatewayApi;
+
+/// Body of a documented response
+enum Body {
+    Empty,
+    /// a model, by schema name
+    Model(&'static str),
+    /// an array of a model
+    List(&'static str),
+    /// an array of a model, also exported as CSV and NDJSON
+    Rows(&'static str),
+    /// a free-form JSON object
+    Object,
+    /// a non-JSON body of the given content type
+    Text(&'static str),
+}
+
+/// Documentation of one route
+struct Operation {
+    method: Method,
+    path: &'static str,
+    summary: &'static str,
+    request: Body,
+    status: u16,
+    response: Body,
+}
+
+fn op(method: Method, path: &'static str, summary: &'static str, request: Body,
+            status: u16, response: Body) -> Operation {
+    Operation { method, path, summary, request, status, response }
+}
+
+/// Every route of the gateway, as registered in `routes()`. The request and
+/// response schemas are derived from the models of the components.
+fn operations() -> Vec<Operation> {
+    use Body::*;
+    use Method::*;
+    vec![
+        op(Get,    "/employees",            "List employees",             Empty, 200, Rows("EmployeeListModel")),
+        op(Get,    "/employees/:id",        "Get an employee",            Empty, 200, List("EmployeeDetailsModel")),
+        op(Get,    "/locations",            "List locations",             Empty, 200, Rows("LocationDetailsModel")),
+        op(Get,    "/locations/:lid",       "Get a location",             Empty, 200, List("LocationDetailsModel")),
+        op(Get,    "/locations/:lid/persons", "List the persons at a location", Empty, 200, Rows("PersonListModel")),
+        op(Get,    "/persons",              "List persons",               Empty, 200, Rows("PersonListModel")),
+        op(Get,    "/persons/:pid",         "Get a person",               Empty, 200, List("PersonDetailsModel")),
+
+        op(Post,   "/employees",            "Create an employee",         Model("CreateEmployeeModel"), 201, Model("EmployeeCreatedModel")),
+        op(Put,    "/employees/:id",        "Update an employee",         Model("UpdateEmployeeModel"), 200, Model("EmployeeUpdatedModel")),
+        op(Delete, "/employees/:id",        "Delete an employee",         Empty, 204, Empty),
+        op(Post,   "/employees/:id/restore", "Restore an employee to a version", Model("RestoreModel"), 200, Model("RestoredModel")),
+        op(Post,   "/employees/:id/undelete", "Undelete an employee",     Empty, 200, Object),
+
+        op(Post,   "/locations",            "Create a location",          Model("CreateLocationModel"), 201, Model("LocationCreatedModel")),
+        op(Post,   "/locations/import",     "Import locations",           Rows("CreateLocationModel"), 201, Model("ImportReportModel")),
+        op(Put,    "/locations/:lid",       "Update a location",          Model("UpdateLocationModel"), 200, Model("LocationUpdatedModel")),
+        op(Delete, "/locations/:lid",       "Delete an unused location",  Empty, 204, Empty),
+        op(Post,   "/locations/:lid/restore", "Restore a location to a version", Model("RestoreModel"), 200, Model("RestoredModel")),
+
+        op(Post,   "/persons",              "Create a person",            Model("CreatePersonModel"), 201, Model("PersonCreatedModel")),
+        op(Post,   "/persons/import",       "Import persons",             Rows("ImportPersonModel"), 201, Model("ImportReportModel")),
+        op(Put,    "/persons/:pid",         "Update a person",            Model("UpdatePersonModel"), 200, Model("PersonUpdatedModel")),
+        op(Delete, "/persons/:pid",         "Delete a person",            Empty, 204, Empty),
+        op(Post,   "/persons/:pid/restore", "Restore a person to a version", Model("RestoreModel"), 200, Model("RestoredModel")),
+        op(Post,   "/persons/:pid/undelete", "Undelete a person",         Empty, 200, Object),
+
+        op(Post,   "/batch",                "Run commands in one transaction", Model("BatchModel"), 200, Model("BatchResultModel")),
+        op(Get,    "/commands/:id",         "Get an asynchronous command", Empty, 200, Model("CommandStatusModel")),
+
+        op(Get,    "/employees/:id/history", "Every version of an employee", Empty, 200, List("EntityVersionModel")),
+        op(Get,    "/locations/:lid/history", "Every version of a location", Empty, 200, List("EntityVersionModel")),
+        op(Get,    "/persons/:pid/history", "Every version of a person",  Empty, 200, List("EntityVersionModel")),
+        op(Get,    "/audit",                "Search the audit log",       Empty, 200, List("AuditRecordModel")),
+        op(Get,    "/metrics",              "Prometheus metrics",         Empty, 200, Text("text/plain")),
+        op(Get,    "/healthz",              "Liveness probe",             Empty, 200, Object),
+        op(Get,    "/readyz",               "Readiness probe",            Empty, 200, Object),
+        op(Get,    "/diagnostics",          "Diagnostics of all components", Empty, 200, Object),
+        op(Get,    "/openapi.json",         "This document",              Empty, 200, Object),
+        op(Get,    "/docs",                 "API documentation page",     Empty, 200, Text("text/html")),
+
+        op(Post,   "/admin/api-keys",       "Create an API key",          Model("CreateApiKeyModel"), 201, Model("ApiKeyCreatedModel")),
+        op(Get,    "/admin/api-keys",       "List API keys",              Empty, 200, List("ApiKeyModel")),
+        op(Delete, "/admin/api-keys/:kid",  "Revoke an API key",          Empty, 204, Empty),
+        op(Post,   "/admin/commands/drain", "Run queued commands",        Empty, 200, Model("DrainModel")),
+        op(Post,   "/admin/purge",          "Purge entities deleted before the retention period", Empty, 200, Model("PurgeModel")),
+        op(Get,    "/admin/employees/:id/export", "Export the personal data of an employee", Empty, 200, Model("PersonalDataModel")),
+        op(Post,   "/admin/employees/:id/erase", "Erase the personal data of an employee", Empty, 200, Model("ErasureModel")),
+        op(Get,    "/admin/persons/:pid/export", "Export the personal data of a person", Empty, 200, Model("PersonalDataModel")),
+        op(Post,   "/admin/persons/:pid/erase", "Erase the personal data of a person", Empty, 200, Model("ErasureModel")),
+        op(Post,   "/admin/encryption/rotate", "Encrypt personal data with the current key", Empty, 200, Model("RotationModel")),
+    ]
+}
+
+/// Serves the OpenAPI document, merging the schemas of the gateway,
+/// `commands` and `queries`
+#[tracing::instrument(name = "openapi", skip_all)]
+pub(crate) async fn get_openapi(req: Request, _: Params) -> Result<impl IntoResponse> {
+    let mut schemas = schemas(serde_json::to_value(GatewayApi::openapi())?);
+    for root in [COMMAND_ROOT_URL, QUERY_ROOT_URL] {
+        match component_schemas(&req, root).await {
+            Ok(component) => schemas.extend(component),
+            Err(e) => tracing::warn!("openapi: {}", e),
+        }
+    }
+    Ok(ResponseBuilder::new(200)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&document(&routes(), schemas))?)
+        .build())
+}
+
+/// Serves the documentation page rendering `/openapi.json`
+pub(crate) fn get_docs(_req: Request, _: Params) -> Result<impl IntoResponse> {
+    Ok(ResponseBuilder::new(200)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(DOCS_PAGE)
+        .build())
+}
+
+/// Schemas of a component, from its private `/openapi` endpoint
+async fn component_schemas(incoming: &Request, root: &str) -> Result<Map<String, Value>> {
+    let mut builder = RequestBuilder::new(Method::Get, format!("{}/openapi", root));
+    builder.header("Accept", "application/json");
+    trace_context::forward(incoming, &mut builder);
+    let res: Response = send(builder.build()).await?;
+    if *res.status() != 200 {
+        anyhow::bail!("{} answered {}", root, res.status());
+    }
+    Ok(schemas(serde_json::from_slice(res.body())?))
+}
+
+fn schemas(mut document: Value) -> Map<String, Value> {
+    match document["components"]["schemas"].take() {
+        Value::Object(schemas) => schemas,
+        _ => Map::new(),
+    }
+}
+
+/// Builds the OpenAPI document for the routes registered in `routes`
+fn document(routes: &crate::routes::Routes, schemas: Map<String, Value>) -> Value {
+    let operations = operations();
+    let mut paths = Map::new();
+    for (method, pattern) in routes.entries() {
+        let Some(operation) = operations.iter().find(|o| o.method == *method && o.path == pattern) else {
+            continue;
+        };
+        let path = paths.entry(openapi_path(pattern)).or_insert_with(|| json!({}));
+        path[method.to_string().to_lowercase()] = operation_object(operation);
+    }
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "cqrs-with-components",
+            "description": "Gateway of the employees, locations and persons CQRS application. \
+                            Paths are also served under /v1 and /v2, or the version is chosen with \
+                            Accept: application/vnd.cqrs.v2+json. Schemas show the internal models; \
+                            v1 list items carry name only, v2 list items firstName and lastName only.",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "apiKey": { "type": "apiKey", "in": "header", "name": "X-Api-Key" },
+            },
+        },
+        "security": [{ "apiKey": [] }],
+    })
+}
+
+/// Whether the route forwards to a command, which `Prefer: respond-async`
+/// queues instead of running
+fn asynchronous(operation: &Operation) -> bool {
+    operation.method != Method::Get && !operation.path.starts_with("/admin")
+}
+
+fn operation_object(operation: &Operation) -> Value {
+    let reference = |name: &str| json!({ "$ref": format!("#/components/schemas/{}", name) });
+    let error = |description: &str| {
+        json!({
+            "description": description,
+            "content": { "application/json": { "schema": reference("ErrorModel") } },
+        })
+    };
+
+    let mut responses = Map::new();
+    let description = match operation.status {
+        201 => "Created",
+        204 => "No Content",
+        _ => "OK",
+    };
+    let content = match operation.response {
+        Body::Empty => None,
+        Body::Model(name) => Some(("application/json", reference(name))),
+        Body::List(name) | Body::Rows(name) => {
+            Some(("application/json", json!({ "type": "array", "items": reference(name) })))
+        }
+        Body::Object => Some(("application/json", json!({ "type": "object" }))),
+        Body::Text(content_type) => Some((content_type, json!({ "type": "string" }))),
+    };
+    let mut success = json!({ "description": description });
+    if let Some((content_type, schema)) = content {
+        success["content"] = json!({ content_type: { "schema": schema } });
+    }
+    if let Body::Rows(name) = operation.response {
+        let description = |format: &str| format!("{} of {}, one row per line", format, name);
+        success["content"]["application/x-ndjson"] =
+            json!({ "schema": { "type": "string", "description": description("JSON objects") } });
+        success["content"]["text/csv"] =
+            json!({ "schema": { "type": "string", "description": description("header and rows") } });
+    }
+    responses.insert(operation.status.to_string(), success);
+
+    let mut parameters: Vec<Value> = operation
+        .path
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix(':'))
+        .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }))
+        .collect();
+    let identified = !parameters.is_empty();
+    let filtered = operation.method == Method::Get && matches!(operation.response, Body::Rows(_));
+    if filtered {
+        parameters.push(json!({
+            "name": "updatedSince",
+            "in": "query",
+            "description": "only rows updated at or after this UTC date or time, e.g. 2026-10-01T08:30:00Z",
+            "schema": { "type": "string" },
+        }));
+    }
+    let history = operation.path.ends_with("/history");
+    let audited = operation.path == "/audit";
+    let time_travel = operation.method == Method::Get
+        && matches!(operation.response, Body::Rows(_) | Body::List(_))
+        && !operation.path.starts_with("/admin") && !history && !audited;
+    if time_travel {
+        parameters.push(json!({
+            "name": "asOf",
+            "in": "query",
+            "description": "state at this UTC date or time, e.g. 2026-10-01T08:30:00Z; a date stands for its start",
+            "schema": { "type": "string" },
+        }));
+    }
+    let soft_deleted = time_travel
+        && (operation.path.starts_with("/employees") || operation.path.contains("/persons"));
+    if soft_deleted {
+        parameters.push(json!({
+            "name": "includeDeleted",
+            "in": "query",
+            "description": "true to include soft-deleted entities; requires the admin scope",
+            "schema": { "type": "boolean", "default": false },
+        }));
+    }
+    if audited {
+        let query = |name: &str, description: &str, schema: Value| {
+            json!({ "name": name, "in": "query", "description": description, "schema": schema })
+        };
+        let text = json!({ "type": "string" });
+        parameters.extend([
+            query("entity", "employees, locations or persons", text.clone()),
+            query("id", "id of the targeted entity", text.clone()),
+            query("actor", "API key id of the caller, system for queued commands", text.clone()),
+            query("from", "records at or after this UTC date or time", text.clone()),
+            query("to", "records before this UTC date or time", text),
+            query("limit", "records per page", json!({ "type": "integer", "default": 50, "maximum": 200 })),
+            query("after", "seq of the last record of the previous page", json!({ "type": "integer" })),
+        ]);
+    }
+    if matches!(operation.request, Body::Rows(_)) {
+        responses.insert("413".to_string(), error("Too many rows"));
+        responses.insert("422".to_string(), json!({
+            "description": "Rows rejected, nothing created",
+            "content": { "application/json": { "schema": reference("ImportReportModel") } },
+        }));
+    }
+    if operation.path == "/batch" {
+        responses.insert("422".to_string(), json!({
+            "description": "An operation failed, everything was rolled back",
+            "content": { "application/json": { "schema": reference("BatchResultModel") } },
+        }));
+    }
+    let cached = operation.method == Method::Get && matches!(operation.response, Body::Rows(_) | Body::List(_))
+        && !operation.path.starts_with("/admin");
+    if cached {
+        responses.insert("304".to_string(), json!({ "description": "Not modified since the ETag in If-None-Match or the date in If-Modified-Since" }));
+        responses.insert("400".to_string(), error("Malformed Consistency-Token"));
+        responses.insert("503".to_string(), error("Read models behind the Consistency-Token, retry after Retry-After"));
+    }
+    if asynchronous(operation) {
+        responses.insert("202".to_string(), json!({
+            "description": "Accepted for asynchronous execution with Prefer: respond-async; \
+                            poll the command in Location",
+            "content": { "application/json": { "schema": reference("CommandStatusModel") } },
+        }));
+    }
+    let conflicts = matches!((&operation.method, operation.path),
+        (Method::Delete, "/locations/:lid") | (Method::Post, "/persons") | (Method::Put, "/persons/:pid"));
+    if conflicts {
+        responses.insert("409".to_string(), error("Location in use or missing"));
+    }
+    if operation.path.ends_with("/restore") {
+        responses.insert("409".to_string(), error("Entity or version deleted, or location missing"));
+        responses.insert("422".to_string(), error("Restored state fails validation"));
+    }
+    if operation.path.ends_with("/erase") {
+        responses.insert("409".to_string(), error("A queued command targeting the entity has not run yet"));
+    }
+    if operation.path == "/admin/encryption/rotate" {
+        responses.insert("409".to_string(), error("No encryption keys configured"));
+    }
+    if filtered || audited || time_travel || !matches!(operation.request, Body::Empty) {
+        responses.insert("400".to_string(), error("Bad Request"));
+    }
+    if identified {
+        responses.insert("404".to_string(), error("Not Found"));
+    }
+    let public = health::PROBES.contains(&operation.path) || PAGES.contains(&operation.path);
+    if !public {
+        responses.insert("401".to_string(), error("Missing or invalid API key"));
+        responses.insert("403".to_string(), error("Scope not granted"));
+        responses.insert("429".to_string(), error("Rate limit or quota exceeded"));
+    }
+
+    let tag = operation.path.split('/').find(|s| !s.is_empty()).unwrap_or("gateway");
+    let mut object = json!({
+        "operationId": operation_id(operation),
+        "summary": operation.summary,
+        "tags": [tag],
+        "responses": responses,
+    });
+    if !parameters.is_empty() {
+        object["parameters"] = Value::Array(parameters);
+    }
+    match operation.request {
+        Body::Model(name) => {
+            object["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": reference(name) } },
+            });
+        }
+        Body::Rows(name) => {
+            object["requestBody"] = json!({
+                "required": true,
+                "content": {
+                    "application/json": { "schema": { "type": "array", "items": reference(name) } },
+                    "text/csv": { "schema": { "type": "string", "description": format!("header and rows of {}", name) } },
+                },
+            });
+            object["parameters"] = json!([{
+                "name": "mode",
+                "in": "query",
+                "schema": { "type": "string", "enum": ["all-or-nothing", "best-effort"], "default": "all-or-nothing" },
+            }]);
+        }
+        _ => {}
+    }
+    if public {
+        object["security"] = json!([]);
+    }
+    object
+}
+
+/// `get_employees_id` for `GET /employees/:id`
+fn operation_id(operation: &Operation) -> String {
+    let mut id = operation.method.to_string().to_lowercase();
+    for segment in operation.path.split('/').filter(|s| !s.is_empty()) {
+        id.push('_');
+        id.push_str(&segment.trim_start_matches(':').replace(['-', '.'], "_"));
+    }
+    id
+}
+
+/// Converts `/employees/:id` to `/employees/{id}`
+fn openapi_path(pattern: &str) -> String {
+    pattern
+        .split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(name) => format!("{{{}}}", name),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    fn route_table() -> BTreeSet<(String, String)> {
+        routes()
+            .entries()
+            .map(|(method, pattern)| (method.to_string(), pattern.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn every_route_is_documented_and_every_operation_routed() {
+        let documented: BTreeSet<(String, String)> = operations()
+            .iter()
+            .map(|o| (o.method.to_string(), o.path.to_string()))
+            .collect();
+        let routes = route_table();
+
+        let undocumented: Vec<_> = routes.difference(&documented).collect();
+        let unrouted: Vec<_> = documented.difference(&routes).collect();
+        assert!(undocumented.is_empty(), "routes missing from the OpenAPI document: {undocumented:?}");
+        assert!(unrouted.is_empty(), "documented operations without a route: {unrouted:?}");
+    }
+
+    #[test]
+    fn document_paths_match_the_route_table() {
+        let document = document(&routes(), Map::new());
+        let mut in_document = BTreeSet::new();
+        for (path, item) in document["paths"].as_object().unwrap() {
+            for method in item.as_object().unwrap().keys() {
+                in_document.insert((method.to_uppercase(), path.clone()));
+            }
+        }
+        let routes: BTreeSet<(String, String)> = route_table()
+            .into_iter()
+            .map(|(method, pattern)| (method, openapi_path(&pattern)))
+            .collect();
+        assert_eq!(in_document, routes);
+    }
+
+    #[test]
+    fn gateway_schemas_are_derived() {
+        let schemas = schemas(serde_json::to_value(GatewayApi::openapi()).unwrap());
+        for name in ["ErrorModel", "CreateApiKeyModel", "QuotaModel", "ApiKeyCreatedModel", "ApiKeyModel"] {
+            assert!(schemas.contains_key(name), "{name} missing");
+        }
+        assert!(schemas["ErrorModel"]["properties"].get("requestId").is_some());
+    }
+}
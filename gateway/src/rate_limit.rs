@@ -0,0 +1,278 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use sha2::Sha256;
+use spin_sdk::http::{Method, Request, Response};
+use spin_sdk::key_value::Store;
+
+use crate::api_keys::ApiKey;
+use crate::config;
+
+const BUCKET_PREFIX: &str = "bucket:";
+
+/// Secret bearer tokens are signed with (HS256). Without it bearer tokens are
+/// not trusted and their clients are limited by address.
+const JWT_SECRET_VARIABLE: &str = "rate_limit_jwt_secret";
+
+/// State of a request limit, reported through the `RateLimit-*` headers
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimit {
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset: u64,
+}
+
+impl RateLimit {
+    pub(crate) fn set_headers(&self, res: &mut Response) {
+        res.set_header("RateLimit-Limit", self.limit.to_string());
+        res.set_header("RateLimit-Remaining", self.remaining.to_string());
+        res.set_header("RateLimit-Reset", self.reset.to_string());
+    }
+
+    pub(crate) fn exceeded(&self, retry_after: u64) -> Response {
+        let mut res = Response::new(429, "Too Many Requests");
+        res.set_header("Retry-After", retry_after.to_string());
+        self.set_headers(&mut res);
+        res
+    }
+
+    /// Picks the limit closest to being exhausted, which is the one clients
+    /// have to respect.
+    pub(crate) fn most_restrictive(a: Option<RateLimit>, b: Option<RateLimit>) -> Option<RateLimit> {
+        match (a, b) {
+            (Some(a), Some(b)) if b.remaining < a.remaining => Some(b),
+            (Some(a), _) => Some(a),
+            (None, b) => b,
+        }
+    }
+}
+
+/// Outcome of taking a token from a client's bucket
+pub(crate) enum Throttle {
+    Allowed(Option<RateLimit>),
+    Limited(Response),
+}
+
+/// Token bucket persisted in the key-value store so that every gateway
+/// instance draws from the same bucket.
+#[derive(Debug, Serialize, Deserialize)]
+struct Bucket {
+    tokens: f64,
+    updated_ms: u64,
+}
+
+impl Bucket {
+    fn full(policy: &Policy, now: u64) -> Bucket {
+        Bucket { tokens: policy.capacity, updated_ms: now }
+    }
+
+    /// Refills the bucket for the time since it was last updated and takes
+    /// one token. Answers the limit to report and, when no token was left,
+    /// the seconds until one is.
+    fn take(&mut self, policy: &Policy, now: u64) -> (RateLimit, Option<u64>) {
+        let elapsed = now.saturating_sub(self.updated_ms) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * policy.refill_per_sec).min(policy.capacity);
+        self.updated_ms = now;
+
+        let allowed = self.tokens >= 1.0;
+        if allowed {
+            self.tokens -= 1.0;
+        }
+        let limit = RateLimit {
+            limit: policy.capacity as u64,
+            remaining: self.tokens.floor() as u64,
+            reset: ((policy.capacity - self.tokens) / policy.refill_per_sec).ceil() as u64,
+        };
+        let retry_after = ((1.0 - self.tokens) / policy.refill_per_sec).ceil() as u64;
+        (limit, (!allowed).then_some(retry_after.max(1)))
+    }
+}
+
+/// Bucket settings for one class of requests
+struct Policy {
+    class: &'static str,
+    capacity: f64,
+    refill_per_sec: f64,
+}
+
+impl Policy {
+    /// Reads are GET/HEAD/OPTIONS, everything else counts as a write.
+    fn for_request(req: &Request) -> Policy {
+        match req.method() {
+            Method::Get | Method::Head | Method::Options => Policy {
+                class: "read",
+                capacity: config::get_or("rate_limit_read_capacity", 120.0),
+                refill_per_sec: config::get_or("rate_limit_read_refill_per_sec", 2.0),
+            },
+            _ => Policy {
+                class: "write",
+                capacity: config::get_or("rate_limit_write_capacity", 30.0),
+                refill_per_sec: config::get_or("rate_limit_write_refill_per_sec", 0.5),
+            },
+        }
+    }
+}
+
+/// Takes one token from the bucket of the client sending `req`. A capacity
+/// of zero disables rate limiting for that class of requests.
+#[tracing::instrument(name = "rate_limit", skip_all)]
+pub(crate) fn throttle(req: &Request, key: Option<&ApiKey>) -> Result<Throttle> {
+    let policy = Policy::for_request(req);
+    if policy.capacity <= 0.0 || policy.refill_per_sec <= 0.0 {
+        return Ok(Throttle::Allowed(None));
+    }
+    let Some(client) = client_key(req, key) else {
+        return Ok(Throttle::Allowed(None));
+    };
+
+    let store = Store::open_default()?;
+    let name = format!("{BUCKET_PREFIX}{}:{client}", policy.class);
+    let now = unix_now_millis();
+    let mut bucket = store.get_json::<Bucket>(&name)?.unwrap_or_else(|| Bucket::full(&policy, now));
+    let (limit, retry_after) = bucket.take(&policy, now);
+    store.set_json(&name, &bucket)?;
+
+    match retry_after {
+        Some(retry_after) => Ok(Throttle::Limited(limit.exceeded(retry_after))),
+        None => Ok(Throttle::Allowed(Some(limit))),
+    }
+}
+
+/// Identifies the client a bucket belongs to: the API key when one was
+/// presented, the subject of a bearer JWT signed with `rate_limit_jwt_secret`,
+/// the peer address Spin reports otherwise.
+fn client_key(req: &Request, key: Option<&ApiKey>) -> Option<String> {
+    if let Some(key) = key {
+        return Some(format!("key:{}", key.kid));
+    }
+    let bearer = req.header("authorization")
+        .and_then(|v| v.as_str())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if let (Some(token), Some(secret)) = (bearer, config::get(JWT_SECRET_VARIABLE)) {
+        if let Some(subject) = jwt_subject(token.trim(), &secret, unix_now_millis() / 1000) {
+            return Some(format!("sub:{subject}"));
+        }
+    }
+    req.header("spin-client-addr")
+        .and_then(|v| v.as_str())
+        .map(|addr| format!("ip:{}", peer_ip(addr)))
+}
+
+/// The address of `host:port` or `[v6]:port`
+fn peer_ip(addr: &str) -> &str {
+    match addr.rsplit_once(':') {
+        Some((ip, _)) => ip.trim_matches(['[', ']']),
+        None => addr,
+    }
+}
+
+/// The `sub` claim of a JWT signed with HS256 under `secret`. `None` when the
+/// token is malformed, signed with another algorithm or key, or expired.
+fn jwt_subject(token: &str, secret: &str, now_secs: u64) -> Option<String> {
+    let decode = |part: &str| -> Option<JsonValue> {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).ok()?).ok()
+    };
+    let mut parts = token.split('.');
+    let (header, claims, signature) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || decode(header)?.get("alg")?.as_str()? != "HS256" {
+        return None;
+    }
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(format!("{header}.{claims}").as_bytes());
+    mac.verify_slice(&URL_SAFE_NO_PAD.decode(signature).ok()?).ok()?;
+
+    let claims = decode(claims)?;
+    if let Some(exp) = claims.get("exp") {
+        if exp.as_u64()? <= now_secs {
+            return None;
+        }
+    }
+    claims.get("sub")?.as_str().filter(|sub| !sub.is_empty()).map(String::from)
+}
+
+fn unix_now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test-secret";
+    const NOW: u64 = 1_800_000_000;
+
+    fn policy(capacity: f64, refill_per_sec: f64) -> Policy {
+        Policy { class: "read", capacity, refill_per_sec }
+    }
+
+    #[test]
+    fn bucket_allows_up_to_its_capacity() {
+        let policy = policy(2.0, 1.0);
+        let mut bucket = Bucket::full(&policy, 0);
+        let (limit, retry_after) = bucket.take(&policy, 0);
+        assert_eq!((limit.limit, limit.remaining, retry_after), (2, 1, None));
+        let (limit, retry_after) = bucket.take(&policy, 0);
+        assert_eq!((limit.remaining, retry_after), (0, None));
+        let (limit, retry_after) = bucket.take(&policy, 0);
+        assert_eq!((limit.remaining, limit.reset, retry_after), (0, 2, Some(1)));
+    }
+
+    #[test]
+    fn bucket_refills_over_time_up_to_its_capacity() {
+        let policy = policy(3.0, 0.5);
+        let mut bucket = Bucket { tokens: 0.0, updated_ms: 0 };
+        let (_, retry_after) = bucket.take(&policy, 1_000);
+        assert_eq!(retry_after, Some(1));
+        let (limit, retry_after) = bucket.take(&policy, 3_000);
+        assert_eq!((limit.remaining, retry_after), (0, None));
+        let (limit, _) = bucket.take(&policy, 3_600_000);
+        assert_eq!(limit.remaining, 2);
+    }
+
+    #[test]
+    fn most_restrictive_limit_has_the_fewest_remaining() {
+        let limit = |remaining| Some(RateLimit { limit: 10, remaining, reset: 1 });
+        assert_eq!(RateLimit::most_restrictive(limit(5), limit(3)).unwrap().remaining, 3);
+        assert_eq!(RateLimit::most_restrictive(limit(3), limit(5)).unwrap().remaining, 3);
+        assert_eq!(RateLimit::most_restrictive(None, limit(5)).unwrap().remaining, 5);
+        assert!(RateLimit::most_restrictive(None, None).is_none());
+    }
+
+    #[test]
+    fn peer_ip_drops_the_port() {
+        assert_eq!(peer_ip("203.0.113.7:52000"), "203.0.113.7");
+        assert_eq!(peer_ip("[2001:db8::1]:52000"), "2001:db8::1");
+        assert_eq!(peer_ip("203.0.113.7"), "203.0.113.7");
+    }
+
+    #[test]
+    fn jwt_subject_of_a_valid_token() {
+        let token = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiJhbGljZSIsImV4cCI6MjAwMDAwMDAwMH0.\
+                     IW04_ge74xLjN8eDbT2-KHJdwuC8Dctq78PQ1EOtWe4";
+        assert_eq!(jwt_subject(token, SECRET, NOW).as_deref(), Some("alice"));
+        let without_exp = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiJhbGljZSJ9.\
+                           2Wn_R6iebOZbc1L2YV525gj5QA8nEtXo9eWRlcPz0sE";
+        assert_eq!(jwt_subject(without_exp, SECRET, NOW).as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn jwt_subject_rejects_untrusted_tokens() {
+        let expired = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiJhbGljZSIsImV4cCI6MTAwMH0.\
+                       Tjwq5uGHH4ajeTCiPPdDdkUpit-U4j7_LGNlnRa2GW0";
+        let other_key = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiJhbGljZSJ9.\
+                         Oa-6xfaXcQToeCZ5H1IKIyAsqewB6bFqZwXcRFYIzqI";
+        let alg_none = "eyJhbGciOiJub25lIiwidHlwIjoiSldUIn0.eyJzdWIiOiJhbGljZSJ9.\
+                        nTzpIXqqZ5YCgwK5-zTwGf7UZzs9IhaipwVEJlV5iZc";
+        for token in [expired, other_key, alg_none, "not-a-token", "a.b.c.d"] {
+            assert_eq!(jwt_subject(token, SECRET, NOW), None, "{token}");
+        }
+    }
+}
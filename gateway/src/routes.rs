@@ -0,0 +1,208 @@
+use std::future::Future;
+
+use spin_sdk::http::conversions::TryFromRequest;
+use spin_sdk::http::{IntoResponse, Method, Params, Request, Response, Router};
+
+/// The gateway router together with the table of registered routes, so that
+/// preflight and `405` responses can report the methods allowed on a path.
+#[derive(Default)]
+pub(crate) struct Routes {
+    router: Router,
+    table: Vec<(Method, &'static str)>,
+}
+
+impl Routes {
+    pub(crate) fn add<F, Req, Resp>(&mut self, path: &'static str, method: Method, handler: F)
+    where
+        F: Fn(Req, Params) -> Resp + 'static,
+        Req: TryFromRequest + 'static,
+        Req::Error: IntoResponse + 'static,
+        Resp: IntoResponse + 'static,
+    {
+        self.table.push((method.clone(), path));
+        self.router.add(path, method, handler);
+    }
+
+    pub(crate) fn add_async<F, Fut, Req, Resp>(&mut self, path: &'static str, method: Method, handler: F)
+    where
+        F: Fn(Req, Params) -> Fut + 'static,
+        Fut: Future<Output = Resp> + 'static,
+        Req: TryFromRequest + 'static,
+        Req::Error: IntoResponse + 'static,
+        Resp: IntoResponse + 'static,
+    {
+        self.table.push((method.clone(), path));
+        self.router.add_async(path, method, handler);
+    }
+
+    pub(crate) fn get<F, Req, Resp>(&mut self, path: &'static str, handler: F)
+    where
+        F: Fn(Req, Params) -> Resp + 'static,
+        Req: TryFromRequest + 'static,
+        Req::Error: IntoResponse + 'static,
+        Resp: IntoResponse + 'static,
+    {
+        self.add(path, Method::Get, handler)
+    }
+
+    pub(crate) fn get_async<F, Fut, Req, Resp>(&mut self, path: &'static str, handler: F)
+    where
+        F: Fn(Req, Params) -> Fut + 'static,
+        Fut: Future<Output = Resp> + 'static,
+        Req: TryFromRequest + 'static,
+        Req::Error: IntoResponse + 'static,
+        Resp: IntoResponse + 'static,
+    {
+        self.add_async(path, Method::Get, handler)
+    }
+
+    pub(crate) fn post<F, Req, Resp>(&mut self, path: &'static str, handler: F)
+    where
+        F: Fn(Req, Params) -> Resp + 'static,
+        Req: TryFromRequest + 'static,
+        Req::Error: IntoResponse + 'static,
+        Resp: IntoResponse + 'static,
+    {
+        self.add(path, Method::Post, handler)
+    }
+
+    pub(crate) fn post_async<F, Fut, Req, Resp>(&mut self, path: &'static str, handler: F)
+    where
+        F: Fn(Req, Params) -> Fut + 'static,
+        Fut: Future<Output = Resp> + 'static,
+        Req: TryFromRequest + 'static,
+        Req::Error: IntoResponse + 'static,
+        Resp: IntoResponse + 'static,
+    {
+        self.add_async(path, Method::Post, handler)
+    }
+
+    pub(crate) fn put_async<F, Fut, Req, Resp>(&mut self, path: &'static str, handler: F)
+    where
+        F: Fn(Req, Params) -> Fut + 'static,
+        Fut: Future<Output = Resp> + 'static,
+        Req: TryFromRequest + 'static,
+        Req::Error: IntoResponse + 'static,
+        Resp: IntoResponse + 'static,
+    {
+        self.add_async(path, Method::Put, handler)
+    }
+
+    pub(crate) fn delete<F, Req, Resp>(&mut self, path: &'static str, handler: F)
+    where
+        F: Fn(Req, Params) -> Resp + 'static,
+        Req: TryFromRequest + 'static,
+        Req::Error: IntoResponse + 'static,
+        Resp: IntoResponse + 'static,
+    {
+        self.add(path, Method::Delete, handler)
+    }
+
+    pub(crate) fn delete_async<F, Fut, Req, Resp>(&mut self, path: &'static str, handler: F)
+    where
+        F: Fn(Req, Params) -> Fut + 'static,
+        Fut: Future<Output = Resp> + 'static,
+        Req: TryFromRequest + 'static,
+        Req::Error: IntoResponse + 'static,
+        Resp: IntoResponse + 'static,
+    {
+        self.add_async(path, Method::Delete, handler)
+    }
+
+    pub(crate) fn handle(&self, req: Request) -> Response {
+        self.router.handle(req)
+    }
+
+    /// Registered routes, in registration order
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&Method, &'static str)> {
+        self.table.iter().map(|(method, pattern)| (method, *pattern))
+    }
+
+    /// The most specific route pattern matching `path`, whatever its method,
+    /// so `/persons/import` is preferred over `/persons/:pid`
+    pub(crate) fn route(&self, path: &str) -> Option<&'static str> {
+        self.table
+            .iter()
+            .filter(|(_, pattern)| matches(pattern, path))
+            .min_by_key(|(_, pattern)| pattern.split('/').filter(|s| s.starts_with(':')).count())
+            .map(|(_, pattern)| *pattern)
+    }
+
+    /// Methods registered for the route `path` takes, with OPTIONS answered
+    /// by the gateway itself. Empty when no route matches.
+    pub(crate) fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let Some(route) = self.route(path) else {
+            return Vec::new();
+        };
+        let mut methods: Vec<Method> = Vec::new();
+        for (method, pattern) in &self.table {
+            if *pattern == route && !methods.contains(method) {
+                methods.push(method.clone());
+            }
+        }
+        methods.push(Method::Options);
+        methods
+    }
+}
+
+/// Matches a path against a route pattern where `:name` segments match any
+/// single segment.
+fn matches(pattern: &str, path: &str) -> bool {
+    let mut pattern = pattern.trim_end_matches('/').split('/');
+    let mut path = path.trim_end_matches('/').split('/');
+    loop {
+        match (pattern.next(), path.next()) {
+            (None, None) => return true,
+            (Some(p), Some(s)) if p.starts_with(':') && !s.is_empty() => continue,
+            (Some(p), Some(s)) if p == s => continue,
+            _ => return false,
+        }
+    }
+}
+
+/// Formats methods for an `Allow` or `Access-Control-Allow-Methods` header
+pub(crate) fn join_methods(methods: &[Method]) -> String {
+    methods
+        .iter()
+        .map(|m| m.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(_: Request, _: Params) -> Response {
+        Response::new(200, ())
+    }
+
+    fn persons() -> Routes {
+        let mut routes = Routes::default();
+        routes.get("/persons", ok);
+        routes.post("/persons", ok);
+        routes.post("/persons/import", ok);
+        routes.get("/persons/:pid", ok);
+        routes.delete("/persons/:pid", ok);
+        routes
+    }
+
+    #[test]
+    fn route_prefers_literal_segments() {
+        let routes = persons();
+        assert_eq!(routes.route("/persons/import"), Some("/persons/import"));
+        assert_eq!(routes.route("/persons/p-1"), Some("/persons/:pid"));
+        assert_eq!(routes.route("/persons/"), Some("/persons"));
+        assert_eq!(routes.route("/persons/p-1/history"), None);
+    }
+
+    #[test]
+    fn allowed_methods_are_those_of_the_route_taken() {
+        let routes = persons();
+        let allowed = |path: &str| join_methods(&routes.allowed_methods(path));
+        assert_eq!(allowed("/persons/import"), "POST, OPTIONS");
+        assert_eq!(allowed("/persons/p-1"), "GET, DELETE, OPTIONS");
+        assert_eq!(allowed("/persons"), "GET, POST, OPTIONS");
+        assert!(routes.allowed_methods("/unknown").is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+use serde::Serialize;
+use spin_sdk::http::{Request, RequestBuilder, Response};
+use telemetry::TraceParent;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+pub(crate) const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Correlation identifiers of a request: the `X-Request-Id` and the W3C trace
+/// context. Both are accepted from the client when well formed and generated
+/// otherwise.
+#[derive(Debug, Clone)]
+pub(crate) struct TraceContext {
+    pub request_id: String,
+    pub trace: TraceParent,
+}
+
+/// Error body of every failed request that carries no body of its own
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ErrorModel<'a> {
+    status: u16,
+    error: &'a str,
+    #[serde(rename = "requestId")]
+    request_id: &'a str,
+}
+
+impl TraceContext {
+    /// Reads the incoming correlation headers. The gateway span gets a fresh
+    /// span id and becomes the parent of every call to the components.
+    pub(crate) fn from_request(req: &Request) -> TraceContext {
+        let request_id = req
+            .header(REQUEST_ID_HEADER)
+            .and_then(|v| v.as_str())
+            .map(str::trim)
+            .filter(|id| valid_request_id(id))
+            .map(String::from)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        let trace = TraceParent::from_header(req.header(TRACEPARENT_HEADER).and_then(|v| v.as_str()));
+
+        TraceContext { request_id, trace }
+    }
+
+    pub(crate) fn traceparent(&self) -> String {
+        self.trace.header()
+    }
+
+    /// Replaces the correlation headers of the incoming request with the
+    /// normalized ones, so handlers forward them as they are.
+    pub(crate) fn apply(&self, req: &mut Request) {
+        req.set_header(REQUEST_ID_HEADER, self.request_id.clone());
+        req.set_header(TRACEPARENT_HEADER, self.traceparent());
+    }
+
+    /// Echoes the correlation headers and gives error responses without a
+    /// body of their own a JSON body carrying the request id.
+    pub(crate) fn decorate(&self, res: &mut Response) {
+        let status = *res.status();
+        if status >= 400 && res.header("content-type").is_none() {
+            let text = String::from_utf8_lossy(res.body()).trim().to_string();
+            let error = ErrorModel {
+                status,
+                error: if text.is_empty() { "Error" } else { &text },
+                request_id: &self.request_id,
+            };
+            if let Ok(body) = serde_json::to_vec(&error) {
+                *res.body_mut() = body;
+                res.set_header("Content-Type", "application/json");
+            }
+        }
+        res.set_header("X-Request-Id", self.request_id.clone());
+        res.set_header("traceparent", self.traceparent());
+    }
+}
+
+/// Copies the correlation headers of an (already normalized) incoming request
+/// onto a request to one of the components. When spans are collected the
+/// current span becomes the parent of the component's span.
+pub(crate) fn forward(req: &Request, builder: &mut RequestBuilder) {
+    if let Some(value) = req.header(REQUEST_ID_HEADER).and_then(|v| v.as_str()) {
+        builder.header(REQUEST_ID_HEADER, value);
+    }
+    let traceparent = telemetry::current_traceparent().or_else(|| {
+        req.header(TRACEPARENT_HEADER)
+            .and_then(|v| v.as_str())
+            .map(String::from)
+    });
+    if let Some(value) = traceparent {
+        builder.header(TRACEPARENT_HEADER, value);
+    }
+}
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.chars().all(|c| c.is_ascii_graphic())
+}
@@ -0,0 +1,282 @@
+use serde_json::Value;
+use shared::http_date;
+use spin_sdk::http::{Request, Response};
+
+use crate::{config, cors};
+
+/// Vendor media type selecting a version through `Accept`, e.g.
+/// `application/vnd.cqrs.v2+json`
+const MEDIA_TYPE_PREFIX: &str = "application/vnd.cqrs.";
+
+/// A version of the public API. The components always answer with the
+/// internal models; each version translates them into its own shapes. A
+/// version is deprecated once the `api_<name>_deprecation` variable names the
+/// date, in seconds since the epoch.
+pub(crate) struct Version {
+    pub name: &'static str,
+    translate: fn(route: &str, body: &mut Value),
+}
+
+/// All versions, oldest first. Unversioned requests get the oldest so that
+/// existing clients keep working.
+const VERSIONS: [Version; 2] = [
+    Version { name: "v1", translate: v1 },
+    Version { name: "v2", translate: v2 },
+];
+
+/// How the client chose the version
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    Prefix,
+    Accept,
+    Default,
+}
+
+/// The API version negotiated for a request
+pub(crate) struct ApiVersion {
+    version: &'static Version,
+    source: Source,
+    /// request path without the version prefix
+    path: String,
+}
+
+impl ApiVersion {
+    /// Picks the version from a `/v1` or `/v2` path prefix, else from a vendor
+    /// media type in `Accept`, and strips the prefix so that routing only
+    /// deals with unversioned paths. `None` when `Accept` names a version
+    /// that does not exist.
+    pub(crate) fn negotiate(req: Request) -> (Request, Option<ApiVersion>) {
+        let path = req.path().to_string();
+        let prefixed = path
+            .split('/')
+            .nth(1)
+            .and_then(|segment| VERSIONS.iter().find(|v| v.name == segment));
+        if let Some(version) = prefixed {
+            let stripped = path[version.name.len() + 1..].to_string();
+            let stripped = if stripped.is_empty() { "/".to_string() } else { stripped };
+            let mut req = strip_prefix(req, version.name);
+            untag_etags(&mut req, version);
+            return (req, Some(ApiVersion { version, source: Source::Prefix, path: stripped }));
+        }
+
+        let accepted = req
+            .header("accept")
+            .and_then(|v| v.as_str())
+            .and_then(requested_version)
+            .map(String::from);
+        let version = match accepted {
+            Some(name) => match VERSIONS.iter().find(|v| v.name == name) {
+                Some(version) => ApiVersion { version, source: Source::Accept, path },
+                None => return (req, None),
+            },
+            None => ApiVersion { version: &VERSIONS[0], source: Source::Default, path },
+        };
+        let mut req = req;
+        untag_etags(&mut req, version.version);
+        (req, Some(version))
+    }
+
+    /// Translates a successful JSON response of `route` into the shapes of
+    /// this version and adds the version headers. NDJSON lists are translated
+    /// line by line; CSV exports keep the columns of the internal models.
+    pub(crate) fn apply(&self, route: &str, res: &mut Response) {
+        let content_type = res.header("content-type").and_then(|v| v.as_str()).unwrap_or_default();
+        let json = content_type.starts_with("application/json");
+        let ndjson = content_type.starts_with("application/x-ndjson");
+        if (200..300).contains(res.status()) && json {
+            if let Ok(mut body) = serde_json::from_slice::<Value>(res.body()) {
+                (self.version.translate)(route, &mut body);
+                if let Ok(body) = serde_json::to_vec(&body) {
+                    *res.body_mut() = body;
+                }
+            }
+            if self.source == Source::Accept {
+                res.set_header("Content-Type", format!("{}{}+json", MEDIA_TYPE_PREFIX, self.version.name));
+            }
+        } else if (200..300).contains(res.status()) && ndjson {
+            if let Some(body) = self.translate_lines(route, res.body()) {
+                *res.body_mut() = body;
+            }
+        }
+
+        // the same internal representation reads differently in every version
+        if let Some(etag) = res.header("etag").and_then(|v| v.as_str()).and_then(|v| v.strip_suffix('"')) {
+            let tagged = format!("{}.{}\"", etag, self.version.name);
+            res.set_header("ETag", tagged);
+        }
+        res.set_header("Api-Version", self.version.name);
+        if self.source != Source::Prefix {
+            cors::vary(res, "Accept");
+        }
+        if let Some(deprecation) = self.configured("deprecation") {
+            res.set_header("Deprecation", format!("@{}", deprecation));
+            if let Some(sunset) = self.configured("sunset") {
+                res.set_header("Sunset", http_date::format(sunset));
+            }
+            let latest = VERSIONS[VERSIONS.len() - 1].name;
+            res.set_header("Link", format!("</{}{}>; rel=\"successor-version\"", latest, self.path));
+        }
+    }
+
+    /// Translates every line of an NDJSON list as a list of one row
+    pub(crate) fn translate_lines(&self, route: &str, body: &[u8]) -> Option<Vec<u8>> {
+        let mut translated = Vec::with_capacity(body.len());
+        for line in body.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+            let mut rows = Value::Array(vec![serde_json::from_slice(line).ok()?]);
+            (self.version.translate)(route, &mut rows);
+            serde_json::to_writer(&mut translated, &rows[0]).ok()?;
+            translated.push(b'\n');
+        }
+        Some(translated)
+    }
+
+    /// A date of this version configured in `api_<name>_<date>`
+    fn configured(&self, date: &str) -> Option<i64> {
+        config::get(&format!("api_{}_{}", self.version.name, date)).and_then(|v| v.parse().ok())
+    }
+}
+
+/// The version named by the first vendor media type in an `Accept` header
+fn requested_version(accept: &str) -> Option<&str> {
+    accept
+        .split(',')
+        .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+        .find_map(|media_type| media_type.strip_prefix(MEDIA_TYPE_PREFIX))
+        .map(|rest| rest.strip_suffix("+json").unwrap_or(rest))
+}
+
+/// Removes the version from the ETags in `If-None-Match`, so that the
+/// components compare them with the tags of their internal representation.
+/// Tags of other versions keep their suffix and never match.
+fn untag_etags(req: &mut Request, version: &Version) {
+    let Some(tags) = req.header("if-none-match").and_then(|v| v.as_str()) else {
+        return;
+    };
+    let suffix = format!(".{}\"", version.name);
+    let untagged = tags
+        .split(',')
+        .map(|tag| match tag.trim().strip_suffix(suffix.as_str()) {
+            Some(etag) => format!("{}\"", etag),
+            None => tag.trim().to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    req.set_header("if-none-match", untagged);
+}
+
+/// Rebuilds the request without the version prefix in its URI
+fn strip_prefix(req: Request, version: &str) -> Request {
+    let uri = req.uri();
+    let path_and_query = req.path_and_query().unwrap_or(uri);
+    let start = uri.len() - path_and_query.len();
+    let rest = &path_and_query[version.len() + 1..];
+    let rest = if rest.is_empty() || rest.starts_with('?') { format!("/{}", rest) } else { rest.to_string() };
+    let stripped = format!("{}{}", &uri[..start], rest);
+
+    let mut builder = Request::builder();
+    builder.method(req.method().clone()).uri(stripped);
+    for (name, value) in req.headers() {
+        builder.header(name, String::from_utf8_lossy(value.as_bytes()));
+    }
+    builder.body(req.into_body()).build()
+}
+
+/// Version 1: list models carry the pre-joined `name` ("Last, First") only
+fn v1(route: &str, body: &mut Value) {
+    if matches!(route, "/employees" | "/persons" | "/locations/:lid/persons") {
+        for item in body.as_array_mut().into_iter().flatten() {
+            if let Some(item) = item.as_object_mut() {
+                item.remove("firstName");
+                item.remove("lastName");
+            }
+        }
+    }
+}
+
+/// Version 2: list models carry `firstName` and `lastName` instead of `name`
+fn v2(route: &str, body: &mut Value) {
+    if matches!(route, "/employees" | "/persons" | "/locations/:lid/persons") {
+        for item in body.as_array_mut().into_iter().flatten() {
+            if let Some(item) = item.as_object_mut() {
+                item.remove("name");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn persons() -> Value {
+        json!([{ "name": "Doe, Jane", "firstName": "Jane", "lastName": "Doe", "createdAt": "t" }])
+    }
+
+    fn request(uri: &str, accept: Option<&str>) -> Request {
+        let mut builder = Request::builder();
+        builder.method(spin_sdk::http::Method::Get).uri(uri);
+        if let Some(accept) = accept {
+            builder.header("accept", accept);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn v1_lists_carry_the_joined_name_only() {
+        let mut body = persons();
+        v1("/persons", &mut body);
+        assert_eq!(body, json!([{ "name": "Doe, Jane", "createdAt": "t" }]));
+        let mut details = persons();
+        v1("/persons/:pid", &mut details);
+        assert_eq!(details, persons());
+    }
+
+    #[test]
+    fn v2_lists_carry_first_and_last_names() {
+        let mut body = persons();
+        v2("/locations/:lid/persons", &mut body);
+        let expected = json!([{ "firstName": "Jane", "lastName": "Doe", "createdAt": "t" }]);
+        assert_eq!(body, expected);
+    }
+
+    #[test]
+    fn version_is_requested_by_vendor_media_type() {
+        assert_eq!(requested_version("application/vnd.cqrs.v2+json"), Some("v2"));
+        assert_eq!(requested_version("text/html, application/vnd.cqrs.v1+json; q=0.9"), Some("v1"));
+        assert_eq!(requested_version("application/vnd.cqrs.v9"), Some("v9"));
+        assert_eq!(requested_version("application/json"), None);
+    }
+
+    #[test]
+    fn prefix_selects_the_version_and_is_stripped() {
+        let (req, version) = ApiVersion::negotiate(request("/v2/persons?limit=5", None));
+        let Some(version) = version else { panic!("no version") };
+        assert_eq!((version.version.name, version.source), ("v2", Source::Prefix));
+        assert_eq!(version.path, "/persons");
+        assert_eq!((req.path(), req.query()), ("/persons", "limit=5"));
+    }
+
+    #[test]
+    fn accept_selects_the_version_and_defaults_to_the_oldest() {
+        let (_, version) = ApiVersion::negotiate(request("/persons", Some("application/vnd.cqrs.v2+json")));
+        let Some(version) = version else { panic!("no version") };
+        assert_eq!((version.version.name, version.source), ("v2", Source::Accept));
+
+        let (_, version) = ApiVersion::negotiate(request("/persons", Some("application/json")));
+        let Some(version) = version else { panic!("no version") };
+        assert_eq!((version.version.name, version.source), ("v1", Source::Default));
+
+        let (_, version) = ApiVersion::negotiate(request("/persons", Some("application/vnd.cqrs.v9+json")));
+        assert!(version.is_none());
+    }
+
+    #[test]
+    fn etags_of_the_negotiated_version_are_untagged() {
+        let mut req = request("/persons", None);
+        req.set_header("if-none-match", "\"abc.v1\", \"def.v2\"");
+        untag_etags(&mut req, &VERSIONS[0]);
+        assert_eq!(req.header("if-none-match").and_then(|v| v.as_str()), Some("\"abc\", \"def.v2\""));
+    }
+}
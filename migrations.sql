@@ -1,5 +1,12 @@
 PRAGMA foreign_keys=ON;
 
+CREATE TABLE IF NOT EXISTS SchemaMigrations (
+    Version INTEGER NOT NULL,
+    Name TEXT NOT NULL,
+    AppliedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (Version)
+);
+
 CREATE TABLE IF NOT EXISTS Employees (
     Id VARCHAR(36) NOT NULL, 
     FirstName TEXT NOT NULL, 
@@ -33,6 +40,250 @@ CREATE TABLE IF NOT EXISTS Locations (
     PRIMARY KEY (Lid)
 );
 
+CREATE TABLE IF NOT EXISTS CommandQueue (
+    Id VARCHAR(36) NOT NULL,
+    Path TEXT NOT NULL,
+    ContentType TEXT,
+    Body BLOB NOT NULL,
+    RequestId TEXT NOT NULL,
+    Client TEXT,
+    Scopes TEXT,
+    IdempotencyKey TEXT,
+    Status TEXT NOT NULL DEFAULT 'pending',
+    ResultStatus INTEGER,
+    ResultContentType TEXT,
+    ResultBody BLOB,
+    CreatedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    StartedAt TEXT,
+    FinishedAt TEXT,
+    PRIMARY KEY (Id)
+);
+
+CREATE INDEX IF NOT EXISTS CommandQueueStatus ON CommandQueue (Status, CreatedAt);
+CREATE UNIQUE INDEX IF NOT EXISTS CommandQueueIdempotencyKey ON CommandQueue (Client, IdempotencyKey)
+    WHERE IdempotencyKey IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS IdempotencyKeys (
+    Client TEXT NOT NULL,
+    Key TEXT NOT NULL,
+    Command TEXT NOT NULL,
+    RequestHash TEXT NOT NULL,
+    Status INTEGER NOT NULL,
+    ContentType TEXT,
+    Body BLOB NOT NULL,
+    CreatedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (Client, Key)
+);
+
+-- version and modification time (unix seconds) of every table the read models
+-- are built from, maintained by the triggers below
+CREATE TABLE IF NOT EXISTS TableVersions (
+    Name TEXT NOT NULL,
+    Version INTEGER NOT NULL DEFAULT 0,
+    ModifiedAt INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    PRIMARY KEY (Name)
+);
+
+INSERT OR IGNORE INTO TableVersions(Name) VALUES ('Employees'), ('Addresses'), ('Persons'), ('Locations');
+
+CREATE TRIGGER IF NOT EXISTS EmployeesInserted AFTER INSERT ON Employees
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Employees';
+END;
+
+CREATE TRIGGER IF NOT EXISTS EmployeesUpdated AFTER UPDATE ON Employees
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Employees';
+END;
+
+CREATE TRIGGER IF NOT EXISTS EmployeesDeleted AFTER DELETE ON Employees
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Employees';
+END;
+
+CREATE TRIGGER IF NOT EXISTS AddressesInserted AFTER INSERT ON Addresses
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Addresses';
+END;
+
+CREATE TRIGGER IF NOT EXISTS AddressesUpdated AFTER UPDATE ON Addresses
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Addresses';
+END;
+
+CREATE TRIGGER IF NOT EXISTS AddressesDeleted AFTER DELETE ON Addresses
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Addresses';
+END;
+
+CREATE TRIGGER IF NOT EXISTS PersonsInserted AFTER INSERT ON Persons
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Persons';
+END;
+
+CREATE TRIGGER IF NOT EXISTS PersonsUpdated AFTER UPDATE ON Persons
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Persons';
+END;
+
+CREATE TRIGGER IF NOT EXISTS PersonsDeleted AFTER DELETE ON Persons
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Persons';
+END;
+
+CREATE TRIGGER IF NOT EXISTS LocationsInserted AFTER INSERT ON Locations
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Locations';
+END;
+
+CREATE TRIGGER IF NOT EXISTS LocationsUpdated AFTER UPDATE ON Locations
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Locations';
+END;
+
+CREATE TRIGGER IF NOT EXISTS LocationsDeleted AFTER DELETE ON Locations
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Locations';
+END;
+
+-- when and by whom every entity was created and last updated, kept by
+-- commands; Addresses are stamped under the id of their employee
+CREATE TABLE IF NOT EXISTS ChangeStamps (
+    Entity TEXT NOT NULL,
+    Id VARCHAR(36) NOT NULL,
+    CreatedAt TEXT,
+    CreatedBy TEXT,
+    UpdatedAt TEXT,
+    UpdatedBy TEXT,
+    PRIMARY KEY (Entity, Id)
+);
+
+CREATE INDEX IF NOT EXISTS ChangeStampsUpdated ON ChangeStamps (Entity, UpdatedAt);
+
+CREATE TRIGGER IF NOT EXISTS EmployeesUnstamped AFTER DELETE ON Employees
+BEGIN
+    DELETE FROM ChangeStamps WHERE Entity = 'Employees' AND Id = OLD.Id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS AddressesUnstamped AFTER DELETE ON Addresses
+BEGIN
+    DELETE FROM ChangeStamps WHERE Entity = 'Addresses' AND Id = OLD.EmployeeId;
+END;
+
+CREATE TRIGGER IF NOT EXISTS PersonsUnstamped AFTER DELETE ON Persons
+BEGIN
+    DELETE FROM ChangeStamps WHERE Entity = 'Persons' AND Id = OLD.Pid;
+END;
+
+CREATE TRIGGER IF NOT EXISTS LocationsUnstamped AFTER DELETE ON Locations
+BEGIN
+    DELETE FROM ChangeStamps WHERE Entity = 'Locations' AND Id = OLD.Lid;
+END;
+
+-- one record per command: who ran it, what it targeted, the entity before and
+-- after as JSON, and how it ended
+CREATE TABLE IF NOT EXISTS AuditLog (
+    Seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    At TEXT NOT NULL,
+    Actor TEXT NOT NULL,
+    RequestId TEXT NOT NULL,
+    Command TEXT NOT NULL,
+    Entity TEXT NOT NULL,
+    TargetId VARCHAR(36),
+    Before TEXT,
+    After TEXT,
+    Status INTEGER NOT NULL,
+    Outcome TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS AuditLogTarget ON AuditLog (Entity, TargetId);
+CREATE INDEX IF NOT EXISTS AuditLogActor ON AuditLog (Actor, At);
+CREATE INDEX IF NOT EXISTS AuditLogAt ON AuditLog (At);
+
+INSERT OR IGNORE INTO TableVersions(Name) VALUES ('AuditLog');
+
+CREATE TRIGGER IF NOT EXISTS AuditLogInserted AFTER INSERT ON AuditLog
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'AuditLog';
+END;
+
+-- every version of every entity as JSON, kept by commands; a deletion is a
+-- version without state
+CREATE TABLE IF NOT EXISTS EntityVersions (
+    Entity TEXT NOT NULL,
+    Id VARCHAR(36) NOT NULL,
+    Version INTEGER NOT NULL,
+    At TEXT NOT NULL,
+    Actor TEXT NOT NULL,
+    Operation TEXT NOT NULL,
+    State TEXT,
+    PRIMARY KEY (Entity, Id, Version)
+);
+
+CREATE INDEX IF NOT EXISTS EntityVersionsAt ON EntityVersions (Entity, At);
+
+INSERT OR IGNORE INTO TableVersions(Name) VALUES ('EntityVersions');
+
+CREATE TRIGGER IF NOT EXISTS EntityVersionsInserted AFTER INSERT ON EntityVersions
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'EntityVersions';
+END;
+
+-- the versions with the time until which they were current, for queries as
+-- of a point in time, and when and by whom their entity was created
+CREATE VIEW IF NOT EXISTS EntityStates AS
+SELECT Versions.Entity, Versions.Id, Versions.Version, Versions.At ValidFrom,
+    (SELECT MIN(Later.At) FROM EntityVersions Later
+     WHERE Later.Entity = Versions.Entity AND Later.Id = Versions.Id AND Later.Version > Versions.Version) ValidTo,
+    Versions.Actor, Versions.State, First.At CreatedAt, First.Actor CreatedBy
+FROM EntityVersions Versions
+INNER JOIN EntityVersions First ON First.Entity = Versions.Entity AND First.Id = Versions.Id AND First.Version = 1;
+
+-- employees and persons deleted but kept until their retention period ends;
+-- queries hide them, commands can undelete them and the purge removes them
+CREATE TABLE IF NOT EXISTS Deletions (
+    Entity TEXT NOT NULL,
+    Id VARCHAR(36) NOT NULL,
+    DeletedAt TEXT NOT NULL,
+    DeletedBy TEXT NOT NULL,
+    PRIMARY KEY (Entity, Id)
+);
+
+CREATE INDEX IF NOT EXISTS DeletionsAt ON Deletions (Entity, DeletedAt);
+
+INSERT OR IGNORE INTO TableVersions(Name) VALUES ('Deletions');
+
+CREATE TRIGGER IF NOT EXISTS DeletionsInserted AFTER INSERT ON Deletions
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Deletions';
+END;
+
+CREATE TRIGGER IF NOT EXISTS DeletionsDeleted AFTER DELETE ON Deletions
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'Deletions';
+END;
+
+CREATE TRIGGER IF NOT EXISTS EmployeesPurged AFTER DELETE ON Employees
+BEGIN
+    DELETE FROM Deletions WHERE Entity = 'Employees' AND Id = OLD.Id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS PersonsPurged AFTER DELETE ON Persons
+BEGIN
+    DELETE FROM Deletions WHERE Entity = 'Persons' AND Id = OLD.Pid;
+END;
+
+-- erasing personal data rewrites history and audit records in place
+CREATE TRIGGER IF NOT EXISTS EntityVersionsUpdated AFTER UPDATE ON EntityVersions
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'EntityVersions';
+END;
+
+CREATE TRIGGER IF NOT EXISTS AuditLogUpdated AFTER UPDATE ON AuditLog
+BEGIN
+    UPDATE TableVersions SET Version = Version + 1, ModifiedAt = strftime('%s', 'now') WHERE Name = 'AuditLog';
+END;
+
 INSERT INTO Employees(Id, FirstName, LastName)
 SELECT '12a33c84-ee60-45a1-848d-428ad3259abc', 'John', 'Doe'
 WHERE
@@ -45,3 +296,80 @@ WHERE
 NOT EXISTS (
 SELECT EmployeeId FROM Addresses WHERE EmployeeId = '12a33c84-ee60-45a1-848d-428ad3259abc');
 
+-- entities written before versions were kept start with their current state
+INSERT OR IGNORE INTO EntityVersions (Entity, Id, Version, At, Actor, Operation, State)
+SELECT 'employees', Employees.Id, 1, COALESCE(Stamps.UpdatedAt, strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+    COALESCE(Stamps.UpdatedBy, 'system'), 'created',
+    json_object('id', Employees.Id, 'firstName', Employees.FirstName, 'lastName', Employees.LastName,
+        'address', json_object('street', Addresses.Street, 'zip', Addresses.Zip, 'city', Addresses.City))
+FROM Employees
+LEFT JOIN Addresses ON Addresses.EmployeeId = Employees.Id
+LEFT JOIN ChangeStamps Stamps ON Stamps.Entity = 'Employees' AND Stamps.Id = Employees.Id;
+
+INSERT OR IGNORE INTO EntityVersions (Entity, Id, Version, At, Actor, Operation, State)
+SELECT 'persons', Pid, 1, COALESCE(Stamps.UpdatedAt, strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+    COALESCE(Stamps.UpdatedBy, 'system'), 'created',
+    json_object('pid', Pid, 'firstName', FirstName, 'lastName', LastName, 'plid', Plid)
+FROM Persons
+LEFT JOIN ChangeStamps Stamps ON Stamps.Entity = 'Persons' AND Stamps.Id = Persons.Pid;
+
+INSERT OR IGNORE INTO EntityVersions (Entity, Id, Version, At, Actor, Operation, State)
+SELECT 'locations', Lid, 1, COALESCE(Stamps.UpdatedAt, strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+    COALESCE(Stamps.UpdatedBy, 'system'), 'created',
+    json_object('lid', Lid, 'street', Street, 'zip', Zip, 'city', City)
+FROM Locations
+LEFT JOIN ChangeStamps Stamps ON Stamps.Entity = 'Locations' AND Stamps.Id = Locations.Lid;
+
+INSERT INTO SchemaMigrations(Version, Name)
+SELECT 1, 'initial schema'
+WHERE
+NOT EXISTS (
+SELECT Version FROM SchemaMigrations WHERE Version = 1);
+
+INSERT INTO SchemaMigrations(Version, Name)
+SELECT 2, 'command queue'
+WHERE
+NOT EXISTS (
+SELECT Version FROM SchemaMigrations WHERE Version = 2);
+
+INSERT INTO SchemaMigrations(Version, Name)
+SELECT 3, 'idempotency keys'
+WHERE
+NOT EXISTS (
+SELECT Version FROM SchemaMigrations WHERE Version = 3);
+
+INSERT INTO SchemaMigrations(Version, Name)
+SELECT 4, 'table versions'
+WHERE
+NOT EXISTS (
+SELECT Version FROM SchemaMigrations WHERE Version = 4);
+
+INSERT INTO SchemaMigrations(Version, Name)
+SELECT 5, 'change stamps'
+WHERE
+NOT EXISTS (
+SELECT Version FROM SchemaMigrations WHERE Version = 5);
+
+INSERT INTO SchemaMigrations(Version, Name)
+SELECT 6, 'audit log'
+WHERE
+NOT EXISTS (
+SELECT Version FROM SchemaMigrations WHERE Version = 6);
+
+INSERT INTO SchemaMigrations(Version, Name)
+SELECT 7, 'entity versions'
+WHERE
+NOT EXISTS (
+SELECT Version FROM SchemaMigrations WHERE Version = 7);
+
+INSERT INTO SchemaMigrations(Version, Name)
+SELECT 8, 'soft deletion'
+WHERE
+NOT EXISTS (
+SELECT Version FROM SchemaMigrations WHERE Version = 8);
+
+INSERT INTO SchemaMigrations(Version, Name)
+SELECT 9, 'personal data erasure'
+WHERE
+NOT EXISTS (
+SELECT Version FROM SchemaMigrations WHERE Version = 9);
@@ -0,0 +1,207 @@
+use anyhow::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use shared::http_date;
+use spin_sdk::http::{Params, Request, Response};
+use spin_sdk::sqlite::Connection;
+use telemetry::RequestContext;
+
+use crate::cache::{Cache, CACHE_HEADER};
+use crate::consistency;
+use crate::formats::{self, Chunks, Format, RowWriter};
+use crate::handlers::*;
+use crate::persistence::{self, Sink};
+
+pub(crate) use shared::query::QueryString;
+
+const SCOPE_ADMIN: &str = "admin";
+/// Parameters only callers with the admin scope may send, whatever their
+/// value. The gateway checks them as well.
+const ADMIN_PARAMETERS: [&str; 1] = ["includeDeleted"];
+
+/// A read model: typed parameters taken from the path, and the rows it
+/// answers. A new query implements this trait and is added to `registry()`.
+/// Its debug form names the representation in the cache and the ETag, so it
+/// must show every parameter the query reads.
+pub(crate) trait Query: Sized + std::fmt::Debug {
+    /// route of the query
+    const PATH: &'static str;
+    /// file name of CSV downloads
+    const NAME: &'static str;
+    /// whether the rows can also be exported as CSV and NDJSON; others always
+    /// answer JSON
+    const EXPORTABLE: bool = false;
+    /// `Cache-Control` of the response. Responses carry an ETag, so clients
+    /// revalidate cheaply.
+    const CACHE_CONTROL: &'static str = "private, no-cache";
+    /// tables the rows are read from; a write to any of them changes the
+    /// ETag and `Last-Modified`
+    const TABLES: &'static [&'static str];
+
+    type Row: Serialize;
+
+    /// Builds the query from the parameters of its path and query string
+    fn from_params(params: &Params, query: &QueryString) -> std::result::Result<Self, String>;
+
+    /// Passes the rows of the result to `sink`, in order
+    fn run(&self, con: &Connection, sink: Sink<Self::Row>) -> Result<()>;
+}
+
+/// Runs a query: negotiates the format, checks the write named by a
+/// consistency token, answers `304` when the client already has the current
+/// representation, and otherwise answers from the cache or encodes its rows.
+/// CSV and NDJSON exports are streamed and never cached.
+fn dispatch<Q: Query + 'static>(req: Request, params: Params) -> Response {
+    let format = match Q::EXPORTABLE {
+        true => match Format::negotiate(&req) {
+            Some(format) => format,
+            None => return Response::new(406, "Not Acceptable"),
+        },
+        false => Format::Json,
+    };
+    let query_string = QueryString(req.query());
+    if let Some(name) = ADMIN_PARAMETERS.iter().find(|name| query_string.get(name).is_some()) {
+        let scopes = RequestContext::from_request(&req).scopes.unwrap_or_default();
+        if !scopes.iter().any(|s| s == SCOPE_ADMIN) {
+            return Response::new(403, format!("{} requires the {} scope", name, SCOPE_ADMIN));
+        }
+    }
+    let query = match Q::from_params(&params, &query_string) {
+        Ok(query) => query,
+        Err(e) => return Response::new(400, e),
+    };
+    let con = match Connection::open_default() {
+        Ok(con) => con,
+        Err(e) => return Response::new(500, e.to_string()),
+    };
+    if let Err(res) = consistency::check(&con, &req) {
+        return res;
+    }
+    // parameters the query does not read, or spelled differently, name the
+    // same representation
+    let target = format!("{:?}", query);
+    let validators = match Validators::of::<Q>(&con, &target, format) {
+        Ok(validators) => validators,
+        Err(e) => return Response::new(500, e.to_string()),
+    };
+    if validators.not_modified(&req) {
+        return validators.apply(Response::new(304, ()), Q::CACHE_CONTROL);
+    }
+
+    if format != Format::Json {
+        let export = move |send: &mut dyn FnMut(Vec<u8>) -> Result<()>| {
+            let mut writer = RowWriter::new(format, Chunks::new(send));
+            query.run(&con, &mut |row| writer.write(&row))?;
+            writer.finish()?;
+            Ok(())
+        };
+        let res = formats::stream(format, Q::NAME, Box::new(export));
+        let mut res = validators.apply(res, Q::CACHE_CONTROL);
+        res.set_header(CACHE_HEADER, "bypass");
+        return res;
+    }
+
+    let cache = Cache::open(&target, format);
+    let (res, outcome) = match &cache {
+        None => (execute(&con, &query), "bypass"),
+        Some(cache) => match cache.get(&validators.etag) {
+            Some(res) => (Ok(res), "hit"),
+            None => {
+                let res = execute(&con, &query);
+                if let Ok(res) = &res {
+                    cache.put(&validators.etag, res);
+                }
+                (res, "miss")
+            }
+        },
+    };
+    match res {
+        Ok(res) => {
+            let mut res = validators.apply(res, Q::CACHE_CONTROL);
+            res.set_header(CACHE_HEADER, outcome);
+            res
+        }
+        Err(e) => Response::new(500, e.to_string()),
+    }
+}
+
+fn execute<Q: Query>(con: &Connection, query: &Q) -> Result<Response> {
+    let mut writer = RowWriter::new(Format::Json, Vec::new());
+    query.run(con, &mut |row| writer.write(&row))?;
+    Ok(Format::Json.response(Q::NAME, writer.finish()?))
+}
+
+/// Validators of a representation, derived from the versions of the tables
+/// the query reads rather than from its body, so a `304` is answered without
+/// running the query
+struct Validators {
+    /// strong ETag: the same table versions always give the same bytes
+    etag: String,
+    /// unix time of the latest change to the tables
+    modified_at: i64,
+}
+
+impl Validators {
+    fn of<Q: Query>(con: &Connection, target: &str, format: Format) -> Result<Validators> {
+        let tables = persistence::table_versions(con, Q::TABLES)?;
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{}\n{:?}\n{:?}", target, format, tables.versions));
+        Ok(Validators {
+            etag: format!("\"{}\"", &hex::encode(hasher.finalize())[..32]),
+            modified_at: tables.modified_at,
+        })
+    }
+
+    /// Whether the client's copy is current: `If-None-Match` names the ETag,
+    /// or, without `If-None-Match`, nothing changed after `If-Modified-Since`
+    fn not_modified(&self, req: &Request) -> bool {
+        if let Some(tags) = req.header("if-none-match").and_then(|v| v.as_str()) {
+            return tags.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == self.etag
+            });
+        }
+        req.header("if-modified-since")
+            .and_then(|v| v.as_str())
+            .and_then(http_date::parse)
+            .is_some_and(|since| self.modified_at <= since)
+    }
+
+    /// Adds the validators and caching hints to a response
+    fn apply(&self, mut res: Response, cache_control: &str) -> Response {
+        res.set_header("ETag", self.etag.clone());
+        res.set_header("Last-Modified", http_date::format(self.modified_at));
+        res.set_header("Cache-Control", cache_control);
+        res
+    }
+}
+
+/// A registered query with its entry point monomorphized for the router
+#[derive(Clone, Copy)]
+pub(crate) struct Registration {
+    pub path: &'static str,
+    pub dispatch: fn(Request, Params) -> Response,
+}
+
+impl Registration {
+    fn of<Q: Query + 'static>() -> Registration {
+        Registration { path: Q::PATH, dispatch: dispatch::<Q> }
+    }
+}
+
+/// Every query
+pub(crate) fn registry() -> Vec<Registration> {
+    vec![
+        Registration::of::<AllEmployees>(),
+        Registration::of::<EmployeeById>(),
+        Registration::of::<AllLocations>(),
+        Registration::of::<LocationById>(),
+        Registration::of::<PersonsByLocation>(),
+        Registration::of::<AllPersons>(),
+        Registration::of::<PersonById>(),
+        Registration::of::<EmployeeHistory>(),
+        Registration::of::<LocationHistory>(),
+        Registration::of::<PersonHistory>(),
+        Registration::of::<AuditLog>(),
+    ]
+}
@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use spin_sdk::http::Response;
+use spin_sdk::key_value::Store;
+
+use crate::crypto;
+use crate::formats::Format;
+
+/// Key of the token `commands` replaces after every successful write. Cached
+/// responses stored under an older token are stale.
+const GENERATION_KEY: &str = "cache:generation";
+const ENTRY_PREFIX: &str = "cache:query:";
+/// Key of the generation whose older entries were last deleted
+const SWEPT_KEY: &str = "cache:swept";
+
+/// Header telling whether the response came from the cache
+pub(crate) const CACHE_HEADER: &str = "X-Cache";
+
+/// A serialized response as kept in the key-value store. Every read model
+/// shows personal data, so the body is encrypted with the keys of the
+/// database while they are configured.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    generation: String,
+    etag: String,
+    #[serde(rename = "contentType")]
+    content_type: String,
+    #[serde(rename = "contentDisposition")]
+    content_disposition: Option<String>,
+    body: String,
+}
+
+/// Cached responses of one query and format. Every query keeps one entry,
+/// overwritten when it is read after a write.
+pub(crate) struct Cache {
+    store: Store,
+    generation: String,
+    key: String,
+}
+
+impl Cache {
+    /// `None` when the key-value store is unavailable, in which case the
+    /// query runs uncached. `target` names the query and its parameters;
+    /// the key holds its hash, which keeps within the length of store keys.
+    pub(crate) fn open(target: &str, format: Format) -> Option<Cache> {
+        let store = Store::open_default().ok()?;
+        let generation = store
+            .get(GENERATION_KEY)
+            .ok()?
+            .map(|g| String::from_utf8_lossy(&g).into_owned())
+            .unwrap_or_default();
+        let key = format!("{}{}:{:?}", ENTRY_PREFIX, hex::encode(Sha256::digest(target)), format);
+        Some(Cache { store, generation, key })
+    }
+
+    /// The cached response, if it was stored since the last write and under
+    /// the current ETag. An entry of an older generation is deleted; one
+    /// encrypted with a key no longer configured is a miss.
+    pub(crate) fn get(&self, etag: &str) -> Option<Response> {
+        let entry: Entry = serde_json::from_slice(&self.store.get(&self.key).ok()??).ok()?;
+        if entry.generation != self.generation {
+            let _ = self.store.delete(&self.key);
+            return None;
+        }
+        if entry.etag != etag {
+            return None;
+        }
+        let body = crypto::open_payload(entry.body.as_bytes()).ok()?;
+        let mut builder = Response::builder();
+        builder.status(200).header("Content-Type", entry.content_type);
+        if let Some(disposition) = entry.content_disposition {
+            builder.header("Content-Disposition", disposition);
+        }
+        Some(builder.body(body).build())
+    }
+
+    /// Stores a successful response. Failures only cost the next request a
+    /// cache miss.
+    pub(crate) fn put(&self, etag: &str, res: &Response) {
+        if *res.status() != 200 {
+            return;
+        }
+        let header = |name: &str| res.header(name).and_then(|v| v.as_str()).map(String::from);
+        let body = crypto::seal_payload(res.body()).ok().and_then(|body| String::from_utf8(body).ok());
+        let (Some(content_type), Some(body)) = (header("content-type"), body) else {
+            return;
+        };
+        let entry = Entry {
+            generation: self.generation.clone(),
+            etag: etag.to_string(),
+            content_type,
+            content_disposition: header("content-disposition"),
+            body,
+        };
+        if let Ok(value) = serde_json::to_vec(&entry) {
+            let _ = self.store.set(&self.key, &value);
+        }
+        let _ = self.sweep();
+    }
+
+    /// Deletes the entries of older generations, once per generation, so
+    /// that the responses of queries nobody repeats do not pile up
+    fn sweep(&self) -> anyhow::Result<()> {
+        if self.store.get(SWEPT_KEY)?.as_deref() == Some(self.generation.as_bytes()) {
+            return Ok(());
+        }
+        for key in self.store.get_keys()?.iter().filter(|key| key.starts_with(ENTRY_PREFIX)) {
+            let entry = self.store.get(key)?.and_then(|value| serde_json::from_slice::<Entry>(&value).ok());
+            if !matches!(entry, Some(entry) if entry.generation == self.generation) {
+                self.store.delete(key)?;
+            }
+        }
+        self.store.set(SWEPT_KEY, self.generation.as_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use spin_sdk::http::{Request, Response};
+use spin_sdk::sqlite::Connection;
+
+use crate::persistence;
+
+/// Header of the commit position `commands` answered a write with
+const TOKEN_HEADER: &str = "consistency-token";
+/// Seconds a client should wait before sending the token again
+const RETRY_AFTER: &str = "1";
+
+/// Checks that the read models include the write named by the request's
+/// consistency token. `Err` with the response to answer when the token is
+/// invalid or beyond the current commit position.
+pub(crate) fn check(con: &Connection, req: &Request) -> Result<(), Response> {
+    let Some(token) = req.header(TOKEN_HEADER).and_then(|v| v.as_str()) else {
+        return Ok(());
+    };
+    let Ok(position) = token.trim().parse::<i64>() else {
+        return Err(Response::new(400, format!("invalid {}", TOKEN_HEADER)));
+    };
+    match persistence::commit_position(con) {
+        Ok(current) => behind(position, current).map_or(Ok(()), Err),
+        Err(e) => Err(Response::new(500, e.to_string())),
+    }
+}
+
+/// `503` with `Retry-After` when the read models have not reached `position`
+/// yet. `commands` and `queries` share one database, so every committed write
+/// is visible at once and the client retries a token of a write still being
+/// committed. `RequestContext::finish` wraps the text in the JSON error body.
+fn behind(position: i64, current: i64) -> Option<Response> {
+    (current < position).then(|| {
+        let text = format!("the read models have not reached {} {} yet", TOKEN_HEADER, position);
+        let mut res = Response::new(503, text);
+        res.set_header("Retry-After", RETRY_AFTER);
+        res
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_up_to_the_commit_position_are_read() {
+        assert!(behind(41, 42).is_none());
+        assert!(behind(42, 42).is_none());
+    }
+
+    #[test]
+    fn tokens_beyond_the_commit_position_are_retried() {
+        let res = behind(43, 42).unwrap();
+        assert_eq!(*res.status(), 503);
+        assert_eq!(res.header("retry-after").and_then(|v| v.as_str()), Some(RETRY_AFTER));
+        assert_eq!(res.body(), b"the read models have not reached consistency-token 43 yet");
+    }
+}
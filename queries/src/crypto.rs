@@ -0,0 +1,40 @@
+use std::rc::Rc;
+
+use anyhow::Result;
+use serde_json::Value as JsonValue;
+use shared::crypto::{self, Keys, KEYS_VARIABLE};
+use spin_sdk::variables;
+
+/// The keys configured in the Spin variable shared with `commands`, read
+/// once per instance
+fn keys() -> Result<Rc<Keys>> {
+    crypto::keys(|| variables::get(KEYS_VARIABLE).unwrap_or_default())
+}
+
+/// Whether keys are configured, so names may be stored encrypted and SQL
+/// cannot order by them
+pub(crate) fn enabled() -> Result<bool> {
+    Ok(keys()?.enabled())
+}
+
+/// Decrypts a value; plain values are returned unchanged
+pub(crate) fn open(value: &str) -> Result<String> {
+    keys()?.open(value)
+}
+
+/// Encrypts a payload as a whole with the current key, such as a cached
+/// response; unchanged when encryption is off
+pub(crate) fn seal_payload(payload: &[u8]) -> Result<Vec<u8>> {
+    keys()?.seal_payload(payload)
+}
+
+/// Decrypts a payload; plain payloads are returned unchanged
+pub(crate) fn open_payload(payload: &[u8]) -> Result<Vec<u8>> {
+    keys()?.open_payload(payload)
+}
+
+/// Decrypts every encrypted string of a JSON document in place, such as the
+/// states of the history
+pub(crate) fn open_json(value: &mut JsonValue) -> Result<()> {
+    keys()?.open_json(value)
+}
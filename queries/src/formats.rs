@@ -0,0 +1,223 @@
+use std::cell::RefCell;
+use std::io::Write;
+
+use anyhow::Result;
+use serde::Serialize;
+use spin_sdk::http::{Request, Response};
+
+/// Size of the chunks an export is sent in
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Writes the rows of an export to the response body through `send`, which
+/// passes on every chunk as soon as it is full
+pub(crate) type Export = Box<dyn FnOnce(&mut dyn FnMut(Vec<u8>) -> Result<()>) -> Result<()>>;
+
+thread_local! {
+    /// Export of the request being handled, run once its headers are sent
+    static EXPORT: RefCell<Option<Export>> = const { RefCell::new(None) };
+}
+
+/// Representations of list query results, chosen through `Accept`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    Json,
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    /// The first acceptable format in the order the client listed them, JSON
+    /// when there is no `Accept`. `None` when nothing listed is supported.
+    pub(crate) fn negotiate(req: &Request) -> Option<Format> {
+        let Some(accept) = req.header("accept").and_then(|v| v.as_str()) else {
+            return Some(Format::Json);
+        };
+        accept
+            .split(',')
+            .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+            .find_map(|media_type| match media_type {
+                "text/csv" => Some(Format::Csv),
+                "application/x-ndjson" => Some(Format::Ndjson),
+                "application/json" | "application/*" | "*/*" => Some(Format::Json),
+                // versioned media types of the gateway, e.g. application/vnd.cqrs.v2+json
+                t if t.starts_with("application/vnd.") && t.ends_with("+json") => Some(Format::Json),
+                _ => None,
+            })
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// A `200` response of this format carrying `body`. `name` is used for
+    /// the file name of CSV downloads.
+    pub(crate) fn response(&self, name: &str, body: Vec<u8>) -> Response {
+        let mut builder = Response::builder();
+        builder.status(200).header("Content-Type", self.content_type());
+        if *self == Format::Csv {
+            builder.header("Content-Disposition", format!("attachment; filename=\"{}.csv\"", name));
+        }
+        builder.body(body).build()
+    }
+}
+
+/// Encodes rows into `out` as they are read from the query result, without
+/// building a list of models first. CSV columns are the serialized field
+/// names of the row model.
+pub(crate) struct RowWriter<W: Write> {
+    format: Format,
+    out: Output<W>,
+    rows: usize,
+}
+
+enum Output<W: Write> {
+    Plain(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> RowWriter<W> {
+    pub(crate) fn new(format: Format, out: W) -> RowWriter<W> {
+        let out = match format {
+            Format::Csv => Output::Csv(Box::new(csv::Writer::from_writer(out))),
+            _ => Output::Plain(out),
+        };
+        RowWriter { format, out, rows: 0 }
+    }
+
+    pub(crate) fn write<T: Serialize>(&mut self, row: &T) -> Result<()> {
+        match (&mut self.out, self.format) {
+            (Output::Csv(csv), _) => csv.serialize(row)?,
+            (Output::Plain(out), Format::Ndjson) => {
+                serde_json::to_writer(&mut *out, row)?;
+                out.write_all(b"\n")?;
+            }
+            (Output::Plain(out), _) => {
+                out.write_all(if self.rows == 0 { b"[" } else { b"," })?;
+                serde_json::to_writer(&mut *out, row)?;
+            }
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Ends the encoding and hands back `out`, flushed
+    pub(crate) fn finish(self) -> Result<W> {
+        let mut out = match self.out {
+            Output::Csv(csv) => csv.into_inner().map_err(|e| anyhow::anyhow!(e.to_string()))?,
+            Output::Plain(mut out) => {
+                match self.format {
+                    Format::Json if self.rows == 0 => out.write_all(b"[]")?,
+                    Format::Json => out.write_all(b"]")?,
+                    _ => {}
+                }
+                out
+            }
+        };
+        out.flush()?;
+        Ok(out)
+    }
+}
+
+/// Collects the bytes written to it into chunks of `CHUNK_SIZE` and passes
+/// each on as soon as it is full, and the rest when flushed
+pub(crate) struct Chunks<'a> {
+    chunk: Vec<u8>,
+    send: &'a mut dyn FnMut(Vec<u8>) -> Result<()>,
+}
+
+impl<'a> Chunks<'a> {
+    pub(crate) fn new(send: &'a mut dyn FnMut(Vec<u8>) -> Result<()>) -> Chunks<'a> {
+        Chunks { chunk: Vec::with_capacity(CHUNK_SIZE), send }
+    }
+}
+
+impl Write for Chunks<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.chunk.extend_from_slice(buf);
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+        (self.send)(chunk).map_err(std::io::Error::other)
+    }
+}
+
+/// Answers with the headers of an export of `format` and keeps `export` to
+/// write its rows once they are sent, so the body is streamed rather than
+/// built in memory
+pub(crate) fn stream(format: Format, name: &str, export: Export) -> Response {
+    EXPORT.with(|pending| *pending.borrow_mut() = Some(export));
+    format.response(name, Vec::new())
+}
+
+/// The export kept by `stream` for the request just handled
+pub(crate) fn take_export() -> Option<Export> {
+    EXPORT.with(|pending| pending.borrow_mut().take())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Row {
+        id: u32,
+        name: &'static str,
+    }
+
+    fn encode(format: Format, rows: &[Row]) -> String {
+        let mut writer = RowWriter::new(format, Vec::new());
+        for row in rows {
+            writer.write(row).unwrap();
+        }
+        String::from_utf8(writer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn rows_are_encoded_in_the_format() {
+        let rows = [Row { id: 1, name: "Doe, Jane" }, Row { id: 2, name: "Roe" }];
+        assert_eq!(encode(Format::Json, &rows), r#"[{"id":1,"name":"Doe, Jane"},{"id":2,"name":"Roe"}]"#);
+        let ndjson = "{\"id\":1,\"name\":\"Doe, Jane\"}\n{\"id\":2,\"name\":\"Roe\"}\n";
+        assert_eq!(encode(Format::Ndjson, &rows), ndjson);
+        assert_eq!(encode(Format::Csv, &rows), "id,name\n1,\"Doe, Jane\"\n2,Roe\n");
+    }
+
+    #[test]
+    fn no_rows_are_encoded_as_an_empty_list() {
+        assert_eq!(encode(Format::Json, &[]), "[]");
+        assert_eq!(encode(Format::Ndjson, &[]), "");
+        assert_eq!(encode(Format::Csv, &[]), "");
+    }
+
+    #[test]
+    fn chunks_are_sent_when_full_and_when_flushed() {
+        let mut sent: Vec<usize> = Vec::new();
+        let mut send = |chunk: Vec<u8>| {
+            sent.push(chunk.len());
+            Ok(())
+        };
+        let mut writer = RowWriter::new(Format::Ndjson, Chunks::new(&mut send));
+        for id in 0..2_000 {
+            writer.write(&Row { id, name: "Doe, Jane" }).unwrap();
+        }
+        writer.finish().unwrap();
+        let row = |id: u32| format!("{{\"id\":{id},\"name\":\"Doe, Jane\"}}\n");
+        let total: usize = (0..2_000).map(|id| row(id).len()).sum();
+        assert_eq!(sent.iter().sum::<usize>(), total);
+        assert!(sent.len() > 1);
+        assert!(sent[..sent.len() - 1].iter().all(|len| *len >= CHUNK_SIZE));
+    }
+}
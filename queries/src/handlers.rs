@@ -0,0 +1,321 @@
+use anyhow::Result;
+use shared::time;
+use spin_sdk::http::Params;
+use spin_sdk::sqlite::Connection;
+
+use crate::bus::{Query, QueryString};
+use crate::models::{AuditRecordModel, EmployeeDetailsModel, EmployeeListModel, EntityVersionModel,
+                    LocationDetailsModel, PersonDetailsModel, PersonListModel};
+use crate::persistence::{self, Filters, Sink};
+
+fn param(params: &Params, name: &str) -> std::result::Result<String, String> {
+    params.get(name).map(String::from).ok_or_else(|| format!("{} is required", name))
+}
+
+/// A time filter of the query string: a UTC date (`2026-10-01`) or time
+/// (`2026-10-01T08:30:00Z`), compared as text with the stamps
+fn utc_time(query: &QueryString, name: &str) -> std::result::Result<Option<String>, String> {
+    let Some(value) = query.get(name) else {
+        return Ok(None);
+    };
+    time::check_utc_time(name, &value)?;
+    Ok(Some(value))
+}
+
+/// The `updatedSince` filter of list queries
+fn updated_since(query: &QueryString) -> std::result::Result<Option<String>, String> {
+    utc_time(query, "updatedSince")
+}
+
+/// The `asOf` parameter: shows the state at that time, read from the history
+/// of the entities. A date stands for the start of that day.
+fn as_of(query: &QueryString) -> std::result::Result<Option<String>, String> {
+    utc_time(query, "asOf")
+}
+
+/// The `includeDeleted` flag: also shows soft-deleted employees and persons.
+/// The gateway and `dispatch` let only admins set it.
+fn include_deleted(query: &QueryString) -> std::result::Result<bool, String> {
+    match query.get("includeDeleted").as_deref() {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(_) => Err("includeDeleted must be true or false".to_string()),
+    }
+}
+
+/// The filters of the employee and person lists
+fn list_filters(query: &QueryString) -> std::result::Result<Filters, String> {
+    Ok(Filters {
+        as_of: as_of(query)?,
+        updated_since: updated_since(query)?,
+        include_deleted: include_deleted(query)?,
+    })
+}
+
+/// The filters of a single employee or person
+fn single_filters(query: &QueryString) -> std::result::Result<Filters, String> {
+    Ok(Filters { as_of: as_of(query)?, include_deleted: include_deleted(query)?, ..Filters::default() })
+}
+
+/// A positive number of the query string, `default` when absent
+fn number(query: &QueryString, name: &str, default: i64) -> std::result::Result<i64, String> {
+    match query.get(name) {
+        None => Ok(default),
+        Some(value) => value
+            .parse()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| format!("{} must be a positive number", name)),
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AllEmployees(Filters);
+
+impl Query for AllEmployees {
+    const PATH: &'static str = "/employees";
+    const NAME: &'static str = "employees";
+    const TABLES: &'static [&'static str] = &["Employees", "Addresses", "Deletions"];
+    const EXPORTABLE: bool = true;
+    type Row = EmployeeListModel;
+
+    fn from_params(_: &Params, query: &QueryString) -> std::result::Result<Self, String> {
+        Ok(AllEmployees(list_filters(query)?))
+    }
+
+    fn run(&self, con: &Connection, sink: Sink<Self::Row>) -> Result<()> {
+        persistence::all_employees(con, &self.0, sink)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct EmployeeById {
+    id: String,
+    filters: Filters,
+}
+
+impl Query for EmployeeById {
+    const PATH: &'static str = "/employees/:id";
+    const NAME: &'static str = "employee";
+    const TABLES: &'static [&'static str] = &["Employees", "Addresses", "Deletions"];
+    type Row = EmployeeDetailsModel;
+
+    fn from_params(params: &Params, query: &QueryString) -> std::result::Result<Self, String> {
+        Ok(EmployeeById { id: param(params, "id")?, filters: single_filters(query)? })
+    }
+
+    fn run(&self, con: &Connection, sink: Sink<Self::Row>) -> Result<()> {
+        persistence::employee_by_id(con, &self.id, &self.filters, sink)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AllLocations(Filters);
+
+impl Query for AllLocations {
+    const PATH: &'static str = "/locations";
+    const NAME: &'static str = "locations";
+    const TABLES: &'static [&'static str] = &["Locations"];
+    const EXPORTABLE: bool = true;
+    type Row = LocationDetailsModel;
+
+    fn from_params(_: &Params, query: &QueryString) -> std::result::Result<Self, String> {
+        Ok(AllLocations(Filters {
+            as_of: as_of(query)?,
+            updated_since: updated_since(query)?,
+            ..Filters::default()
+        }))
+    }
+
+    fn run(&self, con: &Connection, sink: Sink<Self::Row>) -> Result<()> {
+        persistence::all_locations(con, &self.0, sink)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct LocationById {
+    lid: String,
+    filters: Filters,
+}
+
+impl Query for LocationById {
+    const PATH: &'static str = "/locations/:lid";
+    const NAME: &'static str = "location";
+    const TABLES: &'static [&'static str] = &["Locations"];
+    type Row = LocationDetailsModel;
+
+    fn from_params(params: &Params, query: &QueryString) -> std::result::Result<Self, String> {
+        Ok(LocationById {
+            lid: param(params, "lid")?,
+            filters: Filters { as_of: as_of(query)?, ..Filters::default() },
+        })
+    }
+
+    fn run(&self, con: &Connection, sink: Sink<Self::Row>) -> Result<()> {
+        persistence::location_by_id(con, &self.lid, &self.filters, sink)
+    }
+}
+
+/// The persons living at a location, by name
+#[derive(Debug)]
+pub(crate) struct PersonsByLocation {
+    lid: String,
+    filters: Filters,
+}
+
+impl Query for PersonsByLocation {
+    const PATH: &'static str = "/locations/:lid/persons";
+    const NAME: &'static str = "persons";
+    const TABLES: &'static [&'static str] = &["Persons", "Locations", "Deletions"];
+    const EXPORTABLE: bool = true;
+    type Row = PersonListModel;
+
+    fn from_params(params: &Params, query: &QueryString) -> std::result::Result<Self, String> {
+        Ok(PersonsByLocation { lid: param(params, "lid")?, filters: list_filters(query)? })
+    }
+
+    fn run(&self, con: &Connection, sink: Sink<Self::Row>) -> Result<()> {
+        persistence::persons_by_location(con, &self.lid, &self.filters, sink)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AllPersons(Filters);
+
+impl Query for AllPersons {
+    const PATH: &'static str = "/persons";
+    const NAME: &'static str = "persons";
+    const TABLES: &'static [&'static str] = &["Persons", "Locations", "Deletions"];
+    const EXPORTABLE: bool = true;
+    type Row = PersonListModel;
+
+    fn from_params(_: &Params, query: &QueryString) -> std::result::Result<Self, String> {
+        Ok(AllPersons(list_filters(query)?))
+    }
+
+    fn run(&self, con: &Connection, sink: Sink<Self::Row>) -> Result<()> {
+        persistence::all_persons(con, &self.0, sink)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct PersonById {
+    pid: String,
+    filters: Filters,
+}
+
+impl Query for PersonById {
+    const PATH: &'static str = "/persons/:pid";
+    const NAME: &'static str = "person";
+    const TABLES: &'static [&'static str] = &["Persons", "Locations", "Deletions"];
+    type Row = PersonDetailsModel;
+
+    fn from_params(params: &Params, query: &QueryString) -> std::result::Result<Self, String> {
+        Ok(PersonById { pid: param(params, "pid")?, filters: single_filters(query)? })
+    }
+
+    fn run(&self, con: &Connection, sink: Sink<Self::Row>) -> Result<()> {
+        persistence::person_by_id(con, &self.pid, &self.filters, sink)
+    }
+}
+
+/// Every version of an employee, oldest first
+#[derive(Debug)]
+pub(crate) struct EmployeeHistory(String);
+
+impl Query for EmployeeHistory {
+    const PATH: &'static str = "/employees/:id/history";
+    const NAME: &'static str = "history";
+    const TABLES: &'static [&'static str] = &["EntityVersions"];
+    type Row = EntityVersionModel;
+
+    fn from_params(params: &Params, _: &QueryString) -> std::result::Result<Self, String> {
+        Ok(EmployeeHistory(param(params, "id")?))
+    }
+
+    fn run(&self, con: &Connection, sink: Sink<Self::Row>) -> Result<()> {
+        persistence::history(con, "employees", &self.0, sink)
+    }
+}
+
+/// Every version of a location, oldest first
+#[derive(Debug)]
+pub(crate) struct LocationHistory(String);
+
+impl Query for LocationHistory {
+    const PATH: &'static str = "/locations/:lid/history";
+    const NAME: &'static str = "history";
+    const TABLES: &'static [&'static str] = &["EntityVersions"];
+    type Row = EntityVersionModel;
+
+    fn from_params(params: &Params, _: &QueryString) -> std::result::Result<Self, String> {
+        Ok(LocationHistory(param(params, "lid")?))
+    }
+
+    fn run(&self, con: &Connection, sink: Sink<Self::Row>) -> Result<()> {
+        persistence::history(con, "locations", &self.0, sink)
+    }
+}
+
+/// Every version of a person, oldest first
+#[derive(Debug)]
+pub(crate) struct PersonHistory(String);
+
+impl Query for PersonHistory {
+    const PATH: &'static str = "/persons/:pid/history";
+    const NAME: &'static str = "history";
+    const TABLES: &'static [&'static str] = &["EntityVersions"];
+    type Row = EntityVersionModel;
+
+    fn from_params(params: &Params, _: &QueryString) -> std::result::Result<Self, String> {
+        Ok(PersonHistory(param(params, "pid")?))
+    }
+
+    fn run(&self, con: &Connection, sink: Sink<Self::Row>) -> Result<()> {
+        persistence::history(con, "persons", &self.0, sink)
+    }
+}
+
+/// Largest page of the audit log
+const MAX_AUDIT_PAGE: i64 = 200;
+
+/// Records of the audit log, oldest first, filtered by entity, target id,
+/// actor and a time range where `from` is inclusive and `to` exclusive.
+/// Pages hold `limit` records; the next page starts `after` the `seq` of the
+/// last record.
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    filter: persistence::AuditFilter,
+}
+
+impl Query for AuditLog {
+    const PATH: &'static str = "/audit";
+    const NAME: &'static str = "audit";
+    const TABLES: &'static [&'static str] = &["AuditLog"];
+    type Row = AuditRecordModel;
+
+    fn from_params(_: &Params, query: &QueryString) -> std::result::Result<Self, String> {
+        let limit = number(query, "limit", 50)?;
+        if limit > MAX_AUDIT_PAGE {
+            return Err(format!("limit must be at most {}", MAX_AUDIT_PAGE));
+        }
+        Ok(AuditLog {
+            filter: persistence::AuditFilter {
+                entity: query.get("entity"),
+                id: query.get("id"),
+                actor: query.get("actor"),
+                from: utc_time(query, "from")?,
+                to: utc_time(query, "to")?,
+                after: match query.get("after") {
+                    None => 0,
+                    Some(after) => after.parse().map_err(|_| "after must be the seq of an audit record")?,
+                },
+                limit,
+            },
+        })
+    }
+
+    fn run(&self, con: &Connection, sink: Sink<Self::Row>) -> Result<()> {
+        persistence::audit_log(con, &self.filter, sink)
+    }
+}
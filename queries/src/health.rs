@@ -0,0 +1,68 @@
+use anyhow::Result;
+use spin_sdk::http::{IntoResponse, Params, Request, ResponseBuilder};
+use shared::schema;
+use spin_sdk::sqlite::Connection;
+
+use crate::models::{BuildModel, CheckModel, DiagnosticsModel, HealthModel, MigrationModel, SchemaModel};
+use crate::persistence;
+
+const COMPONENT: &str = "queries";
+
+fn check(name: &str, result: Result<()>) -> CheckModel {
+    CheckModel {
+        name: name.to_string(),
+        status: if result.is_ok() { "ok" } else { "fail" }.to_string(),
+        detail: result.err().map(|e| e.to_string()),
+    }
+}
+
+/// Runs the component's checks: SQLite opens and the schema version matches.
+fn run_checks() -> (HealthModel, Option<MigrationModel>) {
+    let sqlite = Connection::open_default().map(|_| ()).map_err(anyhow::Error::from);
+    let migration = persistence::last_migration();
+    let schema = match &migration {
+        Ok(applied) => schema::check(applied.as_ref().map(|m| m.version)).map_err(anyhow::Error::msg),
+        Err(e) => Err(anyhow::anyhow!("{}", e)),
+    };
+
+    let checks = vec![check("sqlite", sqlite), check("schema", schema)];
+    let status = if checks.iter().all(|c| c.status == "ok") { "ok" } else { "fail" };
+    let health = HealthModel {
+        component: COMPONENT.to_string(),
+        status: status.to_string(),
+        checks,
+    };
+    (health, migration.ok().flatten())
+}
+
+#[tracing::instrument(name="health", skip_all)]
+pub(crate) fn health(_req: Request, _: Params) -> Result<impl IntoResponse> {
+    let (health, _) = run_checks();
+    let status = if health.status == "ok" { 200 } else { 503 };
+    Ok(ResponseBuilder::new(status)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&health)?)
+        .build())
+}
+
+#[tracing::instrument(name="diagnostics", skip_all)]
+pub(crate) fn diagnostics(_req: Request, _: Params) -> Result<impl IntoResponse> {
+    let (health, last_migration) = run_checks();
+    let row_counts = persistence::entity_counts()?;
+    let diagnostics = DiagnosticsModel {
+        health,
+        build: BuildModel {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+        schema: SchemaModel {
+            expected: schema::expected_version(),
+            last_migration,
+        },
+        row_counts,
+    };
+    Ok(ResponseBuilder::new(200)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&diagnostics)?)
+        .build())
+}
@@ -1,46 +1,100 @@
+mod bus;
+mod cache;
+mod consistency;
+mod crypto;
+mod formats;
+mod handlers;
+mod health;
 mod models;
+mod openapi;
 mod persistence;
 
-use spin_sdk::http::{IntoResponse, Params, Request, Router};
+use anyhow::anyhow;
+use futures::SinkExt;
+use spin_sdk::http::{
+    run, IntoResponse, OutgoingResponse, Params, Request, Response, ResponseOutparam, Router,
+};
 use spin_sdk::http_component;
+use telemetry::{RequestContext, Telemetry, TraceParent};
 
-#[tracing::instrument(name="handle_queries", skip_all)]
-#[http_component]
-fn handle_queries(req: Request) -> anyhow::Result<impl IntoResponse> {
-    let mut router = Router::default();
+use formats::Export;
 
-    // register routes for queries
-    router.get("/employees",      all_employees);
-    router.get("/employees/:id",  employee_by_id);
-    router.get("/locations",      all_locations);
-    router.get("/persons",        all_persons);
-    router.get("/locations/:lid", location_by_id);
-    router.get("/persons/:pid",   person_by_id);
- 
-    // handle all the requests
-    Ok(router.handle(req))
+#[http_component]
+async fn handle_queries(req: Request, response_out: ResponseOutparam) {
+    let ctx = RequestContext::from_request(&req);
+    let telemetry = Telemetry::new("queries", TraceParent::from_header(ctx.traceparent.as_deref()));
+    let res = telemetry.in_scope(|| queries(&ctx, req));
+    match formats::take_export() {
+        Some(export) => telemetry.in_scope(|| stream(&ctx, res, export, response_out)),
+        None => {
+            if let Err(e) = respond(res, response_out).await {
+                telemetry.in_scope(|| ctx.log(format!("queries: response not sent: {e}")));
+            }
+        }
+    }
+    telemetry.flush();
 }
 
-fn all_employees(_req: Request, _param: Params) -> anyhow::Result<impl IntoResponse> {
-    persistence::pall_employees()
+/// Sends a response whose body is complete
+async fn respond(mut res: Response, response_out: ResponseOutparam) -> anyhow::Result<()> {
+    let body = std::mem::take(res.body_mut());
+    response_out.set_with_body(res.into(), body).await?;
+    Ok(())
 }
 
-fn employee_by_id(_req:Request, params: Params) -> anyhow::Result<impl IntoResponse> {
-    persistence::pemployee_by_id(params)
+/// Sends the headers of an export with its first chunk, so an export failing
+/// before any row is encoded still answers `500`. A failure after that ends
+/// the body early and is logged.
+fn stream(ctx: &RequestContext, res: Response, export: Export, response_out: ResponseOutparam) {
+    let mut head = Some((res, response_out));
+    let mut body = None;
+    let sent = export(&mut |chunk| {
+        if let Some((res, response_out)) = head.take() {
+            let outgoing = OutgoingResponse::from(res);
+            body = Some(outgoing.take_body());
+            response_out.set(outgoing);
+        }
+        match body.as_mut() {
+            Some(body) => run(body.send(chunk)).map_err(|e| anyhow!("{e}")),
+            None => Ok(()),
+        }
+    });
+    let sent = match (sent, head) {
+        // no rows: the headers go out on their own
+        (Ok(()), Some((res, response_out))) => run(respond(res, response_out)),
+        (Err(e), Some((_, response_out))) => {
+            let res = ctx.finish("queries", Response::new(500, e.to_string()));
+            run(respond(res, response_out))
+        }
+        (Ok(()), None) => match body {
+            Some(mut body) => run(body.close()).map_err(|e| anyhow!("{e}")),
+            None => Ok(()),
+        },
+        (Err(e), None) => Err(e),
+    };
+    if let Err(e) = sent {
+        ctx.log(format!("queries: export not sent: {e}"));
+    }
 }
 
-fn all_locations(_req: Request, _param: Params) -> anyhow::Result<impl IntoResponse> {
-    persistence::pall_locations()
-}
+#[tracing::instrument(name="handle_queries", skip_all,
+                      fields(otel.kind = "server", request_id = %ctx.request_id))]
+fn queries(ctx: &RequestContext, req: Request) -> Response {
+    let mut router = Router::default();
 
-fn all_persons(_req: Request, _param: Params) -> anyhow::Result<impl IntoResponse> {
-    persistence::pall_persons()
+    // register routes for queries
+    for registration in bus::registry() {
+        router.get(registration.path, registration.dispatch);
+    }
+    router.get("/counts",         entity_counts);
+    router.get("/health",         health::health);
+    router.get("/diagnostics",    health::diagnostics);
+    router.get("/openapi",        openapi::openapi);
+ 
+    // handle all the requests
+    ctx.finish("queries", router.handle(req))
 }
 
-fn location_by_id(_req:Request, params: Params) -> anyhow::Result<impl IntoResponse> {
-    persistence::plocation_by_id(params)
+fn entity_counts(_req: Request, _param: Params) -> anyhow::Result<impl IntoResponse> {
+    persistence::pentity_counts()
 }
-
-fn person_by_id(_req:Request, params: Params) -> anyhow::Result<impl IntoResponse> {
-    persistence::pperson_by_id(params)
-}
\ No newline at end of file
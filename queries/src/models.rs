@@ -1,13 +1,33 @@
+use std::collections::BTreeMap;
+
 use serde::Serialize;
+use utoipa::ToSchema;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct EmployeeListModel {
     pub id: String,
     pub name: String,
+    #[serde(rename = "firstName")]
+    pub first_name: String,
+    #[serde(rename = "lastName")]
+    pub last_name: String,
     pub city: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<String>,
+    #[serde(rename = "createdBy")]
+    pub created_by: Option<String>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<String>,
+    #[serde(rename = "updatedBy")]
+    pub updated_by: Option<String>,
+    /// set when the caller asked for deleted entities too
+    #[serde(rename = "deletedAt")]
+    pub deleted_at: Option<String>,
+    #[serde(rename = "deletedBy")]
+    pub deleted_by: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct EmployeeDetailsModel {
     pub id: String,
     #[serde(rename = "firstName")]
@@ -15,24 +35,62 @@ pub struct EmployeeDetailsModel {
     #[serde(rename = "lastName")]
     pub last_name: String,
     pub address: AddressDetailsModel,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<String>,
+    #[serde(rename = "createdBy")]
+    pub created_by: Option<String>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<String>,
+    #[serde(rename = "updatedBy")]
+    pub updated_by: Option<String>,
+    /// set when the caller asked for deleted entities too
+    #[serde(rename = "deletedAt")]
+    pub deleted_at: Option<String>,
+    #[serde(rename = "deletedBy")]
+    pub deleted_by: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct AddressDetailsModel {
     pub id: String,
     pub street: String,
     pub city: String,
     pub zip: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<String>,
+    #[serde(rename = "createdBy")]
+    pub created_by: Option<String>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<String>,
+    #[serde(rename = "updatedBy")]
+    pub updated_by: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct PersonListModel {
     pub pid: String,
     pub name: String,
+    #[serde(rename = "firstName")]
+    pub first_name: String,
+    #[serde(rename = "lastName")]
+    pub last_name: String,
     pub city: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<String>,
+    #[serde(rename = "createdBy")]
+    pub created_by: Option<String>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<String>,
+    #[serde(rename = "updatedBy")]
+    pub updated_by: Option<String>,
+    /// set when the caller asked for deleted entities too
+    #[serde(rename = "deletedAt")]
+    pub deleted_at: Option<String>,
+    #[serde(rename = "deletedBy")]
+    pub deleted_by: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct PersonDetailsModel {
     pub pid: String,
     #[serde(rename = "firstName")]
@@ -40,12 +98,118 @@ pub struct PersonDetailsModel {
     #[serde(rename = "lastName")]
     pub last_name: String,
     pub address: LocationDetailsModel,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<String>,
+    #[serde(rename = "createdBy")]
+    pub created_by: Option<String>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<String>,
+    #[serde(rename = "updatedBy")]
+    pub updated_by: Option<String>,
+    /// set when the caller asked for deleted entities too
+    #[serde(rename = "deletedAt")]
+    pub deleted_at: Option<String>,
+    #[serde(rename = "deletedBy")]
+    pub deleted_by: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct LocationDetailsModel {
     pub lid: String,
     pub street: String,
     pub city: String,
     pub zip: String,
-}
\ No newline at end of file
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<String>,
+    #[serde(rename = "createdBy")]
+    pub created_by: Option<String>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<String>,
+    #[serde(rename = "updatedBy")]
+    pub updated_by: Option<String>,
+}
+
+/// A version in the history of an entity
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EntityVersionModel {
+    pub version: i64,
+    pub at: String,
+    pub actor: String,
+    /// created, updated, deleted or undeleted
+    pub operation: String,
+    /// the entity as of this version, absent once deleted
+    #[schema(value_type = Object)]
+    pub state: Option<serde_json::Value>,
+}
+
+/// A command as recorded in the audit log
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AuditRecordModel {
+    /// position in the log, the cursor of the next page
+    pub seq: i64,
+    pub at: String,
+    /// API key id of the caller, `system` for queued commands
+    pub actor: String,
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub command: String,
+    pub entity: String,
+    #[serde(rename = "targetId")]
+    pub target_id: Option<String>,
+    /// the entity before the command, absent for creations
+    #[schema(value_type = Object)]
+    pub before: Option<serde_json::Value>,
+    /// the entity after the command, absent for deletions and commands that
+    /// did not change it
+    #[schema(value_type = Object)]
+    pub after: Option<serde_json::Value>,
+    pub status: i64,
+    /// succeeded, rejected, failed, replayed or rolledBack
+    pub outcome: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthModel {
+    pub component: String,
+    pub status: String,
+    pub checks: Vec<CheckModel>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckModel {
+    pub name: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiagnosticsModel {
+    #[serde(flatten)]
+    pub health: HealthModel,
+    pub build: BuildModel,
+    pub schema: SchemaModel,
+    #[serde(rename = "rowCounts")]
+    pub row_counts: BTreeMap<&'static str, i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BuildModel {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SchemaModel {
+    pub expected: i64,
+    #[serde(rename = "lastMigration")]
+    pub last_migration: Option<MigrationModel>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrationModel {
+    pub version: i64,
+    pub name: String,
+    #[serde(rename = "appliedAt")]
+    pub applied_at: String,
+}
@@ -0,0 +1,22 @@
+use anyhow::Result;
+use spin_sdk::http::{IntoResponse, Params, Request, ResponseBuilder};
+use utoipa::OpenApi;
+
+use crate::models::*;
+
+/// Response models of the queries, merged by the gateway into the API
+/// specification it serves
+#[derive(OpenApi)]
+#[openapi(components(schemas(
+    EmployeeListModel, EmployeeDetailsModel, AddressDetailsModel,
+    PersonListModel, PersonDetailsModel, LocationDetailsModel, AuditRecordModel,
+    EntityVersionModel,
+)))]
+struct QueriesApi;
+
+pub(crate) fn openapi(_req: Request, _: Params) -> Result<impl IntoResponse> {
+    Ok(ResponseBuilder::new(200)
+        .header("Content-Type", "application/json")
+        .body(QueriesApi::openapi().to_json()?)
+        .build())
+}
@@ -1,251 +1,433 @@
+use std::collections::BTreeMap;
+
 use anyhow::anyhow;
-use spin_sdk::sqlite::{Connection, Value};
-use spin_sdk::http::{IntoResponse, Params, Response};
+use spin_sdk::sqlite::{Connection, Error, QueryResult, Row, Value};
+use spin_sdk::http::{IntoResponse, Response};
 
-use crate::models::{AddressDetailsModel, EmployeeDetailsModel, EmployeeListModel,
-                    LocationDetailsModel, PersonDetailsModel, PersonListModel};
+use crate::crypto;
+use crate::models::{AddressDetailsModel, AuditRecordModel, EmployeeDetailsModel, EmployeeListModel,
+                    EntityVersionModel, LocationDetailsModel, MigrationModel, PersonDetailsModel,
+                    PersonListModel};
 
 const QUERY_ALL_EMPLOYEE_COMMAND: &str =
-    "SELECT Employees.Id, Employees.LastName || ', ' || Employees.FirstName Name, Addresses.City FROM Employees INNER JOIN Addresses ON Employees.Id = Addresses.EmployeeId ORDER BY NAME ASC";
+    "SELECT Employees.Id, Employees.LastName || ', ' || Employees.FirstName Name, Employees.FirstName, Employees.LastName, Addresses.City, Stamps.CreatedAt, Stamps.CreatedBy, Stamps.UpdatedAt, Stamps.UpdatedBy, Deletions.DeletedAt, Deletions.DeletedBy FROM Employees INNER JOIN Addresses ON Employees.Id = Addresses.EmployeeId LEFT JOIN ChangeStamps Stamps ON Stamps.Entity = 'Employees' AND Stamps.Id = Employees.Id LEFT JOIN Deletions ON Deletions.Entity = 'Employees' AND Deletions.Id = Employees.Id WHERE (?1 IS NULL OR Stamps.UpdatedAt >= ?1) AND (?2 IS NOT NULL OR Deletions.Id IS NULL) ORDER BY NAME ASC";
 const QUERY_SINGLE_EMPLOYEE_COMMAND: &str = 
-    "SELECT Employees.Id, Employees.FirstName, Employees.LastName, Addresses.Street, Addresses.Zip, Addresses.City FROM Employees INNER JOIN Addresses ON Employees.Id = Addresses.EmployeeId WHERE Employees.Id = ?";
+    "SELECT Employees.Id, Employees.FirstName, Employees.LastName, Addresses.Street, Addresses.Zip, Addresses.City, Stamps.CreatedAt, Stamps.CreatedBy, Stamps.UpdatedAt, Stamps.UpdatedBy, AddressStamps.CreatedAt AddressCreatedAt, AddressStamps.CreatedBy AddressCreatedBy, AddressStamps.UpdatedAt AddressUpdatedAt, AddressStamps.UpdatedBy AddressUpdatedBy, Deletions.DeletedAt, Deletions.DeletedBy FROM Employees INNER JOIN Addresses ON Employees.Id = Addresses.EmployeeId LEFT JOIN ChangeStamps Stamps ON Stamps.Entity = 'Employees' AND Stamps.Id = Employees.Id LEFT JOIN ChangeStamps AddressStamps ON AddressStamps.Entity = 'Addresses' AND AddressStamps.Id = Addresses.EmployeeId LEFT JOIN Deletions ON Deletions.Entity = 'Employees' AND Deletions.Id = Employees.Id WHERE Employees.Id = ?1 AND (?2 IS NOT NULL OR Deletions.Id IS NULL)";
 const QUERY_ALL_PERSON_COMMAND: &str =
-    "SELECT Persons.Pid, Persons.LastName || ', ' || Persons.FirstName Name, Locations.City FROM Locations INNER JOIN Persons ON Locations.Lid = Persons.Plid ORDER BY NAME ASC";
+    "SELECT Persons.Pid, Persons.LastName || ', ' || Persons.FirstName Name, Persons.FirstName, Persons.LastName, Locations.City, Stamps.CreatedAt, Stamps.CreatedBy, Stamps.UpdatedAt, Stamps.UpdatedBy, Deletions.DeletedAt, Deletions.DeletedBy FROM Locations INNER JOIN Persons ON Locations.Lid = Persons.Plid LEFT JOIN ChangeStamps Stamps ON Stamps.Entity = 'Persons' AND Stamps.Id = Persons.Pid LEFT JOIN Deletions ON Deletions.Entity = 'Persons' AND Deletions.Id = Persons.Pid WHERE (?1 IS NULL OR Stamps.UpdatedAt >= ?1) AND (?2 IS NOT NULL OR Deletions.Id IS NULL) ORDER BY NAME ASC";
+const QUERY_PERSONS_BY_LOCATION_COMMAND: &str =
+    "SELECT Persons.Pid, Persons.LastName || ', ' || Persons.FirstName Name, Persons.FirstName, Persons.LastName, Locations.City, Stamps.CreatedAt, Stamps.CreatedBy, Stamps.UpdatedAt, Stamps.UpdatedBy, Deletions.DeletedAt, Deletions.DeletedBy FROM Locations INNER JOIN Persons ON Locations.Lid = Persons.Plid LEFT JOIN ChangeStamps Stamps ON Stamps.Entity = 'Persons' AND Stamps.Id = Persons.Pid LEFT JOIN Deletions ON Deletions.Entity = 'Persons' AND Deletions.Id = Persons.Pid WHERE Locations.Lid = ?1 AND (?2 IS NULL OR Stamps.UpdatedAt >= ?2) AND (?3 IS NOT NULL OR Deletions.Id IS NULL) ORDER BY NAME ASC";
 const QUERY_SINGLE_PERSON_COMMAND: &str = 
-    "SELECT Persons.Pid, Persons.FirstName, Persons.LastName, Locations.Lid, Locations.Street, Locations.Zip, Locations.City FROM Locations INNER JOIN Persons ON Locations.Lid = Persons.Plid WHERE Persons.Pid = ?";
+    "SELECT Persons.Pid, Persons.FirstName, Persons.LastName, Locations.Lid, Locations.Street, Locations.Zip, Locations.City, Stamps.CreatedAt, Stamps.CreatedBy, Stamps.UpdatedAt, Stamps.UpdatedBy, LocationStamps.CreatedAt LocationCreatedAt, LocationStamps.CreatedBy LocationCreatedBy, LocationStamps.UpdatedAt LocationUpdatedAt, LocationStamps.UpdatedBy LocationUpdatedBy, Deletions.DeletedAt, Deletions.DeletedBy FROM Locations INNER JOIN Persons ON Locations.Lid = Persons.Plid LEFT JOIN ChangeStamps Stamps ON Stamps.Entity = 'Persons' AND Stamps.Id = Persons.Pid LEFT JOIN ChangeStamps LocationStamps ON LocationStamps.Entity = 'Locations' AND LocationStamps.Id = Locations.Lid LEFT JOIN Deletions ON Deletions.Entity = 'Persons' AND Deletions.Id = Persons.Pid WHERE Persons.Pid = ?1 AND (?2 IS NOT NULL OR Deletions.Id IS NULL)";
 const QUERY_SINGLE_LOCATION_COMMAND: &str = 
-    "SELECT Lid, Street, Zip, City FROM Locations WHERE Lid = ?";
+    "SELECT Lid, Street, Zip, City, Stamps.CreatedAt LocationCreatedAt, Stamps.CreatedBy LocationCreatedBy, Stamps.UpdatedAt LocationUpdatedAt, Stamps.UpdatedBy LocationUpdatedBy FROM Locations LEFT JOIN ChangeStamps Stamps ON Stamps.Entity = 'Locations' AND Stamps.Id = Locations.Lid WHERE Lid = ?";
 const QUERY_ALL_LOCATION_COMMAND: &str = 
-    "SELECT Lid, Street, Zip, City FROM Locations ORDER BY City";
+    "SELECT Lid, Street, Zip, City, Stamps.CreatedAt LocationCreatedAt, Stamps.CreatedBy LocationCreatedBy, Stamps.UpdatedAt LocationUpdatedAt, Stamps.UpdatedBy LocationUpdatedBy FROM Locations LEFT JOIN ChangeStamps Stamps ON Stamps.Entity = 'Locations' AND Stamps.Id = Locations.Lid WHERE ?1 IS NULL OR Stamps.UpdatedAt >= ?1 ORDER BY City";
+const QUERY_ALL_EMPLOYEE_AS_OF_COMMAND: &str =
+    "SELECT Id, json_extract(State, '$.lastName') || ', ' || json_extract(State, '$.firstName') Name, json_extract(State, '$.firstName') FirstName, json_extract(State, '$.lastName') LastName, json_extract(State, '$.address.city') City, CreatedAt, CreatedBy, ValidFrom UpdatedAt, Actor UpdatedBy FROM EntityStates WHERE Entity = 'employees' AND State IS NOT NULL AND ValidFrom <= ?1 AND (ValidTo IS NULL OR ValidTo > ?1) AND (?2 IS NULL OR ValidFrom >= ?2) ORDER BY NAME ASC";
+const QUERY_SINGLE_EMPLOYEE_AS_OF_COMMAND: &str =
+    "SELECT Id, json_extract(State, '$.firstName') FirstName, json_extract(State, '$.lastName') LastName, json_extract(State, '$.address.street') Street, json_extract(State, '$.address.zip') Zip, json_extract(State, '$.address.city') City, CreatedAt, CreatedBy, ValidFrom UpdatedAt, Actor UpdatedBy, CreatedAt AddressCreatedAt, CreatedBy AddressCreatedBy, ValidFrom AddressUpdatedAt, Actor AddressUpdatedBy FROM EntityStates WHERE Entity = 'employees' AND Id = ?2 AND State IS NOT NULL AND ValidFrom <= ?1 AND (ValidTo IS NULL OR ValidTo > ?1)";
+const QUERY_ALL_PERSON_AS_OF_COMMAND: &str =
+    "SELECT Persons.Id Pid, json_extract(Persons.State, '$.lastName') || ', ' || json_extract(Persons.State, '$.firstName') Name, json_extract(Persons.State, '$.firstName') FirstName, json_extract(Persons.State, '$.lastName') LastName, json_extract(Locations.State, '$.city') City, Persons.CreatedAt, Persons.CreatedBy, Persons.ValidFrom UpdatedAt, Persons.Actor UpdatedBy FROM EntityStates Persons INNER JOIN EntityStates Locations ON Locations.Entity = 'locations' AND Locations.Id = json_extract(Persons.State, '$.plid') AND Locations.State IS NOT NULL AND Locations.ValidFrom <= ?1 AND (Locations.ValidTo IS NULL OR Locations.ValidTo > ?1) WHERE Persons.Entity = 'persons' AND Persons.State IS NOT NULL AND Persons.ValidFrom <= ?1 AND (Persons.ValidTo IS NULL OR Persons.ValidTo > ?1) AND (?2 IS NULL OR Persons.ValidFrom >= ?2) ORDER BY NAME ASC";
+const QUERY_PERSONS_BY_LOCATION_AS_OF_COMMAND: &str =
+    "SELECT Persons.Id Pid, json_extract(Persons.State, '$.lastName') || ', ' || json_extract(Persons.State, '$.firstName') Name, json_extract(Persons.State, '$.firstName') FirstName, json_extract(Persons.State, '$.lastName') LastName, json_extract(Locations.State, '$.city') City, Persons.CreatedAt, Persons.CreatedBy, Persons.ValidFrom UpdatedAt, Persons.Actor UpdatedBy FROM EntityStates Persons INNER JOIN EntityStates Locations ON Locations.Entity = 'locations' AND Locations.Id = json_extract(Persons.State, '$.plid') AND Locations.State IS NOT NULL AND Locations.ValidFrom <= ?1 AND (Locations.ValidTo IS NULL OR Locations.ValidTo > ?1) WHERE Persons.Entity = 'persons' AND Persons.State IS NOT NULL AND Persons.ValidFrom <= ?1 AND (Persons.ValidTo IS NULL OR Persons.ValidTo > ?1) AND Locations.Id = ?3 AND (?2 IS NULL OR Persons.ValidFrom >= ?2) ORDER BY NAME ASC";
+const QUERY_SINGLE_PERSON_AS_OF_COMMAND: &str =
+    "SELECT Persons.Id Pid, json_extract(Persons.State, '$.firstName') FirstName, json_extract(Persons.State, '$.lastName') LastName, Locations.Id Lid, json_extract(Locations.State, '$.street') Street, json_extract(Locations.State, '$.zip') Zip, json_extract(Locations.State, '$.city') City, Persons.CreatedAt, Persons.CreatedBy, Persons.ValidFrom UpdatedAt, Persons.Actor UpdatedBy, Locations.CreatedAt LocationCreatedAt, Locations.CreatedBy LocationCreatedBy, Locations.ValidFrom LocationUpdatedAt, Locations.Actor LocationUpdatedBy FROM EntityStates Persons INNER JOIN EntityStates Locations ON Locations.Entity = 'locations' AND Locations.Id = json_extract(Persons.State, '$.plid') AND Locations.State IS NOT NULL AND Locations.ValidFrom <= ?1 AND (Locations.ValidTo IS NULL OR Locations.ValidTo > ?1) WHERE Persons.Entity = 'persons' AND Persons.Id = ?2 AND Persons.State IS NOT NULL AND Persons.ValidFrom <= ?1 AND (Persons.ValidTo IS NULL OR Persons.ValidTo > ?1)";
+const QUERY_ALL_LOCATION_AS_OF_COMMAND: &str =
+    "SELECT Id Lid, json_extract(State, '$.street') Street, json_extract(State, '$.zip') Zip, json_extract(State, '$.city') City, CreatedAt LocationCreatedAt, CreatedBy LocationCreatedBy, ValidFrom LocationUpdatedAt, Actor LocationUpdatedBy FROM EntityStates WHERE Entity = 'locations' AND State IS NOT NULL AND ValidFrom <= ?1 AND (ValidTo IS NULL OR ValidTo > ?1) AND (?2 IS NULL OR ValidFrom >= ?2) ORDER BY City";
+const QUERY_SINGLE_LOCATION_AS_OF_COMMAND: &str =
+    "SELECT Id Lid, json_extract(State, '$.street') Street, json_extract(State, '$.zip') Zip, json_extract(State, '$.city') City, CreatedAt LocationCreatedAt, CreatedBy LocationCreatedBy, ValidFrom LocationUpdatedAt, Actor LocationUpdatedBy FROM EntityStates WHERE Entity = 'locations' AND Id = ?2 AND State IS NOT NULL AND ValidFrom <= ?1 AND (ValidTo IS NULL OR ValidTo > ?1)";
+const QUERY_HISTORY_COMMAND: &str =
+    "SELECT Version, At, Actor, Operation, State FROM EntityVersions WHERE Entity = ? AND Id = ? ORDER BY Version";
+const QUERY_AUDIT_LOG_COMMAND: &str =
+    "SELECT Seq, At, Actor, RequestId, Command, Entity, TargetId, Before, After, Status, Outcome FROM AuditLog WHERE Seq > ?1 AND (?2 IS NULL OR Entity = ?2) AND (?3 IS NULL OR TargetId = ?3) AND (?4 IS NULL OR Actor = ?4) AND (?5 IS NULL OR At >= ?5) AND (?6 IS NULL OR At < ?6) ORDER BY Seq LIMIT ?7";
+const QUERY_LAST_MIGRATION_COMMAND: &str =
+    "SELECT Version, Name, AppliedAt FROM SchemaMigrations ORDER BY Version DESC LIMIT 1";
+const QUERY_TABLE_VERSIONS_COMMAND: &str =
+    "SELECT Name, Version, ModifiedAt FROM TableVersions";
+const QUERY_COMMIT_POSITION_COMMAND: &str =
+    "SELECT COALESCE(SUM(Version), 0) Position FROM TableVersions";
+const QUERY_ENTITY_COUNTS_COMMAND: &str =
+    "SELECT (SELECT COUNT(*) FROM Employees) Employees, (SELECT COUNT(*) FROM Addresses) Addresses, (SELECT COUNT(*) FROM Persons) Persons, (SELECT COUNT(*) FROM Locations) Locations";
 
-pub fn pall_employees() -> anyhow::Result<impl IntoResponse> {
-    let con = Connection::open_default()?;
-    let query_result = con.execute(QUERY_ALL_EMPLOYEE_COMMAND, &[])?;
- 
-    let products: Vec<_> = query_result
-        .rows()
-        .map(|row| {
-            let id = String::from(
-                row.get::<&str>("Id")
-                    .ok_or_else(|| anyhow!("Employees.Id not present"))?,
-            );
-            let name = String::from(
-                row.get::<&str>("Name")
-                    .ok_or_else(|| anyhow!("Name not present"))?,
-            );
-            let city = String::from(
-                row.get::<&str>("City")
-                    .ok_or_else(|| anyhow!("Addresses.City not present"))?,
-            );
-            anyhow::Ok(EmployeeListModel { id, name, city })
-        })
-        .filter(|item| item.is_ok())
-        .map(|item| item.unwrap())
-        .collect();
-
-    let payload = serde_json::to_vec(&products)?;
-    Ok(Response::builder()
-            .status(200)
-            .header("Content-Type", "application/json")
-            .body(payload)
-            .build())
-    
+/// Runs a statement in its own span, so every SQL statement shows up in the
+/// exported traces.
+#[tracing::instrument(name = "sqlite.execute", skip_all,
+                      fields(db.system = "sqlite", db.statement = statement))]
+fn execute(con: &Connection, statement: &str, parameters: &[Value]) -> Result<QueryResult, Error> {
+    con.execute(statement, parameters)
 }
 
-pub fn pemployee_by_id(params: Params) -> anyhow::Result<impl IntoResponse> {
-    let Some(id) = params.get("id") else {
-        return Ok(Response::new(400, ()));
-    };
+/// Receives the rows of a query one at a time
+pub type Sink<'a, T> = &'a mut dyn FnMut(T) -> anyhow::Result<()>;
 
-    let con = Connection::open_default()?;
-    let id = [Value::Text(id.to_string())];
-    let query_result = con.execute(QUERY_SINGLE_EMPLOYEE_COMMAND, &id)?;
-
-    let products: Vec<_> = query_result
-        .rows()
-        .map(|row| {
-            let id = String::from(
-                row.get::<&str>("Id")
-                    .ok_or_else(|| anyhow!("Employees.Id not present"))?,
-            );
-            let first_name = String::from(
-                row.get::<&str>("FirstName")
-                    .ok_or_else(|| anyhow!("Employees.FirstName not present"))?,
-            );
-            let last_name = String::from(
-                row.get::<&str>("LastName")
-                    .ok_or_else(|| anyhow!("Employees.LastName not present"))?,
-            );
-            let street = String::from(
-                row.get::<&str>("Street")
-                    .ok_or_else(|| anyhow!("Addresses.Street not present"))?,
-            );
-            let zip = String::from(
-                row.get::<&str>("Zip")
-                    .ok_or_else(|| anyhow!("Addresses.Zip not present"))?,
-            );
-            let city = String::from(
-                row.get::<&str>("City")
-                    .ok_or_else(|| anyhow!("Addresses.City not present"))?,
-            );
-            anyhow::Ok(EmployeeDetailsModel {
-                id: id.clone(),
-                first_name,
-                last_name,
-                address: AddressDetailsModel {
-                    id: id.clone(),
-                    street,
-                    zip,
-                    city,
-                },
-            })
-        })
-        .filter(|item| item.is_ok())
-        .map(|item| item.unwrap())
-        .collect();
-
-    let payload = serde_json::to_vec(&products)?;
+fn text(row: &Row, column: &str) -> anyhow::Result<String> {
+    row.get::<&str>(column)
+        .map(String::from)
+        .ok_or_else(|| anyhow!("{} not present", column))
+}
 
-    Ok(Response::builder()
-        .status(200)
-        .header("Content-Type", "application/json")
-        .body(payload)
-        .build())
+/// A text column that may be NULL, such as the stamps of entities created
+/// before they were kept
+fn optional(row: &Row, column: &str) -> Option<String> {
+    row.get::<&str>(column).map(String::from)
 }
 
-pub fn pall_locations() -> anyhow::Result<impl IntoResponse> {
-    let con = Connection::open_default()?;
-    let query_result = con.execute(QUERY_ALL_LOCATION_COMMAND, &[])?;
- 
-    let products: Vec<_> = query_result
-        .rows()
-        .map(|row| {
-            let lid = String::from(
-                row.get::<&str>("Lid")
-                   .ok_or_else(|| anyhow!("Lid not present"))?,
-            );
-            let street = String::from(
-                row.get::<&str>("Street")
-                    .ok_or_else(|| anyhow!("Street not present"))?,
-            );
-            let zip = String::from(
-                row.get::<&str>("Zip")
-                   .ok_or_else(|| anyhow!("Zip not present"))?,
-            );
-            let city = String::from(
-                row.get::<&str>("City")
-                   .ok_or_else(|| anyhow!("City not present"))?,
-            );
-            anyhow::Ok(LocationDetailsModel { lid, street, zip, city })
-        })
-        .filter(|item| item.is_ok())
-        .map(|item| item.unwrap())
-        .collect();
-
-    let payload = serde_json::to_vec(&products)?;
-    Ok(Response::builder()
-            .status(200)
-            .header("Content-Type", "application/json")
-            .body(payload)
-            .build())
-    
+/// A text column `commands` may have stored encrypted, decrypted
+fn secret(row: &Row, column: &str) -> anyhow::Result<String> {
+    crypto::open(&text(row, column)?)
 }
 
-pub fn plocation_by_id(params: Params) -> anyhow::Result<impl IntoResponse> {
-    let Some(lid) = params.get("lid") else {
-        return Ok(Response::new(400, ()));
-    };
+/// A filter such as `updatedSince` as a parameter, NULL when absent
+fn nullable(value: Option<&str>) -> Value {
+    value.map_or(Value::Null, |value| Value::Text(value.to_string()))
+}
 
-    let con = Connection::open_default()?;
-    let lid = [Value::Text(lid.to_string())];
-    let query_result = con.execute(QUERY_SINGLE_LOCATION_COMMAND, &lid)?;
-
-    let products: Vec<_> = query_result
-        .rows()
-        .map(|row| {
-            let lid = String::from(
-                row.get::<&str>("Lid")
-                    .ok_or_else(|| anyhow!("Lid not present"))?,
-            );
-            let street = String::from(
-                row.get::<&str>("Street")
-                    .ok_or_else(|| anyhow!("Street not present"))?,
-            );
-            let zip = String::from(
-                row.get::<&str>("Zip")
-                    .ok_or_else(|| anyhow!("Zip not present"))?,
-            );
-            let city = String::from(
-                row.get::<&str>("City")
-                    .ok_or_else(|| anyhow!("City not present"))?,
-            );
-            anyhow::Ok(LocationDetailsModel{lid, street, zip, city})
-        })
-        .filter(|item| item.is_ok())
-        .map(|item| item.unwrap())
-        .collect();
-    let payload = serde_json::to_vec(&products)?;
-    Ok(Response::builder()
-            .status(200)
-            .header("Content-Type", "application/json")
-            .body(payload)
-            .build())    
+/// Runs a query and passes every row, mapped to its model, to `sink`
+fn each<T>(con: &Connection, statement: &str, parameters: &[Value],
+           map: fn(&Row) -> anyhow::Result<T>, sink: Sink<T>) -> anyhow::Result<()> {
+    let query_result = execute(con, statement, parameters)?;
+    for row in query_result.rows() {
+        sink(map(&row)?)?;
+    }
+    Ok(())
+}
+
+/// Runs a query of a list ordered by name. With encryption on the names are
+/// stored encrypted, so SQL orders ciphertexts and the rows are sorted here.
+fn each_by_name<T>(con: &Connection, statement: &str, parameters: &[Value],
+                   map: fn(&Row) -> anyhow::Result<T>, name: fn(&T) -> &str,
+                   sink: Sink<T>) -> anyhow::Result<()> {
+    if !crypto::enabled()? {
+        return each(con, statement, parameters, map, sink);
+    }
+    let mut rows = Vec::new();
+    each(con, statement, parameters, map, &mut |model| {
+        rows.push(model);
+        Ok(())
+    })?;
+    rows.sort_by(|a, b| name(a).cmp(name(b)));
+    rows.into_iter().try_for_each(sink)
+}
+
+fn employee_list_row(row: &Row) -> anyhow::Result<EmployeeListModel> {
+    let first_name = secret(row, "FirstName")?;
+    let last_name = secret(row, "LastName")?;
+    Ok(EmployeeListModel {
+        id: text(row, "Id")?,
+        name: format!("{}, {}", last_name, first_name),
+        first_name,
+        last_name,
+        city: text(row, "City")?,
+        created_at: optional(row, "CreatedAt"),
+        created_by: optional(row, "CreatedBy"),
+        updated_at: optional(row, "UpdatedAt"),
+        updated_by: optional(row, "UpdatedBy"),
+        deleted_at: optional(row, "DeletedAt"),
+        deleted_by: optional(row, "DeletedBy"),
+    })
+}
+
+fn employee_name(model: &EmployeeListModel) -> &str {
+    &model.name
+}
+
+fn employee_details_row(row: &Row) -> anyhow::Result<EmployeeDetailsModel> {
+    let id = text(row, "Id")?;
+    Ok(EmployeeDetailsModel {
+        id: id.clone(),
+        first_name: secret(row, "FirstName")?,
+        last_name: secret(row, "LastName")?,
+        address: AddressDetailsModel {
+            id,
+            street: secret(row, "Street")?,
+            zip: text(row, "Zip")?,
+            city: text(row, "City")?,
+            created_at: optional(row, "AddressCreatedAt"),
+            created_by: optional(row, "AddressCreatedBy"),
+            updated_at: optional(row, "AddressUpdatedAt"),
+            updated_by: optional(row, "AddressUpdatedBy"),
+        },
+        created_at: optional(row, "CreatedAt"),
+        created_by: optional(row, "CreatedBy"),
+        updated_at: optional(row, "UpdatedAt"),
+        updated_by: optional(row, "UpdatedBy"),
+        deleted_at: optional(row, "DeletedAt"),
+        deleted_by: optional(row, "DeletedBy"),
+    })
+}
+
+fn location_row(row: &Row) -> anyhow::Result<LocationDetailsModel> {
+    Ok(LocationDetailsModel {
+        lid: text(row, "Lid")?,
+        street: secret(row, "Street")?,
+        zip: text(row, "Zip")?,
+        city: text(row, "City")?,
+        created_at: optional(row, "LocationCreatedAt"),
+        created_by: optional(row, "LocationCreatedBy"),
+        updated_at: optional(row, "LocationUpdatedAt"),
+        updated_by: optional(row, "LocationUpdatedBy"),
+    })
+}
+
+fn person_list_row(row: &Row) -> anyhow::Result<PersonListModel> {
+    let first_name = secret(row, "FirstName")?;
+    let last_name = secret(row, "LastName")?;
+    Ok(PersonListModel {
+        pid: text(row, "Pid")?,
+        name: format!("{}, {}", last_name, first_name),
+        first_name,
+        last_name,
+        city: text(row, "City")?,
+        created_at: optional(row, "CreatedAt"),
+        created_by: optional(row, "CreatedBy"),
+        updated_at: optional(row, "UpdatedAt"),
+        updated_by: optional(row, "UpdatedBy"),
+        deleted_at: optional(row, "DeletedAt"),
+        deleted_by: optional(row, "DeletedBy"),
+    })
 }
 
-pub fn pperson_by_id(params: Params) -> anyhow::Result<impl IntoResponse> {
-    let Some(pid) = params.get("pid") else {
-        return Ok(Response::new(400, ()));
+fn person_name(model: &PersonListModel) -> &str {
+    &model.name
+}
+
+fn person_details_row(row: &Row) -> anyhow::Result<PersonDetailsModel> {
+    Ok(PersonDetailsModel {
+        pid: text(row, "Pid")?,
+        first_name: secret(row, "FirstName")?,
+        last_name: secret(row, "LastName")?,
+        address: location_row(row)?,
+        created_at: optional(row, "CreatedAt"),
+        created_by: optional(row, "CreatedBy"),
+        updated_at: optional(row, "UpdatedAt"),
+        updated_by: optional(row, "UpdatedBy"),
+        deleted_at: optional(row, "DeletedAt"),
+        deleted_by: optional(row, "DeletedBy"),
+    })
+}
+
+/// A JSON image of an entity, such as a version of its history, with the
+/// fields `commands` encrypted decrypted
+fn image(row: &Row, column: &str) -> anyhow::Result<Option<serde_json::Value>> {
+    let Some(image) = optional(row, column) else {
+        return Ok(None);
     };
+    let mut image = serde_json::from_str(&image)?;
+    crypto::open_json(&mut image)?;
+    Ok(Some(image))
+}
 
-    let con = Connection::open_default()?;
-    let pid = [Value::Text(pid.to_string())];
-    let query_result = con.execute(QUERY_SINGLE_PERSON_COMMAND, &pid)?;
-  
-    let products: Vec<_> = query_result
-        .rows()
-        .map(|row| {
-            let pid = String::from(
-                row.get::<&str>("Pid")
-                    .ok_or_else(|| anyhow!("Pid not present"))?,
-            );
-            let first_name = String::from(
-                row.get::<&str>("FirstName")
-                    .ok_or_else(|| anyhow!("FirstName not present"))?,
-            );
-            let last_name = String::from(
-                row.get::<&str>("LastName")
-                    .ok_or_else(|| anyhow!("LastName not present"))?,
-            );
-            let lid = String::from(
-                row.get::<&str>("Lid")
-                    .ok_or_else(|| anyhow!("Lid not present"))?,
-            );
-            let street = String::from(
-                row.get::<&str>("Street")
-                    .ok_or_else(|| anyhow!("Street not present"))?,
-            );
-            let zip = String::from(
-                row.get::<&str>("Zip")
-                    .ok_or_else(|| anyhow!("Zip not present"))?,
-            );   
-            let city = String::from(
-                row.get::<&str>("City")
-                    .ok_or_else(|| anyhow!("Locations.City not present"))?,
-            );
-            anyhow::Ok(PersonDetailsModel{
-                                pid, 
-                                first_name, 
-                                last_name,
-                                address: LocationDetailsModel{
-                                    lid, 
-                                    street, 
-                                    zip, city }})
-        })
-        .filter(|item| item.is_ok())
-        .map(|item| item.unwrap())
-        .collect();
-
-    let payload = serde_json::to_vec(&products)?;
+fn entity_version_row(row: &Row) -> anyhow::Result<EntityVersionModel> {
+    Ok(EntityVersionModel {
+        version: row.get::<i64>("Version").ok_or_else(|| anyhow!("Version not present"))?,
+        at: text(row, "At")?,
+        actor: text(row, "Actor")?,
+        operation: text(row, "Operation")?,
+        state: image(row, "State")?,
+    })
+}
+
+fn audit_record_row(row: &Row) -> anyhow::Result<AuditRecordModel> {
+    Ok(AuditRecordModel {
+        seq: row.get::<i64>("Seq").ok_or_else(|| anyhow!("Seq not present"))?,
+        at: text(row, "At")?,
+        actor: text(row, "Actor")?,
+        request_id: text(row, "RequestId")?,
+        command: text(row, "Command")?,
+        entity: text(row, "Entity")?,
+        target_id: optional(row, "TargetId"),
+        before: image(row, "Before")?,
+        after: image(row, "After")?,
+        status: row.get::<i64>("Status").ok_or_else(|| anyhow!("Status not present"))?,
+        outcome: text(row, "Outcome")?,
+    })
+}
+
+/// Filters of the read models, from the query string
+#[derive(Debug, Default)]
+pub struct Filters {
+    /// the state at this time, read from the history of the entities
+    pub as_of: Option<String>,
+    /// only rows updated at or after this time
+    pub updated_since: Option<String>,
+    /// deleted employees and persons too; ignored with `as_of`, where they are
+    /// absent once deleted
+    pub include_deleted: bool,
+}
+
+impl Filters {
+    fn as_of(&self) -> Value {
+        nullable(self.as_of.as_deref())
+    }
+
+    fn updated_since(&self) -> Value {
+        nullable(self.updated_since.as_deref())
+    }
+
+    fn include_deleted(&self) -> Value {
+        if self.include_deleted { Value::Integer(1) } else { Value::Null }
+    }
+}
+
+pub fn all_employees(con: &Connection, filters: &Filters, sink: Sink<EmployeeListModel>) -> anyhow::Result<()> {
+    match filters.as_of {
+        None => {
+            let params = [filters.updated_since(), filters.include_deleted()];
+            each_by_name(con, QUERY_ALL_EMPLOYEE_COMMAND, &params, employee_list_row, employee_name, sink)
+        }
+        Some(_) => {
+            let params = [filters.as_of(), filters.updated_since()];
+            each_by_name(con, QUERY_ALL_EMPLOYEE_AS_OF_COMMAND, &params, employee_list_row, employee_name, sink)
+        }
+    }
+}
+
+pub fn employee_by_id(con: &Connection, id: &str, filters: &Filters,
+                      sink: Sink<EmployeeDetailsModel>) -> anyhow::Result<()> {
+    match filters.as_of {
+        None => {
+            let params = [Value::Text(id.to_string()), filters.include_deleted()];
+            each(con, QUERY_SINGLE_EMPLOYEE_COMMAND, &params, employee_details_row, sink)
+        }
+        Some(_) => {
+            let params = [filters.as_of(), Value::Text(id.to_string())];
+            each(con, QUERY_SINGLE_EMPLOYEE_AS_OF_COMMAND, &params, employee_details_row, sink)
+        }
+    }
+}
+
+pub fn all_locations(con: &Connection, filters: &Filters, sink: Sink<LocationDetailsModel>) -> anyhow::Result<()> {
+    match filters.as_of {
+        None => each(con, QUERY_ALL_LOCATION_COMMAND, &[filters.updated_since()], location_row, sink),
+        Some(_) => {
+            let params = [filters.as_of(), filters.updated_since()];
+            each(con, QUERY_ALL_LOCATION_AS_OF_COMMAND, &params, location_row, sink)
+        }
+    }
+}
+
+pub fn location_by_id(con: &Connection, lid: &str, filters: &Filters,
+                      sink: Sink<LocationDetailsModel>) -> anyhow::Result<()> {
+    match filters.as_of {
+        None => each(con, QUERY_SINGLE_LOCATION_COMMAND, &[Value::Text(lid.to_string())], location_row, sink),
+        Some(_) => {
+            let params = [filters.as_of(), Value::Text(lid.to_string())];
+            each(con, QUERY_SINGLE_LOCATION_AS_OF_COMMAND, &params, location_row, sink)
+        }
+    }
+}
+
+pub fn all_persons(con: &Connection, filters: &Filters, sink: Sink<PersonListModel>) -> anyhow::Result<()> {
+    match filters.as_of {
+        None => {
+            let params = [filters.updated_since(), filters.include_deleted()];
+            each_by_name(con, QUERY_ALL_PERSON_COMMAND, &params, person_list_row, person_name, sink)
+        }
+        Some(_) => {
+            let params = [filters.as_of(), filters.updated_since()];
+            each_by_name(con, QUERY_ALL_PERSON_AS_OF_COMMAND, &params, person_list_row, person_name, sink)
+        }
+    }
+}
+
+pub fn person_by_id(con: &Connection, pid: &str, filters: &Filters,
+                    sink: Sink<PersonDetailsModel>) -> anyhow::Result<()> {
+    match filters.as_of {
+        None => {
+            let params = [Value::Text(pid.to_string()), filters.include_deleted()];
+            each(con, QUERY_SINGLE_PERSON_COMMAND, &params, person_details_row, sink)
+        }
+        Some(_) => {
+            let params = [filters.as_of(), Value::Text(pid.to_string())];
+            each(con, QUERY_SINGLE_PERSON_AS_OF_COMMAND, &params, person_details_row, sink)
+        }
+    }
+}
+
+pub fn persons_by_location(con: &Connection, lid: &str, filters: &Filters,
+                           sink: Sink<PersonListModel>) -> anyhow::Result<()> {
+    match filters.as_of {
+        None => {
+            let params = [Value::Text(lid.to_string()), filters.updated_since(), filters.include_deleted()];
+            each_by_name(con, QUERY_PERSONS_BY_LOCATION_COMMAND, &params, person_list_row, person_name, sink)
+        }
+        Some(_) => {
+            let params = [filters.as_of(), filters.updated_since(), Value::Text(lid.to_string())];
+            each_by_name(con, QUERY_PERSONS_BY_LOCATION_AS_OF_COMMAND, &params, person_list_row, person_name, sink)
+        }
+    }
+}
+
+/// Every version of an entity, oldest first
+pub fn history(con: &Connection, entity: &str, id: &str, sink: Sink<EntityVersionModel>) -> anyhow::Result<()> {
+    let params = [Value::Text(entity.to_string()), Value::Text(id.to_string())];
+    each(con, QUERY_HISTORY_COMMAND, &params, entity_version_row, sink)
+}
+
+/// Filters and page of the audit log; absent filters match every record
+#[derive(Debug)]
+pub struct AuditFilter {
+    pub entity: Option<String>,
+    pub id: Option<String>,
+    pub actor: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// `seq` of the last record of the previous page
+    pub after: i64,
+    pub limit: i64,
+}
+
+pub fn audit_log(con: &Connection, filter: &AuditFilter, sink: Sink<AuditRecordModel>) -> anyhow::Result<()> {
+    let params = [
+        Value::Integer(filter.after),
+        nullable(filter.entity.as_deref()),
+        nullable(filter.id.as_deref()),
+        nullable(filter.actor.as_deref()),
+        nullable(filter.from.as_deref()),
+        nullable(filter.to.as_deref()),
+        Value::Integer(filter.limit),
+    ];
+    each(con, QUERY_AUDIT_LOG_COMMAND, &params, audit_record_row, sink)
+}
+
+/// Versions of the tables a read model is built from, kept by triggers on
+/// every write
+pub struct TableVersions {
+    /// version of every table, in the order they were asked for
+    pub versions: Vec<i64>,
+    /// unix time of the latest change to any of them
+    pub modified_at: i64,
+}
+
+pub fn table_versions(con: &Connection, tables: &[&str]) -> anyhow::Result<TableVersions> {
+    let query_result = execute(con, QUERY_TABLE_VERSIONS_COMMAND, &[])?;
+    let mut versions = Vec::with_capacity(tables.len());
+    let mut modified_at = 0;
+    for table in tables {
+        let row = query_result.rows()
+            .find(|row| row.get::<&str>("Name") == Some(*table))
+            .ok_or_else(|| anyhow!("no version of table {}", table))?;
+        versions.push(row.get::<i64>("Version")
+            .ok_or_else(|| anyhow!("TableVersions.Version not present"))?);
+        modified_at = modified_at.max(row.get::<i64>("ModifiedAt")
+            .ok_or_else(|| anyhow!("TableVersions.ModifiedAt not present"))?);
+    }
+    Ok(TableVersions { versions, modified_at })
+}
+
+/// Number of writes the read models include, the position of a consistency
+/// token
+pub fn commit_position(con: &Connection) -> anyhow::Result<i64> {
+    let query_result = execute(con, QUERY_COMMIT_POSITION_COMMAND, &[])?;
+    query_result.rows().next()
+        .and_then(|row| row.get::<i64>("Position"))
+        .ok_or_else(|| anyhow!("commit position not present"))
+}
+
+pub fn pentity_counts() -> anyhow::Result<impl IntoResponse> {
+    let counts = entity_counts()?;
+    let payload = serde_json::to_vec(&counts)?;
     Ok(Response::builder()
             .status(200)
             .header("Content-Type", "application/json")
@@ -253,34 +435,35 @@ pub fn pperson_by_id(params: Params) -> anyhow::Result<impl IntoResponse> {
             .build())
 }
 
-pub fn pall_persons() -> anyhow::Result<impl IntoResponse> {
+pub fn entity_counts() -> anyhow::Result<BTreeMap<&'static str, i64>> {
     let con = Connection::open_default()?;
-    let query_result = con.execute(QUERY_ALL_PERSON_COMMAND, &[])?;
-    let products: Vec<_> = query_result
-        .rows()
-        .map(|row| {
-            let pid = String::from(
-                row.get::<&str>("Pid")
-                    .ok_or_else(|| anyhow!("Pid not present"))?,
-            );
-            let name = String::from(
-                row.get::<&str>("Name")
-                    .ok_or_else(|| anyhow!("Name not present"))?,
-            );
-            let city = String::from(
-                row.get::<&str>("City")
-                    .ok_or_else(|| anyhow!("Locations.City not present"))?,
-            );
-            anyhow::Ok(PersonListModel { pid, name, city })
-        })
-        .filter(|item| item.is_ok())
-        .map(|item| item.unwrap())
-        .collect();
-    let payload = serde_json::to_vec(&products)?;
-    Ok(Response::builder()
-            .status(200)
-            .header("Content-Type", "application/json")
-            .body(payload)
-            .build())
+    let query_result = execute(&con, QUERY_ENTITY_COUNTS_COMMAND, &[])?;
+    let Some(row) = query_result.rows().next() else {
+        return Err(anyhow!("entity counts not present"));
+    };
+
+    let mut counts = BTreeMap::new();
+    for (column, entity) in [("Employees", "employees"), ("Addresses", "addresses"),
+                             ("Persons", "persons"), ("Locations", "locations")] {
+        let count = row.get::<i64>(column)
+            .ok_or_else(|| anyhow!("{} count not present", column))?;
+        counts.insert(entity, count);
+    }
+    Ok(counts)
 }
 
+pub fn last_migration() -> anyhow::Result<Option<MigrationModel>> {
+    let con = Connection::open_default()?;
+    let query_result = execute(&con, QUERY_LAST_MIGRATION_COMMAND, &[])?;
+    let Some(row) = query_result.rows().next() else {
+        return Ok(None);
+    };
+    Ok(Some(MigrationModel {
+        version: row.get::<i64>("Version")
+            .ok_or_else(|| anyhow!("SchemaMigrations.Version not present"))?,
+        name: String::from(row.get::<&str>("Name")
+            .ok_or_else(|| anyhow!("SchemaMigrations.Name not present"))?),
+        applied_at: String::from(row.get::<&str>("AppliedAt")
+            .ok_or_else(|| anyhow!("SchemaMigrations.AppliedAt not present"))?),
+    }))
+}
@@ -0,0 +1,378 @@
+//! Encryption of personal data at rest, with AES-256-GCM under versioned
+//! keys. `commands` encrypts, both components decrypt.
+
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, Result};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde_json::Value as JsonValue;
+use sha2::Sha256;
+
+/// Prefix of encrypted values, stored as `enc:<key version>:<hex of nonce
+/// and ciphertext>`. Plain values must not start with it.
+pub const PREFIX: &str = "enc:";
+
+/// Spin variable holding the keys as `version:hex` pairs separated by commas,
+/// such as `1:<64 hex digits>,2:<64 hex digits>`. The highest version
+/// encrypts, every version decrypts. Empty turns encryption off.
+pub const KEYS_VARIABLE: &str = "pii_encryption_keys";
+const NONCE_LEN: usize = 12;
+
+/// HKDF labels of the sub-keys derived from every configured key
+const ENCRYPTION_LABEL: &[u8] = b"enc";
+const NONCE_LABEL: &[u8] = b"nonce";
+
+/// The configured keys by version
+pub struct Keys(BTreeMap<u32, SubKeys>);
+
+/// Independent keys derived from one configured key, so that AES-GCM and the
+/// HMAC deriving searchable nonces never share a key
+struct SubKeys {
+    encryption: [u8; 32],
+    nonce: [u8; 32],
+}
+
+impl SubKeys {
+    fn derive(key: &[u8; 32]) -> SubKeys {
+        let hkdf = Hkdf::<Sha256>::new(None, key);
+        let mut sub_keys = SubKeys { encryption: [0; 32], nonce: [0; 32] };
+        // 32 bytes are far below the limit of HKDF-SHA256, so expand cannot fail
+        let _ = hkdf.expand(ENCRYPTION_LABEL, &mut sub_keys.encryption);
+        let _ = hkdf.expand(NONCE_LABEL, &mut sub_keys.nonce);
+        sub_keys
+    }
+}
+
+thread_local! {
+    static KEYS: OnceCell<Rc<Keys>> = const { OnceCell::new() };
+}
+
+/// The keys of `configured`, the value of `KEYS_VARIABLE`, read once per
+/// instance
+pub fn keys(configured: impl FnOnce() -> String) -> Result<Rc<Keys>> {
+    KEYS.with(|cell| {
+        if let Some(keys) = cell.get() {
+            return Ok(keys.clone());
+        }
+        let keys = Rc::new(Keys::parse(&configured())?);
+        let _ = cell.set(keys.clone());
+        Ok(keys)
+    })
+}
+
+/// The key version of an encrypted value, `None` for a plain one
+pub fn version_of(value: &str) -> Option<u32> {
+    value.strip_prefix(PREFIX)?.split_once(':')?.0.parse().ok()
+}
+
+/// The encrypted fields of the images of an entity, as JSON pointers, and
+/// whether they are searchable
+fn fields(entity: &str) -> &'static [(&'static str, bool)] {
+    match entity {
+        "employees" => &[("/firstName", false), ("/lastName", false), ("/address/street", false)],
+        "persons" => &[("/firstName", false), ("/lastName", false)],
+        "locations" => &[("/street", true)],
+        _ => &[],
+    }
+}
+
+fn cipher(key: &[u8; 32]) -> Result<Aes256Gcm> {
+    Aes256Gcm::new_from_slice(key).map_err(|_| anyhow!("invalid key length"))
+}
+
+fn encrypt(version: u32, sub_keys: &SubKeys, value: &[u8], searchable: bool) -> Result<String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    if searchable {
+        // derived from the value, so equal values encrypt equally and can be
+        // looked up
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&sub_keys.nonce)
+            .map_err(|_| anyhow!("invalid key length"))?;
+        mac.update(value);
+        nonce.copy_from_slice(&mac.finalize().into_bytes()[..NONCE_LEN]);
+    } else {
+        getrandom::getrandom(&mut nonce).map_err(|e| anyhow!("no random nonce: {}", e))?;
+    }
+    let ciphertext = cipher(&sub_keys.encryption)?
+        .encrypt(Nonce::from_slice(&nonce), value)
+        .map_err(|_| anyhow!("encryption failed"))?;
+    Ok(format!("{}{}:{}{}", PREFIX, version, hex::encode(nonce), hex::encode(ciphertext)))
+}
+
+impl Keys {
+    /// Parses `version:hex` pairs separated by commas; empty means no keys
+    pub fn parse(configured: &str) -> Result<Keys> {
+        let mut keys = BTreeMap::new();
+        for entry in configured.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (version, key) = entry
+                .split_once(':')
+                .ok_or_else(|| anyhow!("{} entries must be version:key", KEYS_VARIABLE))?;
+            let version: u32 = version
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("{} key version {} is not a number", KEYS_VARIABLE, version))?;
+            let key = hex::decode(key.trim())
+                .ok()
+                .and_then(|key| <[u8; 32]>::try_from(key).ok())
+                .ok_or_else(|| anyhow!("{} key {} is not 32 bytes of hex", KEYS_VARIABLE, version))?;
+            keys.insert(version, SubKeys::derive(&key));
+        }
+        Ok(Keys(keys))
+    }
+
+    /// Whether keys are configured, so values may be stored encrypted
+    pub fn enabled(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    /// The version of the key new values are encrypted with, `None` when
+    /// encryption is off
+    pub fn current_version(&self) -> Option<u32> {
+        self.0.keys().next_back().copied()
+    }
+
+    /// Encrypts a value with the current key; unchanged when encryption is
+    /// off. `searchable` values give equal ciphertexts for equal values
+    /// under the same key, so fields can be looked up by value.
+    pub fn seal(&self, value: &str, searchable: bool) -> Result<String> {
+        match self.0.iter().next_back() {
+            Some((version, sub_keys)) => encrypt(*version, sub_keys, value.as_bytes(), searchable),
+            None => Ok(value.to_string()),
+        }
+    }
+
+    /// Every form a searchable value may be stored in: plain, as written
+    /// before encryption was turned on, and encrypted under each key
+    pub fn probes(&self, value: &str) -> Result<Vec<String>> {
+        let mut probes = vec![value.to_string()];
+        for (version, sub_keys) in self.0.iter().rev() {
+            probes.push(encrypt(*version, sub_keys, value.as_bytes(), true)?);
+        }
+        Ok(probes)
+    }
+
+    /// Decrypts a value; plain values are returned unchanged
+    pub fn open(&self, value: &str) -> Result<String> {
+        match value.starts_with(PREFIX) {
+            true => Ok(String::from_utf8(self.decrypt(value)?)?),
+            false => Ok(value.to_string()),
+        }
+    }
+
+    /// Encrypts a payload as a whole with the current key, such as the body
+    /// of a queued command or of a stored response; unchanged when
+    /// encryption is off or the payload is empty
+    pub fn seal_payload(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        match self.0.iter().next_back() {
+            Some((version, sub_keys)) if !payload.is_empty() => {
+                Ok(encrypt(*version, sub_keys, payload, false)?.into_bytes())
+            }
+            _ => Ok(payload.to_vec()),
+        }
+    }
+
+    /// Decrypts a payload sealed by `seal_payload`; plain payloads are
+    /// returned unchanged
+    pub fn open_payload(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        match payload.starts_with(PREFIX.as_bytes()) {
+            true => self.decrypt(std::str::from_utf8(payload)?),
+            false => Ok(payload.to_vec()),
+        }
+    }
+
+    /// Whether a payload is not encrypted with the current key, while
+    /// encryption is on. Empty payloads hold nothing to encrypt.
+    pub fn stale_payload(&self, payload: &[u8]) -> bool {
+        !payload.is_empty() && self.stale(&String::from_utf8_lossy(payload))
+    }
+
+    /// The plain bytes of an encrypted value
+    fn decrypt(&self, value: &str) -> Result<Vec<u8>> {
+        let encrypted = value.strip_prefix(PREFIX).ok_or_else(|| anyhow!("malformed encrypted value"))?;
+        let (version, sealed) = encrypted
+            .split_once(':')
+            .and_then(|(version, sealed)| Some((version.parse::<u32>().ok()?, hex::decode(sealed).ok()?)))
+            .filter(|(_, sealed)| sealed.len() > NONCE_LEN)
+            .ok_or_else(|| anyhow!("malformed encrypted value"))?;
+        let sub_keys = self.0.get(&version).ok_or_else(|| anyhow!("no key of version {}", version))?;
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        cipher(&sub_keys.encryption)?
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("value of key version {} does not decrypt", version))
+    }
+
+    /// Whether a value is not encrypted with the current key, while
+    /// encryption is on
+    pub fn stale(&self, value: &str) -> bool {
+        self.current_version().is_some_and(|current| version_of(value) != Some(current))
+    }
+
+    /// The image of an entity with its encrypted fields encrypted, as kept in
+    /// the history and the audit log
+    pub fn seal_image(&self, entity: &str, image: &JsonValue) -> Result<JsonValue> {
+        let mut sealed = image.clone();
+        for (pointer, searchable) in fields(entity) {
+            if let Some(field) = sealed.pointer_mut(pointer) {
+                if let Some(value) = field.as_str() {
+                    *field = JsonValue::String(self.seal(&self.open(value)?, *searchable)?);
+                }
+            }
+        }
+        Ok(sealed)
+    }
+
+    /// Whether any encrypted field of an image is not encrypted with the
+    /// current key
+    pub fn stale_image(&self, entity: &str, image: &JsonValue) -> bool {
+        fields(entity)
+            .iter()
+            .filter_map(|(pointer, _)| image.pointer(pointer).and_then(JsonValue::as_str))
+            .any(|value| self.stale(value))
+    }
+
+    /// Decrypts every encrypted string of a JSON document in place, such as
+    /// the states of the history
+    pub fn open_json(&self, value: &mut JsonValue) -> Result<()> {
+        match value {
+            JsonValue::String(text) if text.starts_with(PREFIX) => *text = self.open(text)?,
+            JsonValue::Array(items) => items.iter_mut().try_for_each(|item| self.open_json(item))?,
+            JsonValue::Object(members) => members.values_mut().try_for_each(|member| self.open_json(member))?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const KEY_1: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_2: &str = "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f";
+
+    fn keys(configured: &str) -> Keys {
+        match Keys::parse(configured) {
+            Ok(keys) => keys,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let keys = keys(&format!("1:{}", KEY_1));
+        for searchable in [false, true] {
+            let sealed = keys.seal("Jürgen", searchable).unwrap();
+            assert!(sealed.starts_with("enc:1:"), "{}", sealed);
+            assert_eq!(keys.open(&sealed).unwrap(), "Jürgen");
+        }
+    }
+
+    #[test]
+    fn searchable_values_encrypt_equally() {
+        let keys = keys(&format!("1:{}", KEY_1));
+        assert_eq!(keys.seal("Main Street", true).unwrap(), keys.seal("Main Street", true).unwrap());
+        assert_ne!(keys.seal("Main Street", true).unwrap(), keys.seal("Elm Street", true).unwrap());
+        assert!(keys.probes("Main Street").unwrap().contains(&keys.seal("Main Street", true).unwrap()));
+    }
+
+    #[test]
+    fn other_values_encrypt_differently() {
+        let keys = keys(&format!("1:{}", KEY_1));
+        assert_ne!(keys.seal("John", false).unwrap(), keys.seal("John", false).unwrap());
+    }
+
+    #[test]
+    fn older_key_versions_still_decrypt() {
+        let sealed = keys(&format!("1:{}", KEY_1)).seal("Doe", false).unwrap();
+        let rotated = keys(&format!("1:{},2:{}", KEY_1, KEY_2));
+        assert_eq!(rotated.current_version(), Some(2));
+        assert!(rotated.stale(&sealed));
+        assert_eq!(rotated.open(&sealed).unwrap(), "Doe");
+        assert!(!rotated.stale(&rotated.seal("Doe", false).unwrap()));
+        assert!(keys(&format!("2:{}", KEY_2)).open(&sealed).is_err());
+    }
+
+    #[test]
+    fn the_configured_key_itself_neither_encrypts_nor_derives_nonces() {
+        let keys = keys(&format!("1:{}", KEY_1));
+        let sealed = hex::decode(keys.seal("Main Street", true).unwrap().trim_start_matches("enc:1:")).unwrap();
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let key = <[u8; 32]>::try_from(hex::decode(KEY_1).unwrap()).unwrap();
+        assert!(cipher(&key).unwrap().decrypt(Nonce::from_slice(nonce), ciphertext).is_err());
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key).unwrap();
+        mac.update(b"Main Street");
+        assert_ne!(&mac.finalize().into_bytes()[..NONCE_LEN], nonce);
+    }
+
+    #[test]
+    fn tampered_values_are_rejected() {
+        let keys = keys(&format!("1:{}", KEY_1));
+        let sealed = keys.seal("Doe", false).unwrap();
+        let last = sealed.chars().last().unwrap();
+        let tampered = format!("{}{}", &sealed[..sealed.len() - 1], if last == '0' { '1' } else { '0' });
+        assert!(keys.open(&tampered).is_err());
+        assert!(keys.open(&sealed[..sealed.len() - 2]).is_err());
+        assert!(keys.open("enc:1:00").is_err());
+        assert!(keys.open("enc:x:00").is_err());
+    }
+
+    #[test]
+    fn payloads_round_trip_under_the_current_key() {
+        let body = br#"[{"firstName":"J\u00fcrgen"}]"#;
+        let sealed = keys(&format!("1:{}", KEY_1)).seal_payload(body).unwrap();
+        assert!(sealed.starts_with(b"enc:1:"));
+        let rotated = keys(&format!("1:{},2:{}", KEY_1, KEY_2));
+        assert!(rotated.stale_payload(&sealed));
+        assert!(rotated.stale_payload(body));
+        assert_eq!(rotated.open_payload(&sealed).unwrap(), body);
+        assert!(!rotated.stale_payload(&rotated.seal_payload(body).unwrap()));
+        assert!(!rotated.stale_payload(b""));
+        assert_eq!(rotated.seal_payload(b"").unwrap(), b"");
+    }
+
+    #[test]
+    fn plain_values_pass_unchanged() {
+        let keys = keys("");
+        assert!(!keys.enabled());
+        assert_eq!(keys.seal("John", false).unwrap(), "John");
+        assert_eq!(keys.open("John").unwrap(), "John");
+        assert!(!keys.stale("John"));
+        assert_eq!(keys.seal_payload(b"{}").unwrap(), b"{}");
+        assert_eq!(keys.open_payload(b"{}").unwrap(), b"{}");
+        assert!(!keys.stale_payload(b"{}"));
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        for configured in [
+            KEY_1.to_string(),
+            format!("one:{}", KEY_1),
+            "1:0001".to_string(),
+            format!("1:{}zz", &KEY_1[..62]),
+        ] {
+            assert!(Keys::parse(&configured).is_err(), "{}", configured);
+        }
+        assert_eq!(keys(&format!(" 1 : {} , ", KEY_1)).current_version(), Some(1));
+    }
+
+    #[test]
+    fn images_encrypt_their_personal_fields() {
+        let keys = keys(&format!("1:{}", KEY_1));
+        let image = json!({
+            "id": "7", "firstName": "John", "lastName": "Doe", "address": { "street": "Main", "city": "Boston" }
+        });
+        let mut sealed = keys.seal_image("employees", &image).unwrap();
+        assert_eq!(sealed["id"], "7");
+        assert_eq!(sealed["address"]["city"], "Boston");
+        assert_eq!(version_of(sealed["address"]["street"].as_str().unwrap()), Some(1));
+        assert!(!keys.stale_image("employees", &sealed));
+        keys.open_json(&mut sealed).unwrap();
+        assert_eq!(sealed, image);
+    }
+}
@@ -0,0 +1,72 @@
+//! HTTP dates in the IMF-fixdate format, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`,
+//! as used by `Last-Modified` and `If-Modified-Since`
+
+use crate::time::{civil_from_days, days_from_civil};
+
+const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Formats unix time as an HTTP date
+pub fn format(secs: i64) -> String {
+    let days = secs.div_euclid(86_400);
+    let time = secs.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        DAYS[(days + 4).rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Unix time of an HTTP date, `None` when it is not an IMF-fixdate
+pub fn parse(date: &str) -> Option<i64> {
+    let (_, date) = date.trim().split_once(", ")?;
+    let mut parts = date.split(' ');
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|t| t.parse::<i64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+    if parts.next()? != "GMT" || parts.next().is_some() {
+        return None;
+    }
+    Some(days_from_civil(year, month, day) * 86_400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_times_format_as_imf_fixdates() {
+        assert_eq!(format(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format(784_111_777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format(951_782_400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(format(-1), "Wed, 31 Dec 1969 23:59:59 GMT");
+    }
+
+    #[test]
+    fn imf_fixdates_parse_as_unix_times() {
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784_111_777));
+        assert_eq!(parse(" Thu, 01 Jan 1970 00:00:00 GMT "), Some(0));
+        for secs in [0, 951_782_400, 1_792_400_000] {
+            assert_eq!(parse(&format(secs)), Some(secs));
+        }
+    }
+
+    #[test]
+    fn other_date_formats_are_rejected() {
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), None);
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT extra"), None);
+        assert_eq!(parse("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse(""), None);
+    }
+}
@@ -0,0 +1,8 @@
+//! Logic shared by the gateway, commands and queries components. It does
+//! not depend on Spin, so `cargo test` runs it natively.
+
+pub mod crypto;
+pub mod http_date;
+pub mod query;
+pub mod schema;
+pub mod time;
@@ -0,0 +1,68 @@
+//! Query strings, decoded the same way wherever a parameter is read.
+
+/// Parameters of a query string, such as `updatedSince` in
+/// `?updatedSince=2026-10-01`
+pub struct QueryString<'a>(pub &'a str);
+
+impl QueryString<'_> {
+    /// The percent-decoded value of the first parameter called `name`
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.0
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| decode(value))
+    }
+}
+
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_percent_decoded() {
+        let query = QueryString("name=J%C3%BCrgen%20M%C3%BCller&city=New+York&includeDeleted=%74rue");
+        assert_eq!(query.get("name").as_deref(), Some("Jürgen Müller"));
+        assert_eq!(query.get("city").as_deref(), Some("New York"));
+        assert_eq!(query.get("includeDeleted").as_deref(), Some("true"));
+    }
+
+    #[test]
+    fn the_first_parameter_of_a_name_wins() {
+        let query = QueryString("limit=5&limit=10&x=");
+        assert_eq!(query.get("limit").as_deref(), Some("5"));
+        assert_eq!(query.get("x").as_deref(), Some(""));
+        assert_eq!(query.get("missing"), None);
+    }
+
+    #[test]
+    fn malformed_escapes_are_kept() {
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%zz%4"), "%zz%4");
+        assert_eq!(decode("%2B+"), "+ ");
+    }
+}
@@ -0,0 +1,73 @@
+//! The database schema both components expect, read from migrations.sql so
+//! a new migration only has to be added there.
+
+const MIGRATIONS: &str = include_str!("../../migrations.sql");
+
+/// Schema version this build expects: the last version migrations.sql
+/// records in `SchemaMigrations`
+pub fn expected_version() -> i64 {
+    versions(MIGRATIONS).into_iter().max().unwrap_or_default()
+}
+
+/// Compares the version of the last migration applied with the expected one
+pub fn check(applied: Option<i64>) -> Result<(), String> {
+    let expected = expected_version();
+    match applied {
+        Some(version) if version == expected => Ok(()),
+        Some(version) => Err(format!("expected schema version {}, found {}", expected, version)),
+        None => Err("no migration applied".to_string()),
+    }
+}
+
+/// Versions of the `INSERT INTO SchemaMigrations(Version, Name) SELECT <version>, '<name>'`
+/// statements of a migration script
+fn versions(sql: &str) -> Vec<i64> {
+    let mut lines = sql.lines().map(str::trim);
+    let mut versions = Vec::new();
+    while let Some(line) = lines.next() {
+        if !line.starts_with("INSERT INTO SchemaMigrations") {
+            continue;
+        }
+        let version = lines
+            .next()
+            .and_then(|select| select.strip_prefix("SELECT "))
+            .and_then(|select| select.split(',').next())
+            .and_then(|version| version.trim().parse::<i64>().ok());
+        versions.extend(version);
+    }
+    versions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_of_a_migration_script() {
+        let sql = "CREATE TABLE IF NOT EXISTS SchemaMigrations (Version INTEGER);\n\
+                   INSERT INTO SchemaMigrations(Version, Name)\n\
+                   SELECT 1, 'initial schema'\n\
+                   WHERE NOT EXISTS (SELECT Version FROM SchemaMigrations WHERE Version = 1);\n\
+                   INSERT INTO SchemaMigrations(Version, Name)\n\
+                   SELECT 2, 'command queue'\n\
+                   WHERE NOT EXISTS (SELECT Version FROM SchemaMigrations WHERE Version = 2);\n";
+        assert_eq!(versions(sql), vec![1, 2]);
+    }
+
+    #[test]
+    fn migrations_are_numbered_without_gaps() {
+        let versions = versions(MIGRATIONS);
+        let expected: Vec<i64> = (1..=versions.len() as i64).collect();
+        assert_eq!(versions, expected);
+        assert_eq!(expected_version(), versions.len() as i64);
+    }
+
+    #[test]
+    fn check_compares_the_applied_version() {
+        let expected = expected_version();
+        assert_eq!(check(Some(expected)), Ok(()));
+        assert_eq!(check(Some(expected - 1)),
+                   Err(format!("expected schema version {}, found {}", expected, expected - 1)));
+        assert!(check(None).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! Civil dates of unix time in the proleptic Gregorian calendar, after
+//! Howard Hinnant's `days_from_civil` and `civil_from_days`.
+
+/// UTC time such as `2026-10-19T08:30:00Z`, as commands stamp changes; it
+/// sorts as text
+pub fn timestamp(secs: i64) -> String {
+    let time = secs.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Checks that `value` of the field or parameter `name` is a UTC date
+/// (`2026-10-01`) or time (`2026-10-01T08:30:00Z`) as stamps are written, so
+/// that it compares with them as text
+pub fn check_utc_time(name: &str, value: &str) -> Result<(), String> {
+    let pattern = match value.len() {
+        10 => "dddd-dd-dd",
+        20 => "dddd-dd-ddTdd:dd:ddZ",
+        _ => "",
+    };
+    let valid = !pattern.is_empty()
+        && value.bytes().zip(pattern.bytes()).all(|(c, p)| if p == b'd' { c.is_ascii_digit() } else { c == p });
+    match valid {
+        true => Ok(()),
+        false => Err(format!("{} must be a date like 2026-10-01 or a UTC time like 2026-10-01T08:30:00Z", name)),
+    }
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Year, month and day of the days since 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_epoch_is_day_zero() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(timestamp(0), "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn leap_days_exist_in_leap_years_only() {
+        assert_eq!(days_from_civil(2000, 2, 29), 11_016);
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+        // 1900 and 2100 are not leap years, 2000 is
+        assert_eq!(civil_from_days(days_from_civil(1900, 2, 28) + 1), (1900, 3, 1));
+        assert_eq!(civil_from_days(days_from_civil(2100, 2, 28) + 1), (2100, 3, 1));
+        assert_eq!(civil_from_days(days_from_civil(2023, 2, 28) + 1), (2023, 3, 1));
+    }
+
+    #[test]
+    fn dates_round_trip() {
+        for days in -800_000..800_000 {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn utc_dates_and_times_are_accepted() {
+        assert_eq!(check_utc_time("asOf", "2026-10-01"), Ok(()));
+        assert_eq!(check_utc_time("asOf", "2026-10-01T08:30:00Z"), Ok(()));
+        assert_eq!(check_utc_time("asOf", &timestamp(0)), Ok(()));
+    }
+
+    #[test]
+    fn other_times_are_rejected() {
+        for value in ["", "2026-1-01", "2026/10/01", "2026-10-01T08:30:00", "2026-10-01T08:30:00+02:00",
+                      "2026-10-01 08:30:00Z", "yyyy-mm-dd", "２０２６-10-01"] {
+            let error = check_utc_time("asOf", value).expect_err(value);
+            assert!(error.starts_with("asOf must be a date"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn timestamps_show_the_time_of_day() {
+        assert_eq!(timestamp(951_827_696), "2000-02-29T12:34:56Z");
+        assert_eq!(timestamp(1_792_368_000 - 1), "2026-10-18T23:59:59Z");
+    }
+}
@@ -0,0 +1,77 @@
+use serde::Serialize;
+use spin_sdk::http::{Request, Response};
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const TRACEPARENT_HEADER: &str = "traceparent";
+const CLIENT_HEADER: &str = "x-api-client";
+const SCOPES_HEADER: &str = "x-api-scopes";
+
+/// Correlation and caller headers forwarded by the gateway with every
+/// request to `commands` and `queries`
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub request_id: String,
+    pub traceparent: Option<String>,
+    /// API key id of the caller, `anonymous` without a key
+    pub client: Option<String>,
+    /// scopes the gateway granted the caller. `None` for calls that did not
+    /// come through the gateway, which `commands` refuses.
+    pub scopes: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct ErrorModel<'a> {
+    status: u16,
+    error: &'a str,
+    #[serde(rename = "requestId")]
+    request_id: &'a str,
+}
+
+impl RequestContext {
+    pub fn from_request(req: &Request) -> RequestContext {
+        let header = |name: &str| {
+            req.header(name)
+                .and_then(|v| v.as_str())
+                .map(String::from)
+        };
+        RequestContext {
+            request_id: header(REQUEST_ID_HEADER).unwrap_or_else(|| "-".to_string()),
+            traceparent: header(TRACEPARENT_HEADER),
+            client: header(CLIENT_HEADER),
+            scopes: header(SCOPES_HEADER)
+                .map(|scopes| scopes.split(',').map(|s| s.trim().to_string()).collect()),
+        }
+    }
+
+    /// Logs a `tracing` event tagged with the request id
+    pub fn log(&self, message: impl std::fmt::Display) {
+        tracing::info!(request_id = %self.request_id, "{}", message);
+    }
+
+    /// Logs failed requests, wraps their error text in a JSON body carrying
+    /// the request id and echoes the correlation headers.
+    pub fn finish(&self, component: &str, mut res: Response) -> Response {
+        let status = *res.status();
+        if status >= 500 {
+            let text = String::from_utf8_lossy(res.body()).trim().to_string();
+            self.log(format!("{component}: {status} {text}"));
+        }
+        if status >= 500 && res.header("content-type").is_none() {
+            let text = String::from_utf8_lossy(res.body()).trim().to_string();
+            let error = ErrorModel {
+                status,
+                error: if text.is_empty() { "Internal Server Error" } else { &text },
+                request_id: &self.request_id,
+            };
+            if let Ok(body) = serde_json::to_vec(&error) {
+                *res.body_mut() = body;
+                res.set_header("Content-Type", "application/json");
+            }
+        }
+        res.set_header("X-Request-Id", self.request_id.clone());
+        if let Some(traceparent) = &self.traceparent {
+            res.set_header("traceparent", traceparent.clone());
+        }
+        res
+    }
+}
@@ -0,0 +1,253 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::{new_span_id, unix_nanos, TraceParent};
+
+/// OTLP span kinds set through the `otel.kind` field
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+const KIND_CLIENT: u8 = 3;
+
+/// A span still open, kept in the registry's span extensions
+pub(crate) struct SpanData {
+    pub trace_id: String,
+    pub span_id: String,
+    parent_span_id: Option<String>,
+    name: &'static str,
+    kind: u8,
+    start: u64,
+    attributes: Vec<(String, Value)>,
+    error: Option<String>,
+}
+
+/// A finished span waiting to be exported
+#[derive(Debug, Clone)]
+pub(crate) struct SpanRecord {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub name: &'static str,
+    pub kind: u8,
+    pub start: u64,
+    pub end: u64,
+    pub attributes: Vec<(String, Value)>,
+    pub error: Option<String>,
+}
+
+/// `tracing` layer turning spans into OTLP span records. The first root span
+/// takes the component's span id from the trace context, so the id forwarded
+/// to the caller matches an exported span.
+pub(crate) struct SpanCollector {
+    trace: TraceParent,
+    root_taken: AtomicBool,
+    spans: Arc<Mutex<Vec<SpanRecord>>>,
+}
+
+impl SpanCollector {
+    pub(crate) fn new(trace: TraceParent, spans: Arc<Mutex<Vec<SpanRecord>>>) -> SpanCollector {
+        SpanCollector {
+            trace,
+            root_taken: AtomicBool::new(false),
+            spans,
+        }
+    }
+}
+
+impl<S> Layer<S> for SpanCollector
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span
+            .parent()
+            .and_then(|p| p.extensions().get::<SpanData>().map(|d| d.span_id.clone()));
+        let (span_id, parent_span_id) = match parent {
+            Some(parent) => (new_span_id(), Some(parent)),
+            None if !self.root_taken.swap(true, Ordering::Relaxed) => {
+                (self.trace.span_id.clone(), self.trace.parent_span_id.clone())
+            }
+            None => (new_span_id(), Some(self.trace.span_id.clone())),
+        };
+
+        let mut data = SpanData {
+            trace_id: self.trace.trace_id.clone(),
+            span_id,
+            parent_span_id,
+            name: attrs.metadata().name(),
+            kind: KIND_INTERNAL,
+            start: unix_nanos(),
+            attributes: Vec::new(),
+            error: None,
+        };
+        attrs.record(&mut FieldVisitor(&mut data));
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut FieldVisitor(data));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        if let Some(span) = ctx.event_span(event) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                let mut message = MessageVisitor(None);
+                event.record(&mut message);
+                data.error = Some(message.0.unwrap_or_else(|| "error".to_string()));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        let record = SpanRecord {
+            trace_id: data.trace_id,
+            span_id: data.span_id,
+            parent_span_id: data.parent_span_id,
+            name: data.name,
+            kind: data.kind,
+            start: data.start,
+            end: unix_nanos(),
+            attributes: data.attributes,
+            error: data.error,
+        };
+        if let Ok(mut spans) = self.spans.lock() {
+            spans.push(record);
+        }
+    }
+}
+
+/// Records span fields as OTLP attributes. `otel.kind` sets the span kind and
+/// a `http.response.status_code` of 500 or more marks the span as failed.
+struct FieldVisitor<'a>(&'a mut SpanData);
+
+impl FieldVisitor<'_> {
+    fn set(&mut self, field: &Field, value: Value) {
+        let name = field.name().to_string();
+        match self.0.attributes.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, v)) => *v = value,
+            None => self.0.attributes.push((name, value)),
+        }
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "otel.kind" {
+            self.0.kind = match value {
+                "server" => KIND_SERVER,
+                "client" => KIND_CLIENT,
+                _ => KIND_INTERNAL,
+            };
+            return;
+        }
+        self.set(field, json!({ "stringValue": value }));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if field.name() == "http.response.status_code" && value >= 500 {
+            self.0.error = Some(format!("status {value}"));
+        }
+        self.set(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record_i64(field, value as i64);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, json!({ "boolValue": value }));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field, json!({ "doubleValue": value }));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{value:?}"));
+    }
+}
+
+/// `tracing` layer writing events of level `INFO` and above to standard
+/// output, which Spin keeps as the component log, tagged with the request id
+/// of the event or of the span it happened in
+pub(crate) struct LogWriter;
+
+/// Request id recorded by a span with a `request_id` field
+struct RequestId(String);
+
+impl<S> Layer<S> for LogWriter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut request_id = RequestIdVisitor(None);
+        attrs.record(&mut request_id);
+        if let (Some(span), Some(request_id)) = (ctx.span(id), request_id.0) {
+            span.extensions_mut().insert(RequestId(request_id));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if *event.metadata().level() > Level::INFO {
+            return;
+        }
+        let mut message = MessageVisitor(None);
+        event.record(&mut message);
+        let mut request_id = RequestIdVisitor(None);
+        event.record(&mut request_id);
+        let request_id = request_id.0.or_else(|| {
+            ctx.event_scope(event)?
+                .find_map(|span| span.extensions().get::<RequestId>().map(|id| id.0.clone()))
+        });
+        println!("[{}] {}", request_id.as_deref().unwrap_or("-"), message.0.unwrap_or_default());
+    }
+}
+
+struct RequestIdVisitor(Option<String>);
+
+impl Visit for RequestIdVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "request_id" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "request_id" {
+            self.0 = Some(format!("{value:?}"));
+        }
+    }
+}
+
+struct MessageVisitor(Option<String>);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.0 = Some(format!("{value:?}"));
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Trace and metrics export shared by the gateway, commands and queries
+//! components.
+//!
+//! Spans created through `tracing` while a request is handled are collected
+//! in memory and, together with the request metrics, exported to an OTLP/HTTP
+//! collector when the request is done. Export is enabled by setting the
+//! `otel_exporter_otlp_endpoint` Spin variable, e.g. `http://localhost:4318`.
+//!
+//! `RequestContext` carries the correlation headers between the components.
+//! `tracing` events are written to the component log with the request id of
+//! the span they happened in.
+
+mod context;
+mod layer;
+mod otlp;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub use context::RequestContext;
+use layer::{LogWriter, SpanCollector, SpanData, SpanRecord};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+use uuid::Uuid;
+
+const ENDPOINT_VARIABLE: &str = "otel_exporter_otlp_endpoint";
+
+/// W3C trace context of the request being handled by a component
+#[derive(Debug, Clone)]
+pub struct TraceParent {
+    /// trace identifier, 32 hex digits
+    pub trace_id: String,
+    /// span of the caller, if the request arrived with a `traceparent`
+    pub parent_span_id: Option<String>,
+    /// span of the component handling the request, 16 hex digits
+    pub span_id: String,
+    /// whether the caller sampled the trace
+    pub sampled: bool,
+}
+
+impl TraceParent {
+    /// Continues the trace of a `traceparent` header, or starts a new sampled
+    /// trace when the header is absent or malformed.
+    pub fn from_header(value: Option<&str>) -> TraceParent {
+        match value.and_then(parse_traceparent) {
+            Some((trace_id, parent_span_id, flags)) => TraceParent {
+                trace_id,
+                parent_span_id: Some(parent_span_id),
+                span_id: new_span_id(),
+                sampled: u8::from_str_radix(&flags, 16).map(|f| f & 1 == 1).unwrap_or(false),
+            },
+            None => TraceParent {
+                trace_id: Uuid::new_v4().simple().to_string(),
+                parent_span_id: None,
+                span_id: new_span_id(),
+                sampled: true,
+            },
+        }
+    }
+
+    /// The `traceparent` header naming this component's span as parent
+    pub fn header(&self) -> String {
+        format_traceparent(&self.trace_id, &self.span_id, self.sampled)
+    }
+}
+
+/// Parses `version-traceid-parentid-flags`, returning the trace id, the
+/// parent span id and the flags.
+pub fn parse_traceparent(value: &str) -> Option<(String, String, String)> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    let [version, trace_id, parent_id, flags] = parts.as_slice() else {
+        return None;
+    };
+    let hex = |s: &str, len: usize| s.len() == len && s.chars().all(|c| c.is_ascii_hexdigit());
+    let zero = |s: &str| s.chars().all(|c| c == '0');
+    if !hex(version, 2) || *version == "ff" || !hex(flags, 2) {
+        return None;
+    }
+    if !hex(trace_id, 32) || zero(trace_id) || !hex(parent_id, 16) || zero(parent_id) {
+        return None;
+    }
+    Some((
+        trace_id.to_lowercase(),
+        parent_id.to_lowercase(),
+        flags.to_lowercase(),
+    ))
+}
+
+fn format_traceparent(trace_id: &str, span_id: &str, sampled: bool) -> String {
+    format!("00-{}-{}-{}", trace_id, span_id, if sampled { "01" } else { "00" })
+}
+
+/// A new random span identifier, 16 hex digits
+pub fn new_span_id() -> String {
+    Uuid::new_v4().simple().to_string()[..16].to_string()
+}
+
+/// The `traceparent` naming the current `tracing` span as parent, so calls to
+/// other components nest under the span making them. `None` when no span is
+/// being collected.
+pub fn current_traceparent() -> Option<String> {
+    tracing::dispatcher::get_default(|dispatch| {
+        let registry = dispatch.downcast_ref::<Registry>()?;
+        let id = tracing::Span::current().id()?;
+        let span = registry.span(&id)?;
+        let extensions = span.extensions();
+        let data = extensions.get::<SpanData>()?;
+        Some(format_traceparent(&data.trace_id, &data.span_id, true))
+    })
+}
+
+/// One request handled by a component, exported as request metrics
+#[derive(Debug, Clone)]
+pub(crate) struct RequestMetric {
+    pub route: String,
+    pub method: String,
+    pub status: u16,
+    pub duration: Duration,
+    pub time: u64,
+}
+
+/// Collects the spans and metrics of one request and exports them
+pub struct Telemetry {
+    service: &'static str,
+    endpoint: Option<String>,
+    trace: TraceParent,
+    spans: Arc<Mutex<Vec<SpanRecord>>>,
+    requests: Mutex<Vec<RequestMetric>>,
+}
+
+impl Telemetry {
+    pub fn new(service: &'static str, trace: TraceParent) -> Telemetry {
+        let endpoint = spin_sdk::variables::get(ENDPOINT_VARIABLE)
+            .ok()
+            .map(|v| v.trim().trim_end_matches('/').to_string())
+            .filter(|v| !v.is_empty());
+        Telemetry {
+            service,
+            endpoint,
+            trace,
+            spans: Arc::new(Mutex::new(Vec::new())),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn trace(&self) -> &TraceParent {
+        &self.trace
+    }
+
+    /// Runs `f` with its `tracing` events logged and, if the trace is sampled
+    /// and an endpoint is configured, its spans collected.
+    pub fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        let collector = (self.endpoint.is_some() && self.trace.sampled)
+            .then(|| SpanCollector::new(self.trace.clone(), self.spans.clone()));
+        let subscriber = Registry::default().with(LogWriter).with(collector);
+        tracing::subscriber::with_default(subscriber, f)
+    }
+
+    /// Records a handled request for the request count, latency and error
+    /// metrics of `route`.
+    pub fn record_request(&self, route: &str, method: &str, status: u16, duration: Duration) {
+        if self.endpoint.is_none() {
+            return;
+        }
+        if let Ok(mut requests) = self.requests.lock() {
+            requests.push(RequestMetric {
+                route: route.to_string(),
+                method: method.to_string(),
+                status,
+                duration,
+                time: unix_nanos(),
+            });
+        }
+    }
+
+    /// Exports everything collected. Export failures are logged and never
+    /// affect the response.
+    pub fn flush(self) {
+        let Some(endpoint) = self.endpoint else {
+            return;
+        };
+        let spans = std::mem::take(&mut *self.spans.lock().unwrap_or_else(|e| e.into_inner()));
+        let requests = self.requests.into_inner().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = otlp::export(&endpoint, self.service, &spans, &requests) {
+            // the request's spans are closed, so the failure is logged on its own
+            tracing::subscriber::with_default(Registry::default().with(LogWriter), || {
+                tracing::warn!("{}: telemetry export failed: {}", self.service, e)
+            });
+        }
+    }
+}
+
+pub(crate) fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn traceparent_is_parsed() {
+        let parsed = parse_traceparent(&format!(" 00-{TRACE_ID}-{PARENT_ID}-01 "));
+        assert_eq!(parsed, Some((TRACE_ID.to_string(), PARENT_ID.to_string(), "01".to_string())));
+    }
+
+    #[test]
+    fn upper_case_ids_are_lowered() {
+        let header = format!("00-{}-{}-0A", TRACE_ID.to_uppercase(), PARENT_ID.to_uppercase());
+        let parsed = parse_traceparent(&header);
+        assert_eq!(parsed, Some((TRACE_ID.to_string(), PARENT_ID.to_string(), "0a".to_string())));
+    }
+
+    #[test]
+    fn malformed_traceparents_are_rejected() {
+        let zero_trace = "0".repeat(32);
+        let zero_parent = "0".repeat(16);
+        for header in [
+            String::new(),
+            "garbage".to_string(),
+            format!("00-{TRACE_ID}-{PARENT_ID}"),
+            format!("00-{TRACE_ID}-{PARENT_ID}-01-extra"),
+            format!("ff-{TRACE_ID}-{PARENT_ID}-01"),
+            format!("0-{TRACE_ID}-{PARENT_ID}-01"),
+            format!("00-{}-{PARENT_ID}-01", &TRACE_ID[1..]),
+            format!("00-{TRACE_ID}-{}x-01", &PARENT_ID[1..]),
+            format!("00-{TRACE_ID}-{PARENT_ID}-1"),
+            format!("00-{zero_trace}-{PARENT_ID}-01"),
+            format!("00-{TRACE_ID}-{zero_parent}-01"),
+        ] {
+            assert_eq!(parse_traceparent(&header), None, "{header}");
+        }
+    }
+
+    #[test]
+    fn trace_is_continued_from_a_valid_header() {
+        let trace = TraceParent::from_header(Some(&format!("00-{TRACE_ID}-{PARENT_ID}-00")));
+        assert_eq!(trace.trace_id, TRACE_ID);
+        assert_eq!(trace.parent_span_id.as_deref(), Some(PARENT_ID));
+        assert_ne!(trace.span_id, PARENT_ID);
+        assert!(!trace.sampled);
+        assert_eq!(trace.header(), format!("00-{TRACE_ID}-{}-00", trace.span_id));
+    }
+
+    #[test]
+    fn new_sampled_trace_without_a_valid_header() {
+        let zero_trace = format!("00-{}-{PARENT_ID}-01", "0".repeat(32));
+        for header in [None, Some("garbage"), Some(zero_trace.as_str())] {
+            let trace = TraceParent::from_header(header);
+            assert_ne!(trace.trace_id, TRACE_ID);
+            assert_eq!(trace.trace_id.len(), 32);
+            assert_eq!(trace.span_id.len(), 16);
+            assert_eq!(trace.parent_span_id, None);
+            assert!(trace.sampled);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use spin_sdk::http::{send, Request, Response};
+
+use crate::layer::SpanRecord;
+use crate::RequestMetric;
+
+const SCOPE_NAME: &str = "cqrs-with-components";
+
+/// Histogram bucket bounds for request latency, in milliseconds
+const LATENCY_BOUNDS_MS: [f64; 10] = [5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0];
+
+/// OTLP aggregation temporality: every export covers only the request that
+/// produced it, so all metrics are deltas.
+const TEMPORALITY_DELTA: u8 = 1;
+
+/// OTLP status codes
+const STATUS_OK: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+
+pub(crate) fn export(
+    endpoint: &str,
+    service: &str,
+    spans: &[SpanRecord],
+    requests: &[RequestMetric],
+) -> Result<()> {
+    let mut payloads = Vec::new();
+    if !spans.is_empty() {
+        payloads.push((format!("{endpoint}/v1/traces"), traces(service, spans)));
+    }
+    if !requests.is_empty() {
+        payloads.push((format!("{endpoint}/v1/metrics"), metrics(service, requests)));
+    }
+    if payloads.is_empty() {
+        return Ok(());
+    }
+
+    spin_sdk::http::run(async move {
+        for (url, payload) in payloads {
+            let req = Request::post(url.as_str(), serde_json::to_vec(&payload)?)
+                .header("Content-Type", "application/json")
+                .build();
+            let res: Response = send(req).await?;
+            if !(200..300).contains(res.status()) {
+                bail!("{} answered {}", url, res.status());
+            }
+        }
+        Ok(())
+    })
+}
+
+fn resource(service: &str) -> Value {
+    json!({
+        "attributes": [
+            { "key": "service.name", "value": { "stringValue": service } },
+            { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } }
+        ]
+    })
+}
+
+fn attributes(attributes: &[(String, Value)]) -> Vec<Value> {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": value }))
+        .collect()
+}
+
+fn traces(service: &str, spans: &[SpanRecord]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let status = match &span.error {
+                Some(message) => json!({ "code": STATUS_ERROR, "message": message }),
+                None => json!({ "code": STATUS_OK }),
+            };
+            json!({
+                "traceId": span.trace_id,
+                "spanId": span.span_id,
+                "parentSpanId": span.parent_span_id.clone().unwrap_or_default(),
+                "name": span.name,
+                "kind": span.kind,
+                "startTimeUnixNano": span.start.to_string(),
+                "endTimeUnixNano": span.end.to_string(),
+                "attributes": attributes(&span.attributes),
+                "status": status,
+            })
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": resource(service),
+            "scopeSpans": [{ "scope": { "name": SCOPE_NAME }, "spans": spans }]
+        }]
+    })
+}
+
+fn metrics(service: &str, requests: &[RequestMetric]) -> Value {
+    let mut count = Vec::new();
+    let mut errors = Vec::new();
+    let mut latency = Vec::new();
+    for request in requests {
+        let attrs = attributes(&[
+            ("http.route".to_string(), json!({ "stringValue": request.route })),
+            ("http.request.method".to_string(), json!({ "stringValue": request.method })),
+            ("http.response.status_code".to_string(), json!({ "intValue": request.status.to_string() })),
+        ]);
+        let start = request.time.saturating_sub(request.duration.as_nanos() as u64);
+        let point = |value: &str| {
+            json!({
+                "attributes": attrs,
+                "startTimeUnixNano": start.to_string(),
+                "timeUnixNano": request.time.to_string(),
+                "asInt": value,
+            })
+        };
+        count.push(point("1"));
+        if request.status >= 500 {
+            errors.push(point("1"));
+        }
+
+        let millis = request.duration.as_secs_f64() * 1000.0;
+        let mut buckets = vec![0u64; LATENCY_BOUNDS_MS.len() + 1];
+        let bucket = LATENCY_BOUNDS_MS
+            .iter()
+            .position(|bound| millis <= *bound)
+            .unwrap_or(LATENCY_BOUNDS_MS.len());
+        buckets[bucket] = 1;
+        latency.push(json!({
+            "attributes": attrs,
+            "startTimeUnixNano": start.to_string(),
+            "timeUnixNano": request.time.to_string(),
+            "count": "1",
+            "sum": millis,
+            "bucketCounts": buckets.iter().map(|b| b.to_string()).collect::<Vec<_>>(),
+            "explicitBounds": LATENCY_BOUNDS_MS,
+        }));
+    }
+
+    let sum = |name: &str, points: Vec<Value>| {
+        json!({
+            "name": name,
+            "unit": "1",
+            "sum": {
+                "aggregationTemporality": TEMPORALITY_DELTA,
+                "isMonotonic": true,
+                "dataPoints": points,
+            }
+        })
+    };
+    let mut metrics = vec![
+        sum("http.server.request.count", count),
+        json!({
+            "name": "http.server.request.duration",
+            "unit": "ms",
+            "histogram": {
+                "aggregationTemporality": TEMPORALITY_DELTA,
+                "dataPoints": latency,
+            }
+        }),
+    ];
+    if !errors.is_empty() {
+        metrics.push(sum("http.server.request.errors", errors));
+    }
+
+    json!({
+        "resourceMetrics": [{
+            "resource": resource(service),
+            "scopeMetrics": [{ "scope": { "name": SCOPE_NAME }, "metrics": metrics }]
+        }]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn span(error: Option<&str>) -> SpanRecord {
+        SpanRecord {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+            span_id: "00f067aa0ba902b7".to_string(),
+            parent_span_id: None,
+            name: "handle_gateway",
+            kind: 2,
+            start: 1_000,
+            end: 2_000,
+            attributes: vec![("http.route".to_string(), json!({ "stringValue": "/persons" }))],
+            error: error.map(String::from),
+        }
+    }
+
+    fn request(status: u16, millis: u64) -> RequestMetric {
+        RequestMetric {
+            route: "/persons".to_string(),
+            method: "GET".to_string(),
+            status,
+            duration: Duration::from_millis(millis),
+            time: 2_000_000_000,
+        }
+    }
+
+    #[test]
+    fn spans_are_encoded_with_their_status() {
+        let payload = traces("gateway", &[span(None), span(Some("status 500"))]);
+        let resource = &payload["resourceSpans"][0];
+        assert_eq!(resource["resource"]["attributes"][0]["value"]["stringValue"], "gateway");
+        let spans = &resource["scopeSpans"][0]["spans"];
+        assert_eq!(spans[0]["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(spans[0]["parentSpanId"], "");
+        assert_eq!(spans[0]["kind"], 2);
+        assert_eq!(spans[0]["startTimeUnixNano"], "1000");
+        assert_eq!(spans[0]["endTimeUnixNano"], "2000");
+        let route = json!({ "key": "http.route", "value": { "stringValue": "/persons" } });
+        assert_eq!(spans[0]["attributes"][0], route);
+        assert_eq!(spans[0]["status"], json!({ "code": STATUS_OK }));
+        assert_eq!(spans[1]["status"], json!({ "code": STATUS_ERROR, "message": "status 500" }));
+    }
+
+    #[test]
+    fn requests_are_encoded_as_delta_metrics() {
+        let payload = metrics("queries", &[request(200, 30), request(503, 7_000)]);
+        let metrics = &payload["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["name"], "http.server.request.count");
+        assert_eq!(metrics[0]["sum"]["aggregationTemporality"], TEMPORALITY_DELTA);
+        let count = &metrics[0]["sum"]["dataPoints"][0];
+        assert_eq!(count["asInt"], "1");
+        assert_eq!(count["startTimeUnixNano"], "1970000000");
+        assert_eq!(count["timeUnixNano"], "2000000000");
+
+        let latency = &metrics[1]["histogram"]["dataPoints"];
+        let buckets = |point: &Value| point["bucketCounts"].as_array().unwrap().clone();
+        assert_eq!(buckets(&latency[0])[3], "1");
+        assert_eq!(buckets(&latency[0]).iter().filter(|b| *b == "1").count(), 1);
+        assert_eq!(buckets(&latency[1])[LATENCY_BOUNDS_MS.len()], "1");
+        assert_eq!(latency[1]["sum"], 7000.0);
+
+        assert_eq!(metrics[2]["name"], "http.server.request.errors");
+        let errors = metrics[2]["sum"]["dataPoints"].as_array().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["attributes"][2]["value"]["intValue"], "503");
+    }
+
+    #[test]
+    fn error_metric_only_when_requests_failed() {
+        let payload = metrics("queries", &[request(404, 1)]);
+        let metrics = &payload["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics.as_array().map(Vec::len), Some(2));
+    }
+}
@@ -0,0 +1,33 @@
+#!/usr/bin/env python3
+"""Minimal stand-in for an OTLP/HTTP collector.
+
+Accepts JSON posted to /v1/traces and /v1/metrics on port 4318 and prints it,
+so span and metric export can be checked without running a real collector:
+
+    python3 tools/otlp_stand_in.py
+    SPIN_VARIABLE_OTEL_EXPORTER_OTLP_ENDPOINT=http://localhost:4318 spin up
+"""
+
+import json
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+
+class Handler(BaseHTTPRequestHandler):
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers.get("Content-Length", 0)))
+        try:
+            payload = json.dumps(json.loads(body), indent=2)
+        except ValueError:
+            payload = body.decode("utf-8", "replace")
+        print(f"--- {self.path}\n{payload}", flush=True)
+        self.send_response(200)
+        self.send_header("Content-Type", "application/json")
+        self.end_headers()
+        self.wfile.write(b"{}")
+
+    def log_message(self, *args):
+        pass
+
+
+if __name__ == "__main__":
+    HTTPServer(("127.0.0.1", 4318), Handler).serve_forever()